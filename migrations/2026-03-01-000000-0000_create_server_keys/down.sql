@@ -0,0 +1,2 @@
+DROP TABLE server_key_alerts;
+DROP TABLE server_keys;
@@ -0,0 +1,25 @@
+CREATE TABLE server_keys (
+    id BIGSERIAL PRIMARY KEY,
+    server_id BIGINT NOT NULL REFERENCES servers(id) ON DELETE CASCADE,
+    key_id TEXT NOT NULL,
+    public_key TEXT NOT NULL,
+    valid_until_ts BIGINT,
+    expired_ts BIGINT,
+    is_current BOOLEAN NOT NULL DEFAULT TRUE,
+    signature_valid BOOLEAN NOT NULL DEFAULT FALSE,
+    first_seen_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_seen_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (server_id, key_id, public_key)
+);
+
+CREATE TABLE server_key_alerts (
+    id BIGSERIAL PRIMARY KEY,
+    server_id BIGINT NOT NULL REFERENCES servers(id) ON DELETE CASCADE,
+    key_id TEXT,
+    kind TEXT NOT NULL,
+    message TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_server_keys_server_id ON server_keys(server_id);
+CREATE INDEX idx_server_key_alerts_server_id ON server_key_alerts(server_id);
@@ -0,0 +1 @@
+DROP TABLE well_known_findings;
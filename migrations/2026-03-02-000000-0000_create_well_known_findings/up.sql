@@ -0,0 +1,12 @@
+CREATE TABLE well_known_findings (
+    id BIGSERIAL PRIMARY KEY,
+    server_id BIGINT NOT NULL REFERENCES servers(id) ON DELETE CASCADE,
+    document TEXT NOT NULL,
+    severity TEXT NOT NULL,
+    code TEXT NOT NULL,
+    message TEXT NOT NULL,
+    checked_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_well_known_findings_server_id ON well_known_findings(server_id);
+CREATE INDEX idx_well_known_findings_severity ON well_known_findings(severity);
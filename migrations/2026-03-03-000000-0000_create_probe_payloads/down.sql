@@ -0,0 +1 @@
+DROP TABLE probe_payloads;
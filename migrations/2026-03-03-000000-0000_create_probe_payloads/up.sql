@@ -0,0 +1,14 @@
+CREATE TABLE probe_payloads (
+    id BIGSERIAL PRIMARY KEY,
+    server_id BIGINT NOT NULL REFERENCES servers(id) ON DELETE CASCADE,
+    probe TEXT NOT NULL,
+    url TEXT NOT NULL,
+    status INTEGER,
+    headers JSONB NOT NULL DEFAULT '{}'::jsonb,
+    body JSONB,
+    error TEXT,
+    fetched_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_probe_payloads_server_probe_fetched
+    ON probe_payloads(server_id, probe, fetched_at DESC);
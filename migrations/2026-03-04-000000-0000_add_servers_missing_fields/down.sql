@@ -0,0 +1 @@
+ALTER TABLE servers DROP COLUMN missing_fields;
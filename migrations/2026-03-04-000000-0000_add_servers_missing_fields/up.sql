@@ -0,0 +1 @@
+ALTER TABLE servers ADD COLUMN missing_fields JSONB NOT NULL DEFAULT '[]'::jsonb;
@@ -0,0 +1 @@
+ALTER TABLE probe_payloads DROP COLUMN duration_ms;
@@ -0,0 +1 @@
+ALTER TABLE probe_payloads ADD COLUMN duration_ms INTEGER;
@@ -0,0 +1 @@
+ALTER TABLE servers ADD COLUMN client_api_version TEXT;
@@ -0,0 +1,3 @@
+ALTER TABLE servers DROP COLUMN unstable_features;
+ALTER TABLE servers DROP COLUMN latest_spec_version;
+ALTER TABLE servers DROP COLUMN spec_versions;
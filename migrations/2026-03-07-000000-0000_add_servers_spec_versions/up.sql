@@ -0,0 +1,6 @@
+ALTER TABLE servers ADD COLUMN spec_versions TEXT[] NOT NULL DEFAULT '{}';
+ALTER TABLE servers ADD COLUMN latest_spec_version TEXT;
+ALTER TABLE servers ADD COLUMN unstable_features JSONB NOT NULL DEFAULT '{}'::jsonb;
+
+CREATE INDEX idx_servers_spec_versions ON servers USING GIN (spec_versions);
+CREATE INDEX idx_servers_unstable_features ON servers USING GIN (unstable_features);
@@ -0,0 +1,3 @@
+ALTER TABLE servers DROP COLUMN oidc_issuer;
+ALTER TABLE servers DROP COLUMN sso_providers;
+ALTER TABLE servers DROP COLUMN login_flows;
@@ -0,0 +1,5 @@
+ALTER TABLE servers ADD COLUMN login_flows TEXT[] NOT NULL DEFAULT '{}';
+ALTER TABLE servers ADD COLUMN sso_providers JSONB NOT NULL DEFAULT '[]'::jsonb;
+ALTER TABLE servers ADD COLUMN oidc_issuer TEXT;
+
+CREATE INDEX idx_servers_login_flows ON servers USING GIN (login_flows);
@@ -0,0 +1,2 @@
+ALTER TABLE servers DROP COLUMN support_page;
+ALTER TABLE servers DROP COLUMN support_contacts;
@@ -0,0 +1,2 @@
+ALTER TABLE servers ADD COLUMN support_contacts JSONB NOT NULL DEFAULT '[]'::jsonb;
+ALTER TABLE servers ADD COLUMN support_page TEXT;
@@ -0,0 +1 @@
+ALTER TABLE servers ADD COLUMN terms_policies JSONB NOT NULL DEFAULT '[]'::jsonb;
@@ -0,0 +1,3 @@
+ALTER TABLE servers DROP COLUMN identity_server_id;
+ALTER TABLE servers DROP COLUMN identity_server;
+DROP TABLE identity_servers;
@@ -0,0 +1,13 @@
+CREATE TABLE identity_servers (
+    id BIGSERIAL PRIMARY KEY,
+    base_url TEXT NOT NULL UNIQUE,
+    reachable BOOLEAN,
+    terms_policies JSONB NOT NULL DEFAULT '[]'::jsonb,
+    last_checked_at TIMESTAMP,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+ALTER TABLE servers ADD COLUMN identity_server TEXT;
+ALTER TABLE servers ADD COLUMN identity_server_id BIGINT REFERENCES identity_servers(id) ON DELETE SET NULL;
+
+CREATE INDEX idx_servers_identity_server_id ON servers(identity_server_id);
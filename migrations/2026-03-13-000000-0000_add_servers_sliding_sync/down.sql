@@ -0,0 +1,4 @@
+ALTER TABLE servers DROP COLUMN client_capabilities;
+ALTER TABLE servers DROP COLUMN sliding_sync_proxy_reachable;
+ALTER TABLE servers DROP COLUMN sliding_sync_proxy;
+ALTER TABLE servers DROP COLUMN sliding_sync;
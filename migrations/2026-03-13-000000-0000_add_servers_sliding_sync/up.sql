@@ -0,0 +1,7 @@
+ALTER TABLE servers ADD COLUMN sliding_sync TEXT;
+ALTER TABLE servers ADD COLUMN sliding_sync_proxy TEXT;
+ALTER TABLE servers ADD COLUMN sliding_sync_proxy_reachable BOOLEAN;
+ALTER TABLE servers ADD COLUMN client_capabilities JSONB NOT NULL DEFAULT '{}'::jsonb;
+
+CREATE INDEX idx_servers_sliding_sync ON servers(sliding_sync);
+CREATE INDEX idx_servers_client_capabilities ON servers USING GIN (client_capabilities);
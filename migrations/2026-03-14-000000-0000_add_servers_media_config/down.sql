@@ -0,0 +1,2 @@
+ALTER TABLE servers DROP COLUMN authenticated_media_enforced;
+ALTER TABLE servers DROP COLUMN media_upload_size;
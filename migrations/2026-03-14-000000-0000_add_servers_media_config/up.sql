@@ -0,0 +1,4 @@
+ALTER TABLE servers ADD COLUMN media_upload_size BIGINT;
+ALTER TABLE servers ADD COLUMN authenticated_media_enforced BOOLEAN;
+
+CREATE INDEX idx_servers_media_upload_size ON servers(media_upload_size);
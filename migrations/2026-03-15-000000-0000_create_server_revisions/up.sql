@@ -0,0 +1,10 @@
+CREATE TABLE server_revisions (
+    id BIGSERIAL PRIMARY KEY,
+    server_id BIGINT NOT NULL REFERENCES servers(id) ON DELETE CASCADE,
+    field TEXT NOT NULL,
+    old_value JSONB NOT NULL,
+    new_value JSONB NOT NULL,
+    observed_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_server_revisions_server_observed ON server_revisions(server_id, observed_at DESC);
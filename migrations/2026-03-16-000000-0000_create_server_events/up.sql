@@ -0,0 +1,11 @@
+CREATE TABLE server_events (
+    id BIGSERIAL PRIMARY KEY,
+    server_id BIGINT REFERENCES servers(id) ON DELETE SET NULL,
+    domain TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    details JSONB NOT NULL DEFAULT '{}',
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_server_events_kind ON server_events(kind, id DESC);
+CREATE INDEX idx_server_events_domain ON server_events(domain, id DESC);
@@ -0,0 +1,3 @@
+ALTER TABLE servers ADD COLUMN last_refreshed_at TIMESTAMP;
+
+CREATE INDEX idx_servers_last_refreshed_at ON servers(last_refreshed_at NULLS FIRST);
@@ -0,0 +1,2 @@
+ALTER TABLE webhook_subscriptions DROP COLUMN claimed_until;
+DROP TABLE webhook_outbox;
@@ -0,0 +1,15 @@
+CREATE TABLE webhook_outbox (
+    subscription_id BIGINT NOT NULL REFERENCES webhook_subscriptions(id) ON DELETE CASCADE,
+    event_id BIGINT NOT NULL REFERENCES server_events(id) ON DELETE CASCADE,
+    PRIMARY KEY (subscription_id, event_id)
+);
+
+ALTER TABLE webhook_subscriptions ADD COLUMN claimed_until TIMESTAMP;
+
+-- Events the existing subscriptions have not received yet.
+INSERT INTO webhook_outbox (subscription_id, event_id)
+SELECT s.id, e.id
+FROM webhook_subscriptions s
+JOIN server_events e ON e.id > s.last_event_id
+WHERE (cardinality(s.event_kinds) = 0 OR e.kind = ANY(s.event_kinds))
+  AND (s.domain IS NULL OR s.domain = e.domain);
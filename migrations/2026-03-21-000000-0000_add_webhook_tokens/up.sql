@@ -0,0 +1,3 @@
+-- SHA-256 of the token that manages the subscription. Subscriptions made
+-- before tokens existed can only be managed with the admin token.
+ALTER TABLE webhook_subscriptions ADD COLUMN token_hash TEXT;
@@ -0,0 +1,216 @@
+use crate::app::AppState;
+use crate::error::{error_responses, ApiError};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket_okapi::gen::OpenApiGenerator;
+use rocket_okapi::okapi::openapi3::{Object, Parameter, ParameterValue, Responses};
+use rocket_okapi::request::{OpenApiFromRequest, RequestHeaderInput};
+
+/// The `MXINDEX_ADMIN_TOKEN` admin routes require. Without it they reject
+/// every request.
+pub fn admin_token_from_config() -> Option<String> {
+    std::env::var("MXINDEX_ADMIN_TOKEN")
+        .ok()
+        .filter(|token| !token.is_empty())
+}
+
+/// Compares without returning early, so response times do not reveal how
+/// much of a guess was right.
+pub fn tokens_match(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// The token of an `Authorization: Bearer <token>` header.
+pub fn bearer_token<'r>(request: &'r Request<'_>) -> Option<&'r str> {
+    request
+        .headers()
+        .get_one("Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+/// Request guard for operator-only routes: the request must carry the
+/// configured admin token as a bearer token.
+pub struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ApiError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let expected = request
+            .rocket()
+            .state::<AppState>()
+            .and_then(|state| state.admin_token.as_deref());
+
+        match (bearer_token(request), expected) {
+            (Some(given), Some(expected)) if tokens_match(given, expected) => {
+                Outcome::Success(Admin)
+            }
+            _ => Outcome::Error((Status::Unauthorized, ApiError::Unauthorized)),
+        }
+    }
+}
+
+/// Request guard that never fails: the bearer token, if any, and whether it
+/// is the admin token. For routes that also accept a per-resource token.
+pub struct Credentials {
+    pub admin: bool,
+    pub token: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Credentials {
+    type Error = ApiError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let expected = request
+            .rocket()
+            .state::<AppState>()
+            .and_then(|state| state.admin_token.as_deref());
+        let token = bearer_token(request);
+
+        Outcome::Success(Credentials {
+            admin: matches!((token, expected), (Some(given), Some(expected)) if tokens_match(given, expected)),
+            token: token.map(str::to_string),
+        })
+    }
+}
+
+fn authorization_header(
+    gen: &mut OpenApiGenerator,
+    description: &str,
+    required: bool,
+) -> rocket_okapi::Result<RequestHeaderInput> {
+    Ok(RequestHeaderInput::Parameter(Parameter {
+        name: "Authorization".to_owned(),
+        location: "header".to_owned(),
+        description: Some(description.to_owned()),
+        required,
+        deprecated: false,
+        allow_empty_value: false,
+        value: ParameterValue::Schema {
+            style: None,
+            explode: None,
+            allow_reserved: false,
+            schema: gen.json_schema::<String>(),
+            example: None,
+            examples: None,
+        },
+        extensions: Object::default(),
+    }))
+}
+
+impl<'r> OpenApiFromRequest<'r> for Admin {
+    fn from_request_input(
+        gen: &mut OpenApiGenerator,
+        _name: String,
+        _required: bool,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        authorization_header(gen, "`Bearer` followed by the `MXINDEX_ADMIN_TOKEN`", true)
+    }
+
+    fn get_responses(gen: &mut OpenApiGenerator) -> rocket_okapi::Result<Responses> {
+        Ok(error_responses(
+            gen,
+            &[(401, "Missing or wrong admin token")],
+        ))
+    }
+}
+
+impl<'r> OpenApiFromRequest<'r> for Credentials {
+    fn from_request_input(
+        gen: &mut OpenApiGenerator,
+        _name: String,
+        _required: bool,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        authorization_header(
+            gen,
+            "`Bearer` followed by the `MXINDEX_ADMIN_TOKEN` or the resource's own token",
+            true,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::Cache;
+    use crate::error;
+    use crate::event_stream::EventHub;
+    use crate::rate_limit::RateLimiterState;
+    use diesel::r2d2::{ConnectionManager, Pool};
+    use rocket::http::Header;
+    use rocket::local::blocking::Client;
+    use std::sync::Arc;
+
+    #[get("/admin")]
+    fn admin_only(_admin: Admin) -> &'static str {
+        "ok"
+    }
+
+    fn client(admin_token: Option<&str>) -> Client {
+        let state = AppState {
+            cache: Arc::new(Cache::new()),
+            db_pool: Pool::builder()
+                .build_unchecked(ConnectionManager::new("postgres://localhost/unused")),
+            federation: None,
+            refresh_limiter: RateLimiterState::new(60),
+            live_check_limiter: RateLimiterState::new(60),
+            event_hub: Arc::new(EventHub::new(1)),
+            admin_token: admin_token.map(str::to_string),
+            allow_private_webhooks: false,
+        };
+        let rocket = rocket::build()
+            .manage(state)
+            .mount("/", routes![admin_only])
+            .register("/", catchers![error::unauthorized]);
+        Client::tracked(rocket).expect("valid rocket instance")
+    }
+
+    #[test]
+    fn test_admin_requires_token() {
+        let client = client(Some("s3cret"));
+
+        let response = client
+            .get("/admin")
+            .header(Header::new("Authorization", "Bearer s3cret"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        let response = client
+            .get("/admin")
+            .header(Header::new("Authorization", "Bearer guess"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        let response = client.get("/admin").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        let body: serde_json::Value = response.into_json().unwrap();
+        assert_eq!(body["error"], "unauthorized");
+    }
+
+    #[test]
+    fn test_admin_closed_without_configured_token() {
+        let client = client(None);
+        let response = client
+            .get("/admin")
+            .header(Header::new("Authorization", "Bearer "))
+            .dispatch();
+
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn test_tokens_match() {
+        assert!(tokens_match("s3cret", "s3cret"));
+        assert!(!tokens_match("s3cres", "s3cret"));
+        assert!(!tokens_match("s3cre", "s3cret"));
+        assert!(!tokens_match("", "s3cret"));
+    }
+}
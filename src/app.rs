@@ -1,8 +1,21 @@
 use crate::cache::Cache;
 use crate::db::DbPool;
+use crate::event_stream::EventHub;
+use crate::federation_client::FederationClient;
+use crate::rate_limit::RateLimiterState;
 use std::sync::Arc;
 
 pub struct AppState {
     pub cache: Arc<Cache>,
     pub db_pool: DbPool,
+    pub federation: Option<Arc<FederationClient>>,
+    /// Keyed by domain, for `POST /servers/<domain>/refresh`.
+    pub refresh_limiter: RateLimiterState,
+    /// Keyed by client address, for live checks of arbitrary domains.
+    pub live_check_limiter: RateLimiterState,
+    pub event_hub: Arc<EventHub>,
+    /// Bearer token the admin routes require; they are closed without it.
+    pub admin_token: Option<String>,
+    /// Lets webhook subscriptions target loopback and private networks.
+    pub allow_private_webhooks: bool,
 }
@@ -0,0 +1,756 @@
+use crate::bot_commands::CommandHandler;
+use crate::cache::Cache;
+use crate::db::{get_events_after, get_latest_event_id, DbPool};
+use crate::feeds::{escape, public_base_url};
+use crate::models::{EventKind, ServerEventResponse};
+use crate::rate_limit::RateLimiterState;
+use crate::signing::write_private_file;
+use matrix_sdk::authentication::matrix::MatrixSession;
+use matrix_sdk::config::SyncSettings;
+use matrix_sdk::event_handler::Ctx;
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use matrix_sdk::ruma::events::room::message::{
+    AddMentions, MessageType, OriginalSyncRoomMessageEvent, ReplyWithinThread,
+    RoomMessageEventContent,
+};
+use matrix_sdk::ruma::{OwnedRoomOrAliasId, OwnedUserId};
+use matrix_sdk::store::RoomLoadSettings;
+use matrix_sdk::{Client, Room, RoomState};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tracing::{info, warn};
+
+/// Announced unless `MXINDEX_BOT_EVENT_KINDS` says otherwise.
+const DEFAULT_EVENT_KINDS: [EventKind; 4] = [
+    EventKind::ServerAdded,
+    EventKind::ServerUpgraded,
+    EventKind::ServerOffline,
+    EventKind::ServerOnline,
+];
+
+/// Events announced per pass.
+const BATCH_SIZE: i64 = 100;
+
+#[derive(Error, Debug)]
+pub enum BotError {
+    #[error("Failed to build Matrix client: {0}")]
+    Build(Box<matrix_sdk::ClientBuildError>),
+    #[error("Matrix error: {0}")]
+    Matrix(#[from] matrix_sdk::Error),
+    #[error("Failed to access bot state: {0}")]
+    State(#[from] std::io::Error),
+    #[error("Malformed bot state: {0}")]
+    StateFormat(#[from] serde_json::Error),
+    #[error("Failed to get DB connection: {0}")]
+    Pool(#[from] diesel::r2d2::PoolError),
+    #[error("Failed to load events: {0}")]
+    Database(#[from] diesel::result::Error),
+}
+
+impl BotError {
+    /// Whether the homeserver no longer accepts the bot's access token.
+    pub fn is_unknown_token(&self) -> bool {
+        match self {
+            Self::Matrix(e) => matches!(
+                e.client_api_error_kind(),
+                Some(ErrorKind::UnknownToken { .. })
+            ),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct BotConfig {
+    pub homeserver_url: String,
+    /// Localpart or full MXID of the bot account.
+    pub user: String,
+    pub password: String,
+    /// Room ids or aliases announcements are posted to.
+    pub rooms: Vec<OwnedRoomOrAliasId>,
+    pub event_kinds: Vec<&'static str>,
+    /// Directory holding the SDK store and the bot's own state.
+    pub store_path: PathBuf,
+    pub store_passphrase: Option<String>,
+    /// Longest a sync waits for new room events; also the announcement delay.
+    pub poll_interval: Duration,
+    /// Users allowed to run admin-only commands.
+    pub admins: Vec<OwnedUserId>,
+    pub commands_per_minute: u32,
+}
+
+impl BotConfig {
+    /// The bot is disabled unless `MXINDEX_BOT_HOMESERVER`, `MXINDEX_BOT_USER`
+    /// and `MXINDEX_BOT_PASSWORD` are set.
+    pub fn from_env() -> Option<Self> {
+        let homeserver_url = std::env::var("MXINDEX_BOT_HOMESERVER").ok()?;
+        let user = std::env::var("MXINDEX_BOT_USER").ok()?;
+        let password = std::env::var("MXINDEX_BOT_PASSWORD").ok()?;
+
+        let rooms = parse_list(&std::env::var("MXINDEX_BOT_ROOMS").unwrap_or_default())
+            .filter_map(|room| match OwnedRoomOrAliasId::try_from(room) {
+                Ok(room) => Some(room),
+                Err(e) => {
+                    warn!("Ignoring invalid bot room {}: {}", room, e);
+                    None
+                }
+            })
+            .collect();
+
+        let event_kinds = match std::env::var("MXINDEX_BOT_EVENT_KINDS") {
+            Ok(kinds) => parse_list(&kinds)
+                .filter_map(|kind| {
+                    let parsed = EventKind::parse(kind).map(|k| k.as_str());
+                    if parsed.is_none() {
+                        warn!("Ignoring unknown bot event kind {}", kind);
+                    }
+                    parsed
+                })
+                .collect(),
+            Err(_) => DEFAULT_EVENT_KINDS.iter().map(|k| k.as_str()).collect(),
+        };
+
+        let poll_secs = std::env::var("MXINDEX_BOT_POLL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(30);
+
+        let admins = parse_list(&std::env::var("MXINDEX_BOT_ADMINS").unwrap_or_default())
+            .filter_map(|admin| match OwnedUserId::try_from(admin) {
+                Ok(admin) => Some(admin),
+                Err(e) => {
+                    warn!("Ignoring invalid bot admin {}: {}", admin, e);
+                    None
+                }
+            })
+            .collect();
+
+        let commands_per_minute = std::env::var("MXINDEX_BOT_COMMANDS_PER_MINUTE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(5);
+
+        Some(Self {
+            homeserver_url,
+            user,
+            password,
+            rooms,
+            event_kinds,
+            store_path: std::env::var("MXINDEX_BOT_STORE_PATH")
+                .unwrap_or_else(|_| "mxindex-bot".to_string())
+                .into(),
+            store_passphrase: std::env::var("MXINDEX_BOT_STORE_PASSPHRASE").ok(),
+            poll_interval: Duration::from_secs(poll_secs),
+            admins,
+            commands_per_minute,
+        })
+    }
+
+    fn state_file(&self) -> PathBuf {
+        self.store_path.join("state.json")
+    }
+}
+
+fn parse_list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').map(str::trim).filter(|v| !v.is_empty())
+}
+
+/// What the bot needs to pick up where it left off after a restart.
+#[derive(Serialize, Deserialize, Default)]
+struct BotState {
+    session: Option<MatrixSession>,
+    sync_token: Option<String>,
+    /// Last event announced; events up to it are never posted again.
+    last_event_id: Option<i64>,
+}
+
+impl BotState {
+    fn load(path: &Path) -> Result<Self, BotError> {
+        match std::fs::read_to_string(path) {
+            Ok(data) => Ok(serde_json::from_str(&data)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes through a temporary file so a crash never leaves a torn state.
+    /// Both are readable by the owner only, as the state holds the session's
+    /// access token.
+    fn save(&self, path: &Path) -> Result<(), BotError> {
+        let tmp = path.with_extension("json.tmp");
+        match std::fs::remove_file(&tmp) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        write_private_file(&tmp, &serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+}
+
+/// Plain-text and HTML bodies announcing `event`.
+pub fn format_announcement(event: &ServerEventResponse, base_url: &str) -> (String, String) {
+    let server = format!(
+        "<a href=\"{}\"><b>{}</b></a>",
+        escape(&format!("{}/servers/{}", base_url, event.domain)),
+        escape(&event.domain)
+    );
+    let version = |key: &str| {
+        event.details[key]
+            .as_str()
+            .unwrap_or("an unknown version")
+            .to_string()
+    };
+    let error = event.details["error"].as_str();
+
+    let (icon, html) = match EventKind::parse(&event.kind) {
+        Some(EventKind::ServerAdded) => ("🆕", format!("{} was added to the index", server)),
+        Some(EventKind::ServerUpgraded) => (
+            "⬆️",
+            format!(
+                "{} upgraded from <code>{}</code> to <code>{}</code>",
+                server,
+                escape(&version("old")),
+                escape(&version("new"))
+            ),
+        ),
+        Some(EventKind::ServerOffline) => (
+            "🔴",
+            match error {
+                Some(error) => format!("{} went offline: <i>{}</i>", server, escape(error)),
+                None => format!("{} went offline", server),
+            },
+        ),
+        Some(EventKind::ServerOnline) => ("🟢", format!("{} is back online", server)),
+        _ => ("ℹ️", escape(&event.title)),
+    };
+
+    let plain = match (EventKind::parse(&event.kind), error) {
+        (Some(EventKind::ServerOffline), Some(error)) => {
+            format!("{} {}: {}", icon, event.title, error)
+        }
+        _ => format!("{} {}", icon, event.title),
+    };
+
+    (plain, format!("{} {}", icon, html))
+}
+
+/// A logged-in bot account and the rooms it announces to.
+pub struct Bot {
+    client: Client,
+    config: BotConfig,
+    state: BotState,
+    rooms: Vec<Room>,
+}
+
+impl Bot {
+    /// Restores the saved session, or logs in and saves a new one, then
+    /// catches up with the homeserver and joins the configured rooms. Without
+    /// a saved session the SDK store is started afresh, as it belongs to a
+    /// device the bot can no longer use.
+    pub async fn connect(config: BotConfig) -> Result<Self, BotError> {
+        std::fs::create_dir_all(&config.store_path)?;
+        let mut state = BotState::load(&config.state_file())?;
+
+        if state.session.is_none() {
+            match std::fs::remove_dir_all(config.store_path.join("store")) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+
+        let client = Client::builder()
+            .homeserver_url(&config.homeserver_url)
+            .sqlite_store(
+                config.store_path.join("store"),
+                config.store_passphrase.as_deref(),
+            )
+            .build()
+            .await
+            .map_err(|e| BotError::Build(Box::new(e)))?;
+
+        match state.session.clone() {
+            Some(session) => {
+                client
+                    .matrix_auth()
+                    .restore_session(session, RoomLoadSettings::default())
+                    .await?;
+            }
+            None => {
+                client
+                    .matrix_auth()
+                    .login_username(&config.user, &config.password)
+                    .initial_device_display_name("mxindex")
+                    .send()
+                    .await?;
+                state.session = client.matrix_auth().session();
+                state.save(&config.state_file())?;
+            }
+        }
+
+        let mut bot = Self {
+            client,
+            config,
+            state,
+            rooms: Vec::new(),
+        };
+
+        bot.sync(Duration::ZERO).await?;
+        bot.join_rooms().await;
+
+        Ok(bot)
+    }
+
+    async fn join_rooms(&mut self) {
+        for room in &self.config.rooms {
+            match self.client.join_room_by_id_or_alias(room, &[]).await {
+                Ok(joined) => self.rooms.push(joined),
+                Err(e) => warn!("Bot failed to join {}: {}", room, e),
+            }
+        }
+    }
+
+    /// Answers `!mxindex` commands from now on, in a thread under each
+    /// command. Commands sent before the bot's first sync are never seen.
+    pub fn handle_commands(&self, handler: CommandHandler) {
+        self.client.add_event_handler_context(Arc::new(handler));
+        self.client.add_event_handler(on_room_message);
+    }
+
+    /// One `/sync`, resuming from the saved token and saving the next one.
+    /// A revoked access token drops the saved session, so that the next
+    /// connect logs in again.
+    pub async fn sync(&mut self, timeout: Duration) -> Result<(), BotError> {
+        let mut settings = SyncSettings::default().timeout(timeout);
+        if let Some(token) = &self.state.sync_token {
+            settings = settings.token(token.clone());
+        }
+
+        let response = match self.client.sync_once(settings).await {
+            Ok(response) => response,
+            Err(e) => {
+                let e = BotError::from(e);
+                if e.is_unknown_token() {
+                    self.state.session = None;
+                    self.state.sync_token = None;
+                    self.state.save(&self.config.state_file())?;
+                }
+                return Err(e);
+            }
+        };
+
+        self.state.sync_token = Some(response.next_batch);
+        self.state.save(&self.config.state_file())
+    }
+
+    /// Posts `event` to every room, returning how many rooms it reached.
+    pub async fn announce(&self, event: &ServerEventResponse) -> usize {
+        let (plain, html) = format_announcement(event, &public_base_url());
+        let content = RoomMessageEventContent::notice_html(plain, html);
+
+        let mut reached = 0;
+        for room in &self.rooms {
+            match room.send(content.clone()).await {
+                Ok(_) => reached += 1,
+                Err(e) => warn!(
+                    "Bot failed to announce event {} in {}: {}",
+                    event.id,
+                    room.room_id(),
+                    e
+                ),
+            }
+        }
+        reached
+    }
+
+    /// Announces events logged since the last announcement, oldest first. A
+    /// bot without a saved cursor starts from the latest event rather than
+    /// replaying the log. Stops when an event reaches no room at all, so it
+    /// is retried once the homeserver is back.
+    pub async fn announce_pending(&mut self, db_pool: &DbPool) -> Result<usize, BotError> {
+        let (after, events) = {
+            let mut conn = db_pool.get()?;
+            let after = match self.state.last_event_id {
+                Some(id) => id,
+                None => get_latest_event_id(&mut conn)?,
+            };
+            let events =
+                get_events_after(&mut conn, after, &self.config.event_kinds, None, BATCH_SIZE)?;
+            (after, events)
+        };
+
+        if self.state.last_event_id.is_none() {
+            self.state.last_event_id = Some(after);
+            self.state.save(&self.config.state_file())?;
+        }
+
+        let mut announced = 0;
+        for event in events.into_iter().map(ServerEventResponse::from) {
+            if !self.rooms.is_empty() && self.announce(&event).await == 0 {
+                break;
+            }
+
+            self.state.last_event_id = Some(event.id);
+            self.state.save(&self.config.state_file())?;
+            announced += 1;
+        }
+
+        Ok(announced)
+    }
+}
+
+async fn on_room_message(
+    event: OriginalSyncRoomMessageEvent,
+    room: Room,
+    client: Client,
+    handler: Ctx<Arc<CommandHandler>>,
+) {
+    if room.state() != RoomState::Joined || client.user_id() == Some(&*event.sender) {
+        return;
+    }
+    let MessageType::Text(text) = &event.content.msgtype else {
+        return;
+    };
+
+    let Some((plain, html)) = handler.handle(&event.sender, &text.body).await else {
+        return;
+    };
+
+    let reply = RoomMessageEventContent::notice_html(plain, html).make_for_thread(
+        &event,
+        ReplyWithinThread::No,
+        AddMentions::No,
+    );
+    if let Err(e) = room.send(reply).await {
+        warn!(
+            "Bot failed to answer {} in {}: {}",
+            event.event_id,
+            room.room_id(),
+            e
+        );
+    }
+}
+
+/// Connects the bot, retrying until the homeserver accepts it, and sets it
+/// up to answer commands.
+async fn connect_bot(
+    db_pool: &DbPool,
+    cache: &Arc<Cache>,
+    probe_limiter: &RateLimiterState,
+    config: &BotConfig,
+) -> Bot {
+    let mut delay = Duration::from_secs(5);
+    let bot = loop {
+        match Bot::connect(config.clone()).await {
+            Ok(bot) => break bot,
+            Err(e) => warn!("Matrix bot failed to connect: {}", e),
+        }
+
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(Duration::from_secs(300));
+    };
+
+    info!(
+        "Matrix bot connected as {} in {} rooms",
+        config.user,
+        bot.rooms.len()
+    );
+
+    bot.handle_commands(CommandHandler::new(
+        db_pool.clone(),
+        cache.clone(),
+        config.admins.clone(),
+        config.commands_per_minute,
+        probe_limiter.clone(),
+    ));
+    bot
+}
+
+/// Connects the bot, then syncs, answers commands and announces new events
+/// for the life of the process. The bot logs in again when the homeserver
+/// revokes its access token. `probe_limiter` is the API's per-domain
+/// refresh limiter.
+pub async fn run_bot(
+    db_pool: DbPool,
+    cache: Arc<Cache>,
+    probe_limiter: RateLimiterState,
+    config: BotConfig,
+) {
+    let mut bot = connect_bot(&db_pool, &cache, &probe_limiter, &config).await;
+
+    loop {
+        if let Err(e) = bot.sync(config.poll_interval).await {
+            warn!("Matrix bot sync failed: {}", e);
+            if e.is_unknown_token() {
+                drop(bot);
+                bot = connect_bot(&db_pool, &cache, &probe_limiter, &config).await;
+                continue;
+            }
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        }
+
+        if let Err(e) = bot.announce_pending(&db_pool).await {
+            warn!("Matrix bot failed to announce events: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDateTime;
+    use httptest::matchers::{all_of, any, contains, json_decoded, matches, request, url_decoded};
+    use httptest::responders::{json_encoded, status_code};
+    use httptest::{Expectation, Server};
+    use serde_json::{json, Value};
+
+    const ROOM_ID: &str = "!announce:localhost";
+
+    fn event(kind: &str, details: Value) -> ServerEventResponse {
+        ServerEventResponse {
+            id: 7,
+            kind: kind.to_string(),
+            domain: "example.org".to_string(),
+            title: crate::events::event_title(kind, "example.org", &details),
+            details,
+            created_at: NaiveDateTime::parse_from_str("2024-01-01 00:00:00", "%Y-%m-%d %H:%M:%S")
+                .unwrap(),
+        }
+    }
+
+    fn config(server: &Server, store: &Path) -> BotConfig {
+        BotConfig {
+            homeserver_url: server.url_str(""),
+            user: "mxindex".to_string(),
+            password: "hunter2".to_string(),
+            rooms: vec![OwnedRoomOrAliasId::try_from(ROOM_ID).unwrap()],
+            event_kinds: DEFAULT_EVENT_KINDS.iter().map(|k| k.as_str()).collect(),
+            store_path: store.to_path_buf(),
+            store_passphrase: None,
+            poll_interval: Duration::ZERO,
+            admins: Vec::new(),
+            commands_per_minute: 5,
+        }
+    }
+
+    /// A homeserver that accepts one login, hands out sync tokens and lets
+    /// the bot join and post to `ROOM_ID`. Anything else, such as encryption
+    /// key uploads, gets a 404.
+    fn homeserver() -> Server {
+        homeserver_accepting_logins(1)
+    }
+
+    fn homeserver_accepting_logins(logins: usize) -> Server {
+        let server = Server::run();
+        server.expect(Expectation::matching(any()).times(..).respond_with(
+            status_code(404).body(r#"{"errcode": "M_NOT_FOUND", "error": "Not found"}"#),
+        ));
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/_matrix/client/versions"))
+                .times(..)
+                .respond_with(json_encoded(json!({"versions": ["v1.11"]}))),
+        );
+        server.expect(
+            Expectation::matching(request::method_path("POST", "/_matrix/client/v3/login"))
+                .times(logins)
+                .respond_with(json_encoded(json!({
+                    "user_id": "@mxindex:localhost",
+                    "access_token": "token",
+                    "device_id": "MXINDEX",
+                }))),
+        );
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/_matrix/client/v3/sync"))
+                .times(..)
+                .respond_with(json_encoded(json!({"next_batch": "s1"}))),
+        );
+        server.expect(
+            Expectation::matching(all_of![
+                request::method("POST"),
+                request::path(matches("^/_matrix/client/v3/join/")),
+            ])
+            .times(..)
+            .respond_with(json_encoded(json!({"room_id": ROOM_ID}))),
+        );
+        server
+    }
+
+    #[test]
+    fn test_format_upgrade() {
+        let (plain, html) = format_announcement(
+            &event(
+                "server_upgraded",
+                json!({"old": "1.109.0", "new": "<1.110.0>"}),
+            ),
+            "https://index.example",
+        );
+
+        assert_eq!(plain, "⬆️ example.org upgraded from 1.109.0 to <1.110.0>");
+        assert_eq!(
+            html,
+            "⬆️ <a href=\"https://index.example/servers/example.org\"><b>example.org</b></a> \
+             upgraded from <code>1.109.0</code> to <code>&lt;1.110.0&gt;</code>"
+        );
+    }
+
+    #[test]
+    fn test_format_outage() {
+        let (plain, html) = format_announcement(
+            &event("server_offline", json!({"error": "connection refused"})),
+            "https://index.example",
+        );
+
+        assert_eq!(plain, "🔴 example.org went offline: connection refused");
+        assert!(html.ends_with("went offline: <i>connection refused</i>"));
+    }
+
+    #[tokio::test]
+    async fn test_announces_to_configured_rooms() {
+        let server = homeserver();
+        server.expect(
+            Expectation::matching(all_of![
+                request::method("PUT"),
+                request::path(matches("/rooms/.*/send/m.room.message/")),
+                request::body(json_decoded(|body: &Value| {
+                    body["msgtype"] == "m.notice"
+                        && body["format"] == "org.matrix.custom.html"
+                        && body["formatted_body"]
+                            .as_str()
+                            .is_some_and(|html| html.contains("<b>example.org</b>"))
+                })),
+            ])
+            .times(1)
+            .respond_with(json_encoded(json!({"event_id": "$announcement"}))),
+        );
+        let store = tempfile::tempdir().unwrap();
+
+        let bot = Bot::connect(config(&server, store.path())).await.unwrap();
+
+        assert_eq!(bot.announce(&event("server_added", json!({}))).await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_restart_restores_session_and_sync_token() {
+        let server = homeserver();
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("GET", "/_matrix/client/v3/sync"),
+                request::query(url_decoded(contains(("since", "s1")))),
+            ])
+            .times(1)
+            .respond_with(json_encoded(json!({"next_batch": "s2"}))),
+        );
+        let store = tempfile::tempdir().unwrap();
+
+        let bot = Bot::connect(config(&server, store.path())).await.unwrap();
+        assert_eq!(bot.state.sync_token.as_deref(), Some("s1"));
+        drop(bot);
+
+        // A second login would fail the `times(1)` on the login endpoint.
+        let bot = Bot::connect(config(&server, store.path())).await.unwrap();
+        assert_eq!(bot.state.sync_token.as_deref(), Some("s2"));
+        assert!(bot.state.session.is_some());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_state_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let server = homeserver();
+        let store = tempfile::tempdir().unwrap();
+        let config = config(&server, store.path());
+
+        Bot::connect(config.clone()).await.unwrap();
+
+        let mode = std::fs::metadata(config.state_file())
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[tokio::test]
+    async fn test_revoked_token_logs_in_again() {
+        let server = homeserver_accepting_logins(2);
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("GET", "/_matrix/client/v3/sync"),
+                request::query(url_decoded(contains(("since", "s1")))),
+            ])
+            .times(1)
+            .respond_with(status_code(401).body(
+                r#"{"errcode": "M_UNKNOWN_TOKEN", "error": "Access token has been revoked"}"#,
+            )),
+        );
+        let store = tempfile::tempdir().unwrap();
+        let config = config(&server, store.path());
+
+        let mut bot = Bot::connect(config.clone()).await.unwrap();
+        let error = bot.sync(Duration::ZERO).await.unwrap_err();
+        assert!(error.is_unknown_token());
+        drop(bot);
+
+        let state = BotState::load(&config.state_file()).unwrap();
+        assert!(state.session.is_none());
+        assert!(state.sync_token.is_none());
+
+        // The second login is allowed by `homeserver_accepting_logins(2)`.
+        let bot = Bot::connect(config).await.unwrap();
+        assert!(bot.state.session.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_answers_commands_in_thread() {
+        let server = homeserver();
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("GET", "/_matrix/client/v3/sync"),
+                request::query(url_decoded(contains(("since", "s1")))),
+            ])
+            .times(1)
+            .respond_with(json_encoded(json!({
+                "next_batch": "s2",
+                "rooms": {"join": {ROOM_ID: {"timeline": {"events": [{
+                    "type": "m.room.message",
+                    "event_id": "$command",
+                    "sender": "@alice:localhost",
+                    "origin_server_ts": 1704067200000u64,
+                    "content": {"msgtype": "m.text", "body": "!mxindex help"},
+                }]}}}},
+            }))),
+        );
+        server.expect(
+            Expectation::matching(all_of![
+                request::method("PUT"),
+                request::path(matches("/rooms/.*/send/m.room.message/")),
+                request::body(json_decoded(|body: &Value| {
+                    body["m.relates_to"]["rel_type"] == "m.thread"
+                        && body["m.relates_to"]["event_id"] == "$command"
+                        && body["body"]
+                            .as_str()
+                            .is_some_and(|text| text.starts_with("Commands:"))
+                })),
+            ])
+            .times(1)
+            .respond_with(json_encoded(json!({"event_id": "$reply"}))),
+        );
+        let store = tempfile::tempdir().unwrap();
+        let db_pool = diesel::r2d2::Pool::builder().build_unchecked(
+            diesel::r2d2::ConnectionManager::new("postgres://localhost/unused"),
+        );
+
+        let mut bot = Bot::connect(config(&server, store.path())).await.unwrap();
+        bot.handle_commands(CommandHandler::new(
+            db_pool,
+            Arc::new(Cache::new()),
+            Vec::new(),
+            5,
+            RateLimiterState::per_window(1, Duration::from_secs(300)),
+        ));
+        bot.sync(Duration::ZERO).await.unwrap();
+    }
+}
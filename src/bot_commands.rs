@@ -0,0 +1,594 @@
+use crate::cache::Cache;
+use crate::db::{get_filtered_servers, get_server_by_domain, DbPool, ServerFilter};
+use crate::error::{normalize_domain, validate_domain};
+use crate::feeds::{escape, public_base_url};
+use crate::models::{DiscoveredServerInfo, ServerResponse};
+use crate::rate_limit::RateLimiterState;
+use crate::refresh::refresh_server;
+use crate::services::MatrixService;
+use matrix_sdk::ruma::{OwnedUserId, UserId};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+pub const COMMAND_PREFIX: &str = "!mxindex";
+
+/// Servers listed in a search reply.
+const SEARCH_RESULTS: i32 = 10;
+
+const USAGE: &str = "Commands: info <domain>, search <text>, stats, add <domain> (admins)";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Info(String),
+    Search(String),
+    Stats,
+    Add(String),
+    Help,
+}
+
+impl Command {
+    fn admin_only(&self) -> bool {
+        matches!(self, Command::Add(_))
+    }
+}
+
+/// Parses a message body. `None` when the message is not addressed to the
+/// bot, `Err` with a hint when it is but makes no sense.
+pub fn parse_command(body: &str) -> Option<Result<Command, String>> {
+    let mut words = body.split_whitespace();
+    if words.next()? != COMMAND_PREFIX {
+        return None;
+    }
+
+    let command = words.next().unwrap_or("help");
+    let args: Vec<&str> = words.collect();
+
+    let parsed = match (command, args.as_slice()) {
+        ("info", [domain]) => Ok(Command::Info(normalize_domain(domain))),
+        ("add", [domain]) => Ok(Command::Add(normalize_domain(domain))),
+        ("search", terms) if !terms.is_empty() => Ok(Command::Search(terms.join(" "))),
+        ("stats", []) => Ok(Command::Stats),
+        ("help", _) => Ok(Command::Help),
+        ("info" | "add", _) => Err(format!("Usage: {} {} <domain>", COMMAND_PREFIX, command)),
+        ("search", _) => Err(format!("Usage: {} search <text>", COMMAND_PREFIX)),
+        _ => Err(format!("Unknown command `{}`. {}", command, USAGE)),
+    };
+
+    Some(parsed)
+}
+
+/// A reply as plain text and HTML.
+pub type Reply = (String, String);
+
+fn text_reply(text: &str) -> Reply {
+    (text.to_string(), escape(text))
+}
+
+/// The fields a reply shows, from the index or from a live discovery.
+#[derive(Debug, PartialEq)]
+pub struct ServerSummary {
+    pub domain: String,
+    pub implementation: Option<String>,
+    pub implementation_version: Option<String>,
+    pub registration_open: Option<bool>,
+    pub public_rooms_count: Option<i32>,
+    pub latest_spec_version: Option<String>,
+}
+
+impl From<&ServerResponse> for ServerSummary {
+    fn from(server: &ServerResponse) -> Self {
+        ServerSummary {
+            domain: server.domain.clone(),
+            implementation: server.implementation.clone(),
+            implementation_version: server.federation_version.clone(),
+            registration_open: server.registration_open,
+            public_rooms_count: server.public_rooms_count,
+            latest_spec_version: server.latest_spec_version.clone(),
+        }
+    }
+}
+
+impl ServerSummary {
+    fn from_discovered(domain: &str, info: &DiscoveredServerInfo) -> Self {
+        ServerSummary {
+            domain: domain.to_string(),
+            implementation: info.implementation.clone(),
+            implementation_version: info.federation_version.clone(),
+            registration_open: info.registration_open,
+            public_rooms_count: info.public_rooms_count,
+            latest_spec_version: info.latest_spec_version.clone(),
+        }
+    }
+
+    /// "Synapse 1.110.0" from the federation version probe, or whichever
+    /// part is known.
+    fn software(&self) -> Option<String> {
+        match (&self.implementation, &self.implementation_version) {
+            (Some(name), Some(version)) => Some(format!("{} {}", name, version)),
+            (Some(part), None) | (None, Some(part)) => Some(part.clone()),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Info reply for a server. `indexed` tells whether it came from the index or
+/// from a live check of a server that is not indexed.
+pub fn format_info(server: &ServerSummary, indexed: bool, base_url: &str) -> Reply {
+    let software = server
+        .software()
+        .unwrap_or_else(|| "unknown software".to_string());
+    let registration = match server.registration_open {
+        Some(true) => "open",
+        Some(false) => "closed",
+        None => "unknown",
+    };
+    let rooms = server
+        .public_rooms_count
+        .map(|count| count.to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    let spec = server.latest_spec_version.as_deref().unwrap_or("unknown");
+
+    let facts = format!(
+        "Registration: {} · Public rooms: {} · Spec: {}",
+        registration, rooms, spec
+    );
+    let footer = (!indexed).then(|| {
+        format!(
+            "Not in the index yet; an admin can add it with {} add {}",
+            COMMAND_PREFIX, server.domain
+        )
+    });
+
+    let mut plain = format!("{} runs {}\n{}", server.domain, software, facts);
+
+    let title = if indexed {
+        format!(
+            "<a href=\"{}\"><b>{}</b></a>",
+            escape(&format!("{}/servers/{}", base_url, server.domain)),
+            escape(&server.domain)
+        )
+    } else {
+        format!("<b>{}</b>", escape(&server.domain))
+    };
+    let mut html = format!(
+        "{} runs <code>{}</code><br>{}",
+        title,
+        escape(&software),
+        escape(&facts)
+    );
+
+    if let Some(footer) = footer {
+        plain.push_str(&format!("\n{}", footer));
+        html.push_str(&format!("<br><i>{}</i>", escape(&footer)));
+    }
+
+    (plain, html)
+}
+
+/// Search reply listing the first matches.
+pub fn format_search(query: &str, servers: &[ServerSummary], total: i64, base_url: &str) -> Reply {
+    if servers.is_empty() {
+        return text_reply(&format!("No servers match “{}”.", query));
+    }
+
+    let heading = format!(
+        "{} server{} match “{}”",
+        total,
+        if total == 1 { "" } else { "s" },
+        query
+    );
+    let line = |server: &ServerSummary| {
+        let link = format!(
+            "<a href=\"{}\">{}</a>",
+            escape(&format!("{}/servers/{}", base_url, server.domain)),
+            escape(&server.domain)
+        );
+        match server.software() {
+            Some(software) => (
+                format!("{} ({})", server.domain, software),
+                format!("{} ({})", link, escape(&software)),
+            ),
+            None => (server.domain.clone(), link),
+        }
+    };
+    let lines: Vec<Reply> = servers.iter().map(line).collect();
+
+    let mut plain = format!("{}:", heading);
+    let mut html = format!("{}:<ul>", escape(&heading));
+    for (text, item) in &lines {
+        plain.push_str(&format!("\n• {}", text));
+        html.push_str(&format!("<li>{}</li>", item));
+    }
+    html.push_str("</ul>");
+
+    let more = total - servers.len() as i64;
+    if more > 0 {
+        let link = reqwest::Url::parse_with_params(
+            &format!("{}/servers/search", base_url),
+            [("search", query)],
+        )
+        .map(String::from)
+        .unwrap_or_else(|_| format!("{}/servers/search", base_url));
+        plain.push_str(&format!("\n…and {} more: {}", more, link));
+        html.push_str(&format!(
+            "<a href=\"{}\">…and {} more</a>",
+            escape(&link),
+            more
+        ));
+    }
+
+    (plain, html)
+}
+
+#[derive(Debug, PartialEq)]
+pub struct IndexStats {
+    pub servers: i64,
+    pub registration_open: i64,
+    pub with_public_rooms: i64,
+    pub element_x_compatible: i64,
+}
+
+pub fn format_stats(stats: &IndexStats) -> Reply {
+    let rows = [
+        ("Servers indexed", stats.servers),
+        ("Open registration", stats.registration_open),
+        ("With public rooms", stats.with_public_rooms),
+        ("Element X compatible", stats.element_x_compatible),
+    ];
+
+    let plain = rows
+        .iter()
+        .map(|(label, count)| format!("{}: {}", label, count))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let html = format!(
+        "<table>{}</table>",
+        rows.iter()
+            .map(|(label, count)| format!("<tr><td>{}</td><td><b>{}</b></td></tr>", label, count))
+            .collect::<String>()
+    );
+
+    (plain, html)
+}
+
+/// Answers `!mxindex` commands sent to the bot.
+pub struct CommandHandler {
+    db_pool: DbPool,
+    cache: Arc<Cache>,
+    admins: Vec<OwnedUserId>,
+    /// Keyed by sender MXID.
+    limiter: RateLimiterState,
+    /// The API's per-domain refresh limiter, shared so live checks through
+    /// the bot count against the same window as `/probe`.
+    probe_limiter: RateLimiterState,
+}
+
+impl CommandHandler {
+    pub fn new(
+        db_pool: DbPool,
+        cache: Arc<Cache>,
+        admins: Vec<OwnedUserId>,
+        commands_per_minute: u32,
+        probe_limiter: RateLimiterState,
+    ) -> Self {
+        Self {
+            db_pool,
+            cache,
+            admins,
+            limiter: RateLimiterState::per_window(commands_per_minute, Duration::from_secs(60)),
+            probe_limiter,
+        }
+    }
+
+    /// The reply to a message from `sender`, or `None` when the message is
+    /// not a command. A rate-limited sender is told to slow down once per
+    /// window and gets no reply after that.
+    pub async fn handle(&self, sender: &UserId, body: &str) -> Option<Reply> {
+        let parsed = parse_command(body)?;
+
+        if let Err(e) = self.limiter.check(sender.as_str()) {
+            if !e.first_refusal {
+                return None;
+            }
+            return Some(text_reply(&format!(
+                "Slow down, please; try again in {} seconds.",
+                e.retry_after.as_secs().max(1)
+            )));
+        }
+
+        let command = match parsed {
+            Ok(command) => command,
+            Err(hint) => return Some(text_reply(&hint)),
+        };
+
+        if command.admin_only() && !self.admins.iter().any(|admin| admin == sender) {
+            return Some(text_reply("Only mxindex admins can do that."));
+        }
+
+        let reply = match command {
+            Command::Info(domain) => self.info(&domain).await,
+            Command::Search(query) => self.search(&query),
+            Command::Stats => self.stats(),
+            Command::Add(domain) => self.add(&domain).await,
+            Command::Help => Ok(text_reply(USAGE)),
+        };
+
+        Some(reply.unwrap_or_else(|e| {
+            warn!("Bot command `{}` from {} failed: {}", body, sender, e);
+            text_reply("Something went wrong; please try again later.")
+        }))
+    }
+
+    async fn info(&self, domain: &str) -> Result<Reply, Box<dyn std::error::Error + Send + Sync>> {
+        if validate_domain(domain).is_err() {
+            return Ok(text_reply(&format!("{} is not a valid domain.", domain)));
+        }
+
+        let indexed = {
+            let mut conn = self.db_pool.get()?;
+            get_server_by_domain(&mut conn, domain)?
+        };
+
+        if let Some(server) = indexed {
+            let server = ServerResponse::from(server);
+            return Ok(format_info(
+                &ServerSummary::from(&server),
+                true,
+                &public_base_url(),
+            ));
+        }
+
+        if let Err(e) = self.probe_limiter.check(&format!("probe:{}", domain)) {
+            return Ok(text_reply(&format!(
+                "{} is not in the index and was checked recently; try again in {} seconds.",
+                domain,
+                e.retry_after.as_secs().max(1)
+            )));
+        }
+
+        match MatrixService::discover_server_info(domain).await {
+            Ok((info, _)) => Ok(format_info(
+                &ServerSummary::from_discovered(domain, &info),
+                false,
+                &public_base_url(),
+            )),
+            Err(e) => Ok(text_reply(&format!(
+                "{} is not in the index and could not be reached: {}",
+                domain, e
+            ))),
+        }
+    }
+
+    fn search(&self, query: &str) -> Result<Reply, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.db_pool.get()?;
+        let found = get_filtered_servers(
+            &mut conn,
+            &ServerFilter {
+                search: Some(query.to_string()),
+                limit: Some(SEARCH_RESULTS),
+                ..Default::default()
+            },
+        )?;
+
+        let servers: Vec<ServerSummary> = found
+            .servers
+            .into_iter()
+            .map(ServerResponse::from)
+            .map(|server| ServerSummary::from(&server))
+            .collect();
+
+        Ok(format_search(
+            query,
+            &servers,
+            found.total,
+            &public_base_url(),
+        ))
+    }
+
+    fn stats(&self) -> Result<Reply, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = self.db_pool.get()?;
+        let mut count = |filter: ServerFilter| {
+            get_filtered_servers(
+                &mut conn,
+                &ServerFilter {
+                    limit: Some(1),
+                    ..filter
+                },
+            )
+            .map(|found| found.total)
+        };
+
+        let stats = IndexStats {
+            servers: count(ServerFilter::default())?,
+            registration_open: count(ServerFilter {
+                registration_open: Some(true),
+                ..Default::default()
+            })?,
+            with_public_rooms: count(ServerFilter {
+                has_rooms: Some(true),
+                ..Default::default()
+            })?,
+            element_x_compatible: count(ServerFilter {
+                element_x: Some(true),
+                ..Default::default()
+            })?,
+        };
+
+        Ok(format_stats(&stats))
+    }
+
+    async fn add(&self, domain: &str) -> Result<Reply, Box<dyn std::error::Error + Send + Sync>> {
+        if validate_domain(domain).is_err() {
+            return Ok(text_reply(&format!("{} is not a valid domain.", domain)));
+        }
+
+        let existing = {
+            let mut conn = self.db_pool.get()?;
+            get_server_by_domain(&mut conn, domain)?
+        };
+        if existing.is_some() {
+            return Ok(text_reply(&format!("{} is already indexed.", domain)));
+        }
+
+        let added = match refresh_server(&self.db_pool, domain).await {
+            Ok(added) => added,
+            Err(crate::refresh::RefreshError::Discovery(e)) => {
+                return Ok(text_reply(&format!("Could not add {}: {}", domain, e)));
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let _ = self.cache.invalidate_pattern("servers:*").await;
+        let _ = self
+            .cache
+            .delete(&format!("server:detail:{}", domain))
+            .await;
+
+        let (plain, html) = format_info(
+            &ServerSummary::from(&added.server),
+            true,
+            &public_base_url(),
+        );
+        Ok((
+            format!("Added {}.\n{}", domain, plain),
+            format!("Added {}.<br>{}", escape(domain), html),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diesel::r2d2::{ConnectionManager, Pool};
+
+    fn summary() -> ServerSummary {
+        ServerSummary {
+            domain: "example.org".to_string(),
+            implementation: Some("Synapse".to_string()),
+            implementation_version: Some("1.110.0".to_string()),
+            registration_open: Some(false),
+            public_rooms_count: Some(12),
+            latest_spec_version: Some("v1.11".to_string()),
+        }
+    }
+
+    /// A handler whose pool never connects; enough for replies that do not
+    /// touch the database.
+    fn handler(commands_per_minute: u32) -> CommandHandler {
+        let pool =
+            Pool::builder().build_unchecked(ConnectionManager::new("postgres://localhost/unused"));
+        CommandHandler::new(
+            pool,
+            Arc::new(Cache::new()),
+            vec![OwnedUserId::try_from("@admin:example.org").unwrap()],
+            commands_per_minute,
+            RateLimiterState::per_window(1, Duration::from_secs(300)),
+        )
+    }
+
+    fn user(id: &str) -> OwnedUserId {
+        OwnedUserId::try_from(id).unwrap()
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse_command("hello"), None);
+        assert_eq!(parse_command("!mxindexer stats"), None);
+        assert_eq!(
+            parse_command("!mxindex info Example.ORG"),
+            Some(Ok(Command::Info("example.org".to_string())))
+        );
+        assert_eq!(
+            parse_command("!mxindex add Matrix.org."),
+            Some(Ok(Command::Add("matrix.org".to_string())))
+        );
+        assert_eq!(
+            parse_command("!mxindex search rust  matrix"),
+            Some(Ok(Command::Search("rust matrix".to_string())))
+        );
+        assert_eq!(parse_command("!mxindex"), Some(Ok(Command::Help)));
+        assert_eq!(
+            parse_command("!mxindex add"),
+            Some(Err("Usage: !mxindex add <domain>".to_string()))
+        );
+        assert!(matches!(parse_command("!mxindex frobnicate"), Some(Err(_))));
+    }
+
+    #[test]
+    fn test_format_info() {
+        let (plain, html) = format_info(&summary(), true, "https://index.example");
+
+        assert_eq!(
+            plain,
+            "example.org runs Synapse 1.110.0\n\
+             Registration: closed · Public rooms: 12 · Spec: v1.11"
+        );
+        assert!(html.starts_with(
+            "<a href=\"https://index.example/servers/example.org\"><b>example.org</b></a>"
+        ));
+
+        let (plain, _) = format_info(&summary(), false, "https://index.example");
+        assert!(plain.ends_with("an admin can add it with !mxindex add example.org"));
+
+        let unversioned = ServerSummary {
+            implementation_version: None,
+            ..summary()
+        };
+        let (plain, _) = format_info(&unversioned, true, "https://index.example");
+        assert!(plain.starts_with("example.org runs Synapse\n"));
+    }
+
+    #[test]
+    fn test_format_search() {
+        let (plain, html) = format_search("syn", &[summary()], 3, "https://index.example");
+
+        assert_eq!(
+            plain,
+            "3 servers match “syn”:\n\
+             • example.org (Synapse 1.110.0)\n\
+             …and 2 more: https://index.example/servers/search?search=syn"
+        );
+        assert!(html.contains("<li><a href=\"https://index.example/servers/example.org\">"));
+
+        let (plain, _) = format_search("nothing", &[], 0, "https://index.example");
+        assert_eq!(plain, "No servers match “nothing”.");
+    }
+
+    #[tokio::test]
+    async fn test_add_is_admin_only() {
+        let handler = handler(5);
+
+        let (plain, _) = handler
+            .handle(&user("@alice:example.org"), "!mxindex add example.org")
+            .await
+            .unwrap();
+        assert_eq!(plain, "Only mxindex admins can do that.");
+
+        let (plain, _) = handler
+            .handle(&user("@admin:example.org"), "!mxindex add not/a/domain")
+            .await
+            .unwrap();
+        assert_eq!(plain, "not/a/domain is not a valid domain.");
+    }
+
+    #[tokio::test]
+    async fn test_rate_limits_per_user() {
+        let handler = handler(2);
+        let alice = user("@alice:example.org");
+
+        assert!(handler.handle(&alice, "!mxindex help").await.is_some());
+        assert!(handler.handle(&alice, "!mxindex help").await.is_some());
+        let (plain, _) = handler.handle(&alice, "!mxindex help").await.unwrap();
+        assert!(plain.starts_with("Slow down"));
+        assert!(handler.handle(&alice, "!mxindex help").await.is_none());
+        assert!(handler.handle(&alice, "!mxindex stats").await.is_none());
+
+        let (plain, _) = handler
+            .handle(&user("@bob:example.org"), "!mxindex help")
+            .await
+            .unwrap();
+        assert_eq!(plain, USAGE);
+        assert!(handler.handle(&alice, "good morning").await.is_none());
+    }
+}
@@ -0,0 +1,328 @@
+use crate::db::{get_server_ids_by_domains, record_server_bridges, DbPool, NewServerBridge};
+use crate::models::{ProbeKind, ProbePayload};
+use diesel::PgConnection;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+
+/// A bridge we know how to recognise: the third-party protocol IDs its
+/// appservice registers and the localpart prefixes of the users it puppets.
+struct BridgeSignature {
+    bridge: &'static str,
+    protocols: &'static [&'static str],
+    localpart_prefixes: &'static [&'static str],
+}
+
+const BRIDGE_SIGNATURES: &[BridgeSignature] = &[
+    BridgeSignature {
+        bridge: "telegram",
+        protocols: &["telegram"],
+        localpart_prefixes: &["telegram_", "telegrambot"],
+    },
+    BridgeSignature {
+        bridge: "discord",
+        protocols: &["discord"],
+        localpart_prefixes: &["discord_", "_discord_", "discordbot"],
+    },
+    BridgeSignature {
+        bridge: "irc",
+        protocols: &["irc"],
+        localpart_prefixes: &["irc_", "_irc_", "libera_", "oftc_"],
+    },
+    BridgeSignature {
+        bridge: "slack",
+        protocols: &["slack"],
+        localpart_prefixes: &["slack_", "_slack_", "slackbot"],
+    },
+    BridgeSignature {
+        bridge: "whatsapp",
+        protocols: &["whatsapp"],
+        localpart_prefixes: &["whatsapp_", "whatsappbot"],
+    },
+    BridgeSignature {
+        bridge: "signal",
+        protocols: &["signal"],
+        localpart_prefixes: &["signal_", "signalbot"],
+    },
+    BridgeSignature {
+        bridge: "facebook",
+        protocols: &["facebook", "messenger"],
+        localpart_prefixes: &["facebook_", "messenger_", "facebookbot"],
+    },
+];
+
+/// Bridges not seen again for this long are no longer listed or matched by
+/// searches, so a bridge a server retired drops out of the index.
+pub const BRIDGE_STALE_DAYS: i64 = 30;
+
+/// Sightings older than this are stale.
+pub fn bridges_seen_since() -> chrono::NaiveDateTime {
+    chrono::Utc::now().naive_utc() - chrono::Duration::days(BRIDGE_STALE_DAYS)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BridgeSource {
+    /// `/thirdparty/protocols`. The spec requires an access token for it, so
+    /// only servers that answer it anonymously are detected this way.
+    Protocols,
+    Heroes,
+}
+
+impl BridgeSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BridgeSource::Protocols => "protocols",
+            BridgeSource::Heroes => "heroes",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DetectedBridge {
+    pub bridge: &'static str,
+    pub source: BridgeSource,
+    pub evidence: String,
+}
+
+/// Matches the protocol IDs of a `/thirdparty/protocols` response.
+pub fn detect_from_protocols(json: &Value) -> Vec<DetectedBridge> {
+    let Some(protocols) = json.as_object() else {
+        return Vec::new();
+    };
+
+    let mut detected = Vec::new();
+    for protocol in protocols.keys() {
+        let protocol_lower = protocol.to_lowercase();
+        if let Some(signature) = BRIDGE_SIGNATURES
+            .iter()
+            .find(|s| s.protocols.contains(&protocol_lower.as_str()))
+        {
+            if !detected
+                .iter()
+                .any(|d: &DetectedBridge| d.bridge == signature.bridge)
+            {
+                detected.push(DetectedBridge {
+                    bridge: signature.bridge,
+                    source: BridgeSource::Protocols,
+                    evidence: protocol.clone(),
+                });
+            }
+        }
+    }
+    detected
+}
+
+/// Matches bridge puppet localparts among user IDs hosted on `domain`. Users
+/// of other servers say nothing about which bridges `domain` runs.
+pub fn detect_from_mxids<'a>(
+    mxids: impl IntoIterator<Item = &'a str>,
+    domain: &str,
+) -> Vec<DetectedBridge> {
+    let mut detected: Vec<DetectedBridge> = Vec::new();
+
+    for mxid in mxids {
+        let Some((localpart, server_name)) =
+            mxid.strip_prefix('@').and_then(|rest| rest.split_once(':'))
+        else {
+            continue;
+        };
+        if !server_name.eq_ignore_ascii_case(domain) {
+            continue;
+        }
+
+        let localpart = localpart.to_lowercase();
+        if let Some(signature) = BRIDGE_SIGNATURES.iter().find(|s| {
+            s.localpart_prefixes
+                .iter()
+                .any(|prefix| localpart.starts_with(prefix))
+        }) {
+            if !detected.iter().any(|d| d.bridge == signature.bridge) {
+                detected.push(DetectedBridge {
+                    bridge: signature.bridge,
+                    source: BridgeSource::Heroes,
+                    evidence: mxid.to_string(),
+                });
+            }
+        }
+    }
+
+    detected
+}
+
+/// Collects the hero user IDs of a `publicRooms` response.
+pub fn hero_mxids(json: &Value) -> Vec<&str> {
+    json["chunk"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|chunk| chunk["heroes"].as_array())
+        .flatten()
+        .filter_map(|hero| hero["mxid"].as_str())
+        .collect()
+}
+
+/// Combines the bridges advertised by a server's `/thirdparty/protocols` with
+/// the puppets among the heroes of its own room directory.
+pub fn detect_from_payloads(domain: &str, payloads: &[ProbePayload]) -> Vec<DetectedBridge> {
+    let body_of = |kind: ProbeKind| {
+        payloads
+            .iter()
+            .find(|p| p.probe == kind)
+            .and_then(|p| p.success_body())
+    };
+
+    let mut detected = body_of(ProbeKind::ThirdPartyProtocols)
+        .map(detect_from_protocols)
+        .unwrap_or_default();
+
+    if let Some(rooms) = body_of(ProbeKind::PublicRooms) {
+        detected.extend(detect_from_mxids(hero_mxids(rooms), domain));
+    }
+
+    detected
+}
+
+pub fn record_bridges_from_payloads(
+    conn: &mut PgConnection,
+    server_id: i64,
+    domain: &str,
+    payloads: &[ProbePayload],
+) -> Result<usize, diesel::result::Error> {
+    let detected = detect_from_payloads(domain, payloads);
+    record_server_bridges(conn, &new_server_bridges(server_id, &detected))
+}
+
+fn new_server_bridges(server_id: i64, detected: &[DetectedBridge]) -> Vec<NewServerBridge<'_>> {
+    detected
+        .iter()
+        .map(|d| NewServerBridge {
+            server_id,
+            bridge: d.bridge,
+            source: d.source.as_str(),
+            evidence: &d.evidence,
+        })
+        .collect()
+}
+
+/// Attributes the bridge puppets among a room directory's heroes to their
+/// homeservers, for every hero server that is already in the index.
+pub fn record_bridges_from_heroes(
+    db_pool: &DbPool,
+    json: &Value,
+) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+    let mut by_domain: HashMap<&str, Vec<&str>> = HashMap::new();
+    for mxid in hero_mxids(json) {
+        if let Some((_, domain)) = mxid.split_once(':') {
+            by_domain.entry(domain).or_default().push(mxid);
+        }
+    }
+
+    let detected: BTreeMap<&str, Vec<DetectedBridge>> = by_domain
+        .into_iter()
+        .map(|(domain, mxids)| (domain, detect_from_mxids(mxids, domain)))
+        .filter(|(_, bridges)| !bridges.is_empty())
+        .collect();
+
+    if detected.is_empty() {
+        return Ok(0);
+    }
+
+    let mut conn = db_pool.get()?;
+    let domains: Vec<&str> = detected.keys().copied().collect();
+    let server_ids = get_server_ids_by_domains(&mut conn, &domains)?;
+
+    let mut recorded = 0;
+    for (server_id, domain) in server_ids {
+        if let Some(bridges) = detected.get(domain.as_str()) {
+            recorded += record_server_bridges(&mut conn, &new_server_bridges(server_id, bridges))?;
+        }
+    }
+
+    Ok(recorded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_from_protocols() {
+        let json = serde_json::json!({
+            "irc": {"user_fields": ["network", "nickname"]},
+            "Telegram": {},
+            "gitter": {}
+        });
+
+        let detected = detect_from_protocols(&json);
+        let bridges: Vec<&str> = detected.iter().map(|d| d.bridge).collect();
+
+        assert_eq!(detected.len(), 2);
+        assert!(bridges.contains(&"irc"));
+        assert!(bridges.contains(&"telegram"));
+        assert!(detected.iter().all(|d| d.source == BridgeSource::Protocols));
+    }
+
+    #[test]
+    fn test_detect_from_mxids_only_counts_local_users() {
+        let detected = detect_from_mxids(
+            [
+                "@telegram_123:bridged.org",
+                "@telegram_456:bridged.org",
+                "@_discord_789:bridged.org",
+                "@slack_U1:elsewhere.org",
+                "@alice:bridged.org",
+            ],
+            "bridged.org",
+        );
+
+        assert_eq!(detected.len(), 2);
+        assert_eq!(detected[0].bridge, "telegram");
+        assert_eq!(detected[0].evidence, "@telegram_123:bridged.org");
+        assert_eq!(detected[1].bridge, "discord");
+    }
+
+    #[test]
+    fn test_detect_from_payloads() {
+        let payload = |probe, status, body| ProbePayload {
+            probe,
+            url: String::new(),
+            status,
+            headers: serde_json::json!({}),
+            body: Some(body),
+            error: None,
+            fetched_at: chrono::Utc::now().naive_utc(),
+            duration_ms: None,
+        };
+
+        let payloads = vec![
+            payload(
+                ProbeKind::ThirdPartyProtocols,
+                Some(401),
+                serde_json::json!({"errcode": "M_MISSING_TOKEN"}),
+            ),
+            payload(
+                ProbeKind::PublicRooms,
+                Some(200),
+                serde_json::json!({
+                    "chunk": [{"heroes": [{"mxid": "@whatsapp_4412345:bridged.org"}]}]
+                }),
+            ),
+        ];
+
+        let detected = detect_from_payloads("bridged.org", &payloads);
+        assert_eq!(detected.len(), 1);
+        assert_eq!(detected[0].bridge, "whatsapp");
+        assert_eq!(detected[0].source, BridgeSource::Heroes);
+    }
+
+    #[test]
+    fn test_hero_mxids() {
+        let json = serde_json::json!({
+            "chunk": [
+                {"heroes": [{"mxid": "@a:one.org"}, {"mxid": "@b:two.org"}]},
+                {"name": "no heroes"}
+            ]
+        });
+
+        assert_eq!(hero_mxids(&json), vec!["@a:one.org", "@b:two.org"]);
+    }
+}
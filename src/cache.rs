@@ -90,6 +90,54 @@ impl Cache {
         Ok(exists)
     }
 
+    pub async fn publish<T: Serialize>(&self, channel: &str, value: &T) -> Result<(), CacheError> {
+        let mut guard = self.connection.write().await;
+        let conn = guard.as_mut().ok_or(CacheError::NotInitialized)?;
+
+        let data = serde_json::to_string(value)?;
+        let _receivers: usize = conn.publish(channel, data).await?;
+        Ok(())
+    }
+
+    /// Prepends `value` to the list at `key`, keeping the newest `max_len`
+    /// entries.
+    pub async fn push_capped<T: Serialize>(
+        &self,
+        key: &str,
+        value: &T,
+        max_len: usize,
+    ) -> Result<(), CacheError> {
+        let mut guard = self.connection.write().await;
+        let conn = guard.as_mut().ok_or(CacheError::NotInitialized)?;
+
+        let data = serde_json::to_string(value)?;
+
+        let _: () = redis::pipe()
+            .atomic()
+            .lpush(key, data)
+            .ignore()
+            .ltrim(key, 0, max_len as isize - 1)
+            .ignore()
+            .query_async(conn)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Reads a list written by `push_capped`, newest first. Entries that no
+    /// longer deserialize are skipped.
+    pub async fn list<T: DeserializeOwned>(&self, key: &str) -> Result<Vec<T>, CacheError> {
+        let mut guard = self.connection.write().await;
+        let conn = guard.as_mut().ok_or(CacheError::NotInitialized)?;
+
+        let entries: Vec<String> = conn.lrange(key, 0, -1).await?;
+
+        Ok(entries
+            .iter()
+            .filter_map(|entry| serde_json::from_str(entry).ok())
+            .collect())
+    }
+
     pub async fn invalidate_pattern(&self, pattern: &str) -> Result<(), CacheError> {
         let mut guard = self.connection.write().await;
         let conn = guard.as_mut().ok_or(CacheError::NotInitialized)?;
@@ -1,11 +1,16 @@
-use crate::schema::servers;
+use crate::models::{DiscoveredServerInfo, FieldChange, ProbeKind, ProbePayload};
+use crate::schema::{
+    identity_servers, probe_payloads, server_bridges, server_events, server_key_alerts,
+    server_keys, server_revisions, servers, webhook_deliveries, webhook_outbox,
+    webhook_subscriptions, well_known_findings,
+};
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use diesel::r2d2::{ConnectionManager, Pool};
 
 pub type DbPool = Pool<ConnectionManager<PgConnection>>;
 
-#[derive(Queryable, Selectable, Debug, serde::Serialize)]
+#[derive(Queryable, Selectable, Clone, Debug, serde::Serialize)]
 #[diesel(table_name = servers)]
 #[serde(rename_all = "camelCase")]
 pub struct Server {
@@ -23,10 +28,31 @@ pub struct Server {
     pub room_versions: Option<String>,
     pub created_at: chrono::NaiveDateTime,
     pub updated_at: chrono::NaiveDateTime,
+    pub missing_fields: serde_json::Value,
+    pub client_api_version: Option<String>,
+    pub spec_versions: Vec<String>,
+    pub latest_spec_version: Option<String>,
+    pub unstable_features: serde_json::Value,
+    pub login_flows: Vec<String>,
+    pub sso_providers: serde_json::Value,
+    pub oidc_issuer: Option<String>,
+    pub support_contacts: serde_json::Value,
+    pub support_page: Option<String>,
+    pub terms_policies: serde_json::Value,
+    pub identity_server: Option<String>,
+    pub identity_server_id: Option<i64>,
+    pub sliding_sync: Option<String>,
+    pub sliding_sync_proxy: Option<String>,
+    pub sliding_sync_proxy_reachable: Option<bool>,
+    pub client_capabilities: serde_json::Value,
+    pub media_upload_size: Option<i64>,
+    pub authenticated_media_enforced: Option<bool>,
+    pub implementation: Option<String>,
+    pub last_refreshed_at: Option<chrono::NaiveDateTime>,
 }
 
-#[derive(Insertable, Debug)]
-#[diesel(table_name = servers)]
+#[derive(Insertable, AsChangeset, Debug)]
+#[diesel(table_name = servers, treat_none_as_null = true)]
 pub struct NewServer<'a> {
     pub domain: &'a str,
     pub name: Option<&'a str>,
@@ -39,14 +65,369 @@ pub struct NewServer<'a> {
     pub federation_version: Option<&'a str>,
     pub delegated_server: Option<&'a str>,
     pub room_versions: Option<&'a str>,
+    pub missing_fields: serde_json::Value,
+    pub client_api_version: Option<&'a str>,
+    pub spec_versions: &'a [String],
+    pub latest_spec_version: Option<&'a str>,
+    pub unstable_features: &'a serde_json::Value,
+    pub login_flows: &'a [String],
+    pub sso_providers: serde_json::Value,
+    pub oidc_issuer: Option<&'a str>,
+    pub support_contacts: serde_json::Value,
+    pub support_page: Option<&'a str>,
+    pub terms_policies: serde_json::Value,
+    pub identity_server: Option<&'a str>,
+    pub sliding_sync: Option<&'a str>,
+    pub sliding_sync_proxy: Option<&'a str>,
+    pub sliding_sync_proxy_reachable: Option<bool>,
+    pub client_capabilities: serde_json::Value,
+    pub media_upload_size: Option<i64>,
+    pub authenticated_media_enforced: Option<bool>,
+    pub implementation: Option<&'a str>,
+}
+
+impl<'a> NewServer<'a> {
+    /// The `servers` row for `domain` as derived by discovery.
+    pub fn from_discovered(domain: &'a str, info: &'a DiscoveredServerInfo) -> Self {
+        NewServer {
+            domain,
+            name: info.name.as_deref(),
+            description: info.description.as_deref(),
+            logo_url: info.logo_url.as_deref(),
+            theme: info.theme.as_deref(),
+            registration_open: info.registration_open,
+            public_rooms_count: info.public_rooms_count,
+            version: info.version.as_deref(),
+            federation_version: info.federation_version.as_deref(),
+            delegated_server: info.delegated_server.as_deref(),
+            room_versions: info.room_versions.as_deref(),
+            missing_fields: info.missing_fields_json(),
+            client_api_version: info.client_api_version.map(|v| v.as_str()),
+            spec_versions: &info.spec_versions,
+            latest_spec_version: info.latest_spec_version.as_deref(),
+            unstable_features: &info.unstable_features,
+            login_flows: &info.login_flows,
+            sso_providers: serde_json::to_value(&info.sso_providers)
+                .unwrap_or_else(|_| serde_json::json!([])),
+            oidc_issuer: info.oidc_issuer.as_deref(),
+            support_contacts: serde_json::to_value(&info.support_contacts)
+                .unwrap_or_else(|_| serde_json::json!([])),
+            support_page: info.support_page.as_deref(),
+            terms_policies: serde_json::to_value(&info.terms_policies)
+                .unwrap_or_else(|_| serde_json::json!([])),
+            identity_server: info.identity_server.as_deref(),
+            sliding_sync: info.sliding_sync.map(|s| s.as_str()),
+            sliding_sync_proxy: info.sliding_sync_proxy.as_deref(),
+            sliding_sync_proxy_reachable: info.sliding_sync_proxy_reachable,
+            client_capabilities: serde_json::to_value(&info.client_capabilities)
+                .unwrap_or_else(|_| serde_json::json!({})),
+            media_upload_size: info.media_upload_size,
+            authenticated_media_enforced: info.authenticated_media_enforced,
+            implementation: info.implementation.as_deref(),
+        }
+    }
+}
+
+#[derive(Queryable, Selectable, Debug, serde::Serialize)]
+#[diesel(table_name = server_keys)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerKey {
+    pub id: i64,
+    pub server_id: i64,
+    pub key_id: String,
+    pub public_key: String,
+    pub valid_until_ts: Option<i64>,
+    pub expired_ts: Option<i64>,
+    pub is_current: bool,
+    pub signature_valid: bool,
+    pub first_seen_at: chrono::NaiveDateTime,
+    pub last_seen_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = server_keys)]
+pub struct NewServerKey<'a> {
+    pub server_id: i64,
+    pub key_id: &'a str,
+    pub public_key: &'a str,
+    pub valid_until_ts: Option<i64>,
+    pub expired_ts: Option<i64>,
+    pub is_current: bool,
+    pub signature_valid: bool,
+}
+
+#[derive(Queryable, Selectable, Debug, serde::Serialize)]
+#[diesel(table_name = server_key_alerts)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerKeyAlert {
+    pub id: i64,
+    pub server_id: i64,
+    pub key_id: Option<String>,
+    pub kind: String,
+    pub message: String,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = server_key_alerts)]
+pub struct NewServerKeyAlert<'a> {
+    pub server_id: i64,
+    pub key_id: Option<&'a str>,
+    pub kind: &'a str,
+    pub message: &'a str,
+}
+
+#[derive(Queryable, Selectable, Debug, serde::Serialize)]
+#[diesel(table_name = well_known_findings)]
+#[serde(rename_all = "camelCase")]
+pub struct WellKnownFinding {
+    pub id: i64,
+    pub server_id: i64,
+    pub document: String,
+    pub severity: String,
+    pub code: String,
+    pub message: String,
+    pub checked_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = well_known_findings)]
+pub struct NewWellKnownFinding<'a> {
+    pub server_id: i64,
+    pub document: &'a str,
+    pub severity: &'a str,
+    pub code: &'a str,
+    pub message: &'a str,
+}
+
+#[derive(Queryable, Selectable, Debug, serde::Serialize)]
+#[diesel(table_name = server_bridges)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerBridge {
+    pub id: i64,
+    pub server_id: i64,
+    pub bridge: String,
+    pub source: String,
+    pub evidence: String,
+    pub first_seen_at: chrono::NaiveDateTime,
+    pub last_seen_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = server_bridges)]
+pub struct NewServerBridge<'a> {
+    pub server_id: i64,
+    pub bridge: &'a str,
+    pub source: &'a str,
+    pub evidence: &'a str,
+}
+
+#[derive(Queryable, Selectable, Debug, serde::Serialize)]
+#[diesel(table_name = server_revisions)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerRevision {
+    pub id: i64,
+    pub server_id: i64,
+    pub field: String,
+    pub old_value: serde_json::Value,
+    pub new_value: serde_json::Value,
+    pub observed_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = server_revisions)]
+pub struct NewServerRevision<'a> {
+    pub server_id: i64,
+    pub field: &'a str,
+    pub old_value: &'a serde_json::Value,
+    pub new_value: &'a serde_json::Value,
+}
+
+#[derive(Queryable, Selectable, Debug, serde::Serialize)]
+#[diesel(table_name = server_events)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerEvent {
+    pub id: i64,
+    pub server_id: Option<i64>,
+    pub domain: String,
+    pub kind: String,
+    pub details: serde_json::Value,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = server_events)]
+pub struct NewServerEvent<'a> {
+    pub server_id: Option<i64>,
+    pub domain: &'a str,
+    pub kind: &'a str,
+    pub details: &'a serde_json::Value,
+}
+
+#[derive(Queryable, Selectable, Debug, serde::Serialize)]
+#[diesel(table_name = webhook_subscriptions)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookSubscription {
+    pub id: i64,
+    pub url: String,
+    #[serde(skip)]
+    pub secret: String,
+    pub event_kinds: Vec<String>,
+    pub domain: Option<String>,
+    pub enabled: bool,
+    pub consecutive_failures: i32,
+    /// Events up to this id have been delivered.
+    pub last_event_id: i64,
+    pub disabled_at: Option<chrono::NaiveDateTime>,
+    pub created_at: chrono::NaiveDateTime,
+    #[serde(skip)]
+    pub token_hash: Option<String>,
+}
+
+impl WebhookSubscription {
+    /// Whether `event` matches the kinds and domain the subscription asked
+    /// for.
+    pub fn wants(&self, event: &ServerEvent) -> bool {
+        (self.event_kinds.is_empty() || self.event_kinds.contains(&event.kind))
+            && self
+                .domain
+                .as_deref()
+                .is_none_or(|wanted| wanted == event.domain)
+    }
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = webhook_subscriptions)]
+pub struct NewWebhookSubscription<'a> {
+    pub url: &'a str,
+    pub secret: &'a str,
+    pub event_kinds: Vec<String>,
+    pub domain: Option<&'a str>,
+    pub last_event_id: i64,
+    pub token_hash: &'a str,
 }
 
-#[derive(Debug, Default)]
+#[derive(Queryable, Selectable, Debug, serde::Serialize)]
+#[diesel(table_name = webhook_deliveries)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub subscription_id: i64,
+    pub event_id: i64,
+    pub success: bool,
+    pub attempts: i32,
+    pub status: Option<i32>,
+    pub error: Option<String>,
+    pub delivered_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = webhook_deliveries)]
+pub struct NewWebhookDelivery<'a> {
+    pub subscription_id: i64,
+    pub event_id: i64,
+    pub success: bool,
+    pub attempts: i32,
+    pub status: Option<i32>,
+    pub error: Option<&'a str>,
+}
+
+#[derive(Queryable, Selectable, Debug, serde::Serialize)]
+#[diesel(table_name = identity_servers)]
+#[serde(rename_all = "camelCase")]
+pub struct IdentityServer {
+    pub id: i64,
+    pub base_url: String,
+    pub reachable: Option<bool>,
+    pub terms_policies: serde_json::Value,
+    pub last_checked_at: Option<chrono::NaiveDateTime>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Queryable, Selectable, Debug, serde::Serialize)]
+#[diesel(table_name = probe_payloads)]
+#[serde(rename_all = "camelCase")]
+pub struct ProbePayloadRecord {
+    pub id: i64,
+    pub server_id: i64,
+    pub probe: String,
+    pub url: String,
+    pub status: Option<i32>,
+    pub headers: serde_json::Value,
+    pub body: Option<serde_json::Value>,
+    pub error: Option<String>,
+    pub fetched_at: chrono::NaiveDateTime,
+    pub duration_ms: Option<i32>,
+}
+
+impl ProbePayloadRecord {
+    /// Converts a stored row back into a payload. Rows written by a probe this
+    /// build no longer knows about are dropped.
+    pub fn into_payload(self) -> Option<ProbePayload> {
+        Some(ProbePayload {
+            probe: ProbeKind::parse(&self.probe)?,
+            url: self.url,
+            status: self.status.and_then(|s| u16::try_from(s).ok()),
+            headers: self.headers,
+            body: self.body,
+            error: self.error,
+            fetched_at: self.fetched_at,
+            duration_ms: self.duration_ms.and_then(|d| u32::try_from(d).ok()),
+        })
+    }
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = probe_payloads)]
+pub struct NewProbePayload<'a> {
+    pub server_id: i64,
+    pub probe: &'a str,
+    pub url: &'a str,
+    pub status: Option<i32>,
+    pub headers: &'a serde_json::Value,
+    pub body: Option<&'a serde_json::Value>,
+    pub error: Option<&'a str>,
+    pub fetched_at: chrono::NaiveDateTime,
+    pub duration_ms: Option<i32>,
+}
+
+impl<'a> NewProbePayload<'a> {
+    pub fn from_payload(server_id: i64, payload: &'a ProbePayload) -> Self {
+        NewProbePayload {
+            server_id,
+            probe: payload.probe.as_str(),
+            url: &payload.url,
+            status: payload.status.map(i32::from),
+            headers: &payload.headers,
+            body: payload.body.as_ref(),
+            error: payload.error.as_deref(),
+            fetched_at: payload.fetched_at,
+            duration_ms: payload
+                .duration_ms
+                .map(|d| i32::try_from(d).unwrap_or(i32::MAX)),
+        }
+    }
+}
+
+#[derive(Debug, Default, serde::Serialize)]
 pub struct ServerFilter {
     pub search: Option<String>,
     pub registration_open: Option<bool>,
     pub has_rooms: Option<bool>,
     pub room_version: Option<String>,
+    pub well_known_problems: Option<bool>,
+    pub spec_version: Option<String>,
+    pub feature: Option<String>,
+    pub login: Option<String>,
+    pub oidc: Option<bool>,
+    pub has_terms: Option<bool>,
+    pub bridge: Option<String>,
+    pub sliding_sync: Option<String>,
+    pub element_x: Option<bool>,
+    pub capability: Option<String>,
+    pub min_upload_size: Option<i64>,
+    pub max_upload_size: Option<i64>,
+    pub authenticated_media: Option<bool>,
     pub sort_by: Option<String>,
     pub sort_order: Option<String>,
     pub limit: Option<i32>,
@@ -92,9 +473,21 @@ pub fn insert_server(
 
     diesel::insert_into(servers)
         .values(new_server)
-        .execute(conn)?;
+        .returning(Server::as_returning())
+        .get_result(conn)
+}
 
-    servers.order(id.desc()).first(conn)
+/// Overwrites the discovered columns of a server and bumps `updated_at`.
+pub fn update_server(
+    conn: &mut PgConnection,
+    server_id: i64,
+    changes: &NewServer,
+) -> Result<Server, diesel::result::Error> {
+    use crate::schema::servers::dsl::*;
+
+    diesel::update(servers.find(server_id))
+        .set((changes, updated_at.eq(diesel::dsl::now)))
+        .get_result(conn)
 }
 
 pub fn get_server_by_domain(
@@ -109,89 +502,168 @@ pub fn get_server_by_domain(
         .optional()
 }
 
-#[allow(dead_code)]
 pub fn get_all_servers(conn: &mut PgConnection) -> Result<Vec<Server>, diesel::result::Error> {
     use crate::schema::servers::dsl::*;
 
     servers.load(conn)
 }
 
-pub fn get_filtered_servers(
-    conn: &mut PgConnection,
-    filter: &ServerFilter,
-) -> Result<PaginatedServers, diesel::result::Error> {
-    use crate::schema::servers::dsl::*;
-
-    let limit = filter.limit.unwrap_or(50).clamp(1, 100);
-    let offset = filter.offset.unwrap_or(0).max(0);
-
-    let sort_by = filter.sort_by.as_deref().unwrap_or("created_at");
-    let sort_order = filter.sort_order.as_deref().unwrap_or("desc");
+/// Accepts both `sso` and `m.login.sso` for the `login` filter.
+fn login_flow_type(login: &str) -> String {
+    if login.contains('.') {
+        login.to_string()
+    } else {
+        format!("m.login.{}", login)
+    }
+}
 
-    let search_pattern = filter
-        .search
-        .as_ref()
-        .map(|s| format!("%{}%", s.to_lowercase()));
-    let room_version_pattern = filter.room_version.as_ref().map(|rv| format!("%{}%", rv));
+type BoxedServersQuery<'a> = servers::BoxedQuery<'a, diesel::pg::Pg>;
 
-    let mut count_query = servers.into_boxed();
+/// Applies every `ServerFilter` condition to a boxed `servers` query so the
+/// count and page queries always agree.
+fn apply_server_filter<'a>(
+    mut query: BoxedServersQuery<'a>,
+    filter: &ServerFilter,
+) -> BoxedServersQuery<'a> {
+    use crate::schema::servers::dsl::*;
 
-    if let Some(ref pattern) = search_pattern {
-        count_query = count_query.filter(
+    if let Some(ref search) = filter.search {
+        let pattern = format!("%{}%", search.to_lowercase());
+        query = query.filter(
             domain
-                .ilike(pattern)
-                .or(name.ilike(pattern))
+                .ilike(pattern.clone())
+                .or(name.ilike(pattern.clone()))
                 .or(description.ilike(pattern)),
         );
     }
 
     if let Some(reg_open) = filter.registration_open {
-        count_query = count_query.filter(registration_open.eq(reg_open));
+        query = query.filter(registration_open.eq(reg_open));
     }
 
     if let Some(has_rooms) = filter.has_rooms {
         if has_rooms {
-            count_query = count_query.filter(public_rooms_count.gt(0));
+            query = query.filter(public_rooms_count.gt(0));
         } else {
-            count_query =
-                count_query.filter(public_rooms_count.le(0).or(public_rooms_count.is_null()));
+            query = query.filter(public_rooms_count.le(0).or(public_rooms_count.is_null()));
         }
     }
 
-    if let Some(ref pattern) = room_version_pattern {
-        count_query = count_query.filter(room_versions.like(pattern));
+    if let Some(ref rv) = filter.room_version {
+        query = query.filter(room_versions.like(format!("%{}%", rv)));
     }
 
-    let total = count_query.count().get_result::<i64>(conn)?;
+    if let Some(ref spec_version) = filter.spec_version {
+        query = query.filter(spec_versions.contains(vec![spec_version.clone()]));
+    }
 
-    let mut result_query = servers.into_boxed();
+    if let Some(ref feature) = filter.feature {
+        query = query.filter(unstable_features.contains(serde_json::json!({ feature: true })));
+    }
 
-    if let Some(ref pattern) = search_pattern {
-        result_query = result_query.filter(
-            domain
-                .ilike(pattern)
-                .or(name.ilike(pattern))
-                .or(description.ilike(pattern)),
-        );
+    if let Some(ref login) = filter.login {
+        query = query.filter(login_flows.contains(vec![login_flow_type(login)]));
     }
 
-    if let Some(reg_open) = filter.registration_open {
-        result_query = result_query.filter(registration_open.eq(reg_open));
+    if let Some(oidc) = filter.oidc {
+        if oidc {
+            query = query.filter(oidc_issuer.is_not_null());
+        } else {
+            query = query.filter(oidc_issuer.is_null());
+        }
     }
 
-    if let Some(has_rooms) = filter.has_rooms {
-        if has_rooms {
-            result_query = result_query.filter(public_rooms_count.gt(0));
+    if let Some(has_terms) = filter.has_terms {
+        if has_terms {
+            query = query.filter(terms_policies.ne(serde_json::json!([])));
         } else {
-            result_query =
-                result_query.filter(public_rooms_count.le(0).or(public_rooms_count.is_null()));
+            query = query.filter(terms_policies.eq(serde_json::json!([])));
         }
     }
 
-    if let Some(ref pattern) = room_version_pattern {
-        result_query = result_query.filter(room_versions.like(pattern));
+    if let Some(ref mode) = filter.sliding_sync {
+        query = query.filter(sliding_sync.eq(mode.to_lowercase()));
+    }
+
+    // Mirrors `SlidingSync::element_x_compatible`.
+    if let Some(element_x) = filter.element_x {
+        if element_x {
+            query = query.filter(
+                sliding_sync.eq("native").or(sliding_sync
+                    .eq("proxy")
+                    .and(sliding_sync_proxy_reachable.eq(true))),
+            );
+        } else {
+            query = query.filter(
+                sliding_sync.is_null().or(sliding_sync
+                    .eq("proxy")
+                    .and(sliding_sync_proxy_reachable.is_distinct_from(true))),
+            );
+        }
+    }
+
+    if let Some(ref capability) = filter.capability {
+        query = query.filter(client_capabilities.contains(serde_json::json!({ capability: true })));
+    }
+
+    if let Some(min_size) = filter.min_upload_size {
+        query = query.filter(media_upload_size.ge(min_size));
+    }
+
+    if let Some(max_size) = filter.max_upload_size {
+        query = query.filter(media_upload_size.le(max_size));
     }
 
+    if let Some(enforced) = filter.authenticated_media {
+        query = query.filter(authenticated_media_enforced.eq(enforced));
+    }
+
+    if let Some(ref bridge_name) = filter.bridge {
+        query = query.filter(diesel::dsl::exists(
+            server_bridges::table
+                .filter(server_bridges::server_id.eq(id))
+                .filter(server_bridges::bridge.eq(bridge_name.to_lowercase()))
+                .filter(server_bridges::last_seen_at.ge(crate::bridges::bridges_seen_since())),
+        ));
+    }
+
+    if let Some(has_problems) = filter.well_known_problems {
+        use crate::schema::well_known_findings;
+
+        let problems = diesel::dsl::exists(
+            well_known_findings::table
+                .filter(well_known_findings::server_id.eq(id))
+                .filter(well_known_findings::severity.ne("info")),
+        );
+
+        if has_problems {
+            query = query.filter(problems);
+        } else {
+            query = query.filter(diesel::dsl::not(problems));
+        }
+    }
+
+    query
+}
+
+pub fn get_filtered_servers(
+    conn: &mut PgConnection,
+    filter: &ServerFilter,
+) -> Result<PaginatedServers, diesel::result::Error> {
+    use crate::schema::servers::dsl::*;
+
+    let limit = filter.limit.unwrap_or(50).clamp(1, 100);
+    let offset = filter.offset.unwrap_or(0).max(0);
+
+    let sort_by = filter.sort_by.as_deref().unwrap_or("created_at");
+    let sort_order = filter.sort_order.as_deref().unwrap_or("desc");
+
+    let total = apply_server_filter(servers.into_boxed(), filter)
+        .count()
+        .get_result::<i64>(conn)?;
+
+    let result_query = apply_server_filter(servers.into_boxed(), filter);
+
     let result_servers: Vec<Server> = match sort_by {
         "name" => {
             if sort_order == "asc" {
@@ -234,6 +706,792 @@ pub fn get_filtered_servers(
     })
 }
 
+pub fn get_current_server_keys(
+    conn: &mut PgConnection,
+    target_server_id: i64,
+) -> Result<Vec<ServerKey>, diesel::result::Error> {
+    use crate::schema::server_keys::dsl::*;
+
+    server_keys
+        .filter(server_id.eq(target_server_id))
+        .filter(is_current.eq(true))
+        .load(conn)
+}
+
+pub fn get_server_keys(
+    conn: &mut PgConnection,
+    target_server_id: i64,
+) -> Result<Vec<ServerKey>, diesel::result::Error> {
+    use crate::schema::server_keys::dsl::*;
+
+    server_keys
+        .filter(server_id.eq(target_server_id))
+        .order((is_current.desc(), first_seen_at.desc()))
+        .load(conn)
+}
+
+/// Upserts every observed key for a server. Keys missing from the latest
+/// observation stay in the history but are no longer marked current.
+pub fn record_server_keys(
+    conn: &mut PgConnection,
+    target_server_id: i64,
+    new_keys: &[NewServerKey],
+) -> Result<(), diesel::result::Error> {
+    use crate::schema::server_keys::dsl::*;
+
+    conn.transaction(|conn| {
+        diesel::update(server_keys.filter(server_id.eq(target_server_id)))
+            .set(is_current.eq(false))
+            .execute(conn)?;
+
+        for key in new_keys {
+            diesel::insert_into(server_keys)
+                .values(key)
+                .on_conflict((server_id, key_id, public_key))
+                .do_update()
+                .set((
+                    valid_until_ts.eq(key.valid_until_ts),
+                    expired_ts.eq(key.expired_ts),
+                    is_current.eq(key.is_current),
+                    signature_valid.eq(key.signature_valid),
+                    last_seen_at.eq(diesel::dsl::now),
+                ))
+                .execute(conn)?;
+        }
+
+        Ok(())
+    })
+}
+
+pub fn insert_key_alerts(
+    conn: &mut PgConnection,
+    alerts: &[NewServerKeyAlert],
+) -> Result<usize, diesel::result::Error> {
+    use crate::schema::server_key_alerts::dsl::*;
+
+    if alerts.is_empty() {
+        return Ok(0);
+    }
+
+    diesel::insert_into(server_key_alerts)
+        .values(alerts)
+        .execute(conn)
+}
+
+pub fn get_key_alerts(
+    conn: &mut PgConnection,
+    target_server_id: i64,
+) -> Result<Vec<ServerKeyAlert>, diesel::result::Error> {
+    use crate::schema::server_key_alerts::dsl::*;
+
+    server_key_alerts
+        .filter(server_id.eq(target_server_id))
+        .order(created_at.desc())
+        .limit(100)
+        .load(conn)
+}
+
+/// Replaces the stored well-known findings of a server with a fresh lint run.
+pub fn replace_well_known_findings(
+    conn: &mut PgConnection,
+    target_server_id: i64,
+    findings: &[NewWellKnownFinding],
+) -> Result<(), diesel::result::Error> {
+    use crate::schema::well_known_findings::dsl::*;
+
+    conn.transaction(|conn| {
+        diesel::delete(well_known_findings.filter(server_id.eq(target_server_id))).execute(conn)?;
+
+        if !findings.is_empty() {
+            diesel::insert_into(well_known_findings)
+                .values(findings)
+                .execute(conn)?;
+        }
+
+        Ok(())
+    })
+}
+
+pub fn get_well_known_findings(
+    conn: &mut PgConnection,
+    target_server_id: i64,
+) -> Result<Vec<WellKnownFinding>, diesel::result::Error> {
+    use crate::schema::well_known_findings::dsl::*;
+
+    well_known_findings
+        .filter(server_id.eq(target_server_id))
+        .order((document.asc(), id.asc()))
+        .load(conn)
+}
+
+pub fn insert_probe_payloads(
+    conn: &mut PgConnection,
+    target_server_id: i64,
+    payloads: &[ProbePayload],
+) -> Result<usize, diesel::result::Error> {
+    use crate::schema::probe_payloads::dsl::*;
+
+    if payloads.is_empty() {
+        return Ok(0);
+    }
+
+    let rows: Vec<NewProbePayload> = payloads
+        .iter()
+        .map(|p| NewProbePayload::from_payload(target_server_id, p))
+        .collect();
+
+    diesel::insert_into(probe_payloads)
+        .values(&rows)
+        .execute(conn)
+}
+
+/// The most recent stored payload of one probe for a server.
+pub fn get_latest_probe_payload(
+    conn: &mut PgConnection,
+    target_server_id: i64,
+    kind: ProbeKind,
+) -> Result<Option<ProbePayloadRecord>, diesel::result::Error> {
+    use crate::schema::probe_payloads::dsl::*;
+
+    probe_payloads
+        .filter(server_id.eq(target_server_id))
+        .filter(probe.eq(kind.as_str()))
+        .order((fetched_at.desc(), id.desc()))
+        .first(conn)
+        .optional()
+}
+
+/// When a probe ran for a server since `since`, and the status it got.
+pub fn get_probe_history(
+    conn: &mut PgConnection,
+    target_server_id: i64,
+    kind: ProbeKind,
+    since: chrono::NaiveDateTime,
+) -> Result<Vec<(chrono::NaiveDateTime, Option<i32>)>, diesel::result::Error> {
+    use crate::schema::probe_payloads::dsl::*;
+
+    probe_payloads
+        .filter(server_id.eq(target_server_id))
+        .filter(probe.eq(kind.as_str()))
+        .filter(fetched_at.ge(since))
+        .select((fetched_at, status))
+        .order(fetched_at.desc())
+        .load(conn)
+}
+
+/// Returns the most recent stored payload of every probe kind for a server.
+pub fn get_latest_probe_payloads(
+    conn: &mut PgConnection,
+    target_server_id: i64,
+) -> Result<Vec<ProbePayloadRecord>, diesel::result::Error> {
+    use crate::schema::probe_payloads::dsl::*;
+
+    probe_payloads
+        .filter(server_id.eq(target_server_id))
+        .distinct_on(probe)
+        .order((probe.asc(), fetched_at.desc(), id.desc()))
+        .load(conn)
+}
+
+/// Ids of the rows of one probe to prune, given its `(id, fetched_at)` rows
+/// newest first: everything past the `keep_latest` newest, except
+/// `/versions` checks since `uptime_since`, which feed the uptime summary.
+pub fn stale_probe_payloads(
+    kind: &str,
+    rows: &[(i64, chrono::NaiveDateTime)],
+    keep_latest: usize,
+    uptime_since: chrono::NaiveDateTime,
+) -> Vec<i64> {
+    let keeps_uptime = kind == ProbeKind::ClientVersions.as_str();
+
+    rows.iter()
+        .skip(keep_latest)
+        .filter(|(_, at)| !(keeps_uptime && *at >= uptime_since))
+        .map(|(row_id, _)| *row_id)
+        .collect()
+}
+
+/// Deletes a server's probe payloads that [`stale_probe_payloads`] no longer
+/// needs. Returns how many rows went.
+pub fn prune_probe_payloads(
+    conn: &mut PgConnection,
+    target_server_id: i64,
+    keep_latest: usize,
+    uptime_since: chrono::NaiveDateTime,
+) -> Result<usize, diesel::result::Error> {
+    use crate::schema::probe_payloads::dsl::*;
+
+    let kinds: Vec<String> = probe_payloads
+        .filter(server_id.eq(target_server_id))
+        .select(probe)
+        .distinct()
+        .load(conn)?;
+
+    let mut stale = Vec::new();
+    for kind in kinds {
+        let rows: Vec<(i64, chrono::NaiveDateTime)> = probe_payloads
+            .filter(server_id.eq(target_server_id))
+            .filter(probe.eq(&kind))
+            .order((fetched_at.desc(), id.desc()))
+            .select((id, fetched_at))
+            .load(conn)?;
+        stale.extend(stale_probe_payloads(
+            &kind,
+            &rows,
+            keep_latest,
+            uptime_since,
+        ));
+    }
+
+    if stale.is_empty() {
+        return Ok(0);
+    }
+
+    diesel::delete(probe_payloads.filter(id.eq_any(&stale))).execute(conn)
+}
+
+/// Ids of every indexed server, for maintenance passes over all of them.
+pub fn get_server_ids(conn: &mut PgConnection) -> Result<Vec<i64>, diesel::result::Error> {
+    use crate::schema::servers::dsl::*;
+
+    servers.select(id).order(id.asc()).load(conn)
+}
+
+/// Records detected bridges, refreshing the evidence and last sighting of
+/// bridges already known from the same source.
+pub fn record_server_bridges(
+    conn: &mut PgConnection,
+    bridges: &[NewServerBridge],
+) -> Result<usize, diesel::result::Error> {
+    use crate::schema::server_bridges::dsl::*;
+    use diesel::upsert::excluded;
+
+    if bridges.is_empty() {
+        return Ok(0);
+    }
+
+    diesel::insert_into(server_bridges)
+        .values(bridges)
+        .on_conflict((server_id, bridge, source))
+        .do_update()
+        .set((
+            evidence.eq(excluded(evidence)),
+            last_seen_at.eq(diesel::dsl::now),
+        ))
+        .execute(conn)
+}
+
+/// The bridges of a server seen within the last
+/// [`BRIDGE_STALE_DAYS`](crate::bridges::BRIDGE_STALE_DAYS).
+pub fn get_server_bridges(
+    conn: &mut PgConnection,
+    target_server_id: i64,
+) -> Result<Vec<ServerBridge>, diesel::result::Error> {
+    use crate::schema::server_bridges::dsl::*;
+
+    server_bridges
+        .filter(server_id.eq(target_server_id))
+        .filter(last_seen_at.ge(crate::bridges::bridges_seen_since()))
+        .order((bridge.asc(), source.asc()))
+        .load(conn)
+}
+
+/// Stores one revision per changed field. Revisions written together share
+/// their `observed_at`.
+pub fn record_server_revisions(
+    conn: &mut PgConnection,
+    target_server_id: i64,
+    changes: &[FieldChange],
+) -> Result<usize, diesel::result::Error> {
+    use crate::schema::server_revisions::dsl::*;
+
+    if changes.is_empty() {
+        return Ok(0);
+    }
+
+    let rows: Vec<NewServerRevision> = changes
+        .iter()
+        .map(|change| NewServerRevision {
+            server_id: target_server_id,
+            field: &change.field,
+            old_value: &change.old,
+            new_value: &change.new,
+        })
+        .collect();
+
+    diesel::insert_into(server_revisions)
+        .values(&rows)
+        .execute(conn)
+}
+
+/// Returns a server's revisions newest first, optionally for one field only.
+pub fn get_server_revisions(
+    conn: &mut PgConnection,
+    target_server_id: i64,
+    target_field: Option<&str>,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<ServerRevision>, diesel::result::Error> {
+    use crate::schema::server_revisions::dsl::*;
+
+    let mut query = server_revisions
+        .filter(server_id.eq(target_server_id))
+        .into_boxed();
+
+    if let Some(target_field) = target_field {
+        query = query.filter(field.eq(target_field));
+    }
+
+    query
+        .order((observed_at.desc(), field.asc()))
+        .limit(limit)
+        .offset(offset)
+        .load(conn)
+}
+
+/// Logs events and, in the same transaction, queues each one for the
+/// webhooks that want it. Disabled webhooks are queued too, so that they
+/// catch up once re-enabled.
+pub fn insert_server_events(
+    conn: &mut PgConnection,
+    events: &[NewServerEvent],
+) -> Result<Vec<ServerEvent>, diesel::result::Error> {
+    if events.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    conn.transaction(|conn| {
+        let logged: Vec<ServerEvent> = diesel::insert_into(server_events::table)
+            .values(events)
+            .get_results(conn)?;
+
+        let subscriptions: Vec<WebhookSubscription> = webhook_subscriptions::table
+            .select(WebhookSubscription::as_select())
+            .load(conn)?;
+        let queued: Vec<_> = subscriptions
+            .iter()
+            .flat_map(|subscription| {
+                logged
+                    .iter()
+                    .filter(|event| subscription.wants(event))
+                    .map(|event| {
+                        (
+                            webhook_outbox::subscription_id.eq(subscription.id),
+                            webhook_outbox::event_id.eq(event.id),
+                        )
+                    })
+            })
+            .collect();
+
+        if !queued.is_empty() {
+            diesel::insert_into(webhook_outbox::table)
+                .values(&queued)
+                .execute(conn)?;
+        }
+
+        Ok(logged)
+    })
+}
+
+type BoxedEventsQuery<'a> = server_events::BoxedQuery<'a, diesel::pg::Pg>;
+
+fn filter_events<'a>(kinds: &'a [&'a str], target_domain: Option<&'a str>) -> BoxedEventsQuery<'a> {
+    use crate::schema::server_events::dsl::*;
+
+    let mut query = server_events.into_boxed();
+
+    if !kinds.is_empty() {
+        query = query.filter(kind.eq_any(kinds));
+    }
+
+    if let Some(target_domain) = target_domain {
+        query = query.filter(domain.eq(target_domain));
+    }
+
+    query
+}
+
+/// Returns a page of events newest first and the total matching `kinds`
+/// (all kinds when empty) and `target_domain`.
+pub fn get_server_events(
+    conn: &mut PgConnection,
+    kinds: &[&str],
+    target_domain: Option<&str>,
+    limit: i64,
+    offset: i64,
+) -> Result<(Vec<ServerEvent>, i64), diesel::result::Error> {
+    use crate::schema::server_events::dsl::*;
+
+    let total = filter_events(kinds, target_domain)
+        .count()
+        .get_result::<i64>(conn)?;
+
+    let events = filter_events(kinds, target_domain)
+        .order(id.desc())
+        .limit(limit)
+        .offset(offset)
+        .load(conn)?;
+
+    Ok((events, total))
+}
+
+/// Removes a server and logs `removal` in the same transaction, returning the
+/// logged event. Its stored checks, keys, findings, bridges and revisions go
+/// with it; its events stay in the log.
+pub fn delete_server(
+    conn: &mut PgConnection,
+    target_server_id: i64,
+    removal: &NewServerEvent,
+) -> Result<Vec<ServerEvent>, diesel::result::Error> {
+    conn.transaction(|conn| {
+        let logged = insert_server_events(conn, std::slice::from_ref(removal))?;
+        diesel::delete(servers::table.find(target_server_id)).execute(conn)?;
+        Ok(logged)
+    })
+}
+
+/// Claims up to `limit` servers not refreshed since `stale_before`, least
+/// recently refreshed first, and marks them refreshed so that no other
+/// replica picks them up. Rows another replica is claiming are skipped.
+pub fn claim_servers_to_refresh(
+    conn: &mut PgConnection,
+    stale_before: chrono::NaiveDateTime,
+    limit: i64,
+) -> Result<Vec<String>, diesel::result::Error> {
+    use crate::schema::servers::dsl::*;
+
+    conn.transaction(|conn| {
+        let claimed: Vec<(i64, String)> = servers
+            .filter(
+                last_refreshed_at
+                    .is_null()
+                    .or(last_refreshed_at.lt(stale_before)),
+            )
+            .order(last_refreshed_at.asc().nulls_first())
+            .limit(limit)
+            .select((id, domain))
+            .for_update()
+            .skip_locked()
+            .load(conn)?;
+
+        let ids: Vec<i64> = claimed.iter().map(|(server_id, _)| *server_id).collect();
+        diesel::update(servers.filter(id.eq_any(&ids)))
+            .set(last_refreshed_at.eq(diesel::dsl::now.nullable()))
+            .execute(conn)?;
+
+        Ok(claimed
+            .into_iter()
+            .map(|(_, server_domain)| server_domain)
+            .collect())
+    })
+}
+
+/// The id of the newest logged event, 0 when the log is empty.
+pub fn get_latest_event_id(conn: &mut PgConnection) -> Result<i64, diesel::result::Error> {
+    use crate::schema::server_events::dsl::*;
+
+    server_events
+        .select(diesel::dsl::max(id))
+        .first::<Option<i64>>(conn)
+        .map(|latest| latest.unwrap_or(0))
+}
+
+/// Returns events newer than `after_id`, oldest first, filtered like
+/// `get_server_events`.
+pub fn get_events_after(
+    conn: &mut PgConnection,
+    after_id: i64,
+    kinds: &[&str],
+    target_domain: Option<&str>,
+    limit: i64,
+) -> Result<Vec<ServerEvent>, diesel::result::Error> {
+    use crate::schema::server_events::dsl::*;
+
+    filter_events(kinds, target_domain)
+        .filter(id.gt(after_id))
+        .order(id.asc())
+        .limit(limit)
+        .load(conn)
+}
+
+pub fn insert_webhook(
+    conn: &mut PgConnection,
+    subscription: &NewWebhookSubscription,
+) -> Result<WebhookSubscription, diesel::result::Error> {
+    diesel::insert_into(webhook_subscriptions::table)
+        .values(subscription)
+        .returning(WebhookSubscription::as_returning())
+        .get_result(conn)
+}
+
+pub fn get_webhooks(
+    conn: &mut PgConnection,
+) -> Result<Vec<WebhookSubscription>, diesel::result::Error> {
+    use crate::schema::webhook_subscriptions::dsl::*;
+
+    webhook_subscriptions
+        .order(id.asc())
+        .select(WebhookSubscription::as_select())
+        .load(conn)
+}
+
+/// Claims the enabled subscriptions with queued events that no worker is
+/// delivering to, for `lease_secs`. Rows another replica is claiming are
+/// skipped, so each event is delivered by one worker.
+pub fn claim_webhooks(
+    conn: &mut PgConnection,
+    lease_secs: i32,
+) -> Result<Vec<WebhookSubscription>, diesel::result::Error> {
+    use crate::schema::webhook_subscriptions::dsl::*;
+    use diesel::dsl::{exists, now, IntervalDsl};
+
+    conn.transaction(|conn| {
+        let claimed: Vec<WebhookSubscription> = webhook_subscriptions
+            .filter(enabled.eq(true))
+            .filter(claimed_until.is_null().or(claimed_until.lt(now.nullable())))
+            .filter(exists(
+                webhook_outbox::table.filter(webhook_outbox::subscription_id.eq(id)),
+            ))
+            .order(id.asc())
+            .select(WebhookSubscription::as_select())
+            .for_update()
+            .skip_locked()
+            .load(conn)?;
+
+        let ids: Vec<i64> = claimed.iter().map(|subscription| subscription.id).collect();
+        diesel::update(webhook_subscriptions.filter(id.eq_any(&ids)))
+            .set(claimed_until.eq((now + lease_secs.seconds()).nullable()))
+            .execute(conn)?;
+
+        Ok(claimed)
+    })
+}
+
+/// Hands a claimed subscription back before its lease runs out.
+pub fn release_webhook(
+    conn: &mut PgConnection,
+    webhook_id: i64,
+) -> Result<usize, diesel::result::Error> {
+    use crate::schema::webhook_subscriptions::dsl::*;
+
+    diesel::update(webhook_subscriptions.find(webhook_id))
+        .set(claimed_until.eq(None::<chrono::NaiveDateTime>))
+        .execute(conn)
+}
+
+/// Events queued for a subscription, oldest first.
+pub fn get_queued_webhook_events(
+    conn: &mut PgConnection,
+    webhook_id: i64,
+    limit: i64,
+) -> Result<Vec<ServerEvent>, diesel::result::Error> {
+    server_events::table
+        .inner_join(webhook_outbox::table)
+        .filter(webhook_outbox::subscription_id.eq(webhook_id))
+        .order(server_events::id.asc())
+        .limit(limit)
+        .select(ServerEvent::as_select())
+        .load(conn)
+}
+
+pub fn get_webhook(
+    conn: &mut PgConnection,
+    webhook_id: i64,
+) -> Result<Option<WebhookSubscription>, diesel::result::Error> {
+    use crate::schema::webhook_subscriptions::dsl::*;
+
+    webhook_subscriptions
+        .find(webhook_id)
+        .select(WebhookSubscription::as_select())
+        .first(conn)
+        .optional()
+}
+
+pub fn delete_webhook(
+    conn: &mut PgConnection,
+    webhook_id: i64,
+) -> Result<usize, diesel::result::Error> {
+    diesel::delete(webhook_subscriptions::table.find(webhook_id)).execute(conn)
+}
+
+/// Re-enables a subscription with a clean failure count. Delivery resumes
+/// from the first event it has not received.
+pub fn enable_webhook(
+    conn: &mut PgConnection,
+    webhook_id: i64,
+) -> Result<Option<WebhookSubscription>, diesel::result::Error> {
+    use crate::schema::webhook_subscriptions::dsl::*;
+
+    diesel::update(webhook_subscriptions.find(webhook_id))
+        .set((
+            enabled.eq(true),
+            consecutive_failures.eq(0),
+            disabled_at.eq(None::<chrono::NaiveDateTime>),
+        ))
+        .returning(WebhookSubscription::as_returning())
+        .get_result(conn)
+        .optional()
+}
+
+/// Logs a delivery attempt and moves the subscription along: a success
+/// takes the event off its queue and clears the failure count, a failure
+/// leaves the event queued so it is retried and disables the subscription
+/// once `max_failures` failures happened in a row. Either way the claim on
+/// the subscription is renewed for `lease_secs`. Returns whether the
+/// subscription was disabled.
+pub fn record_webhook_delivery(
+    conn: &mut PgConnection,
+    delivery: &NewWebhookDelivery,
+    max_failures: i32,
+    lease_secs: i32,
+) -> Result<bool, diesel::result::Error> {
+    use crate::schema::webhook_subscriptions::dsl::*;
+    use diesel::dsl::{now, IntervalDsl};
+
+    conn.transaction(|conn| {
+        diesel::insert_into(webhook_deliveries::table)
+            .values(delivery)
+            .execute(conn)?;
+
+        let subscription = webhook_subscriptions.find(delivery.subscription_id);
+        diesel::update(subscription)
+            .set(claimed_until.eq((now + lease_secs.seconds()).nullable()))
+            .execute(conn)?;
+
+        if delivery.success {
+            diesel::delete(
+                webhook_outbox::table.find((delivery.subscription_id, delivery.event_id)),
+            )
+            .execute(conn)?;
+            diesel::update(subscription)
+                .set(consecutive_failures.eq(0))
+                .execute(conn)?;
+            diesel::update(subscription.filter(last_event_id.lt(delivery.event_id)))
+                .set(last_event_id.eq(delivery.event_id))
+                .execute(conn)?;
+            return Ok(false);
+        }
+
+        let failures: i32 = diesel::update(subscription)
+            .set(consecutive_failures.eq(consecutive_failures + 1))
+            .returning(consecutive_failures)
+            .get_result(conn)?;
+
+        if failures < max_failures {
+            return Ok(false);
+        }
+
+        diesel::update(subscription)
+            .set((enabled.eq(false), disabled_at.eq(diesel::dsl::now)))
+            .execute(conn)?;
+        Ok(true)
+    })
+}
+
+pub fn get_webhook_deliveries(
+    conn: &mut PgConnection,
+    webhook_id: i64,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<WebhookDelivery>, diesel::result::Error> {
+    use crate::schema::webhook_deliveries::dsl::*;
+
+    webhook_deliveries
+        .filter(subscription_id.eq(webhook_id))
+        .order(id.desc())
+        .limit(limit)
+        .offset(offset)
+        .load(conn)
+}
+
+/// Maps domains to the ids of the servers already indexed under them.
+pub fn get_server_ids_by_domains(
+    conn: &mut PgConnection,
+    domains: &[&str],
+) -> Result<Vec<(i64, String)>, diesel::result::Error> {
+    servers::table
+        .filter(servers::domain.eq_any(domains))
+        .select((servers::id, servers::domain))
+        .load(conn)
+}
+
+/// Points a homeserver at the identity server it recommends, creating the
+/// identity server on first sight. `None` clears the link.
+pub fn link_identity_server(
+    conn: &mut PgConnection,
+    target_server_id: i64,
+    identity_base_url: Option<&str>,
+) -> Result<Option<IdentityServer>, diesel::result::Error> {
+    conn.transaction(|conn| {
+        let identity_server = match identity_base_url {
+            Some(url) => {
+                diesel::insert_into(identity_servers::table)
+                    .values(identity_servers::base_url.eq(url))
+                    .on_conflict(identity_servers::base_url)
+                    .do_nothing()
+                    .execute(conn)?;
+
+                Some(
+                    identity_servers::table
+                        .filter(identity_servers::base_url.eq(url))
+                        .select(IdentityServer::as_select())
+                        .first(conn)?,
+                )
+            }
+            None => None,
+        };
+
+        diesel::update(servers::table.find(target_server_id))
+            .set(servers::identity_server_id.eq(identity_server.as_ref().map(|is| is.id)))
+            .execute(conn)?;
+
+        Ok(identity_server)
+    })
+}
+
+pub fn update_identity_server_check(
+    conn: &mut PgConnection,
+    target_id: i64,
+    is_reachable: bool,
+    policies: &serde_json::Value,
+) -> Result<(), diesel::result::Error> {
+    use crate::schema::identity_servers::dsl::*;
+
+    diesel::update(identity_servers.find(target_id))
+        .set((
+            reachable.eq(Some(is_reachable)),
+            terms_policies.eq(policies),
+            last_checked_at.eq(diesel::dsl::now.nullable()),
+        ))
+        .execute(conn)?;
+
+    Ok(())
+}
+
+/// Every identity server with the number of indexed homeservers
+/// recommending it, most used first.
+pub fn get_identity_servers_with_usage(
+    conn: &mut PgConnection,
+) -> Result<Vec<(IdentityServer, i64)>, diesel::result::Error> {
+    let homeserver_count = diesel::dsl::count(servers::id.nullable());
+
+    identity_servers::table
+        .left_join(servers::table)
+        .group_by(identity_servers::id)
+        .select((IdentityServer::as_select(), homeserver_count))
+        .order((homeserver_count.desc(), identity_servers::base_url.asc()))
+        .load(conn)
+}
+
+pub fn count_servers_with_identity_server(
+    conn: &mut PgConnection,
+) -> Result<i64, diesel::result::Error> {
+    servers::table
+        .filter(servers::identity_server_id.is_not_null())
+        .count()
+        .get_result(conn)
+}
+
 pub fn run_migrations(conn: &mut PgConnection) {
     use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 
@@ -257,9 +1515,28 @@ mod tests {
             registration_open: Some(true),
             public_rooms_count: Some(100),
             version: Some("v1.11"),
-            federation_version: Some("Synapse/1.99"),
+            federation_version: Some("1.99.0"),
             delegated_server: Some("matrix.org:8448"),
             room_versions: Some("1,2,6"),
+            missing_fields: serde_json::json!([]),
+            client_api_version: Some("v3"),
+            spec_versions: &["v1.10".to_string(), "v1.11".to_string()],
+            latest_spec_version: Some("v1.11"),
+            unstable_features: &serde_json::json!({"org.matrix.msc3575": true}),
+            login_flows: &["m.login.password".to_string(), "m.login.sso".to_string()],
+            sso_providers: serde_json::json!([{"id": "oidc-github", "name": "GitHub"}]),
+            oidc_issuer: None,
+            support_contacts: serde_json::json!([]),
+            support_page: None,
+            terms_policies: serde_json::json!([]),
+            identity_server: None,
+            sliding_sync: None,
+            sliding_sync_proxy: None,
+            sliding_sync_proxy_reachable: None,
+            client_capabilities: serde_json::json!({}),
+            media_upload_size: None,
+            authenticated_media_enforced: None,
+            implementation: None,
         };
 
         assert_eq!(new_server.domain, "matrix.org");
@@ -281,6 +1558,25 @@ mod tests {
             federation_version: None,
             delegated_server: None,
             room_versions: None,
+            missing_fields: serde_json::json!([]),
+            client_api_version: None,
+            spec_versions: &[],
+            latest_spec_version: None,
+            unstable_features: &serde_json::json!({}),
+            login_flows: &[],
+            sso_providers: serde_json::json!([]),
+            oidc_issuer: None,
+            support_contacts: serde_json::json!([]),
+            support_page: None,
+            terms_policies: serde_json::json!([]),
+            identity_server: None,
+            sliding_sync: None,
+            sliding_sync_proxy: None,
+            sliding_sync_proxy_reachable: None,
+            client_capabilities: serde_json::json!({}),
+            media_upload_size: None,
+            authenticated_media_enforced: None,
+            implementation: None,
         };
 
         assert_eq!(new_server.domain, "test.org");
@@ -288,6 +1584,60 @@ mod tests {
         assert!(new_server.description.is_none());
     }
 
+    #[test]
+    fn test_probe_payload_record_round_trip() {
+        let record = ProbePayloadRecord {
+            id: 1,
+            server_id: 7,
+            probe: "client_versions".to_string(),
+            url: "https://matrix.org/_matrix/client/versions".to_string(),
+            status: Some(200),
+            headers: serde_json::json!({"content-type": "application/json"}),
+            body: Some(serde_json::json!({"versions": ["v1.11"]})),
+            error: None,
+            fetched_at: chrono::NaiveDateTime::parse_from_str(
+                "2024-01-01 00:00:00",
+                "%Y-%m-%d %H:%M:%S",
+            )
+            .unwrap(),
+            duration_ms: Some(250),
+        };
+
+        let payload = record.into_payload().unwrap();
+        assert_eq!(payload.probe, ProbeKind::ClientVersions);
+        assert_eq!(payload.status, Some(200));
+
+        let row = NewProbePayload::from_payload(7, &payload);
+        assert_eq!(row.probe, "client_versions");
+        assert_eq!(row.status, Some(200));
+        assert_eq!(row.duration_ms, Some(250));
+    }
+
+    #[test]
+    fn test_stale_probe_payloads() {
+        let now = chrono::Utc::now().naive_utc();
+        let rows: Vec<(i64, chrono::NaiveDateTime)> = (0..5)
+            .map(|i| (i, now - chrono::Duration::days(i * 10)))
+            .collect();
+        let since = now - chrono::Duration::days(30);
+
+        assert_eq!(
+            stale_probe_payloads("well_known_client", &rows, 2, since),
+            vec![2, 3, 4]
+        );
+        assert_eq!(
+            stale_probe_payloads("client_versions", &rows, 2, since),
+            vec![4]
+        );
+        assert!(stale_probe_payloads("client_versions", &rows, 10, since).is_empty());
+    }
+
+    #[test]
+    fn test_login_flow_type() {
+        assert_eq!(login_flow_type("sso"), "m.login.sso");
+        assert_eq!(login_flow_type("m.login.password"), "m.login.password");
+    }
+
     #[test]
     fn test_server_filter_default() {
         let filter = ServerFilter::default();
@@ -303,6 +1653,19 @@ mod tests {
             registration_open: Some(true),
             has_rooms: Some(true),
             room_version: Some("6".to_string()),
+            well_known_problems: Some(false),
+            spec_version: Some("v1.11".to_string()),
+            feature: Some("org.matrix.msc3575".to_string()),
+            login: Some("sso".to_string()),
+            oidc: Some(true),
+            has_terms: Some(true),
+            bridge: Some("telegram".to_string()),
+            sliding_sync: Some("native".to_string()),
+            element_x: Some(true),
+            capability: Some("m.set_displayname".to_string()),
+            min_upload_size: Some(10 * 1024 * 1024),
+            max_upload_size: None,
+            authenticated_media: Some(true),
             sort_by: Some("name".to_string()),
             sort_order: Some("asc".to_string()),
             limit: Some(10),
@@ -313,4 +1676,36 @@ mod tests {
         assert_eq!(filter.registration_open, Some(true));
         assert_eq!(filter.limit, Some(10));
     }
+
+    #[test]
+    fn test_webhook_subscription_wants() {
+        let at = chrono::NaiveDateTime::parse_from_str("2024-01-01 00:00:00", "%Y-%m-%d %H:%M:%S")
+            .unwrap();
+        let subscription = |kinds: &[&str], domain: Option<&str>| WebhookSubscription {
+            id: 1,
+            url: "https://hooks.example/mx".to_string(),
+            secret: "s3cret".to_string(),
+            event_kinds: kinds.iter().map(|k| k.to_string()).collect(),
+            domain: domain.map(str::to_string),
+            enabled: true,
+            consecutive_failures: 0,
+            last_event_id: 0,
+            disabled_at: None,
+            created_at: at,
+            token_hash: None,
+        };
+        let event = ServerEvent {
+            id: 7,
+            server_id: Some(3),
+            domain: "matrix.org".to_string(),
+            kind: "server_upgraded".to_string(),
+            details: serde_json::json!({"old": "1.109.0", "new": "1.110.0"}),
+            created_at: at,
+        };
+
+        assert!(subscription(&[], None).wants(&event));
+        assert!(subscription(&["server_upgraded"], Some("matrix.org")).wants(&event));
+        assert!(!subscription(&["server_offline"], None).wants(&event));
+        assert!(!subscription(&[], Some("example.org")).wants(&event));
+    }
 }
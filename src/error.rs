@@ -0,0 +1,452 @@
+use crate::models::ErrorResponse;
+use crate::rate_limit::RateLimitError;
+use crate::refresh::RefreshError;
+use rocket::http::{Header, Status};
+use rocket::response::{self, Responder};
+use rocket::serde::json::Json;
+use rocket::{Request, Response};
+use rocket_okapi::gen::OpenApiGenerator;
+use rocket_okapi::okapi::openapi3::{MediaType, RefOr, Response as OpenApiResponse, Responses};
+use rocket_okapi::okapi::Map;
+use rocket_okapi::response::OpenApiResponderInner;
+use std::marker::PhantomData;
+use thiserror::Error;
+
+/// Every error the API returns. Each variant has a fixed HTTP status and a
+/// stable `error` code clients can match on; the message is for humans.
+#[derive(Error, Debug)]
+pub enum ApiError {
+    #[error("Domain must be a valid domain name without path or port")]
+    InvalidDomain,
+    #[error("Invalid value for `{name}`: {message}")]
+    InvalidParameter { name: &'static str, message: String },
+    #[error("The request body could not be understood")]
+    BadRequest,
+    #[error("The request body is well-formed but invalid")]
+    UnprocessableEntity,
+    #[error("A valid admin or resource token is required")]
+    Unauthorized,
+    #[error("Server is not in the index")]
+    ServerNotFound,
+    #[error("No such resource")]
+    NotFound,
+    #[error("MXINDEX_SERVER_NAME is not configured")]
+    FederationDisabled,
+    #[error("Server already exists in the index")]
+    ServerExists,
+    #[error("Too many requests, retry in {retry_after_secs}s")]
+    RateLimited { retry_after_secs: u64 },
+    #[error("{context}: {source}")]
+    Database {
+        context: &'static str,
+        source: diesel::result::Error,
+    },
+    #[error("Failed to get DB connection: {0}")]
+    DatabaseUnavailable(#[from] diesel::r2d2::PoolError),
+    #[error("Failed to discover server information: {0}")]
+    DiscoveryFailed(String),
+    #[error("Failed to fetch server keys: {0}")]
+    KeyFetchFailed(String),
+    #[error("{0}")]
+    Internal(String),
+    /// A status Rocket produced itself, such as 413 for an oversized body.
+    #[error("Request failed with {0}")]
+    Http(Status),
+}
+
+/// The statuses any route returning `ApiError` can produce. Route-specific
+/// ones are listed by the route's `RouteError` family, or by its request
+/// guards, such as 401 for admin routes.
+const DOCUMENTED_STATUSES: [(u16, &str); 4] = [
+    (400, "Invalid domain or malformed request"),
+    (404, "Unknown server or resource"),
+    (500, "Database or internal failure"),
+    (
+        503,
+        "The database is unavailable or the feature is not configured",
+    ),
+];
+
+impl ApiError {
+    /// Wraps a query failure with what the route was trying to do.
+    pub fn database(context: &'static str) -> impl FnOnce(diesel::result::Error) -> Self {
+        move |source| ApiError::Database { context, source }
+    }
+
+    pub fn invalid_parameter(name: &'static str, message: impl Into<String>) -> Self {
+        ApiError::InvalidParameter {
+            name,
+            message: message.into(),
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        match self {
+            ApiError::InvalidDomain | ApiError::BadRequest => Status::BadRequest,
+            ApiError::Unauthorized => Status::Unauthorized,
+            ApiError::ServerNotFound | ApiError::NotFound => Status::NotFound,
+            ApiError::ServerExists => Status::Conflict,
+            ApiError::InvalidParameter { .. } | ApiError::UnprocessableEntity => {
+                Status::UnprocessableEntity
+            }
+            ApiError::RateLimited { .. } => Status::TooManyRequests,
+            ApiError::Database { .. } | ApiError::Internal(_) => Status::InternalServerError,
+            ApiError::DiscoveryFailed(_) | ApiError::KeyFetchFailed(_) => Status::BadGateway,
+            ApiError::DatabaseUnavailable(_) | ApiError::FederationDisabled => {
+                Status::ServiceUnavailable
+            }
+            ApiError::Http(status) => *status,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::InvalidDomain => "invalid_domain",
+            ApiError::InvalidParameter { .. } => "invalid_parameter",
+            ApiError::BadRequest => "bad_request",
+            ApiError::UnprocessableEntity => "unprocessable_entity",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::ServerNotFound => "server_not_found",
+            ApiError::NotFound => "not_found",
+            ApiError::FederationDisabled => "federation_disabled",
+            ApiError::ServerExists => "server_exists",
+            ApiError::RateLimited { .. } => "rate_limit_exceeded",
+            ApiError::Database { .. } => "database_error",
+            ApiError::DatabaseUnavailable(_) => "pool_error",
+            ApiError::DiscoveryFailed(_) => "discovery_failed",
+            ApiError::KeyFetchFailed(_) => "key_fetch_failed",
+            ApiError::Internal(_) => "internal_error",
+            ApiError::Http(status) if status.class().is_client_error() => "client_error",
+            ApiError::Http(_) => "server_error",
+        }
+    }
+
+    pub fn body(&self) -> ErrorResponse {
+        ErrorResponse {
+            error: self.code().to_string(),
+            message: self.to_string(),
+        }
+    }
+}
+
+impl From<RateLimitError> for ApiError {
+    fn from(e: RateLimitError) -> Self {
+        let retry_after = e.retry_after;
+        ApiError::RateLimited {
+            retry_after_secs: retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0),
+        }
+    }
+}
+
+impl From<RefreshError> for ApiError {
+    fn from(e: RefreshError) -> Self {
+        match e {
+            RefreshError::Discovery(message) => ApiError::DiscoveryFailed(message),
+            RefreshError::Pool(source) => ApiError::DatabaseUnavailable(source),
+            RefreshError::Database(source) => ApiError::Database {
+                context: "Failed to save server",
+                source,
+            },
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build_from(Json(self.body()).respond_to(req)?)
+            .status(self.status())
+            .finalize();
+
+        if let ApiError::RateLimited { retry_after_secs } = self {
+            response.set_header(Header::new("Retry-After", retry_after_secs.to_string()));
+        }
+
+        Ok(response)
+    }
+}
+
+/// OpenAPI entries for `statuses`, each with the `ErrorResponse` body.
+pub fn error_responses(gen: &mut OpenApiGenerator, statuses: &[(u16, &str)]) -> Responses {
+    let schema = gen.json_schema::<ErrorResponse>();
+    let mut responses = Responses::default();
+
+    for (status, description) in statuses {
+        let mut content = Map::new();
+        content.insert(
+            "application/json".to_string(),
+            MediaType {
+                schema: Some(schema.clone()),
+                ..MediaType::default()
+            },
+        );
+
+        responses.responses.insert(
+            status.to_string(),
+            RefOr::Object(OpenApiResponse {
+                description: description.to_string(),
+                content,
+                ..OpenApiResponse::default()
+            }),
+        );
+    }
+
+    responses
+}
+
+impl OpenApiResponderInner for ApiError {
+    fn responses(gen: &mut OpenApiGenerator) -> rocket_okapi::Result<Responses> {
+        Ok(error_responses(gen, &DOCUMENTED_STATUSES))
+    }
+}
+
+/// The statuses a family of routes can answer on top of the shared ones.
+pub trait RouteStatuses {
+    const STATUSES: &'static [(u16, &'static str)];
+}
+
+/// An `ApiError` from a route of family `F`. It responds exactly like the
+/// error it wraps; only the OpenAPI document differs, listing `F::STATUSES`
+/// next to the shared statuses.
+pub struct RouteError<F>(pub ApiError, PhantomData<F>);
+
+impl<F, E: Into<ApiError>> From<E> for RouteError<F> {
+    fn from(e: E) -> Self {
+        RouteError(e.into(), PhantomData)
+    }
+}
+
+impl<'r, F> Responder<'r, 'static> for RouteError<F> {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        self.0.respond_to(req)
+    }
+}
+
+impl<F: RouteStatuses> OpenApiResponderInner for RouteError<F> {
+    fn responses(gen: &mut OpenApiGenerator) -> rocket_okapi::Result<Responses> {
+        let statuses: Vec<(u16, &str)> = DOCUMENTED_STATUSES
+            .iter()
+            .chain(F::STATUSES)
+            .copied()
+            .collect();
+        Ok(error_responses(gen, &statuses))
+    }
+}
+
+/// Live checks that are rate limited per domain or per client.
+pub enum Limited {}
+
+impl RouteStatuses for Limited {
+    const STATUSES: &'static [(u16, &'static str)] = &[(429, "Rate limit exceeded")];
+}
+
+/// Rate-limited refreshes that fail when the server cannot be reached.
+pub enum Refresh {}
+
+impl RouteStatuses for Refresh {
+    const STATUSES: &'static [(u16, &'static str)] = &[
+        (429, "Rate limit exceeded"),
+        (502, "The server could not be reached"),
+    ];
+}
+
+/// Adding a server to the index.
+pub enum AddServer {}
+
+impl RouteStatuses for AddServer {
+    const STATUSES: &'static [(u16, &'static str)] = &[
+        (409, "Server already exists in the index"),
+        (422, "The request body is well-formed but invalid"),
+        (502, "The server could not be reached"),
+    ];
+}
+
+/// Routes taking parameters or bodies that are checked beyond their syntax.
+pub enum Validated {}
+
+impl RouteStatuses for Validated {
+    const STATUSES: &'static [(u16, &'static str)] =
+        &[(422, "A parameter or the request body is invalid")];
+}
+
+/// Rejects names that cannot be a bare server name: empty, or carrying a
+/// path or port.
+pub fn validate_domain(domain: &str) -> Result<(), ApiError> {
+    if domain.is_empty() || domain.contains('/') || domain.contains(':') {
+        Err(ApiError::InvalidDomain)
+    } else {
+        Ok(())
+    }
+}
+
+/// The form of a server name used for lookups and rate limit keys:
+/// trimmed, lowercased and without a trailing dot, so `Matrix.org.` and
+/// `matrix.org` are the same server.
+pub fn normalize_domain(domain: &str) -> String {
+    domain.trim().trim_end_matches('.').to_lowercase()
+}
+
+#[catch(400)]
+pub fn bad_request() -> ApiError {
+    ApiError::BadRequest
+}
+
+#[catch(401)]
+pub fn unauthorized() -> ApiError {
+    ApiError::Unauthorized
+}
+
+#[catch(404)]
+pub fn not_found() -> ApiError {
+    ApiError::NotFound
+}
+
+#[catch(422)]
+pub fn unprocessable_entity() -> ApiError {
+    ApiError::UnprocessableEntity
+}
+
+#[catch(default)]
+pub fn default_catcher(status: Status, _req: &Request) -> ApiError {
+    ApiError::Http(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::local::blocking::Client;
+
+    #[get("/exists")]
+    fn exists() -> Result<Json<()>, ApiError> {
+        Err(ApiError::ServerExists)
+    }
+
+    #[get("/limited")]
+    fn limited() -> Result<Json<()>, ApiError> {
+        Err(RateLimitError {
+            retry_after: std::time::Duration::from_millis(59_500),
+            first_refusal: true,
+        }
+        .into())
+    }
+
+    #[post("/echo", format = "json", data = "<body>")]
+    fn echo(body: Json<serde_json::Value>) -> Json<serde_json::Value> {
+        body
+    }
+
+    fn client() -> Client {
+        let config = rocket::Config::figment().merge(("limits.json", 16));
+        let rocket = rocket::custom(config)
+            .mount("/", routes![exists, limited, echo])
+            .register(
+                "/",
+                catchers![
+                    bad_request,
+                    not_found,
+                    unprocessable_entity,
+                    default_catcher
+                ],
+            );
+        Client::tracked(rocket).expect("valid rocket instance")
+    }
+
+    #[test]
+    fn test_error_statuses_and_codes() {
+        let cases = [
+            (ApiError::InvalidDomain, 400, "invalid_domain"),
+            (ApiError::Unauthorized, 401, "unauthorized"),
+            (ApiError::ServerNotFound, 404, "server_not_found"),
+            (ApiError::ServerExists, 409, "server_exists"),
+            (
+                ApiError::invalid_parameter("limit", "must be positive"),
+                422,
+                "invalid_parameter",
+            ),
+            (
+                ApiError::RateLimited {
+                    retry_after_secs: 30,
+                },
+                429,
+                "rate_limit_exceeded",
+            ),
+            (
+                ApiError::database("Failed to fetch servers")(diesel::result::Error::NotFound),
+                500,
+                "database_error",
+            ),
+            (
+                ApiError::DiscoveryFailed("connection refused".to_string()),
+                502,
+                "discovery_failed",
+            ),
+            (ApiError::FederationDisabled, 503, "federation_disabled"),
+        ];
+
+        for (error, status, code) in cases {
+            assert_eq!(error.status().code, status, "{}", code);
+            assert_eq!(error.code(), code);
+        }
+    }
+
+    #[test]
+    fn test_error_response_body_and_status() {
+        let client = client();
+        let response = client.get("/exists").dispatch();
+
+        assert_eq!(response.status(), Status::Conflict);
+        let body: serde_json::Value = response.into_json().unwrap();
+        assert_eq!(body["error"], "server_exists");
+        assert_eq!(body["message"], "Server already exists in the index");
+    }
+
+    #[test]
+    fn test_rate_limited_sets_retry_after() {
+        let client = client();
+        let response = client.get("/limited").dispatch();
+
+        assert_eq!(response.status(), Status::TooManyRequests);
+        assert_eq!(response.headers().get_one("Retry-After"), Some("60"));
+    }
+
+    #[test]
+    fn test_unknown_route_is_json_404() {
+        let client = client();
+        let response = client.get("/nowhere").dispatch();
+
+        assert_eq!(response.status(), Status::NotFound);
+        let body: serde_json::Value = response.into_json().unwrap();
+        assert_eq!(body["error"], "not_found");
+    }
+
+    #[test]
+    fn test_unhandled_status_is_kept() {
+        let client = client();
+        let response = client
+            .post("/echo")
+            .header(rocket::http::ContentType::JSON)
+            .body(r#"{"domain": "a-domain-name-longer-than-the-limit.org"}"#)
+            .dispatch();
+
+        assert_eq!(response.status(), Status::PayloadTooLarge);
+        let body: serde_json::Value = response.into_json().unwrap();
+        assert_eq!(body["error"], "client_error");
+        assert_eq!(body["message"], "Request failed with 413 Payload Too Large");
+    }
+
+    #[test]
+    fn test_normalize_domain() {
+        assert_eq!(normalize_domain("matrix.org"), "matrix.org");
+        assert_eq!(normalize_domain("Matrix.ORG"), "matrix.org");
+        assert_eq!(normalize_domain(" matrix.org. "), "matrix.org");
+    }
+
+    #[test]
+    fn test_validate_domain() {
+        assert!(validate_domain("matrix.org").is_ok());
+        assert!(matches!(
+            validate_domain("matrix.org:8448"),
+            Err(ApiError::InvalidDomain)
+        ));
+        assert!(validate_domain("").is_err());
+    }
+}
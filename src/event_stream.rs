@@ -0,0 +1,277 @@
+use crate::cache::Cache;
+use crate::models::ServerEventResponse;
+use futures::StreamExt;
+use once_cell::sync::OnceCell;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket_okapi::gen::OpenApiGenerator;
+use rocket_okapi::okapi::openapi3::{Object, Parameter, ParameterValue};
+use rocket_okapi::request::{OpenApiFromRequest, RequestHeaderInput};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+use tracing::{info, warn};
+
+/// Redis channel every replica publishes its new events to.
+pub const EVENTS_CHANNEL: &str = "mxindex:events";
+/// Redis list of the most recent events, newest first, for `Last-Event-ID`
+/// resumes.
+pub const EVENTS_BUFFER_KEY: &str = "mxindex:events:recent";
+
+/// Events waiting to be published, filled from synchronous database code.
+static OUTBOX: OnceCell<mpsc::UnboundedSender<ServerEventResponse>> = OnceCell::new();
+
+/// Queues freshly logged events for the live stream. Does nothing until
+/// `start` has run, as in tests and one-off tools.
+pub fn publish(events: impl IntoIterator<Item = ServerEventResponse>) {
+    let Some(outbox) = OUTBOX.get() else {
+        return;
+    };
+
+    for event in events {
+        if outbox.send(event).is_err() {
+            return;
+        }
+    }
+}
+
+/// The events newer than `last_id`, oldest first and without duplicates.
+fn events_after(
+    mut events: Vec<ServerEventResponse>,
+    last_id: Option<i64>,
+) -> Vec<ServerEventResponse> {
+    if let Some(last_id) = last_id {
+        events.retain(|e| e.id > last_id);
+    }
+    events.sort_by_key(|e| e.id);
+    events.dedup_by_key(|e| e.id);
+    events
+}
+
+/// Fans events out to the SSE clients connected to this replica.
+pub struct EventHub {
+    sender: broadcast::Sender<ServerEventResponse>,
+    /// Copy of the Redis buffer, used for resumes while Redis is unreachable.
+    recent: Mutex<VecDeque<ServerEventResponse>>,
+    buffer_len: usize,
+}
+
+impl EventHub {
+    pub fn new(buffer_len: usize) -> Self {
+        let buffer_len = buffer_len.max(1);
+        let (sender, _) = broadcast::channel(buffer_len.max(16));
+        Self {
+            sender,
+            recent: Mutex::new(VecDeque::with_capacity(buffer_len)),
+            buffer_len,
+        }
+    }
+
+    /// Keeps the last `EVENT_STREAM_BUFFER` events (default 200) for resumes.
+    pub fn from_env() -> Self {
+        let buffer_len = std::env::var("EVENT_STREAM_BUFFER")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(200);
+
+        Self::new(buffer_len)
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerEventResponse> {
+        self.sender.subscribe()
+    }
+
+    fn broadcast(&self, event: ServerEventResponse) {
+        {
+            let mut recent = self.recent.lock().unwrap();
+            recent.push_front(event.clone());
+            recent.truncate(self.buffer_len);
+        }
+
+        // No receivers just means nobody is connected.
+        let _ = self.sender.send(event);
+    }
+
+    /// Buffered events a client reconnecting with `last_id` has missed, oldest
+    /// first. Without `last_id` there is nothing to resume.
+    pub async fn replay(&self, cache: &Cache, last_id: Option<i64>) -> Vec<ServerEventResponse> {
+        if last_id.is_none() {
+            return Vec::new();
+        }
+
+        let buffered = match cache.list(EVENTS_BUFFER_KEY).await {
+            Ok(events) => events,
+            Err(_) => self.recent.lock().unwrap().iter().cloned().collect(),
+        };
+
+        events_after(buffered, last_id)
+    }
+}
+
+/// Publishes queued events to Redis, where every replica's subscriber picks
+/// them up. Without Redis the events only reach this replica's clients.
+async fn relay(
+    cache: Arc<Cache>,
+    hub: Arc<EventHub>,
+    mut outbox: mpsc::UnboundedReceiver<ServerEventResponse>,
+) {
+    while let Some(event) = outbox.recv().await {
+        let published = async {
+            cache
+                .push_capped(EVENTS_BUFFER_KEY, &event, hub.buffer_len)
+                .await?;
+            cache.publish(EVENTS_CHANNEL, &event).await
+        }
+        .await;
+
+        if published.is_err() {
+            hub.broadcast(event);
+        }
+    }
+}
+
+/// Forwards events published by any replica to this replica's clients,
+/// reconnecting when the subscription drops.
+async fn subscribe(hub: Arc<EventHub>, redis_url: String) {
+    let mut delay = Duration::from_secs(1);
+
+    loop {
+        match listen(&hub, &redis_url).await {
+            Ok(()) => warn!("Event stream subscription closed, reconnecting"),
+            Err(e) => warn!("Event stream subscription failed: {}", e),
+        }
+
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(Duration::from_secs(60));
+    }
+}
+
+async fn listen(hub: &EventHub, redis_url: &str) -> Result<(), redis::RedisError> {
+    let client = redis::Client::open(redis_url)?;
+    let mut pubsub = client.get_async_pubsub().await?;
+    pubsub.subscribe(EVENTS_CHANNEL).await?;
+    info!("Subscribed to {} for the event stream", EVENTS_CHANNEL);
+
+    let mut messages = pubsub.into_on_message();
+    while let Some(message) = messages.next().await {
+        let event = message
+            .get_payload::<String>()
+            .ok()
+            .and_then(|payload| serde_json::from_str(&payload).ok());
+
+        match event {
+            Some(event) => hub.broadcast(event),
+            None => warn!("Ignoring malformed message on {}", EVENTS_CHANNEL),
+        }
+    }
+
+    Ok(())
+}
+
+/// Starts relaying logged events to the hub, through Redis at `redis_url`.
+pub fn start(cache: Arc<Cache>, hub: Arc<EventHub>, redis_url: String) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    if OUTBOX.set(sender).is_err() {
+        return;
+    }
+
+    tokio::spawn(relay(cache, hub.clone(), receiver));
+    tokio::spawn(subscribe(hub, redis_url));
+}
+
+/// The `Last-Event-ID` header an `EventSource` sends when it reconnects.
+pub struct LastEventId(pub Option<i64>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LastEventId {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let id = request
+            .headers()
+            .get_one("Last-Event-ID")
+            .and_then(|value| value.trim().parse().ok());
+
+        Outcome::Success(LastEventId(id))
+    }
+}
+
+impl<'r> OpenApiFromRequest<'r> for LastEventId {
+    fn from_request_input(
+        gen: &mut OpenApiGenerator,
+        _name: String,
+        _required: bool,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        Ok(RequestHeaderInput::Parameter(Parameter {
+            name: "Last-Event-ID".to_owned(),
+            location: "header".to_owned(),
+            description: Some(
+                "Id of the last event received; buffered events after it are sent first".to_owned(),
+            ),
+            required: false,
+            deprecated: false,
+            allow_empty_value: false,
+            value: ParameterValue::Schema {
+                style: None,
+                explode: None,
+                allow_reserved: false,
+                schema: gen.json_schema::<i64>(),
+                example: None,
+                examples: None,
+            },
+            extensions: Object::default(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDateTime;
+
+    fn event(id: i64) -> ServerEventResponse {
+        ServerEventResponse {
+            id,
+            kind: "server_added".to_string(),
+            domain: "example.org".to_string(),
+            title: "example.org was added to the index".to_string(),
+            details: serde_json::json!({}),
+            created_at: NaiveDateTime::parse_from_str("2024-01-01 00:00:00", "%Y-%m-%d %H:%M:%S")
+                .unwrap(),
+        }
+    }
+
+    fn ids(events: &[ServerEventResponse]) -> Vec<i64> {
+        events.iter().map(|e| e.id).collect()
+    }
+
+    #[test]
+    fn test_events_after() {
+        let buffered = vec![event(5), event(4), event(4), event(3), event(2)];
+
+        assert_eq!(ids(&events_after(buffered.clone(), Some(3))), vec![4, 5]);
+        assert_eq!(ids(&events_after(buffered, None)), vec![2, 3, 4, 5]);
+    }
+
+    #[tokio::test]
+    async fn test_replay_falls_back_to_local_buffer() {
+        let hub = EventHub::new(2);
+        for id in 1..=3 {
+            hub.broadcast(event(id));
+        }
+
+        let cache = Cache::new();
+        assert_eq!(ids(&hub.replay(&cache, Some(1)).await), vec![2, 3]);
+        assert!(hub.replay(&cache, None).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_reaches_subscribers() {
+        let hub = EventHub::new(4);
+        let mut receiver = hub.subscribe();
+
+        hub.broadcast(event(9));
+
+        assert_eq!(receiver.recv().await.unwrap().id, 9);
+    }
+}
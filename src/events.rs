@@ -0,0 +1,323 @@
+use crate::db::{get_latest_probe_payload, insert_server_events, NewServerEvent, ServerEvent};
+use crate::event_stream;
+use crate::models::{EventKind, FieldChange, ProbeKind, ProbePayload, ServerEventResponse};
+use diesel::PgConnection;
+use serde_json::{json, Value};
+
+/// Events implied by the field changes of a refresh. The upgrade follows the
+/// implementation version from `/_matrix/federation/v1/version`, and a first
+/// sighting of it is not an upgrade.
+pub fn events_from_changes(changes: &[FieldChange]) -> Vec<(EventKind, Value)> {
+    changes
+        .iter()
+        .filter_map(|change| match change.field.as_str() {
+            "federation_version" if !change.old.is_null() && !change.new.is_null() => Some((
+                EventKind::ServerUpgraded,
+                json!({ "old": change.old, "new": change.new }),
+            )),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Whether the `/register` probe found registration open: a 401 offering
+/// flows means open, a 403 means closed, anything else tells nothing.
+pub fn registration_state(payload: &ProbePayload) -> Option<bool> {
+    match payload.status? {
+        401 => payload
+            .body
+            .as_ref()
+            .and_then(|body| body.get("flows"))
+            .and_then(Value::as_array)
+            .map(|flows| !flows.is_empty()),
+        403 => Some(false),
+        _ => None,
+    }
+}
+
+/// The event for a `/register` probe whose answer differs from the previous
+/// one. Registration only flips between two known states.
+pub fn registration_event(
+    previous: Option<&ProbePayload>,
+    current: &ProbePayload,
+) -> Option<(EventKind, Value)> {
+    match (registration_state(previous?)?, registration_state(current)?) {
+        (false, true) => Some((EventKind::RegistrationOpened, json!({}))),
+        (true, false) => Some((EventKind::RegistrationClosed, json!({}))),
+        _ => None,
+    }
+}
+
+/// The event for a `/_matrix/client/versions` check whose outcome differs
+/// from the previous one. Without a previous check there is nothing to flip.
+pub fn availability_event(
+    previous: Option<&ProbePayload>,
+    current: &ProbePayload,
+) -> Option<(EventKind, Value)> {
+    let was_online = previous?.success_body().is_some();
+
+    match (was_online, current.success_body().is_some()) {
+        (true, false) => {
+            let error = current
+                .error
+                .clone()
+                .or_else(|| current.status.map(|status| format!("HTTP {}", status)));
+            Some((EventKind::ServerOffline, json!({ "error": error })))
+        }
+        (false, true) => Some((EventKind::ServerOnline, json!({}))),
+        _ => None,
+    }
+}
+
+/// Logs events and hands them to the live stream.
+pub fn record_events(
+    conn: &mut PgConnection,
+    server_id: Option<i64>,
+    domain: &str,
+    events: &[(EventKind, Value)],
+) -> Result<usize, diesel::result::Error> {
+    let rows: Vec<NewServerEvent> = events
+        .iter()
+        .map(|(kind, details)| NewServerEvent {
+            server_id,
+            domain,
+            kind: kind.as_str(),
+            details,
+        })
+        .collect();
+
+    let logged = insert_server_events(conn, &rows)?;
+    let count = logged.len();
+    event_stream::publish(logged.into_iter().map(ServerEventResponse::from));
+    Ok(count)
+}
+
+/// Logs `server_offline` or `server_online` when the `/versions` check among
+/// `payloads` flipped, and `registration_opened` or `registration_closed`
+/// when the `/register` probe did. Must run before the payloads are stored,
+/// since the previous probes are read back from `probe_payloads`.
+pub fn record_probe_events(
+    conn: &mut PgConnection,
+    server_id: i64,
+    domain: &str,
+    payloads: &[ProbePayload],
+) -> Result<usize, diesel::result::Error> {
+    type Detector = fn(Option<&ProbePayload>, &ProbePayload) -> Option<(EventKind, Value)>;
+    let detectors: [(ProbeKind, Detector); 2] = [
+        (ProbeKind::ClientVersions, availability_event),
+        (ProbeKind::Registration, registration_event),
+    ];
+
+    let mut events = Vec::new();
+    for (probe, detect) in detectors {
+        let Some(current) = payloads.iter().find(|p| p.probe == probe) else {
+            continue;
+        };
+
+        let previous = get_latest_probe_payload(conn, server_id, probe)?
+            .and_then(|record| record.into_payload());
+        events.extend(detect(previous.as_ref(), current));
+    }
+
+    record_events(conn, Some(server_id), domain, &events)
+}
+
+/// One-line description of an event, shared by the JSON log and the feeds.
+pub fn event_title(kind: &str, domain: &str, details: &Value) -> String {
+    match EventKind::parse(kind) {
+        Some(EventKind::ServerAdded) => format!("{} was added to the index", domain),
+        Some(EventKind::ServerRemoved) => format!("{} was removed from the index", domain),
+        Some(EventKind::ServerOffline) => format!("{} went offline", domain),
+        Some(EventKind::ServerOnline) => format!("{} is back online", domain),
+        Some(EventKind::ServerUpgraded) => format!(
+            "{} upgraded from {} to {}",
+            domain,
+            details["old"].as_str().unwrap_or("an unknown version"),
+            details["new"].as_str().unwrap_or("an unknown version")
+        ),
+        Some(EventKind::RegistrationOpened) => format!("{} opened registration", domain),
+        Some(EventKind::RegistrationClosed) => format!("{} closed registration", domain),
+        None => format!("{}: {}", domain, kind),
+    }
+}
+
+impl From<ServerEvent> for ServerEventResponse {
+    fn from(event: ServerEvent) -> Self {
+        ServerEventResponse {
+            title: event_title(&event.kind, &event.domain, &event.details),
+            id: event.id,
+            kind: event.kind,
+            domain: event.domain,
+            details: event.details,
+            created_at: event.created_at,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ServerResponse;
+    use chrono::NaiveDateTime;
+
+    fn check(status: Option<u16>, error: Option<&str>) -> ProbePayload {
+        ProbePayload {
+            probe: ProbeKind::ClientVersions,
+            url: "https://matrix.org/_matrix/client/versions".to_string(),
+            status,
+            headers: json!({}),
+            body: status.map(|_| json!({"versions": ["v1.11"]})),
+            error: error.map(str::to_string),
+            fetched_at: NaiveDateTime::parse_from_str("2024-01-01 00:00:00", "%Y-%m-%d %H:%M:%S")
+                .unwrap(),
+            duration_ms: Some(42),
+        }
+    }
+
+    /// A server as a crawl stores it, before any change.
+    fn indexed_server() -> ServerResponse {
+        serde_json::from_value(json!({
+            "id": 7,
+            "domain": "matrix.org",
+            "name": "Matrix.org",
+            "description": null,
+            "logo_url": null,
+            "theme": null,
+            "registration_open": true,
+            "public_rooms_count": 20,
+            "version": "r0.6.1, v1.1, v1.2, v1.3, v1.4, v1.5, v1.6, v1.7, v1.8, v1.9, v1.10, v1.11",
+            "federation_version": "1.109.0",
+            "delegated_server": "matrix-federation.matrix.org:443",
+            "room_versions": "1,2,3,4,5,6,7,8,9,10,11",
+            "created_at": "2024-01-01T00:00:00",
+            "updated_at": "2024-01-01T00:00:00",
+            "missing_fields": [],
+            "client_api_version": "v3",
+            "spec_versions": ["r0.6.1", "v1.1", "v1.2", "v1.3", "v1.4", "v1.5", "v1.6", "v1.7", "v1.8", "v1.9", "v1.10", "v1.11"],
+            "latest_spec_version": "v1.11",
+            "unstable_features": {"org.matrix.msc3575": true},
+            "login_flows": ["m.login.password", "m.login.sso"],
+            "sso_providers": [],
+            "oidc_issuer": null,
+            "support_contacts": [],
+            "support_page": null,
+            "terms_policies": [],
+            "identity_server": "https://vector.im",
+            "sliding_sync": "native",
+            "sliding_sync_proxy": null,
+            "sliding_sync_proxy_reachable": null,
+            "element_x_compatible": true,
+            "client_capabilities": {"m.change_password": true},
+            "media_upload_size": null,
+            "authenticated_media_enforced": true,
+            "implementation": "Synapse"
+        }))
+        .unwrap()
+    }
+
+    fn register(status: Option<u16>, body: Option<Value>) -> ProbePayload {
+        ProbePayload {
+            probe: ProbeKind::Registration,
+            url: "https://matrix.org/_matrix/client/v3/register".to_string(),
+            body,
+            ..check(status, None)
+        }
+    }
+
+    #[test]
+    fn test_events_from_changes() {
+        let before = indexed_server();
+        let mut after = indexed_server();
+        after.federation_version = Some("1.110.0".to_string());
+        after.version = Some(format!("{}, v1.12", before.version.clone().unwrap()));
+        after.registration_open = Some(false);
+        after
+            .client_capabilities
+            .insert("m.change_password".to_string(), false);
+        after.public_rooms_count = Some(22);
+
+        assert_eq!(
+            events_from_changes(&after.changes_since(Some(&before))),
+            vec![(
+                EventKind::ServerUpgraded,
+                json!({"old": "1.109.0", "new": "1.110.0"})
+            )]
+        );
+    }
+
+    #[test]
+    fn test_events_from_changes_ignores_unknown_previous_values() {
+        let mut before = indexed_server();
+        before.federation_version = None;
+        let after = indexed_server();
+
+        assert!(events_from_changes(&after.changes_since(Some(&before))).is_empty());
+        assert!(events_from_changes(&before.changes_since(Some(&after))).is_empty());
+    }
+
+    #[test]
+    fn test_registration_event() {
+        let open = register(
+            Some(401),
+            Some(json!({"session": "abc", "flows": [{"stages": ["m.login.dummy"]}], "params": {}})),
+        );
+        let closed = register(
+            Some(403),
+            Some(json!({"errcode": "M_FORBIDDEN", "error": "Registration has been disabled"})),
+        );
+        let unreachable = register(None, None);
+
+        assert_eq!(registration_state(&open), Some(true));
+        assert_eq!(registration_state(&closed), Some(false));
+        assert_eq!(registration_state(&unreachable), None);
+
+        assert_eq!(
+            registration_event(Some(&closed), &open),
+            Some((EventKind::RegistrationOpened, json!({})))
+        );
+        assert_eq!(
+            registration_event(Some(&open), &closed),
+            Some((EventKind::RegistrationClosed, json!({})))
+        );
+        assert_eq!(registration_event(Some(&open), &open), None);
+        assert_eq!(registration_event(Some(&unreachable), &closed), None);
+        assert_eq!(registration_event(Some(&open), &unreachable), None);
+        assert_eq!(registration_event(None, &closed), None);
+    }
+
+    #[test]
+    fn test_availability_event() {
+        let online = check(Some(200), None);
+        let offline = check(None, Some("connection refused"));
+
+        assert_eq!(
+            availability_event(Some(&online), &offline),
+            Some((
+                EventKind::ServerOffline,
+                json!({"error": "connection refused"})
+            ))
+        );
+        assert_eq!(
+            availability_event(Some(&offline), &online),
+            Some((EventKind::ServerOnline, json!({})))
+        );
+        assert_eq!(availability_event(Some(&online), &online), None);
+        assert_eq!(availability_event(None, &offline), None);
+    }
+
+    #[test]
+    fn test_event_title() {
+        assert_eq!(
+            event_title(
+                "server_upgraded",
+                "matrix.org",
+                &json!({"old": "1.109.0", "new": "1.110.0"})
+            ),
+            "matrix.org upgraded from 1.109.0 to 1.110.0"
+        );
+        assert_eq!(
+            event_title("registration_closed", "matrix.org", &json!({})),
+            "matrix.org closed registration"
+        );
+    }
+}
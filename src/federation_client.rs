@@ -0,0 +1,440 @@
+use crate::http_client::get_http_client;
+use crate::signing::ServerSigningKey;
+use hickory_resolver::TokioAsyncResolver;
+use reqwest::Client;
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+const DEFAULT_FEDERATION_PORT: u16 = 8448;
+
+#[derive(Deserialize)]
+struct WellKnownServer {
+    #[serde(rename = "m.server")]
+    m_server: Option<String>,
+}
+
+/// Where federation requests for a server name should be sent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvedServer {
+    pub server_name: String,
+    /// Hostname used for TLS SNI and the `Host` header.
+    pub host: String,
+    pub port: u16,
+    /// SRV target to connect to when it differs from `host`.
+    pub srv_target: Option<String>,
+    /// Human readable description of each resolution step taken.
+    pub steps: Vec<String>,
+}
+
+impl ResolvedServer {
+    fn direct(server_name: &str, host: &str, port: u16, steps: Vec<String>) -> Self {
+        Self {
+            server_name: server_name.to_string(),
+            host: host.to_string(),
+            port,
+            srv_target: None,
+            steps,
+        }
+    }
+
+    pub fn base_url(&self) -> String {
+        if self.host.parse::<std::net::Ipv6Addr>().is_ok() {
+            format!("https://[{}]:{}", self.host, self.port)
+        } else {
+            format!("https://{}:{}", self.host, self.port)
+        }
+    }
+
+    /// The host a TCP connection is actually opened to.
+    pub fn connect_host(&self) -> &str {
+        self.srv_target.as_deref().unwrap_or(&self.host)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SrvRecord {
+    pub service: String,
+    pub target: String,
+    pub port: u16,
+    pub priority: u16,
+    pub weight: u16,
+}
+
+/// Splits `host[:port]`, handling bracketed IPv6 literals.
+pub fn split_host_port(name: &str) -> (String, Option<u16>) {
+    if let Some(rest) = name.strip_prefix('[') {
+        if let Some((host, after)) = rest.split_once(']') {
+            let port = after.strip_prefix(':').and_then(|p| p.parse().ok());
+            return (host.to_string(), port);
+        }
+    }
+
+    match name.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') => match port.parse() {
+            Ok(port) => (host.to_string(), Some(port)),
+            Err(_) => (name.to_string(), None),
+        },
+        _ => (name.to_string(), None),
+    }
+}
+
+/// Looks up `_matrix-fed._tcp` and the deprecated `_matrix._tcp` SRV records
+/// for `hostname`, sorted by priority and weight within each service.
+pub async fn lookup_srv_records(hostname: &str) -> Vec<SrvRecord> {
+    let Ok(resolver) = TokioAsyncResolver::tokio_from_system_conf() else {
+        return Vec::new();
+    };
+
+    let mut records = Vec::new();
+
+    for service in ["_matrix-fed._tcp", "_matrix._tcp"] {
+        let name = format!("{}.{}.", service, hostname.trim_end_matches('.'));
+        if let Ok(lookup) = resolver.srv_lookup(name).await {
+            let mut found: Vec<SrvRecord> = lookup
+                .iter()
+                .map(|r| SrvRecord {
+                    service: service.to_string(),
+                    target: r.target().to_utf8().trim_end_matches('.').to_string(),
+                    port: r.port(),
+                    priority: r.priority(),
+                    weight: r.weight(),
+                })
+                .collect();
+            found.sort_by_key(|r| (r.priority, u16::MAX - r.weight));
+            records.extend(found);
+        }
+    }
+
+    records
+}
+
+pub async fn fetch_delegated_server(server_name: &str) -> Option<String> {
+    let url = format!("https://{}/.well-known/matrix/server", server_name);
+    let response = get_http_client().get(&url).send().await.ok()?;
+
+    if !response.status().is_success() {
+        return None;
+    }
+
+    response
+        .json::<WellKnownServer>()
+        .await
+        .ok()
+        .and_then(|w| w.m_server)
+        .filter(|s| !s.is_empty())
+}
+
+async fn resolve_hostname(
+    server_name: &str,
+    hostname: &str,
+    mut steps: Vec<String>,
+) -> ResolvedServer {
+    match lookup_srv_records(hostname).await.into_iter().next() {
+        Some(record) => {
+            steps.push(format!(
+                "SRV {}.{} points to {}:{}",
+                record.service, hostname, record.target, record.port
+            ));
+            ResolvedServer {
+                server_name: server_name.to_string(),
+                host: hostname.to_string(),
+                port: record.port,
+                srv_target: (record.target != hostname).then_some(record.target),
+                steps,
+            }
+        }
+        None => {
+            steps.push(format!(
+                "No SRV records for {}, using port {}",
+                hostname, DEFAULT_FEDERATION_PORT
+            ));
+            ResolvedServer::direct(server_name, hostname, DEFAULT_FEDERATION_PORT, steps)
+        }
+    }
+}
+
+/// Resolves a server name following the Matrix server discovery rules:
+/// IP literals and explicit ports are used as-is, then `.well-known`
+/// delegation, then SRV records, then port 8448.
+pub async fn resolve_server_name(server_name: &str) -> ResolvedServer {
+    let (host, port) = split_host_port(server_name);
+
+    if host.parse::<IpAddr>().is_ok() || port.is_some() {
+        let steps = vec![format!(
+            "{} is an IP literal or has an explicit port",
+            server_name
+        )];
+        return ResolvedServer::direct(
+            server_name,
+            &host,
+            port.unwrap_or(DEFAULT_FEDERATION_PORT),
+            steps,
+        );
+    }
+
+    if let Some(delegated) = fetch_delegated_server(server_name).await {
+        let mut steps = vec![format!(
+            "/.well-known/matrix/server delegates to {}",
+            delegated
+        )];
+        let (delegated_host, delegated_port) = split_host_port(&delegated);
+
+        if delegated_host.parse::<IpAddr>().is_ok() || delegated_port.is_some() {
+            steps.push(format!(
+                "{} is an IP literal or has an explicit port",
+                delegated
+            ));
+            return ResolvedServer::direct(
+                server_name,
+                &delegated_host,
+                delegated_port.unwrap_or(DEFAULT_FEDERATION_PORT),
+                steps,
+            );
+        }
+
+        return resolve_hostname(server_name, &delegated_host, steps).await;
+    }
+
+    let steps = vec!["No usable /.well-known/matrix/server delegation".to_string()];
+    resolve_hostname(server_name, &host, steps).await
+}
+
+/// Returns an HTTP client that connects to the SRV target of `resolved`
+/// while still presenting its hostname for TLS and `Host`.
+pub async fn client_for(
+    resolved: &ResolvedServer,
+) -> Result<Client, Box<dyn std::error::Error + Send + Sync>> {
+    let Some(target) = resolved.srv_target.as_deref() else {
+        return Ok(get_http_client().clone());
+    };
+
+    let addr = tokio::net::lookup_host((target, resolved.port))
+        .await?
+        .next()
+        .ok_or_else(|| format!("SRV target {} did not resolve", target))?;
+
+    Ok(Client::builder()
+        .timeout(std::time::Duration::from_secs(10))
+        .resolve(&resolved.host, addr)
+        .build()?)
+}
+
+/// Resolves `destination` and sends an unauthenticated federation `GET`, as
+/// used for endpoints such as `/_matrix/key/v2/server`.
+pub async fn fetch_federation_json(
+    destination: &str,
+    path: &str,
+) -> Result<serde_json::Value, Box<dyn std::error::Error + Send + Sync>> {
+    let resolved = resolve_server_name(destination).await;
+    let client = client_for(&resolved).await?;
+
+    let response = client
+        .get(format!("{}{}", resolved.base_url(), path))
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        return Err(format!(
+            "Federation request to {} returned status {}",
+            destination,
+            response.status()
+        )
+        .into());
+    }
+
+    Ok(response.json().await?)
+}
+
+/// Sends federation requests signed with mxindex's own server key.
+pub struct FederationClient {
+    key: Arc<ServerSigningKey>,
+}
+
+impl std::fmt::Debug for FederationClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FederationClient")
+            .field("server_name", &self.server_name())
+            .finish()
+    }
+}
+
+impl FederationClient {
+    pub fn new(key: Arc<ServerSigningKey>) -> Self {
+        Self { key }
+    }
+
+    pub fn server_name(&self) -> &str {
+        &self.key.server_name
+    }
+
+    pub fn signing_key(&self) -> &ServerSigningKey {
+        &self.key
+    }
+
+    /// Resolves `destination` and sends a signed `GET` for `path_and_query`.
+    pub async fn get_json(
+        &self,
+        destination: &str,
+        path_and_query: &str,
+    ) -> Result<serde_json::Value, Box<dyn std::error::Error + Send + Sync>> {
+        let resolved = resolve_server_name(destination).await;
+        let client = client_for(&resolved).await?;
+        self.get_json_from(&client, &resolved.base_url(), destination, path_and_query)
+            .await
+    }
+
+    /// Sends a signed `GET` to an already known base URL.
+    pub async fn get_json_from(
+        &self,
+        client: &Client,
+        base_url: &str,
+        destination: &str,
+        path_and_query: &str,
+    ) -> Result<serde_json::Value, Box<dyn std::error::Error + Send + Sync>> {
+        let authorization =
+            self.key
+                .x_matrix_authorization("GET", path_and_query, destination, None);
+
+        let response = client
+            .get(format!(
+                "{}{}",
+                base_url.trim_end_matches('/'),
+                path_and_query
+            ))
+            .header(reqwest::header::AUTHORIZATION, authorization)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(format!(
+                "Federation request to {} returned status {}",
+                destination,
+                response.status()
+            )
+            .into());
+        }
+
+        Ok(response.json().await?)
+    }
+}
+
+pub fn public_rooms_path(limit: u32) -> String {
+    format!("/_matrix/federation/v1/publicRooms?limit={}", limit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httptest::matchers::{all_of, contains, key, matches, request};
+    use httptest::responders::json_encoded;
+    use httptest::{Expectation, Server};
+
+    fn test_client() -> FederationClient {
+        FederationClient::new(Arc::new(ServerSigningKey::new(
+            "mxindex.test",
+            "a_test",
+            [3u8; 32],
+        )))
+    }
+
+    #[test]
+    fn test_split_host_port() {
+        assert_eq!(
+            split_host_port("matrix.org"),
+            ("matrix.org".to_string(), None)
+        );
+        assert_eq!(
+            split_host_port("matrix.org:8448"),
+            ("matrix.org".to_string(), Some(8448))
+        );
+        assert_eq!(
+            split_host_port("[::1]:8448"),
+            ("::1".to_string(), Some(8448))
+        );
+        assert_eq!(split_host_port("[::1]"), ("::1".to_string(), None));
+    }
+
+    #[test]
+    fn test_resolved_server_base_url() {
+        let resolved = ResolvedServer::direct("example.org", "example.org", 8448, vec![]);
+        assert_eq!(resolved.base_url(), "https://example.org:8448");
+        assert_eq!(resolved.connect_host(), "example.org");
+
+        let resolved = ResolvedServer::direct("[::1]", "::1", 8448, vec![]);
+        assert_eq!(resolved.base_url(), "https://[::1]:8448");
+    }
+
+    #[tokio::test]
+    async fn test_resolve_explicit_port_and_ip_literal() {
+        let resolved = resolve_server_name("example.org:1234").await;
+        assert_eq!(resolved.host, "example.org");
+        assert_eq!(resolved.port, 1234);
+        assert_eq!(resolved.steps.len(), 1);
+
+        let resolved = resolve_server_name("127.0.0.1").await;
+        assert_eq!(resolved.host, "127.0.0.1");
+        assert_eq!(resolved.port, DEFAULT_FEDERATION_PORT);
+    }
+
+    #[test]
+    fn test_public_rooms_path() {
+        assert_eq!(
+            public_rooms_path(100),
+            "/_matrix/federation/v1/publicRooms?limit=100"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_json_sends_x_matrix_authorization() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("GET", "/_matrix/federation/v1/publicRooms"),
+                request::headers(contains((
+                    "authorization",
+                    matches("^X-Matrix origin=\"mxindex.test\",destination=\"remote.test\"")
+                ))),
+            ])
+            .respond_with(json_encoded(serde_json::json!({
+                "chunk": [],
+                "total_room_count_estimate": 3
+            }))),
+        );
+
+        let client = test_client();
+        let base_url = server.url_str("");
+        let body = client
+            .get_json_from(
+                get_http_client(),
+                &base_url,
+                "remote.test",
+                &public_rooms_path(100),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(body["total_room_count_estimate"], 3);
+    }
+
+    #[tokio::test]
+    async fn test_get_json_error_status() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(request::headers(contains(key("authorization"))))
+                .respond_with(httptest::responders::status_code(401)),
+        );
+
+        let client = test_client();
+        let result = client
+            .get_json_from(
+                get_http_client(),
+                &server.url_str(""),
+                "remote.test",
+                "/_matrix/federation/v1/version",
+            )
+            .await;
+
+        assert!(result.is_err());
+    }
+}
@@ -1,6 +1,9 @@
-use crate::db::{insert_server, DbPool};
+use crate::bridges::{record_bridges_from_heroes, record_bridges_from_payloads};
+use crate::db::{insert_probe_payloads, insert_server, DbPool};
+use crate::events::record_events;
+use crate::federation_client::{public_rooms_path, FederationClient};
 use crate::http_client::get_http_client;
-use crate::models::CreateServerRequest;
+use crate::models::{CreateServerRequest, EventKind};
 use crate::services::MatrixService;
 use diesel::prelude::*;
 use futures::stream::{self, StreamExt};
@@ -17,10 +20,11 @@ pub struct FederationDiscovery {
     max_depth: usize,
     batch_size: usize,
     seed_servers: Vec<String>,
+    federation: Option<Arc<FederationClient>>,
 }
 
 impl FederationDiscovery {
-    pub fn new(db_pool: DbPool) -> Self {
+    pub fn new(db_pool: DbPool, federation: Option<Arc<FederationClient>>) -> Self {
         let max_concurrent = std::env::var("FEDERATION_DISCOVERY_CONCURRENT")
             .ok()
             .and_then(|v| v.parse().ok())
@@ -47,6 +51,7 @@ impl FederationDiscovery {
             max_depth,
             batch_size,
             seed_servers,
+            federation,
         }
     }
 
@@ -81,11 +86,17 @@ impl FederationDiscovery {
             )> = stream::iter(servers_to_check.clone())
                 .map(|server| {
                     let semaphore = semaphore.clone();
+                    let federation = self.federation.clone();
+                    let db_pool = self.db_pool.clone();
                     async move {
                         let _permit = semaphore.acquire().await.expect("Failed to acquire permit");
                         let result = tokio::time::timeout(
                             std::time::Duration::from_secs(10),
-                            Self::discover_servers_from_federation(&server),
+                            Self::discover_servers_from_federation(
+                                &server,
+                                federation.as_deref(),
+                                &db_pool,
+                            ),
                         )
                         .await;
                         match result {
@@ -109,13 +120,8 @@ impl FederationDiscovery {
                 match result {
                     Ok(new_servers) => {
                         for new_server in new_servers {
-                            if !discovered.contains(&new_server) {
-                                discovered.insert(new_server.clone());
-                                servers_to_check.push(new_server.clone());
-
-                                if self.add_server_to_index(&new_server).await {
-                                    added_count += 1;
-                                }
+                            if discovered.insert(new_server.clone()) {
+                                servers_to_check.push(new_server);
                             }
                         }
                     }
@@ -125,6 +131,15 @@ impl FederationDiscovery {
                 }
             }
 
+            // Each server's probes already run concurrently; indexing several
+            // servers at once lets throughput follow `max_concurrent`.
+            added_count += stream::iter(servers_to_check.clone())
+                .map(|server| async move { self.add_server_to_index(&server).await })
+                .buffer_unordered(self.max_concurrent)
+                .filter(|added| futures::future::ready(*added))
+                .count()
+                .await;
+
             if servers_to_check.len() > self.batch_size {
                 servers_to_check.truncate(self.batch_size);
             }
@@ -139,10 +154,28 @@ impl FederationDiscovery {
 
     async fn discover_servers_from_federation(
         server: &str,
+        federation: Option<&FederationClient>,
+        db_pool: &DbPool,
     ) -> Result<HashSet<String>, Box<dyn std::error::Error + Send + Sync>> {
         let mut discovered_servers: HashSet<String> = HashSet::new();
 
-        let servers_from_rooms = Self::discover_servers_from_public_rooms(server).await?;
+        if let Some(federation) = federation {
+            match federation.get_json(server, &public_rooms_path(100)).await {
+                Ok(json) => {
+                    record_hero_bridges(db_pool, &json, server);
+                    discovered_servers.extend(extract_servers_from_public_rooms(&json, server));
+                    return Ok(discovered_servers);
+                }
+                Err(e) => {
+                    warn!(
+                        "Federation publicRooms failed for {}, falling back to client API: {}",
+                        server, e
+                    );
+                }
+            }
+        }
+
+        let servers_from_rooms = Self::discover_servers_from_public_rooms(server, db_pool).await?;
         discovered_servers.extend(servers_from_rooms);
 
         Ok(discovered_servers)
@@ -150,8 +183,8 @@ impl FederationDiscovery {
 
     async fn discover_servers_from_public_rooms(
         server: &str,
+        db_pool: &DbPool,
     ) -> Result<HashSet<String>, Box<dyn std::error::Error + Send + Sync>> {
-        let mut servers: HashSet<String> = HashSet::new();
         let server_url = format!("https://{}/_matrix/client/r0/publicRooms", server);
 
         let http_client = get_http_client();
@@ -163,36 +196,13 @@ impl FederationDiscovery {
             .await?;
 
         if !response.status().is_success() {
-            return Ok(servers);
+            return Ok(HashSet::new());
         }
 
         let json: serde_json::Value = response.json().await?;
+        record_hero_bridges(db_pool, &json, server);
 
-        if let Some(chunks) = json["chunk"].as_array() {
-            for chunk in chunks {
-                if let Some(heroes) = chunk["heroes"].as_array() {
-                    for hero in heroes {
-                        if let Some(mxid) = hero["mxid"].as_str() {
-                            if let Some(domain) = extract_domain_from_mxid(mxid) {
-                                if domain != server {
-                                    servers.insert(domain);
-                                }
-                            }
-                        }
-                    }
-                }
-
-                if let Some(topic) = chunk["topic"].as_str() {
-                    for domain in extract_domains_from_text(topic) {
-                        if domain != server {
-                            servers.insert(domain);
-                        }
-                    }
-                }
-            }
-        }
-
-        Ok(servers)
+        Ok(extract_servers_from_public_rooms(&json, server))
     }
 
     async fn add_server_to_index(&self, domain: &str) -> bool {
@@ -211,7 +221,7 @@ impl FederationDiscovery {
         };
 
         match MatrixService::discover_server_info(domain).await {
-            Ok(info) => {
+            Ok((info, payloads)) => {
                 use crate::db::NewServer;
 
                 let mut conn = match self.db_pool.get() {
@@ -224,23 +234,32 @@ impl FederationDiscovery {
 
                 let domain_str = domain.to_string();
 
-                let new_server = NewServer {
-                    domain: &request.domain,
-                    name: info.name.as_deref(),
-                    description: info.description.as_deref(),
-                    logo_url: info.logo_url.as_deref(),
-                    theme: info.theme.as_deref(),
-                    registration_open: info.registration_open,
-                    public_rooms_count: info.public_rooms_count,
-                    version: info.version.as_deref(),
-                    federation_version: info.federation_version.as_deref(),
-                    delegated_server: info.delegated_server.as_deref(),
-                    room_versions: info.room_versions.as_deref(),
-                };
+                let new_server = NewServer::from_discovered(&request.domain, &info);
 
                 match insert_server(&mut conn, &new_server) {
-                    Ok(_) => {
+                    Ok(server) => {
                         info!("Added server from federation discovery: {}", domain_str);
+
+                        if let Err(e) = record_events(
+                            &mut conn,
+                            Some(server.id),
+                            domain,
+                            &[(EventKind::ServerAdded, serde_json::json!({}))],
+                        ) {
+                            warn!("Failed to record events for {}: {}", domain_str, e);
+                        }
+
+                        if let Err(e) = insert_probe_payloads(&mut conn, server.id, &payloads) {
+                            warn!("Failed to store probe payloads for {}: {}", domain_str, e);
+                        }
+
+                        if let Err(e) =
+                            record_bridges_from_payloads(&mut conn, server.id, domain, &payloads)
+                        {
+                            warn!("Failed to store bridges for {}: {}", domain_str, e);
+                        }
+
+                        MatrixService::record_server_checks(&self.db_pool, &server).await;
                         true
                     }
                     Err(e) => {
@@ -271,6 +290,47 @@ impl FederationDiscovery {
     }
 }
 
+/// Room directories list heroes from many servers; bridge puppets among them
+/// are evidence for the bridges of the indexed servers they belong to.
+fn record_hero_bridges(db_pool: &DbPool, json: &serde_json::Value, server: &str) {
+    if let Err(e) = record_bridges_from_heroes(db_pool, json) {
+        warn!(
+            "Failed to store bridges seen in {}'s directory: {}",
+            server, e
+        );
+    }
+}
+
+fn extract_servers_from_public_rooms(json: &serde_json::Value, server: &str) -> HashSet<String> {
+    let mut servers: HashSet<String> = HashSet::new();
+
+    if let Some(chunks) = json["chunk"].as_array() {
+        for chunk in chunks {
+            if let Some(heroes) = chunk["heroes"].as_array() {
+                for hero in heroes {
+                    if let Some(mxid) = hero["mxid"].as_str() {
+                        if let Some(domain) = extract_domain_from_mxid(mxid) {
+                            if domain != server {
+                                servers.insert(domain);
+                            }
+                        }
+                    }
+                }
+            }
+
+            if let Some(topic) = chunk["topic"].as_str() {
+                for domain in extract_domains_from_text(topic) {
+                    if domain != server {
+                        servers.insert(domain);
+                    }
+                }
+            }
+        }
+    }
+
+    servers
+}
+
 fn extract_domain_from_mxid(mxid: &str) -> Option<String> {
     if mxid.starts_with('@') {
         let parts: Vec<&str> = mxid.splitn(2, ':').collect();
@@ -296,3 +356,76 @@ fn extract_domains_from_text(text: &str) -> Vec<String> {
 
     domains
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signing::ServerSigningKey;
+    use httptest::matchers::{contains, key, request};
+    use httptest::responders::json_encoded;
+    use httptest::{all_of, Expectation, Server};
+
+    #[test]
+    fn test_extract_domain_from_mxid() {
+        assert_eq!(
+            extract_domain_from_mxid("@alice:example.org"),
+            Some("example.org".to_string())
+        );
+        assert_eq!(extract_domain_from_mxid("#room:example.org"), None);
+        assert_eq!(extract_domain_from_mxid("@nodomain"), None);
+    }
+
+    #[test]
+    fn test_extract_domains_from_text() {
+        let domains = extract_domains_from_text("Bridged from example.org and foo.onion");
+        assert_eq!(domains, vec!["example.org".to_string()]);
+    }
+
+    #[test]
+    fn test_extract_servers_from_public_rooms_skips_self() {
+        let json = serde_json::json!({
+            "chunk": [
+                {"heroes": [{"mxid": "@a:remote.org"}, {"mxid": "@b:origin.org"}]},
+                {"topic": "See also other.org"}
+            ]
+        });
+
+        let servers = extract_servers_from_public_rooms(&json, "origin.org");
+        assert!(servers.contains("remote.org"));
+        assert!(servers.contains("other.org"));
+        assert!(!servers.contains("origin.org"));
+    }
+
+    #[tokio::test]
+    async fn test_federation_public_rooms_against_mock_homeserver() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("GET", "/_matrix/federation/v1/publicRooms"),
+                request::headers(contains(key("authorization"))),
+            ])
+            .respond_with(json_encoded(serde_json::json!({
+                "chunk": [{"heroes": [{"mxid": "@bob:found.org"}]}]
+            }))),
+        );
+
+        let federation = FederationClient::new(Arc::new(ServerSigningKey::new(
+            "mxindex.test",
+            "a_test",
+            [1u8; 32],
+        )));
+        let json = federation
+            .get_json_from(
+                get_http_client(),
+                &server.url_str(""),
+                "origin.org",
+                &public_rooms_path(100),
+            )
+            .await
+            .unwrap();
+
+        let servers = extract_servers_from_public_rooms(&json, "origin.org");
+        assert_eq!(servers.len(), 1);
+        assert!(servers.contains("found.org"));
+    }
+}
@@ -0,0 +1,495 @@
+use crate::federation_client::{
+    lookup_srv_records, resolve_server_name, split_host_port, ResolvedServer, SrvRecord,
+};
+use crate::models::{CheckStatus, FederationCheck, FederationReportResponse};
+use crate::server_keys::{parse_server_keys, SERVER_KEYS_PATH};
+use crate::webhooks::{is_public_ip, pinned_client, public_client};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn check(name: &str, status: CheckStatus, message: impl Into<String>) -> FederationCheck {
+    FederationCheck {
+        name: name.to_string(),
+        status,
+        message: message.into(),
+    }
+}
+
+pub fn overall_status(checks: &[FederationCheck]) -> CheckStatus {
+    checks
+        .iter()
+        .map(|c| c.status)
+        .max()
+        .unwrap_or(CheckStatus::Pass)
+}
+
+/// Grades a `/.well-known/matrix/server` response. A missing document is
+/// fine because resolution falls back to SRV and port 8448.
+pub fn well_known_check(status: Option<u16>, body: Option<&str>) -> FederationCheck {
+    let name = "well_known_server";
+
+    match status {
+        None => check(name, CheckStatus::Pass, "Not reachable, no delegation used"),
+        Some(404) => check(name, CheckStatus::Pass, "Not present, no delegation used"),
+        Some(code) if !(200..300).contains(&code) => check(
+            name,
+            CheckStatus::Warn,
+            format!("Returned HTTP {}, delegation ignored", code),
+        ),
+        Some(_) => match body.map(serde_json::from_str::<serde_json::Value>) {
+            Some(Ok(json)) => match json["m.server"].as_str() {
+                Some(server) if !server.is_empty() => {
+                    let (host, _) = split_host_port(server);
+                    if host.is_empty() {
+                        check(
+                            name,
+                            CheckStatus::Fail,
+                            format!("m.server {:?} is not a valid server name", server),
+                        )
+                    } else {
+                        check(name, CheckStatus::Pass, format!("Delegates to {}", server))
+                    }
+                }
+                _ => check(
+                    name,
+                    CheckStatus::Warn,
+                    "Document has no m.server, delegation ignored",
+                ),
+            },
+            _ => check(
+                name,
+                CheckStatus::Warn,
+                "Document is not valid JSON, delegation ignored",
+            ),
+        },
+    }
+}
+
+pub fn srv_check(hostname: &str, records: &[SrvRecord]) -> FederationCheck {
+    let name = "srv_records";
+
+    if records.is_empty() {
+        return check(
+            name,
+            CheckStatus::Pass,
+            format!("No SRV records for {}", hostname),
+        );
+    }
+
+    let listed = records
+        .iter()
+        .map(|r| {
+            format!(
+                "{} {} {} {}:{}",
+                r.service, r.priority, r.weight, r.target, r.port
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("; ");
+
+    if records.iter().any(|r| r.service == "_matrix-fed._tcp") {
+        check(name, CheckStatus::Pass, listed)
+    } else {
+        check(
+            name,
+            CheckStatus::Warn,
+            format!("Only deprecated _matrix._tcp records: {}", listed),
+        )
+    }
+}
+
+/// Turns the results of a strict handshake and the two relaxed retries into
+/// separate certificate chain and SNI/hostname checks.
+pub fn tls_checks(
+    host: &str,
+    strict: Result<(), String>,
+    ignore_hostname: Result<(), String>,
+    ignore_all: Result<(), String>,
+) -> Vec<FederationCheck> {
+    match (strict, ignore_hostname, ignore_all) {
+        (Ok(()), _, _) => vec![
+            check(
+                "tls_certificate_chain",
+                CheckStatus::Pass,
+                "Certificate chain is trusted",
+            ),
+            check(
+                "tls_sni_match",
+                CheckStatus::Pass,
+                format!("Certificate is valid for {}", host),
+            ),
+        ],
+        (Err(e), Ok(()), _) => vec![
+            check(
+                "tls_certificate_chain",
+                CheckStatus::Pass,
+                "Certificate chain is trusted",
+            ),
+            check(
+                "tls_sni_match",
+                CheckStatus::Fail,
+                format!("Certificate is not valid for {}: {}", host, e),
+            ),
+        ],
+        (Err(e), Err(_), Ok(())) => vec![check(
+            "tls_certificate_chain",
+            CheckStatus::Fail,
+            format!("Certificate chain is not trusted: {}", e),
+        )],
+        (Err(e), Err(_), Err(_)) => vec![check(
+            "tls_handshake",
+            CheckStatus::Fail,
+            format!("TLS handshake failed: {}", e),
+        )],
+    }
+}
+
+pub fn server_keys_check(json: &serde_json::Value, domain: &str, now_ms: i64) -> FederationCheck {
+    let name = "server_keys";
+
+    match parse_server_keys(json, domain) {
+        Ok(observed) if !observed.signature_valid => check(
+            name,
+            CheckStatus::Fail,
+            format!(
+                "Signature check failed: {}",
+                observed.signature_error.unwrap_or_default()
+            ),
+        ),
+        Ok(observed) => match observed.valid_until_ts {
+            Some(ts) if ts <= now_ms => {
+                check(name, CheckStatus::Warn, format!("Keys expired at {}", ts))
+            }
+            _ => check(
+                name,
+                CheckStatus::Pass,
+                format!(
+                    "{} verify key(s) with valid signatures",
+                    observed.keys.iter().filter(|k| k.is_current).count()
+                ),
+            ),
+        },
+        Err(e) => check(name, CheckStatus::Fail, e),
+    }
+}
+
+async fn fetch_well_known(domain: &str) -> (Option<u16>, Option<String>) {
+    let url = format!("https://{}/.well-known/matrix/server", domain);
+    let Ok(client) = public_client(&url).await else {
+        return (None, None);
+    };
+
+    match client.get(&url).send().await {
+        Ok(response) => {
+            let status = response.status().as_u16();
+            (Some(status), response.text().await.ok())
+        }
+        Err(_) => (None, None),
+    }
+}
+
+/// Grades the addresses the connect host resolves to. Only a host whose
+/// addresses are all public passes; the first one is what later checks
+/// connect to.
+pub fn dns_check(host: &str, addrs: &[SocketAddr]) -> (FederationCheck, Option<SocketAddr>) {
+    if addrs.is_empty() {
+        return (
+            check(
+                "dns",
+                CheckStatus::Fail,
+                format!("{} has no addresses", host),
+            ),
+            None,
+        );
+    }
+
+    if addrs.iter().any(|addr| !is_public_ip(addr.ip())) {
+        return (
+            check(
+                "dns",
+                CheckStatus::Fail,
+                format!("{} resolves to a non-public address", host),
+            ),
+            None,
+        );
+    }
+
+    let listed: Vec<String> = addrs.iter().map(|a| a.ip().to_string()).collect();
+    (
+        check(
+            "dns",
+            CheckStatus::Pass,
+            format!("{} resolves to {}", host, listed.join(", ")),
+        ),
+        Some(addrs[0]),
+    )
+}
+
+async fn resolve(resolved: &ResolvedServer) -> (FederationCheck, Option<SocketAddr>) {
+    let host = resolved.connect_host();
+
+    match tokio::net::lookup_host((host, resolved.port)).await {
+        Ok(addrs) => dns_check(host, &addrs.collect::<Vec<_>>()),
+        Err(_) => (
+            check(
+                "dns",
+                CheckStatus::Fail,
+                format!("{} did not resolve", host),
+            ),
+            None,
+        ),
+    }
+}
+
+async fn tls_handshake(
+    resolved: &ResolvedServer,
+    addr: SocketAddr,
+    accept_invalid_hostnames: bool,
+    accept_invalid_certs: bool,
+) -> Result<(), String> {
+    let tcp = tokio::time::timeout(CHECK_TIMEOUT, TcpStream::connect(addr))
+        .await
+        .map_err(|_| "connection timed out".to_string())?
+        .map_err(|e| e.to_string())?;
+
+    let connector = native_tls::TlsConnector::builder()
+        .danger_accept_invalid_hostnames(accept_invalid_hostnames)
+        .danger_accept_invalid_certs(accept_invalid_certs)
+        .build()
+        .map_err(|e| e.to_string())?;
+
+    tokio::time::timeout(
+        CHECK_TIMEOUT,
+        tokio_native_tls::TlsConnector::from(connector).connect(&resolved.host, tcp),
+    )
+    .await
+    .map_err(|_| "handshake timed out".to_string())?
+    .map(|_| ())
+    .map_err(|e| e.to_string())
+}
+
+async fn federation_get(
+    resolved: &ResolvedServer,
+    addr: SocketAddr,
+    path: &str,
+) -> Result<serde_json::Value, String> {
+    let url = format!("{}{}", resolved.base_url(), path);
+    let client = pinned_client(&url, addr)?;
+
+    let response = client.get(&url).send().await.map_err(|e| e.to_string())?;
+
+    if !response.status().is_success() {
+        return Err(format!("HTTP {}", response.status()));
+    }
+
+    response.json().await.map_err(|e| e.to_string())
+}
+
+/// Runs a federation-tester style check of how `domain` is resolved and
+/// whether its federation endpoint and signing keys are usable.
+pub async fn build_federation_report(domain: &str) -> FederationReportResponse {
+    let mut checks = Vec::new();
+
+    let (status, body) = fetch_well_known(domain).await;
+    checks.push(well_known_check(status, body.as_deref()));
+
+    let resolved = resolve_server_name(domain).await;
+
+    let srv_records = lookup_srv_records(&resolved.host).await;
+    checks.push(srv_check(&resolved.host, &srv_records));
+
+    checks.push(check(
+        "resolution",
+        CheckStatus::Pass,
+        format!(
+            "Federation traffic goes to {}:{}",
+            resolved.connect_host(),
+            resolved.port
+        ),
+    ));
+
+    let (dns, addr) = resolve(&resolved).await;
+    checks.push(dns);
+
+    if let Some(addr) = addr {
+        let strict = tls_handshake(&resolved, addr, false, false).await;
+        let (ignore_hostname, ignore_all) = if strict.is_ok() {
+            (Ok(()), Ok(()))
+        } else {
+            (
+                tls_handshake(&resolved, addr, true, false).await,
+                tls_handshake(&resolved, addr, true, true).await,
+            )
+        };
+        checks.extend(tls_checks(
+            &resolved.host,
+            strict,
+            ignore_hostname,
+            ignore_all,
+        ));
+
+        match federation_get(&resolved, addr, "/_matrix/federation/v1/version").await {
+            Ok(json) => {
+                let name = json["server"]["name"].as_str().unwrap_or("unknown");
+                let version = json["server"]["version"].as_str().unwrap_or("unknown");
+                checks.push(check(
+                    "federation_version",
+                    CheckStatus::Pass,
+                    format!("{} {}", name, version),
+                ));
+            }
+            Err(e) => checks.push(check(
+                "federation_version",
+                CheckStatus::Fail,
+                format!("/_matrix/federation/v1/version failed: {}", e),
+            )),
+        }
+
+        match federation_get(&resolved, addr, SERVER_KEYS_PATH).await {
+            Ok(json) => checks.push(server_keys_check(
+                &json,
+                domain,
+                chrono::Utc::now().timestamp_millis(),
+            )),
+            Err(e) => checks.push(check(
+                "server_keys",
+                CheckStatus::Fail,
+                format!("Key fetch failed: {}", e),
+            )),
+        }
+    }
+
+    FederationReportResponse {
+        domain: domain.to_string(),
+        status: overall_status(&checks),
+        resolved_host: resolved.host.clone(),
+        resolved_port: resolved.port,
+        connect_host: resolved.connect_host().to_string(),
+        resolution_path: resolved.steps,
+        checks,
+        generated_at: chrono::Utc::now().naive_utc(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signing::ServerSigningKey;
+
+    fn srv(service: &str) -> SrvRecord {
+        SrvRecord {
+            service: service.to_string(),
+            target: "fed.example.org".to_string(),
+            port: 443,
+            priority: 10,
+            weight: 5,
+        }
+    }
+
+    #[test]
+    fn test_overall_status_is_worst_check() {
+        let checks = vec![
+            check("a", CheckStatus::Pass, ""),
+            check("b", CheckStatus::Warn, ""),
+        ];
+        assert_eq!(overall_status(&checks), CheckStatus::Warn);
+        assert_eq!(overall_status(&[]), CheckStatus::Pass);
+    }
+
+    #[test]
+    fn test_well_known_check() {
+        assert_eq!(well_known_check(Some(404), None).status, CheckStatus::Pass);
+        assert_eq!(
+            well_known_check(Some(200), Some(r#"{"m.server": "fed.example.org:443"}"#)).status,
+            CheckStatus::Pass
+        );
+        assert_eq!(
+            well_known_check(Some(200), Some("<html>")).status,
+            CheckStatus::Warn
+        );
+        assert_eq!(well_known_check(Some(500), None).status, CheckStatus::Warn);
+    }
+
+    #[test]
+    fn test_srv_check() {
+        assert_eq!(srv_check("example.org", &[]).status, CheckStatus::Pass);
+        assert_eq!(
+            srv_check("example.org", &[srv("_matrix-fed._tcp")]).status,
+            CheckStatus::Pass
+        );
+        assert_eq!(
+            srv_check("example.org", &[srv("_matrix._tcp")]).status,
+            CheckStatus::Warn
+        );
+    }
+
+    #[test]
+    fn test_tls_checks_distinguish_failures() {
+        let ok = tls_checks("example.org", Ok(()), Ok(()), Ok(()));
+        assert!(ok.iter().all(|c| c.status == CheckStatus::Pass));
+
+        let sni = tls_checks("example.org", Err("mismatch".into()), Ok(()), Ok(()));
+        assert_eq!(sni[1].name, "tls_sni_match");
+        assert_eq!(sni[1].status, CheckStatus::Fail);
+
+        let chain = tls_checks(
+            "example.org",
+            Err("self signed".into()),
+            Err("self signed".into()),
+            Ok(()),
+        );
+        assert_eq!(chain[0].name, "tls_certificate_chain");
+        assert_eq!(chain[0].status, CheckStatus::Fail);
+
+        let handshake = tls_checks(
+            "example.org",
+            Err("reset".into()),
+            Err("reset".into()),
+            Err("reset".into()),
+        );
+        assert_eq!(handshake[0].name, "tls_handshake");
+    }
+
+    #[test]
+    fn test_dns_check_refuses_non_public_addresses() {
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+
+        let (result, target) = dns_check("fed.example.org", &[addr("93.184.216.34:8448")]);
+        assert_eq!(result.status, CheckStatus::Pass);
+        assert_eq!(target, Some(addr("93.184.216.34:8448")));
+
+        let (result, target) = dns_check(
+            "fed.example.org",
+            &[addr("93.184.216.34:8448"), addr("10.0.0.5:8448")],
+        );
+        assert_eq!(result.status, CheckStatus::Fail);
+        assert!(!result.message.contains("10.0.0.5"));
+        assert_eq!(target, None);
+
+        let (result, target) = dns_check("fed.example.org", &[]);
+        assert_eq!(result.status, CheckStatus::Fail);
+        assert_eq!(target, None);
+    }
+
+    #[test]
+    fn test_server_keys_check() {
+        let key = ServerSigningKey::new("example.org", "a", [9u8; 32]);
+        let json = key.server_keys_response(2_000);
+
+        assert_eq!(
+            server_keys_check(&json, "example.org", 1_000).status,
+            CheckStatus::Pass
+        );
+        assert_eq!(
+            server_keys_check(&json, "example.org", 3_000).status,
+            CheckStatus::Warn
+        );
+        assert_eq!(
+            server_keys_check(&json, "other.org", 1_000).status,
+            CheckStatus::Fail
+        );
+    }
+}
@@ -0,0 +1,170 @@
+use crate::models::ServerEventResponse;
+use chrono::{NaiveDateTime, SecondsFormat};
+
+const FEED_TITLE: &str = "mxindex events";
+const FEED_DESCRIPTION: &str = "Servers added to and removed from the Matrix homeserver index, \
+     outages, upgrades and registration changes";
+
+/// Absolute base URL of this instance, used for links in the feeds.
+pub fn public_base_url() -> String {
+    std::env::var("MXINDEX_PUBLIC_URL")
+        .unwrap_or_else(|_| "http://localhost:8000".to_string())
+        .trim_end_matches('/')
+        .to_string()
+}
+
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn rfc3339(at: NaiveDateTime) -> String {
+    at.and_utc().to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn event_guid(event: &ServerEventResponse) -> String {
+    format!("urn:mxindex:event:{}", event.id)
+}
+
+fn server_link(base_url: &str, event: &ServerEventResponse) -> String {
+    escape(&format!("{}/servers/{}", base_url, event.domain))
+}
+
+/// Renders events, newest first, as an Atom 1.0 feed.
+pub fn atom_feed(events: &[ServerEventResponse], base_url: &str, self_url: &str) -> String {
+    let updated = events
+        .first()
+        .map(|e| e.created_at)
+        .unwrap_or_else(|| chrono::Utc::now().naive_utc());
+
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <feed xmlns=\"http://www.w3.org/2005/Atom\">\n\
+         <title>{}</title>\n\
+         <subtitle>{}</subtitle>\n\
+         <id>{}</id>\n\
+         <link rel=\"self\" href=\"{}\"/>\n\
+         <updated>{}</updated>\n",
+        FEED_TITLE,
+        escape(FEED_DESCRIPTION),
+        escape(&format!("{}/events", base_url)),
+        escape(self_url),
+        rfc3339(updated)
+    );
+
+    for event in events {
+        xml.push_str(&format!(
+            "<entry>\n\
+             <title>{}</title>\n\
+             <id>{}</id>\n\
+             <link href=\"{}\"/>\n\
+             <updated>{}</updated>\n\
+             <category term=\"{}\"/>\n\
+             <author><name>mxindex</name></author>\n\
+             </entry>\n",
+            escape(&event.title),
+            event_guid(event),
+            server_link(base_url, event),
+            rfc3339(event.created_at),
+            escape(&event.kind)
+        ));
+    }
+
+    xml.push_str("</feed>\n");
+    xml
+}
+
+/// Renders events, newest first, as an RSS 2.0 feed.
+pub fn rss_feed(events: &[ServerEventResponse], base_url: &str) -> String {
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <rss version=\"2.0\">\n\
+         <channel>\n\
+         <title>{}</title>\n\
+         <link>{}</link>\n\
+         <description>{}</description>\n",
+        FEED_TITLE,
+        escape(&format!("{}/events", base_url)),
+        escape(FEED_DESCRIPTION)
+    );
+
+    if let Some(latest) = events.first() {
+        xml.push_str(&format!(
+            "<lastBuildDate>{}</lastBuildDate>\n",
+            latest.created_at.and_utc().to_rfc2822()
+        ));
+    }
+
+    for event in events {
+        xml.push_str(&format!(
+            "<item>\n\
+             <title>{}</title>\n\
+             <link>{}</link>\n\
+             <guid isPermaLink=\"false\">{}</guid>\n\
+             <pubDate>{}</pubDate>\n\
+             <category>{}</category>\n\
+             </item>\n",
+            escape(&event.title),
+            server_link(base_url, event),
+            event_guid(event),
+            event.created_at.and_utc().to_rfc2822(),
+            escape(&event.kind)
+        ));
+    }
+
+    xml.push_str("</channel>\n</rss>\n");
+    xml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event() -> ServerEventResponse {
+        ServerEventResponse {
+            id: 7,
+            kind: "server_upgraded".to_string(),
+            domain: "example.org".to_string(),
+            title: "example.org upgraded from <1.0> to 1.1 & more".to_string(),
+            details: serde_json::json!({}),
+            created_at: NaiveDateTime::parse_from_str("2024-01-01 12:00:00", "%Y-%m-%d %H:%M:%S")
+                .unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_atom_feed() {
+        let xml = atom_feed(
+            &[event()],
+            "https://index.example",
+            "https://index.example/events/atom?kind=server_upgraded",
+        );
+
+        assert!(xml.contains("<updated>2024-01-01T12:00:00Z</updated>"));
+        assert!(xml.contains("<id>urn:mxindex:event:7</id>"));
+        assert!(xml.contains("<link href=\"https://index.example/servers/example.org\"/>"));
+        assert!(xml.contains("upgraded from &lt;1.0&gt; to 1.1 &amp; more"));
+        assert!(xml.contains("<category term=\"server_upgraded\"/>"));
+        assert!(xml.contains("href=\"https://index.example/events/atom?kind=server_upgraded\""));
+    }
+
+    #[test]
+    fn test_rss_feed() {
+        let xml = rss_feed(&[event()], "https://index.example");
+
+        assert!(xml.contains("<pubDate>Mon, 1 Jan 2024 12:00:00 +0000</pubDate>"));
+        assert!(xml.contains("<guid isPermaLink=\"false\">urn:mxindex:event:7</guid>"));
+        assert!(xml.ends_with("</channel>\n</rss>\n"));
+    }
+
+    #[test]
+    fn test_empty_rss_feed_has_no_build_date() {
+        let xml = rss_feed(&[], "https://index.example");
+
+        assert!(!xml.contains("lastBuildDate"));
+        assert!(xml.contains("<link>https://index.example/events</link>"));
+    }
+}
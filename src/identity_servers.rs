@@ -0,0 +1,132 @@
+use crate::db::{link_identity_server, update_identity_server_check, DbPool};
+use crate::models::TermsPolicy;
+use crate::services::{probe_timeout, terms_policies_from_params};
+use crate::webhooks::public_client;
+use serde_json::Value;
+
+pub const IDENTITY_API_PATH: &str = "/_matrix/identity/v2";
+pub const IDENTITY_TERMS_PATH: &str = "/_matrix/identity/v2/terms";
+
+/// Identity servers are shared by many homeservers, so one is re-checked at
+/// most this often no matter how many of them recommend it.
+const RECHECK_INTERVAL_HOURS: i64 = 6;
+
+/// Links a homeserver to the identity server its `/.well-known/matrix/client`
+/// recommends and, when the last check is stale, re-checks that identity
+/// server's reachability and terms.
+pub async fn refresh_identity_server(
+    db_pool: &DbPool,
+    server_id: i64,
+    base_url: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let identity_server = {
+        let mut conn = db_pool.get()?;
+        link_identity_server(&mut conn, server_id, base_url)?
+    };
+
+    let Some(identity_server) = identity_server else {
+        return Ok(());
+    };
+
+    let stale_before =
+        chrono::Utc::now().naive_utc() - chrono::Duration::hours(RECHECK_INTERVAL_HOURS);
+    if identity_server
+        .last_checked_at
+        .is_some_and(|checked| checked > stale_before)
+    {
+        return Ok(());
+    }
+
+    // Identity servers whose URL resolves to a non-public address are never
+    // contacted and count as unreachable.
+    let client = public_client(&identity_server.base_url).await.ok();
+    let reachable = match &client {
+        Some(client) => check_identity_api(client, &identity_server.base_url).await,
+        None => false,
+    };
+    let policies = match &client {
+        Some(client) if reachable => fetch_identity_terms(client, &identity_server.base_url).await,
+        _ => Vec::new(),
+    };
+
+    let mut conn = db_pool.get()?;
+    update_identity_server_check(
+        &mut conn,
+        identity_server.id,
+        reachable,
+        &serde_json::to_value(&policies)?,
+    )?;
+
+    Ok(())
+}
+
+/// An identity server is reachable when its v2 status endpoint answers 200.
+async fn check_identity_api(client: &reqwest::Client, base_url: &str) -> bool {
+    client
+        .get(format!("{}{}", base_url, IDENTITY_API_PATH))
+        .timeout(probe_timeout())
+        .send()
+        .await
+        .map(|response| response.status().is_success())
+        .unwrap_or(false)
+}
+
+async fn fetch_identity_terms(client: &reqwest::Client, base_url: &str) -> Vec<TermsPolicy> {
+    let response = client
+        .get(format!("{}{}", base_url, IDENTITY_TERMS_PATH))
+        .timeout(probe_timeout())
+        .send()
+        .await;
+
+    match response {
+        Ok(response) if response.status().is_success() => response
+            .json::<Value>()
+            .await
+            .ok()
+            .as_ref()
+            .map(parse_identity_terms)
+            .unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+/// Parses `GET /_matrix/identity/v2/terms`, which uses the same policy
+/// layout as the `m.login.terms` registration stage.
+pub fn parse_identity_terms(json: &Value) -> Vec<TermsPolicy> {
+    json.get("policies")
+        .and_then(|p| p.as_object())
+        .cloned()
+        .map(terms_policies_from_params)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_identity_terms() {
+        let json = serde_json::json!({
+            "policies": {
+                "terms_of_service": {
+                    "version": "2.0",
+                    "en": {"name": "Terms of Service", "url": "https://vector.im/terms"}
+                }
+            }
+        });
+
+        let policies = parse_identity_terms(&json);
+        assert_eq!(policies.len(), 1);
+        assert_eq!(policies[0].id, "terms_of_service");
+        assert_eq!(policies[0].version, Some("2.0".to_string()));
+        assert_eq!(
+            policies[0].translations["en"].url,
+            "https://vector.im/terms".to_string()
+        );
+    }
+
+    #[test]
+    fn test_parse_identity_terms_without_policies() {
+        assert!(parse_identity_terms(&serde_json::json!({})).is_empty());
+    }
+}
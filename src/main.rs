@@ -7,25 +7,52 @@ use std::sync::{Arc, RwLock};
 #[macro_use]
 extern crate rocket;
 
+mod admin;
 mod app;
+mod bot;
+mod bot_commands;
+mod bridges;
 mod cache;
 mod db;
+mod error;
+mod event_stream;
+mod events;
+mod federation_client;
 mod federation_discovery;
+mod federation_report;
+mod feeds;
 mod http_client;
+mod identity_servers;
 mod metrics;
 mod models;
 mod rate_limit;
+mod refresh;
+mod retention;
 mod routes;
 mod schema;
+mod server_keys;
 mod services;
+mod signing;
+mod webhooks;
+mod well_known_lint;
 
+use admin::admin_token_from_config;
+use bot::{run_bot, BotConfig};
 use cache::Cache;
 use db::{create_pool, establish_connection, run_migrations};
+use event_stream::EventHub;
+use federation_client::FederationClient;
 use metrics::Metrics;
-use rate_limit::rate_limiter_from_config;
+use rate_limit::{
+    live_check_limiter_from_config, rate_limiter_from_config, refresh_limiter_from_config,
+};
+use refresh::{run_refresh_worker, RefreshConfig};
+use retention::{run_prune_worker, ProbeRetention};
 use rocket_okapi::openapi_get_routes;
 use rocket_okapi::swagger_ui::{make_swagger_ui, SwaggerUIConfig};
+use signing::signing_key_from_config;
 use tracing::{info, warn};
+use webhooks::{allow_private_targets_from_config, run_webhook_worker, WebhookConfig};
 
 use app::AppState;
 
@@ -56,11 +83,36 @@ fn rocket() -> Rocket<Build> {
         std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://localhost:6379".to_string());
     let metrics = Metrics::new();
     let rate_limiter = rate_limiter_from_config();
+    let federation = signing_key_from_config().map(|key| {
+        info!(
+            "Federation requests signed as {} with key {}",
+            key.server_name, key.key_id
+        );
+        Arc::new(FederationClient::new(Arc::new(key)))
+    });
+
+    let webhook_pool = db_pool.clone();
+    let refresh_pool = db_pool.clone();
+    let prune_pool = db_pool.clone();
+    let bot_pool = db_pool.clone();
+    let bot_cache = cache.clone();
+    let refresh_limiter = refresh_limiter_from_config();
+    let bot_probe_limiter = refresh_limiter.clone();
+    let event_hub = Arc::new(EventHub::from_env());
+    let stream_cache = cache.clone();
+    let stream_hub = event_hub.clone();
+    let stream_redis_url = redis_url.clone();
 
     rocket::build()
         .manage(AppState {
             cache: cache.clone(),
             db_pool,
+            federation,
+            refresh_limiter,
+            live_check_limiter: live_check_limiter_from_config(),
+            event_hub,
+            admin_token: admin_token_from_config(),
+            allow_private_webhooks: allow_private_targets_from_config(),
         })
         .manage(metrics)
         .manage(rate_limiter)
@@ -73,19 +125,83 @@ fn rocket() -> Rocket<Build> {
                 }
             })
         }))
+        .attach(AdHoc::on_liftoff("Event Stream", move |_rocket| {
+            Box::pin(async move {
+                event_stream::start(stream_cache, stream_hub, stream_redis_url);
+            })
+        }))
+        .attach(AdHoc::on_liftoff("Webhook Delivery", move |_rocket| {
+            Box::pin(async move {
+                tokio::spawn(run_webhook_worker(webhook_pool, WebhookConfig::from_env()));
+            })
+        }))
+        .attach(AdHoc::on_liftoff("Server Refresh", move |_rocket| {
+            Box::pin(async move {
+                tokio::spawn(run_refresh_worker(refresh_pool, RefreshConfig::from_env()));
+            })
+        }))
+        .attach(AdHoc::on_liftoff("Probe Payload Pruning", move |_rocket| {
+            Box::pin(async move {
+                tokio::spawn(run_prune_worker(prune_pool, ProbeRetention::from_env()));
+            })
+        }))
+        .attach(AdHoc::on_liftoff("Matrix Bot", move |_rocket| {
+            Box::pin(async move {
+                match BotConfig::from_env() {
+                    Some(config) => {
+                        tokio::spawn(run_bot(bot_pool, bot_cache, bot_probe_limiter, config));
+                    }
+                    None => info!("Matrix bot disabled; MXINDEX_BOT_HOMESERVER, MXINDEX_BOT_USER and MXINDEX_BOT_PASSWORD are not all set"),
+                }
+            })
+        }))
         .mount(
             "/",
             openapi_get_routes![
                 routes::index,
-                routes::server_info,
+                routes::server_detail,
+                routes::probe_server,
+                routes::refresh_server,
                 routes::add_server,
+                routes::remove_server,
                 routes::list_servers,
                 routes::search_servers,
                 routes::health,
                 routes::discover_federation,
+                routes::server_keys,
+                routes::server_signing_keys,
+                routes::refresh_server_signing_keys,
+                routes::federation_report,
+                routes::well_known_report,
+                routes::lint_well_known_documents,
+                routes::server_bridges,
+                routes::server_history,
+                routes::list_events,
+                routes::events_atom,
+                routes::events_rss,
+                routes::stream_events,
+                routes::create_webhook,
+                routes::list_webhooks,
+                routes::webhook,
+                routes::remove_webhook,
+                routes::enable_webhook,
+                routes::webhook_deliveries,
+                routes::reprocess_servers,
+                routes::list_identity_servers,
+                routes::identity_server_stats,
                 metrics_endpoint
             ],
         )
+        .register(
+            "/",
+            catchers![
+                error::bad_request,
+                error::unauthorized,
+                error::not_found,
+                error::unprocessable_entity,
+                error::default_catcher
+            ],
+        )
         .mount(
             "/swagger",
             make_swagger_ui(&SwaggerUIConfig {
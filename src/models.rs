@@ -1,6 +1,7 @@
 use chrono::NaiveDateTime;
 use rocket::serde::{Deserialize, Serialize};
 use rocket_okapi::JsonSchema;
+use std::collections::BTreeMap;
 
 #[derive(Serialize, Deserialize, JsonSchema)]
 pub struct ApiInfo {
@@ -17,6 +18,124 @@ pub struct ServerInfo {
     pub error: Option<String>,
 }
 
+impl ServerInfo {
+    /// Summarises a `/_matrix/client/versions` check of `server`.
+    pub fn from_check(server: &str, check: &ProbePayload) -> Self {
+        let version = check
+            .success_body()
+            .and_then(|body| body.get("versions"))
+            .and_then(|versions| versions.as_array())
+            .map(|versions| {
+                versions
+                    .iter()
+                    .filter_map(|v| v.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            });
+
+        ServerInfo {
+            server: server.to_string(),
+            status: check_status(check).to_string(),
+            version,
+            error: check_error(check),
+        }
+    }
+}
+
+fn check_status(check: &ProbePayload) -> &'static str {
+    if check.success_body().is_some() {
+        "online"
+    } else {
+        "offline"
+    }
+}
+
+/// Classifies why a check failed: `dns_error`, `connection_error` or
+/// `server_error` for anything the server itself answered badly.
+fn check_error(check: &ProbePayload) -> Option<String> {
+    if check.success_body().is_some() {
+        return None;
+    }
+
+    let error = check.error.as_deref().unwrap_or_default();
+    let error_type = if error.contains("dns") {
+        "dns_error"
+    } else if error.contains("connect") {
+        "connection_error"
+    } else {
+        "server_error"
+    };
+    Some(error_type.to_string())
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct ServerCheckResponse {
+    pub status: String,
+    pub http_status: Option<u16>,
+    pub error: Option<String>,
+    pub checked_at: NaiveDateTime,
+}
+
+impl From<&ProbePayload> for ServerCheckResponse {
+    fn from(check: &ProbePayload) -> Self {
+        ServerCheckResponse {
+            status: check_status(check).to_string(),
+            http_status: check.status,
+            error: check_error(check),
+            checked_at: check.fetched_at,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq)]
+pub struct UptimeWindow {
+    pub checks: i64,
+    pub successful: i64,
+    pub uptime_percent: Option<f64>,
+}
+
+/// Share of successful `/versions` checks over the last day, week and month.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq)]
+pub struct UptimeSummary {
+    pub last_24h: UptimeWindow,
+    pub last_7d: UptimeWindow,
+    pub last_30d: UptimeWindow,
+}
+
+impl UptimeSummary {
+    /// Builds the summary from `(checked_at, successful)` pairs.
+    pub fn from_checks(checks: &[(NaiveDateTime, bool)], now: NaiveDateTime) -> Self {
+        let window = |days: i64| {
+            let since = now - chrono::Duration::days(days);
+            let in_window = checks.iter().filter(|(at, _)| *at >= since);
+            let total = in_window.clone().count() as i64;
+            let successful = in_window.filter(|(_, ok)| *ok).count() as i64;
+
+            UptimeWindow {
+                checks: total,
+                successful,
+                uptime_percent: (total > 0).then(|| successful as f64 * 100.0 / total as f64),
+            }
+        };
+
+        UptimeSummary {
+            last_24h: window(1),
+            last_7d: window(7),
+            last_30d: window(30),
+        }
+    }
+}
+
+/// The stored record of an indexed server with its check history.
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct ServerDetailResponse {
+    #[serde(flatten)]
+    pub server: ServerResponse,
+    pub last_check: Option<ServerCheckResponse>,
+    pub uptime: UptimeSummary,
+    pub last_refreshed_at: NaiveDateTime,
+}
+
 #[derive(Serialize, Deserialize, JsonSchema)]
 pub struct ErrorResponse {
     pub error: String,
@@ -44,6 +163,198 @@ pub struct ServerResponse {
     pub room_versions: Option<String>,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    pub missing_fields: Vec<MissingField>,
+    pub client_api_version: Option<String>,
+    pub spec_versions: Vec<String>,
+    pub latest_spec_version: Option<String>,
+    pub unstable_features: serde_json::Value,
+    pub login_flows: Vec<String>,
+    pub sso_providers: Vec<SsoProvider>,
+    pub oidc_issuer: Option<String>,
+    pub support_contacts: Vec<SupportContact>,
+    pub support_page: Option<String>,
+    pub terms_policies: Vec<TermsPolicy>,
+    pub identity_server: Option<String>,
+    pub sliding_sync: Option<String>,
+    pub sliding_sync_proxy: Option<String>,
+    pub sliding_sync_proxy_reachable: Option<bool>,
+    pub element_x_compatible: bool,
+    pub client_capabilities: BTreeMap<String, bool>,
+    /// `m.upload.size` of the media config. Both config endpoints require an
+    /// access token on most servers and the index probes anonymously, so
+    /// this is usually unknown.
+    pub media_upload_size: Option<i64>,
+    /// Inferred from which media config endpoints the server recognises.
+    /// Unknown when the legacy endpoint only asks for a token, which says
+    /// nothing either way.
+    pub authenticated_media_enforced: Option<bool>,
+    /// Server software name from `/_matrix/federation/v1/version`; its
+    /// version is in `federation_version`.
+    pub implementation: Option<String>,
+}
+
+/// Fields every write touches; they say nothing about the server itself.
+const UNTRACKED_FIELDS: [&str; 3] = ["id", "created_at", "updated_at"];
+
+/// A public field whose value differs between two snapshots of a server.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq)]
+pub struct FieldChange {
+    pub field: String,
+    pub old: serde_json::Value,
+    pub new: serde_json::Value,
+}
+
+impl ServerResponse {
+    /// Lists the fields that differ from `before`, in field name order. With
+    /// no earlier snapshot every field with a value counts as changed from
+    /// `null`. `missing_fields` is compared by field name only: its failure
+    /// reasons, such as timeouts, change on almost every probe.
+    pub fn changes_since(&self, before: Option<&ServerResponse>) -> Vec<FieldChange> {
+        fn fields(server: &ServerResponse) -> serde_json::Map<String, serde_json::Value> {
+            let mut map = match serde_json::to_value(server) {
+                Ok(serde_json::Value::Object(map)) => map,
+                _ => serde_json::Map::new(),
+            };
+
+            let missing: std::collections::BTreeSet<&str> = server
+                .missing_fields
+                .iter()
+                .map(|m| m.field.as_str())
+                .collect();
+            map.insert("missing_fields".to_string(), serde_json::json!(missing));
+
+            map
+        }
+
+        let before = before.map(fields).unwrap_or_default();
+
+        fields(self)
+            .into_iter()
+            .filter(|(field, _)| !UNTRACKED_FIELDS.contains(&field.as_str()))
+            .filter_map(|(field, new)| {
+                let old = before
+                    .get(&field)
+                    .cloned()
+                    .unwrap_or(serde_json::Value::Null);
+                (old != new).then_some(FieldChange { field, old, new })
+            })
+            .collect()
+    }
+}
+
+/// Result of a manual refresh: what changed and the server as now stored.
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct RefreshResponse {
+    /// Whether the refresh added a server that was not indexed before.
+    pub created: bool,
+    pub changes: Vec<FieldChange>,
+    pub server: ServerResponse,
+}
+
+/// A change to one field of a server, as seen by a refresh.
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct ServerRevisionResponse {
+    pub field: String,
+    pub old: serde_json::Value,
+    pub new: serde_json::Value,
+    pub observed_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct ServerHistoryResponse {
+    pub domain: String,
+    /// Newest first.
+    pub revisions: Vec<ServerRevisionResponse>,
+    pub limit: i64,
+    pub offset: i64,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+pub struct ServerEventResponse {
+    pub id: i64,
+    /// One of `server_added`, `server_removed`, `server_offline`,
+    /// `server_online`, `server_upgraded`, `registration_opened` or
+    /// `registration_closed`.
+    pub kind: String,
+    pub domain: String,
+    /// A one-line description of the event.
+    pub title: String,
+    /// Kind-specific data, e.g. the `old` and `new` version of an upgrade.
+    pub details: serde_json::Value,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct EventsResponse {
+    /// Newest first.
+    pub events: Vec<ServerEventResponse>,
+    pub total: i64,
+    pub limit: i64,
+    pub offset: i64,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct CreateWebhookRequest {
+    /// `http` or `https` URL that receives a POST per event.
+    pub url: String,
+    /// Key for the `X-Mxindex-Signature` HMAC-SHA256 of each body.
+    pub secret: String,
+    /// Event kinds to deliver; empty delivers every kind.
+    #[serde(default)]
+    pub event_kinds: Vec<String>,
+    /// Only deliver events about this server.
+    pub domain: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct WebhookResponse {
+    pub id: i64,
+    pub url: String,
+    pub event_kinds: Vec<String>,
+    pub domain: Option<String>,
+    /// Cleared after repeated failed deliveries.
+    pub enabled: bool,
+    pub consecutive_failures: i32,
+    /// Events up to this id have been delivered.
+    pub last_event_id: i64,
+    pub disabled_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+    /// Bearer token that manages this subscription. Only returned when the
+    /// subscription is created.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct WebhooksResponse {
+    pub webhooks: Vec<WebhookResponse>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct WebhookDeliveryResponse {
+    pub event_id: i64,
+    pub success: bool,
+    pub attempts: i32,
+    /// HTTP status of the last attempt.
+    pub status: Option<i32>,
+    pub error: Option<String>,
+    pub delivered_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct WebhookDeliveriesResponse {
+    pub webhook_id: i64,
+    /// Newest first.
+    pub deliveries: Vec<WebhookDeliveryResponse>,
+    pub limit: i64,
+    pub offset: i64,
+}
+
+/// The JSON body POSTed to a webhook for each event.
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct WebhookPayload {
+    pub webhook_id: i64,
+    pub event: ServerEventResponse,
 }
 
 #[derive(Serialize, Deserialize, JsonSchema)]
@@ -54,6 +365,386 @@ pub struct PaginatedServersResponse {
     pub offset: i32,
 }
 
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct ServerKeyResponse {
+    pub key_id: String,
+    pub public_key: String,
+    pub valid_until_ts: Option<i64>,
+    pub expired_ts: Option<i64>,
+    pub is_current: bool,
+    pub signature_valid: bool,
+    pub first_seen_at: NaiveDateTime,
+    pub last_seen_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct ServerKeyAlertResponse {
+    pub kind: String,
+    pub key_id: Option<String>,
+    pub message: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct ServerKeysResponse {
+    pub domain: String,
+    pub keys: Vec<ServerKeyResponse>,
+    pub alerts: Vec<ServerKeyAlertResponse>,
+}
+
+#[derive(
+    Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct FederationCheck {
+    pub name: String,
+    pub status: CheckStatus,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct FederationReportResponse {
+    pub domain: String,
+    pub status: CheckStatus,
+    pub resolved_host: String,
+    pub resolved_port: u16,
+    pub connect_host: String,
+    pub resolution_path: Vec<String>,
+    pub checks: Vec<FederationCheck>,
+    pub generated_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct WellKnownFindingResponse {
+    pub document: String,
+    pub severity: String,
+    pub code: String,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct WellKnownReportResponse {
+    pub domain: String,
+    pub checked_at: Option<NaiveDateTime>,
+    pub has_problems: bool,
+    pub findings: Vec<WellKnownFindingResponse>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct ServerBridgeResponse {
+    pub bridge: String,
+    /// `heroes` for bridge puppets among room directory heroes, or
+    /// `protocols` for `/thirdparty/protocols`, which most servers only serve
+    /// with an access token and so rarely yields anything.
+    pub source: String,
+    pub evidence: String,
+    pub first_seen_at: NaiveDateTime,
+    pub last_seen_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct ServerBridgesResponse {
+    pub domain: String,
+    pub bridges: Vec<ServerBridgeResponse>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct ReprocessResponse {
+    pub reprocessed: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+/// What happened to a server, as recorded in the `server_events` log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    ServerAdded,
+    ServerRemoved,
+    ServerOffline,
+    ServerOnline,
+    ServerUpgraded,
+    RegistrationOpened,
+    RegistrationClosed,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 7] = [
+        EventKind::ServerAdded,
+        EventKind::ServerRemoved,
+        EventKind::ServerOffline,
+        EventKind::ServerOnline,
+        EventKind::ServerUpgraded,
+        EventKind::RegistrationOpened,
+        EventKind::RegistrationClosed,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::ServerAdded => "server_added",
+            EventKind::ServerRemoved => "server_removed",
+            EventKind::ServerOffline => "server_offline",
+            EventKind::ServerOnline => "server_online",
+            EventKind::ServerUpgraded => "server_upgraded",
+            EventKind::RegistrationOpened => "registration_opened",
+            EventKind::RegistrationClosed => "registration_closed",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.as_str() == value)
+    }
+}
+
+/// The discovery endpoints whose raw responses are kept in `probe_payloads`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeKind {
+    Capabilities,
+    PublicRooms,
+    WellKnownClient,
+    ClientVersions,
+    FederationVersion,
+    WellKnownServer,
+    LoginFlows,
+    AuthMetadata,
+    AuthIssuer,
+    WellKnownSupport,
+    Registration,
+    ThirdPartyProtocols,
+    SlidingSyncProxy,
+    MediaConfig,
+    AuthenticatedMediaConfig,
+}
+
+impl ProbeKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProbeKind::Capabilities => "capabilities",
+            ProbeKind::PublicRooms => "public_rooms",
+            ProbeKind::WellKnownClient => "well_known_client",
+            ProbeKind::ClientVersions => "client_versions",
+            ProbeKind::FederationVersion => "federation_version",
+            ProbeKind::WellKnownServer => "well_known_server",
+            ProbeKind::LoginFlows => "login_flows",
+            ProbeKind::AuthMetadata => "auth_metadata",
+            ProbeKind::AuthIssuer => "auth_issuer",
+            ProbeKind::WellKnownSupport => "well_known_support",
+            ProbeKind::Registration => "registration",
+            ProbeKind::ThirdPartyProtocols => "thirdparty_protocols",
+            ProbeKind::SlidingSyncProxy => "sliding_sync_proxy",
+            ProbeKind::MediaConfig => "media_config",
+            ProbeKind::AuthenticatedMediaConfig => "authenticated_media_config",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "capabilities" => Some(ProbeKind::Capabilities),
+            "public_rooms" => Some(ProbeKind::PublicRooms),
+            "well_known_client" => Some(ProbeKind::WellKnownClient),
+            "client_versions" => Some(ProbeKind::ClientVersions),
+            "federation_version" => Some(ProbeKind::FederationVersion),
+            "well_known_server" => Some(ProbeKind::WellKnownServer),
+            "login_flows" => Some(ProbeKind::LoginFlows),
+            "auth_metadata" => Some(ProbeKind::AuthMetadata),
+            "auth_issuer" => Some(ProbeKind::AuthIssuer),
+            "well_known_support" => Some(ProbeKind::WellKnownSupport),
+            "registration" => Some(ProbeKind::Registration),
+            "thirdparty_protocols" => Some(ProbeKind::ThirdPartyProtocols),
+            "sliding_sync_proxy" => Some(ProbeKind::SlidingSyncProxy),
+            "media_config" => Some(ProbeKind::MediaConfig),
+            "authenticated_media_config" => Some(ProbeKind::AuthenticatedMediaConfig),
+            _ => None,
+        }
+    }
+}
+
+/// How a server offers sliding sync (MSC3575), which Element X requires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlidingSync {
+    /// Built into the homeserver (simplified sliding sync, MSC4186).
+    Native,
+    /// A separate proxy advertised in `/.well-known/matrix/client`.
+    Proxy,
+}
+
+impl SlidingSync {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SlidingSync::Native => "native",
+            SlidingSync::Proxy => "proxy",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "native" => Some(SlidingSync::Native),
+            "proxy" => Some(SlidingSync::Proxy),
+            _ => None,
+        }
+    }
+
+    /// Element X can sync against native support, or against a proxy that
+    /// answered when it was last checked.
+    pub fn element_x_compatible(sliding_sync: Option<Self>, proxy_reachable: Option<bool>) -> bool {
+        match sliding_sync {
+            Some(SlidingSync::Native) => true,
+            Some(SlidingSync::Proxy) => proxy_reachable == Some(true),
+            None => false,
+        }
+    }
+}
+
+/// The client-server API path generation used for versioned endpoints.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientApiVersion {
+    V3,
+    R0,
+}
+
+impl ClientApiVersion {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ClientApiVersion::V3 => "v3",
+            ClientApiVersion::R0 => "r0",
+        }
+    }
+
+    pub fn path_prefix(&self) -> &'static str {
+        match self {
+            ClientApiVersion::V3 => "/_matrix/client/v3",
+            ClientApiVersion::R0 => "/_matrix/client/r0",
+        }
+    }
+
+    /// Picks the path generation from the `versions` list of
+    /// `/_matrix/client/versions`. `v3` paths arrived with spec v1.1, so any
+    /// `v1.x` release selects them; servers advertising only `r0.x` get `r0`.
+    pub fn negotiate(versions: &[String]) -> Option<Self> {
+        if versions.iter().any(|v| v.starts_with("v1.")) {
+            Some(ClientApiVersion::V3)
+        } else if versions.iter().any(|v| v.starts_with("r0.")) {
+            Some(ClientApiVersion::R0)
+        } else {
+            None
+        }
+    }
+
+    /// The generation a probe URL was built with, if it is a versioned path.
+    pub fn from_url(url: &str) -> Option<Self> {
+        [ClientApiVersion::V3, ClientApiVersion::R0]
+            .into_iter()
+            .find(|v| url.contains(&format!("{}/", v.path_prefix())))
+    }
+}
+
+/// One raw probe response. `body` holds the parsed JSON, or the raw text as a
+/// JSON string when the response was not JSON; `error` is set when the
+/// request itself failed or missed its deadline.
+#[derive(Debug, Clone)]
+pub struct ProbePayload {
+    pub probe: ProbeKind,
+    pub url: String,
+    pub status: Option<u16>,
+    pub headers: serde_json::Value,
+    pub body: Option<serde_json::Value>,
+    pub error: Option<String>,
+    pub fetched_at: NaiveDateTime,
+    pub duration_ms: Option<u32>,
+}
+
+impl ProbePayload {
+    /// The body of a 2xx response, if any.
+    pub fn success_body(&self) -> Option<&serde_json::Value> {
+        match self.status {
+            Some(status) if (200..300).contains(&status) => self.body.as_ref(),
+            _ => None,
+        }
+    }
+}
+
+/// An SSO identity provider offered by the `m.login.sso` login flow.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct SsoProvider {
+    pub id: String,
+    pub name: Option<String>,
+    pub brand: Option<String>,
+    pub icon: Option<String>,
+}
+
+/// A contact from `/.well-known/matrix/support` (MSC1929).
+/// `matrix_id_on_domain` is set when a Matrix ID is given and tells whether
+/// it is a well-formed user ID on the server's own domain. It is a syntax
+/// check only: the user is not looked up, and contacts on other servers are
+/// legitimate.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct SupportContact {
+    pub matrix_id: Option<String>,
+    pub email_address: Option<String>,
+    pub role: Option<String>,
+    #[serde(alias = "matrix_id_valid")]
+    pub matrix_id_on_domain: Option<bool>,
+}
+
+/// A policy users must accept to register (`m.login.terms`), with its name
+/// and URL per language code.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct TermsPolicy {
+    pub id: String,
+    pub version: Option<String>,
+    pub translations: BTreeMap<String, PolicyTranslation>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct PolicyTranslation {
+    pub name: String,
+    pub url: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct IdentityServerResponse {
+    pub base_url: String,
+    pub reachable: Option<bool>,
+    pub terms_policies: Vec<TermsPolicy>,
+    pub homeserver_count: i64,
+    pub last_checked_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct IdentityServersResponse {
+    pub identity_servers: Vec<IdentityServerResponse>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct IdentityServerUsage {
+    pub base_url: String,
+    pub homeserver_count: i64,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct IdentityServerStatsResponse {
+    pub identity_servers: i64,
+    pub reachable_identity_servers: i64,
+    pub homeservers_with_identity_server: i64,
+    pub most_used: Vec<IdentityServerUsage>,
+}
+
+/// A server field that could not be discovered, with the probe it comes from
+/// and the reason that probe gave nothing usable.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct MissingField {
+    pub field: String,
+    pub probe: String,
+    pub reason: String,
+}
+
 #[derive(Debug)]
 pub struct DiscoveredServerInfo {
     pub name: Option<String>,
@@ -66,6 +757,32 @@ pub struct DiscoveredServerInfo {
     pub federation_version: Option<String>,
     pub delegated_server: Option<String>,
     pub room_versions: Option<String>,
+    pub missing_fields: Vec<MissingField>,
+    pub client_api_version: Option<ClientApiVersion>,
+    pub spec_versions: Vec<String>,
+    pub latest_spec_version: Option<String>,
+    pub unstable_features: serde_json::Value,
+    pub login_flows: Vec<String>,
+    pub sso_providers: Vec<SsoProvider>,
+    pub oidc_issuer: Option<String>,
+    pub support_contacts: Vec<SupportContact>,
+    pub support_page: Option<String>,
+    pub terms_policies: Vec<TermsPolicy>,
+    pub identity_server: Option<String>,
+    pub sliding_sync: Option<SlidingSync>,
+    pub sliding_sync_proxy: Option<String>,
+    pub sliding_sync_proxy_reachable: Option<bool>,
+    pub client_capabilities: BTreeMap<String, bool>,
+    pub media_upload_size: Option<i64>,
+    pub authenticated_media_enforced: Option<bool>,
+    pub implementation: Option<String>,
+}
+
+impl DiscoveredServerInfo {
+    /// `missing_fields` in the shape stored on the `servers` row.
+    pub fn missing_fields_json(&self) -> serde_json::Value {
+        serde_json::to_value(&self.missing_fields).unwrap_or_else(|_| serde_json::json!([]))
+    }
 }
 
 #[cfg(test)]
@@ -110,6 +827,60 @@ mod tests {
         assert!(info.error.is_some());
     }
 
+    #[test]
+    fn test_server_info_from_check() {
+        let check = ProbePayload {
+            probe: ProbeKind::ClientVersions,
+            url: "https://matrix.org/_matrix/client/versions".to_string(),
+            status: Some(200),
+            headers: serde_json::json!({}),
+            body: Some(serde_json::json!({"versions": ["v1.10", "v1.11"]})),
+            error: None,
+            fetched_at: NaiveDateTime::parse_from_str("2024-01-01 00:00:00", "%Y-%m-%d %H:%M:%S")
+                .unwrap(),
+            duration_ms: Some(80),
+        };
+
+        let info = ServerInfo::from_check("matrix.org", &check);
+        assert_eq!(info.status, "online");
+        assert_eq!(info.version, Some("v1.10, v1.11".to_string()));
+        assert!(info.error.is_none());
+
+        let unreachable = ProbePayload {
+            status: None,
+            body: None,
+            error: Some("error trying to connect: dns error: no record".to_string()),
+            ..check
+        };
+        let info = ServerInfo::from_check("matrix.org", &unreachable);
+        assert_eq!(info.status, "offline");
+        assert_eq!(info.error, Some("dns_error".to_string()));
+    }
+
+    #[test]
+    fn test_uptime_summary_from_checks() {
+        let now =
+            NaiveDateTime::parse_from_str("2024-02-01 00:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let checks = [
+            (now - chrono::Duration::hours(1), true),
+            (now - chrono::Duration::hours(2), false),
+            (now - chrono::Duration::days(3), true),
+            (now - chrono::Duration::days(20), true),
+        ];
+
+        let summary = UptimeSummary::from_checks(&checks, now);
+
+        assert_eq!(summary.last_24h.checks, 2);
+        assert_eq!(summary.last_24h.uptime_percent, Some(50.0));
+        assert_eq!(summary.last_7d.successful, 2);
+        assert_eq!(summary.last_30d.checks, 4);
+        assert_eq!(summary.last_30d.uptime_percent, Some(75.0));
+        assert_eq!(
+            UptimeSummary::from_checks(&[], now).last_24h.uptime_percent,
+            None
+        );
+    }
+
     #[test]
     fn test_error_response() {
         let err = ErrorResponse {
@@ -141,16 +912,309 @@ mod tests {
             registration_open: Some(true),
             public_rooms_count: Some(500),
             version: Some("v1.11".to_string()),
-            federation_version: Some("Synapse/1.99".to_string()),
+            federation_version: Some("1.99.0".to_string()),
             delegated_server: None,
             room_versions: Some("1,2,6,9".to_string()),
             created_at: NaiveDateTime::parse_from_str("2024-01-01 00:00:00", "%Y-%m-%d %H:%M:%S")
                 .unwrap(),
             updated_at: NaiveDateTime::parse_from_str("2024-01-01 00:00:00", "%Y-%m-%d %H:%M:%S")
                 .unwrap(),
+            missing_fields: vec![MissingField {
+                field: "delegated_server".to_string(),
+                probe: "well_known_server".to_string(),
+                reason: "HTTP 404".to_string(),
+            }],
+            client_api_version: Some("v3".to_string()),
+            spec_versions: vec!["v1.10".to_string(), "v1.11".to_string()],
+            latest_spec_version: Some("v1.11".to_string()),
+            unstable_features: serde_json::json!({"org.matrix.msc3575": true}),
+            login_flows: vec!["m.login.sso".to_string()],
+            sso_providers: vec![SsoProvider {
+                id: "oidc-github".to_string(),
+                name: Some("GitHub".to_string()),
+                brand: Some("github".to_string()),
+                icon: None,
+            }],
+            oidc_issuer: Some("https://account.matrix.org/".to_string()),
+            support_contacts: vec![SupportContact {
+                matrix_id: Some("@admin:matrix.org".to_string()),
+                email_address: None,
+                role: Some("m.role.admin".to_string()),
+                matrix_id_on_domain: Some(true),
+            }],
+            support_page: Some("https://matrix.org/support".to_string()),
+            terms_policies: vec![],
+            identity_server: Some("https://vector.im".to_string()),
+            sliding_sync: Some("native".to_string()),
+            sliding_sync_proxy: None,
+            sliding_sync_proxy_reachable: None,
+            element_x_compatible: true,
+            client_capabilities: BTreeMap::from([("m.set_displayname".to_string(), true)]),
+            media_upload_size: Some(50 * 1024 * 1024),
+            authenticated_media_enforced: Some(true),
+            implementation: Some("Synapse".to_string()),
         };
 
         assert_eq!(response.id, 1);
         assert_eq!(response.domain, "matrix.org");
     }
+
+    #[test]
+    fn test_support_contact_reads_stored_rows() {
+        let contact: SupportContact = serde_json::from_value(serde_json::json!({
+            "matrix_id": "@admin:matrix.org",
+            "email_address": null,
+            "role": "m.role.admin",
+            "matrix_id_valid": true,
+        }))
+        .unwrap();
+
+        assert_eq!(contact.matrix_id_on_domain, Some(true));
+    }
+
+    #[test]
+    fn test_element_x_compatible() {
+        assert!(SlidingSync::element_x_compatible(
+            Some(SlidingSync::Native),
+            None
+        ));
+        assert!(SlidingSync::element_x_compatible(
+            Some(SlidingSync::Proxy),
+            Some(true)
+        ));
+        assert!(!SlidingSync::element_x_compatible(
+            Some(SlidingSync::Proxy),
+            Some(false)
+        ));
+        assert!(!SlidingSync::element_x_compatible(None, None));
+        assert_eq!(SlidingSync::parse("proxy"), Some(SlidingSync::Proxy));
+    }
+
+    #[test]
+    fn test_server_keys_response() {
+        let response = ServerKeysResponse {
+            domain: "matrix.org".to_string(),
+            keys: vec![ServerKeyResponse {
+                key_id: "ed25519:a_RXGa".to_string(),
+                public_key: "l8Hft5qXKn1vfHrg3p4+W8gELQVo8N13JkluMfmn2sQ".to_string(),
+                valid_until_ts: Some(1_700_000_000_000),
+                expired_ts: None,
+                is_current: true,
+                signature_valid: true,
+                first_seen_at: NaiveDateTime::parse_from_str(
+                    "2024-01-01 00:00:00",
+                    "%Y-%m-%d %H:%M:%S",
+                )
+                .unwrap(),
+                last_seen_at: NaiveDateTime::parse_from_str(
+                    "2024-01-02 00:00:00",
+                    "%Y-%m-%d %H:%M:%S",
+                )
+                .unwrap(),
+            }],
+            alerts: vec![],
+        };
+
+        assert_eq!(response.keys.len(), 1);
+        assert!(response.keys[0].is_current);
+        assert!(response.alerts.is_empty());
+    }
+
+    #[test]
+    fn test_probe_kind_round_trip() {
+        let kinds = [
+            ProbeKind::Capabilities,
+            ProbeKind::PublicRooms,
+            ProbeKind::WellKnownClient,
+            ProbeKind::ClientVersions,
+            ProbeKind::FederationVersion,
+            ProbeKind::WellKnownServer,
+            ProbeKind::LoginFlows,
+            ProbeKind::AuthMetadata,
+            ProbeKind::AuthIssuer,
+            ProbeKind::WellKnownSupport,
+            ProbeKind::Registration,
+            ProbeKind::ThirdPartyProtocols,
+            ProbeKind::SlidingSyncProxy,
+            ProbeKind::MediaConfig,
+            ProbeKind::AuthenticatedMediaConfig,
+        ];
+        for kind in kinds {
+            assert_eq!(ProbeKind::parse(kind.as_str()), Some(kind));
+        }
+
+        let names: std::collections::HashSet<_> = kinds.iter().map(|k| k.as_str()).collect();
+        assert_eq!(names.len(), kinds.len());
+        assert_eq!(ProbeKind::parse("unknown"), None);
+    }
+
+    #[test]
+    fn test_client_api_version_negotiation() {
+        let versions = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(
+            ClientApiVersion::negotiate(&versions(&["r0.6.1", "v1.1", "v1.11"])),
+            Some(ClientApiVersion::V3)
+        );
+        assert_eq!(
+            ClientApiVersion::negotiate(&versions(&["r0.5.0", "r0.6.1"])),
+            Some(ClientApiVersion::R0)
+        );
+        assert_eq!(ClientApiVersion::negotiate(&[]), None);
+    }
+
+    #[test]
+    fn test_client_api_version_from_url() {
+        assert_eq!(
+            ClientApiVersion::from_url("https://matrix.org/_matrix/client/v3/capabilities"),
+            Some(ClientApiVersion::V3)
+        );
+        assert_eq!(
+            ClientApiVersion::from_url("https://old.org/_matrix/client/r0/publicRooms?limit=1"),
+            Some(ClientApiVersion::R0)
+        );
+        assert_eq!(
+            ClientApiVersion::from_url("https://matrix.org/_matrix/client/versions"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_probe_payload_success_body() {
+        let mut payload = ProbePayload {
+            probe: ProbeKind::ClientVersions,
+            url: "https://matrix.org/_matrix/client/versions".to_string(),
+            status: Some(200),
+            headers: serde_json::json!({}),
+            body: Some(serde_json::json!({"versions": ["v1.11"]})),
+            error: None,
+            fetched_at: NaiveDateTime::parse_from_str("2024-01-01 00:00:00", "%Y-%m-%d %H:%M:%S")
+                .unwrap(),
+            duration_ms: Some(42),
+        };
+        assert!(payload.success_body().is_some());
+
+        payload.status = Some(404);
+        assert!(payload.success_body().is_none());
+    }
+
+    #[test]
+    fn test_check_status_serialization() {
+        assert_eq!(
+            serde_json::to_string(&CheckStatus::Pass).unwrap(),
+            "\"pass\""
+        );
+        assert_eq!(
+            serde_json::to_string(&CheckStatus::Fail).unwrap(),
+            "\"fail\""
+        );
+        assert!(CheckStatus::Fail > CheckStatus::Warn);
+        assert!(CheckStatus::Warn > CheckStatus::Pass);
+    }
+
+    fn server_response(version: &str) -> ServerResponse {
+        let at = NaiveDateTime::parse_from_str("2024-01-01 00:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        ServerResponse {
+            id: 1,
+            domain: "matrix.org".to_string(),
+            name: None,
+            description: None,
+            logo_url: None,
+            theme: None,
+            registration_open: Some(false),
+            public_rooms_count: Some(10),
+            version: Some(version.to_string()),
+            federation_version: None,
+            delegated_server: None,
+            room_versions: None,
+            created_at: at,
+            updated_at: at,
+            missing_fields: Vec::new(),
+            client_api_version: None,
+            spec_versions: Vec::new(),
+            latest_spec_version: None,
+            unstable_features: serde_json::json!({}),
+            login_flows: Vec::new(),
+            sso_providers: Vec::new(),
+            oidc_issuer: None,
+            support_contacts: Vec::new(),
+            support_page: None,
+            terms_policies: Vec::new(),
+            identity_server: None,
+            sliding_sync: None,
+            sliding_sync_proxy: None,
+            sliding_sync_proxy_reachable: None,
+            element_x_compatible: false,
+            client_capabilities: BTreeMap::new(),
+            media_upload_size: None,
+            authenticated_media_enforced: None,
+            implementation: None,
+        }
+    }
+
+    #[test]
+    fn test_server_changes_since() {
+        let before = server_response("1.109.0");
+        let mut after = server_response("1.110.0");
+        after.registration_open = Some(true);
+        after.updated_at += chrono::Duration::hours(1);
+
+        assert_eq!(
+            after.changes_since(Some(&before)),
+            vec![
+                FieldChange {
+                    field: "registration_open".to_string(),
+                    old: serde_json::json!(false),
+                    new: serde_json::json!(true),
+                },
+                FieldChange {
+                    field: "version".to_string(),
+                    old: serde_json::json!("1.109.0"),
+                    new: serde_json::json!("1.110.0"),
+                },
+            ]
+        );
+        assert!(before.changes_since(Some(&before)).is_empty());
+    }
+
+    #[test]
+    fn test_server_changes_since_ignores_missing_field_reasons() {
+        let missing = |field: &str, reason: &str| MissingField {
+            field: field.to_string(),
+            probe: "client_versions".to_string(),
+            reason: reason.to_string(),
+        };
+
+        let mut before = server_response("1.110.0");
+        before.missing_fields = vec![missing("version", "timed out after 5000ms")];
+        let mut after = server_response("1.110.0");
+        after.missing_fields = vec![missing("version", "HTTP 502")];
+
+        assert!(after.changes_since(Some(&before)).is_empty());
+
+        after
+            .missing_fields
+            .push(missing("public_rooms_count", "HTTP 401"));
+        assert_eq!(
+            after.changes_since(Some(&before)),
+            vec![FieldChange {
+                field: "missing_fields".to_string(),
+                old: serde_json::json!(["version"]),
+                new: serde_json::json!(["public_rooms_count", "version"]),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_server_changes_since_nothing() {
+        let changes = server_response("1.110.0").changes_since(None);
+
+        assert!(changes
+            .iter()
+            .any(|c| c.field == "domain" && c.old.is_null()));
+        assert!(changes
+            .iter()
+            .all(|c| c.field != "id" && c.field != "updated_at"));
+        assert!(changes.iter().all(|c| c.field != "name"));
+    }
 }
@@ -1,60 +1,86 @@
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket_okapi::gen::OpenApiGenerator;
+use rocket_okapi::request::{OpenApiFromRequest, RequestHeaderInput};
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::time::{Duration, Instant};
 
+/// Clones share their counters.
 #[allow(dead_code)]
+#[derive(Clone)]
 pub struct RateLimiterState {
-    pub requests_per_minute: u64,
+    pub requests_per_window: u64,
+    pub window: Duration,
     pub client_requests: Arc<Mutex<HashMap<String, (u64, Instant)>>>,
+    /// When keys whose window ran out were last dropped.
+    pruned_at: Arc<Mutex<Instant>>,
 }
 
 #[allow(dead_code)]
 impl RateLimiterState {
     pub fn new(requests_per_minute: u32) -> Self {
+        Self::per_window(requests_per_minute, Duration::from_secs(60))
+    }
+
+    /// Allows `requests` per key in each fixed `window`.
+    pub fn per_window(requests: u32, window: Duration) -> Self {
         Self {
-            requests_per_minute: requests_per_minute as u64,
+            requests_per_window: requests as u64,
+            window,
             client_requests: Arc::new(Mutex::new(HashMap::new())),
+            pruned_at: Arc::new(Mutex::new(Instant::now())),
         }
     }
 
     #[allow(dead_code)]
     pub fn check(&self, client_id: &str) -> Result<(), RateLimitError> {
-        if self.requests_per_minute == 0 {
+        if self.requests_per_window == 0 {
             return Ok(());
         }
 
         let mut requests = self.client_requests.lock().unwrap();
         let now = Instant::now();
 
-        let should_reset = requests
-            .get(client_id)
-            .map(|(_, start)| start.elapsed() >= Duration::from_secs(60))
-            .unwrap_or(true);
-
-        if should_reset {
-            requests.insert(client_id.to_string(), (1, now));
-            return Ok(());
+        // Once per window, forget keys whose window is over, so keys seen
+        // once do not pile up.
+        let mut pruned_at = self.pruned_at.lock().unwrap();
+        if pruned_at.elapsed() >= self.window {
+            requests.retain(|_, (_, start)| start.elapsed() < self.window);
+            *pruned_at = now;
         }
-
-        let current_count = requests
-            .get(client_id)
-            .map(|(count, _)| *count)
-            .unwrap_or(0);
-
-        if current_count >= self.requests_per_minute {
-            return Err(RateLimitError);
+        drop(pruned_at);
+
+        let (count, start) = match requests.get(client_id) {
+            Some(&(count, start)) if start.elapsed() < self.window => (count, start),
+            _ => {
+                requests.insert(client_id.to_string(), (1, now));
+                return Ok(());
+            }
+        };
+
+        // Refused requests still count, so a caller can tell the first one
+        // of the window from the rest.
+        requests.insert(client_id.to_string(), (count + 1, start));
+
+        if count >= self.requests_per_window {
+            return Err(RateLimitError {
+                retry_after: self.window.saturating_sub(start.elapsed()),
+                first_refusal: count == self.requests_per_window,
+            });
         }
 
-        requests.insert(client_id.to_string(), (current_count + 1, now));
-
         Ok(())
     }
 }
 
-#[allow(dead_code)]
 #[derive(Debug)]
-pub struct RateLimitError;
+pub struct RateLimitError {
+    /// Time until the current window for the key resets.
+    pub retry_after: Duration,
+    /// Whether this is the first request refused in the current window.
+    pub first_refusal: bool,
+}
 
 impl std::fmt::Display for RateLimitError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -64,26 +90,6 @@ impl std::fmt::Display for RateLimitError {
 
 impl std::error::Error for RateLimitError {}
 
-impl<'r> rocket::response::Responder<'r, 'r> for RateLimitError {
-    fn respond_to(self, _req: &rocket::Request) -> rocket::response::Result<'r> {
-        rocket::Response::build()
-            .status(rocket::http::Status::TooManyRequests)
-            .header(rocket::http::Header::new("X-RateLimit-Limit", "60"))
-            .header(rocket::http::Header::new("X-RateLimit-Remaining", "0"))
-            .header(rocket::http::Header::new(
-                "Content-Type",
-                "application/json",
-            ))
-            .sized_body(
-                None,
-                std::io::Cursor::new(
-                    r#"{"error":"rate_limit_exceeded","message":"Too many requests"}"#,
-                ),
-            )
-            .ok()
-    }
-}
-
 pub fn rate_limiter_from_config() -> Option<RateLimiterState> {
     let requests_per_minute = std::env::var("RATE_LIMIT_PER_MINUTE")
         .ok()
@@ -96,3 +102,106 @@ pub fn rate_limiter_from_config() -> Option<RateLimiterState> {
         None
     }
 }
+
+/// Per-domain limiter for manual refreshes: `REFRESH_RATE_LIMIT` refreshes
+/// (default 1) every `REFRESH_RATE_LIMIT_WINDOW_SECS` (default 300).
+pub fn refresh_limiter_from_config() -> RateLimiterState {
+    let requests = std::env::var("REFRESH_RATE_LIMIT")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(1);
+    let window_secs = std::env::var("REFRESH_RATE_LIMIT_WINDOW_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(300);
+
+    RateLimiterState::per_window(requests, Duration::from_secs(window_secs))
+}
+
+/// Per-client limiter for live checks of domains that may not be indexed,
+/// such as federation reports: `LIVE_CHECK_RATE_LIMIT` checks (default 10)
+/// every `LIVE_CHECK_RATE_LIMIT_WINDOW_SECS` (default 60).
+pub fn live_check_limiter_from_config() -> RateLimiterState {
+    let requests = std::env::var("LIVE_CHECK_RATE_LIMIT")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(10);
+    let window_secs = std::env::var("LIVE_CHECK_RATE_LIMIT_WINDOW_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(60);
+
+    RateLimiterState::per_window(requests, Duration::from_secs(window_secs))
+}
+
+/// The address a request came from, as a rate limit key. Requests whose
+/// address is unknown share one key.
+pub struct ClientIp(pub String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientIp {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let ip = request
+            .client_ip()
+            .map(|ip| ip.to_string())
+            .unwrap_or_default();
+        Outcome::Success(ClientIp(ip))
+    }
+}
+
+impl<'r> OpenApiFromRequest<'r> for ClientIp {
+    fn from_request_input(
+        _gen: &mut OpenApiGenerator,
+        _name: String,
+        _required: bool,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        Ok(RequestHeaderInput::None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits_per_key() {
+        let limiter = RateLimiterState::per_window(1, Duration::from_secs(300));
+
+        assert!(limiter.check("matrix.org").is_ok());
+        let err = limiter.check("matrix.org").unwrap_err();
+        assert!(err.retry_after <= Duration::from_secs(300));
+        assert!(err.retry_after > Duration::from_secs(290));
+        assert!(err.first_refusal);
+        assert!(!limiter.check("matrix.org").unwrap_err().first_refusal);
+        assert!(limiter.check("example.org").is_ok());
+    }
+
+    #[test]
+    fn test_window_expiry_resets_count() {
+        let limiter = RateLimiterState::per_window(1, Duration::ZERO);
+
+        assert!(limiter.check("matrix.org").is_ok());
+        assert!(limiter.check("matrix.org").is_ok());
+    }
+
+    #[test]
+    fn test_expired_keys_are_dropped() {
+        let limiter = RateLimiterState::per_window(1, Duration::ZERO);
+
+        for domain in ["a.org", "b.org", "c.org"] {
+            assert!(limiter.check(domain).is_ok());
+        }
+        assert_eq!(limiter.client_requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_zero_requests_disables_limit() {
+        let limiter = RateLimiterState::per_window(0, Duration::from_secs(60));
+
+        for _ in 0..5 {
+            assert!(limiter.check("matrix.org").is_ok());
+        }
+    }
+}
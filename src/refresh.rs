@@ -0,0 +1,194 @@
+use crate::bridges::record_bridges_from_payloads;
+use crate::db::{
+    claim_servers_to_refresh, get_server_by_domain, insert_probe_payloads, insert_server,
+    record_server_revisions, update_server, DbPool, NewServer,
+};
+use crate::events::{events_from_changes, record_events, record_probe_events};
+use crate::models::{EventKind, RefreshResponse, ServerResponse};
+use crate::services::MatrixService;
+use futures::StreamExt;
+use std::time::Duration;
+use thiserror::Error;
+use tracing::{info, warn};
+
+#[derive(Error, Debug)]
+pub enum RefreshError {
+    #[error("{0}")]
+    Discovery(String),
+    #[error("Failed to get DB connection: {0}")]
+    Pool(#[from] diesel::r2d2::PoolError),
+    #[error("Failed to save server: {0}")]
+    Database(#[from] diesel::result::Error),
+}
+
+/// Re-runs discovery for `domain` and writes the result over its row, adding
+/// the server when it is not indexed yet. Probe payloads, bridges and the
+/// follow-up checks are stored as they are for a newly added server, and every
+/// field an update changes is kept as a revision. Changes worth announcing,
+/// including the server going offline or coming back, go to the event log;
+/// an indexed server that cannot be reached keeps its failed checks.
+pub async fn refresh_server(
+    db_pool: &DbPool,
+    domain: &str,
+) -> Result<RefreshResponse, RefreshError> {
+    let payloads = MatrixService::probe_server(domain).await;
+    let unreachable = MatrixService::unreachable_error(domain, &payloads);
+
+    let (created, changes, server) = {
+        let mut conn = db_pool.get()?;
+        let before = get_server_by_domain(&mut conn, domain)?;
+
+        if let Some(existing) = &before {
+            if let Err(e) = record_probe_events(&mut conn, existing.id, domain, &payloads) {
+                warn!("Failed to record probe events for {}: {}", domain, e);
+            }
+        }
+
+        if let Some(error) = unreachable {
+            if let Some(existing) = &before {
+                if let Err(e) = insert_probe_payloads(&mut conn, existing.id, &payloads) {
+                    warn!("Failed to store probe payloads for {}: {}", domain, e);
+                }
+            }
+            return Err(RefreshError::Discovery(error));
+        }
+
+        let discovered = MatrixService::derive_server_info(domain, &payloads);
+        let new_server = NewServer::from_discovered(domain, &discovered);
+
+        let server = match &before {
+            Some(existing) => update_server(&mut conn, existing.id, &new_server)?,
+            None => insert_server(&mut conn, &new_server)?,
+        };
+
+        let before = before.map(ServerResponse::from);
+        let changes = ServerResponse::from(server.clone()).changes_since(before.as_ref());
+
+        let events = if before.is_some() {
+            record_server_revisions(&mut conn, server.id, &changes)?;
+            events_from_changes(&changes)
+        } else {
+            vec![(EventKind::ServerAdded, serde_json::json!({}))]
+        };
+
+        if let Err(e) = record_events(&mut conn, Some(server.id), domain, &events) {
+            warn!("Failed to record events for {}: {}", domain, e);
+        }
+
+        if let Err(e) = insert_probe_payloads(&mut conn, server.id, &payloads) {
+            warn!("Failed to store probe payloads for {}: {}", domain, e);
+        }
+
+        if let Err(e) = record_bridges_from_payloads(&mut conn, server.id, domain, &payloads) {
+            warn!("Failed to store bridges for {}: {}", domain, e);
+        }
+
+        (before.is_none(), changes, server)
+    };
+
+    MatrixService::record_server_checks(db_pool, &server).await;
+
+    Ok(RefreshResponse {
+        created,
+        changes,
+        server: ServerResponse::from(server),
+    })
+}
+
+/// Settings of the background re-probe of indexed servers.
+pub struct RefreshConfig {
+    /// How long a server goes without a refresh; zero turns re-probing off.
+    pub interval: Duration,
+    pub poll_interval: Duration,
+    pub batch_size: i64,
+    pub concurrency: usize,
+}
+
+impl RefreshConfig {
+    /// Re-probes each server every `REFRESH_INTERVAL_SECS`, looking for due
+    /// servers every `REFRESH_POLL_SECS` (default 60) and claiming
+    /// `REFRESH_BATCH_SIZE` (default 20) at a time, of which
+    /// `REFRESH_CONCURRENCY` (default 4) are probed at once. Re-probing is
+    /// off unless the interval is set, as every refresh posts an empty
+    /// registration request to the server.
+    pub fn from_env() -> Self {
+        let interval_secs = std::env::var("REFRESH_INTERVAL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+        let poll_secs = std::env::var("REFRESH_POLL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(60);
+        let batch_size: i64 = std::env::var("REFRESH_BATCH_SIZE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(20);
+        let concurrency: usize = std::env::var("REFRESH_CONCURRENCY")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(4);
+
+        Self {
+            interval: Duration::from_secs(interval_secs),
+            poll_interval: Duration::from_secs(poll_secs.max(1)),
+            batch_size: batch_size.max(1),
+            concurrency: concurrency.max(1),
+        }
+    }
+}
+
+/// Refreshes every server that is due, a claimed batch at a time, so that
+/// the crawler logs the same events a manual refresh would. Returns how many
+/// servers were probed.
+async fn refresh_due_servers(db_pool: &DbPool, config: &RefreshConfig) -> usize {
+    let mut probed = 0;
+
+    loop {
+        let stale_before = chrono::Utc::now().naive_utc()
+            - chrono::Duration::from_std(config.interval).unwrap_or(chrono::Duration::MAX);
+        let claimed = match db_pool.get() {
+            Ok(mut conn) => claim_servers_to_refresh(&mut conn, stale_before, config.batch_size),
+            Err(e) => {
+                warn!("Failed to get DB connection for re-probing: {}", e);
+                return probed;
+            }
+        };
+        let domains = match claimed {
+            Ok(domains) if domains.is_empty() => return probed,
+            Ok(domains) => domains,
+            Err(e) => {
+                warn!("Failed to claim servers to re-probe: {}", e);
+                return probed;
+            }
+        };
+
+        probed += domains.len();
+        futures::stream::iter(domains)
+            .for_each_concurrent(config.concurrency, |domain| async move {
+                if let Err(e) = refresh_server(db_pool, &domain).await {
+                    info!("Re-probe of {} failed: {}", domain, e);
+                }
+            })
+            .await;
+    }
+}
+
+/// Re-probes indexed servers in the background. Replicas claim servers from
+/// the database, so each due server is probed by one of them.
+pub async fn run_refresh_worker(db_pool: DbPool, config: RefreshConfig) {
+    if config.interval.is_zero() {
+        info!("Re-probing of indexed servers disabled; REFRESH_INTERVAL_SECS is not set");
+        return;
+    }
+
+    let mut interval = tokio::time::interval(config.poll_interval);
+
+    loop {
+        interval.tick().await;
+        let probed = refresh_due_servers(&db_pool, &config).await;
+        if probed > 0 {
+            info!("Re-probed {} indexed servers", probed);
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use crate::db::{get_server_ids, prune_probe_payloads, DbPool};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// How many stored probe payloads are kept per server.
+#[derive(Clone, Copy)]
+pub struct ProbeRetention {
+    /// Newest payloads kept of each probe, whatever their age.
+    pub keep_latest: usize,
+    /// `/versions` checks younger than this are all kept, for the uptime
+    /// summary of the detail endpoint.
+    pub uptime_window: Duration,
+    /// How often old payloads are pruned; zero turns pruning off.
+    pub interval: Duration,
+}
+
+impl ProbeRetention {
+    /// Keeps the `PROBE_PAYLOAD_KEEP` (default 10) newest payloads of each
+    /// probe and every `/versions` check of the last
+    /// `PROBE_UPTIME_RETENTION_DAYS` (default 30), pruning every
+    /// `PROBE_PRUNE_INTERVAL_SECS` (default 3600).
+    pub fn from_env() -> Self {
+        let keep_latest: usize = std::env::var("PROBE_PAYLOAD_KEEP")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(10);
+        let uptime_days: u64 = std::env::var("PROBE_UPTIME_RETENTION_DAYS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(30);
+        let interval_secs = std::env::var("PROBE_PRUNE_INTERVAL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(3600);
+
+        Self {
+            keep_latest: keep_latest.max(1),
+            uptime_window: Duration::from_secs(uptime_days * 86400),
+            interval: Duration::from_secs(interval_secs),
+        }
+    }
+}
+
+/// Prunes the payloads of every server once. Returns how many rows went.
+pub fn prune_all_probe_payloads(
+    db_pool: &DbPool,
+    retention: &ProbeRetention,
+) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+    let uptime_since = chrono::Utc::now().naive_utc()
+        - chrono::Duration::from_std(retention.uptime_window).unwrap_or(chrono::Duration::MAX);
+
+    let mut conn = db_pool.get()?;
+    let mut pruned = 0;
+    for server_id in get_server_ids(&mut conn)? {
+        pruned += prune_probe_payloads(&mut conn, server_id, retention.keep_latest, uptime_since)?;
+    }
+
+    Ok(pruned)
+}
+
+/// Prunes old probe payloads in the background.
+pub async fn run_prune_worker(db_pool: DbPool, retention: ProbeRetention) {
+    if retention.interval.is_zero() {
+        info!("Probe payload pruning disabled; PROBE_PRUNE_INTERVAL_SECS is 0");
+        return;
+    }
+
+    let mut interval = tokio::time::interval(retention.interval);
+
+    loop {
+        interval.tick().await;
+
+        let pool = db_pool.clone();
+        let result =
+            tokio::task::spawn_blocking(move || prune_all_probe_payloads(&pool, &retention)).await;
+        match result {
+            Ok(Ok(0)) => {}
+            Ok(Ok(pruned)) => info!("Pruned {} old probe payloads", pruned),
+            Ok(Err(e)) => warn!("Failed to prune probe payloads: {}", e),
+            Err(e) => warn!("Probe payload pruning stopped: {}", e),
+        }
+    }
+}
@@ -1,25 +1,115 @@
+use crate::admin::{tokens_match, Admin, Credentials};
 use crate::app::AppState;
-use crate::db::{get_filtered_servers, get_server_by_domain, insert_server, ServerFilter};
+use crate::bridges::record_bridges_from_payloads;
+use crate::db::{
+    self, count_servers_with_identity_server, delete_server, delete_webhook, get_filtered_servers,
+    get_identity_servers_with_usage, get_key_alerts, get_latest_event_id, get_latest_probe_payload,
+    get_probe_history, get_server_bridges, get_server_by_domain, get_server_events,
+    get_server_keys, get_server_revisions, get_webhook, get_webhook_deliveries, get_webhooks,
+    get_well_known_findings, insert_probe_payloads, insert_server, insert_webhook, NewServerEvent,
+    NewWebhookSubscription, Server, ServerFilter, WebhookSubscription,
+};
+use crate::error::{
+    normalize_domain, validate_domain, AddServer, ApiError, Limited, Refresh, RouteError, Validated,
+};
+use crate::event_stream::{self, LastEventId};
+use crate::events::{record_events, record_probe_events};
 use crate::federation_discovery::FederationDiscovery;
+use crate::federation_report::build_federation_report;
+use crate::feeds::{atom_feed, public_base_url, rss_feed};
 use crate::models::{
-    ApiInfo, CreateServerRequest, ErrorResponse, PaginatedServersResponse, ServerInfo,
-    ServerResponse,
+    ApiInfo, CreateServerRequest, CreateWebhookRequest, EventKind, EventsResponse,
+    FederationReportResponse, IdentityServerResponse, IdentityServerStatsResponse,
+    IdentityServerUsage, IdentityServersResponse, PaginatedServersResponse, ProbeKind,
+    RefreshResponse, ReprocessResponse, ServerBridgeResponse, ServerBridgesResponse,
+    ServerCheckResponse, ServerDetailResponse, ServerEventResponse, ServerHistoryResponse,
+    ServerInfo, ServerKeyAlertResponse, ServerKeyResponse, ServerKeysResponse, ServerResponse,
+    ServerRevisionResponse, SlidingSync, UptimeSummary, WebhookDeliveriesResponse,
+    WebhookDeliveryResponse, WebhookResponse, WebhooksResponse, WellKnownFindingResponse,
+    WellKnownReportResponse,
 };
+use crate::rate_limit::ClientIp;
+use crate::refresh;
+use crate::server_keys::refresh_server_keys;
 use crate::services::MatrixService;
+use crate::signing::key_validity_ms;
+use crate::webhooks;
+use crate::well_known_lint::lint_and_store;
 use diesel::r2d2::{ConnectionManager, PooledConnection};
 use diesel::PgConnection;
+use futures::stream::{BoxStream, StreamExt};
+use rocket::http::ContentType;
+use rocket::response::status::NoContent;
+use rocket::response::stream::{stream, Event, EventStream};
 use rocket::serde::json::Json;
+use rocket::tokio::sync::broadcast::error::RecvError;
+use rocket::Shutdown;
 use rocket::State;
 use rocket_okapi::openapi;
+use std::collections::HashSet;
+use tracing::warn;
 
 const CACHE_TTL_SHORT: usize = 60;
 const CACHE_TTL_MEDIUM: usize = 300;
 #[allow(dead_code)]
 const CACHE_TTL_LONG: usize = 3600;
 
-#[allow(dead_code)]
 type DbConn = PooledConnection<ConnectionManager<PgConnection>>;
 
+impl From<crate::db::Server> for ServerResponse {
+    fn from(s: crate::db::Server) -> Self {
+        ServerResponse {
+            id: s.id,
+            domain: s.domain,
+            name: s.name,
+            description: s.description,
+            logo_url: s.logo_url,
+            theme: s.theme,
+            registration_open: s.registration_open,
+            public_rooms_count: s.public_rooms_count,
+            version: s.version,
+            federation_version: s.federation_version,
+            delegated_server: s.delegated_server,
+            room_versions: s.room_versions,
+            created_at: s.created_at,
+            updated_at: s.updated_at,
+            missing_fields: serde_json::from_value(s.missing_fields).unwrap_or_default(),
+            client_api_version: s.client_api_version,
+            spec_versions: s.spec_versions,
+            latest_spec_version: s.latest_spec_version,
+            unstable_features: s.unstable_features,
+            login_flows: s.login_flows,
+            sso_providers: serde_json::from_value(s.sso_providers).unwrap_or_default(),
+            oidc_issuer: s.oidc_issuer,
+            support_contacts: serde_json::from_value(s.support_contacts).unwrap_or_default(),
+            support_page: s.support_page,
+            terms_policies: serde_json::from_value(s.terms_policies).unwrap_or_default(),
+            identity_server: s.identity_server,
+            element_x_compatible: SlidingSync::element_x_compatible(
+                s.sliding_sync.as_deref().and_then(SlidingSync::parse),
+                s.sliding_sync_proxy_reachable,
+            ),
+            sliding_sync: s.sliding_sync,
+            sliding_sync_proxy: s.sliding_sync_proxy,
+            sliding_sync_proxy_reachable: s.sliding_sync_proxy_reachable,
+            client_capabilities: serde_json::from_value(s.client_capabilities).unwrap_or_default(),
+            media_upload_size: s.media_upload_size,
+            authenticated_media_enforced: s.authenticated_media_enforced,
+            implementation: s.implementation,
+        }
+    }
+}
+
+fn db_conn(state: &AppState) -> Result<DbConn, ApiError> {
+    Ok(state.db_pool.get()?)
+}
+
+fn load_server(conn: &mut PgConnection, domain: &str) -> Result<Server, ApiError> {
+    get_server_by_domain(conn, domain)
+        .map_err(ApiError::database("Failed to fetch server"))?
+        .ok_or(ApiError::ServerNotFound)
+}
+
 #[openapi]
 #[get("/")]
 pub fn index() -> Json<ApiInfo> {
@@ -44,204 +134,307 @@ pub async fn health(state: &State<AppState>) -> Json<serde_json::Value> {
 }
 
 #[openapi]
-#[get("/servers/<server>")]
-pub async fn server_info(
-    server: &str,
+#[get("/servers/<domain>")]
+pub async fn server_detail(
+    domain: &str,
     state: &State<AppState>,
-) -> Result<Json<ServerInfo>, Json<ErrorResponse>> {
-    if server.is_empty() || server.contains('/') || server.contains(':') {
-        return Err(Json(ErrorResponse {
-            error: "invalid_server".to_string(),
-            message: "Server name must be a valid domain name without path or port".to_string(),
-        }));
-    }
+) -> Result<Json<ServerDetailResponse>, ApiError> {
+    validate_domain(domain)?;
+    let domain = &normalize_domain(domain);
 
-    let cache_key = format!("server:info:{}", server);
+    let cache_key = format!("server:detail:{}", domain);
 
-    if let Ok(cached) = state.cache.get::<ServerInfo>(&cache_key).await {
+    if let Ok(cached) = state.cache.get::<ServerDetailResponse>(&cache_key).await {
         return Ok(Json(cached));
     }
 
-    let version = MatrixService::get_server_version(server).await.ok();
+    let mut conn = db_conn(state)?;
+    let server = load_server(&mut conn, domain)?;
 
-    let result = match MatrixService::check_server_status(server).await {
-        Ok(_) => ServerInfo {
-            server: server.to_string(),
-            status: "online".to_string(),
-            version,
-            error: None,
-        },
-        Err(e) => {
-            let error_type = if e.to_string().contains("dns") {
-                "dns_error"
-            } else if e.to_string().contains("connect") {
-                "connection_error"
-            } else {
-                "server_error"
-            };
+    let now = chrono::Utc::now().naive_utc();
+    let last_check = get_latest_probe_payload(&mut conn, server.id, ProbeKind::ClientVersions)
+        .map_err(ApiError::database("Failed to fetch latest check"))?
+        .and_then(|record| record.into_payload());
+    let history = get_probe_history(
+        &mut conn,
+        server.id,
+        ProbeKind::ClientVersions,
+        now - chrono::Duration::days(30),
+    )
+    .map_err(ApiError::database("Failed to fetch check history"))?;
 
-            ServerInfo {
-                server: server.to_string(),
-                status: "offline".to_string(),
-                version,
-                error: Some(error_type.to_string()),
-            }
+    let checks: Vec<(chrono::NaiveDateTime, bool)> = history
+        .into_iter()
+        .map(|(at, status)| (at, status.is_some_and(|s| (200..300).contains(&s))))
+        .collect();
+
+    let response = ServerDetailResponse {
+        last_refreshed_at: server.updated_at,
+        last_check: last_check.as_ref().map(ServerCheckResponse::from),
+        uptime: UptimeSummary::from_checks(&checks, now),
+        server: ServerResponse::from(server),
+    };
+
+    let _ = state
+        .cache
+        .set(&cache_key, &response, CACHE_TTL_SHORT)
+        .await;
+
+    Ok(Json(response))
+}
+
+/// Checks a server live. When it is indexed the result is kept in its check
+/// history, which feeds the uptime summary of the detail endpoint. Probes
+/// share the refresh rate limit window per domain, counted separately from
+/// `/refresh`, and probes of servers that are not indexed are also limited
+/// per client.
+#[openapi]
+#[post("/servers/<domain>/probe")]
+pub async fn probe_server(
+    domain: &str,
+    client: ClientIp,
+    state: &State<AppState>,
+) -> Result<Json<ServerInfo>, RouteError<Limited>> {
+    validate_domain(domain)?;
+    let domain = &normalize_domain(domain);
+
+    let indexed = {
+        let mut conn = db_conn(state)?;
+        get_server_by_domain(&mut conn, domain)
+            .map_err(ApiError::database("Failed to fetch server"))?
+    };
+    if indexed.is_none() {
+        state.live_check_limiter.check(&client.0)?;
+    }
+    state.refresh_limiter.check(&format!("probe:{}", domain))?;
+
+    let check = MatrixService::check_server(domain).await;
+
+    let mut conn = db_conn(state)?;
+    if let Some(server) = indexed {
+        if let Err(e) =
+            record_probe_events(&mut conn, server.id, domain, std::slice::from_ref(&check))
+        {
+            warn!("Failed to record availability of {}: {}", domain, e);
         }
+
+        insert_probe_payloads(&mut conn, server.id, std::slice::from_ref(&check))
+            .map_err(ApiError::database("Failed to store check"))?;
+        let _ = state
+            .cache
+            .delete(&format!("server:detail:{}", domain))
+            .await;
+    }
+
+    Ok(Json(ServerInfo::from_check(domain, &check)))
+}
+
+/// Re-runs discovery for a server now instead of on the next crawl, adding
+/// it when it is not indexed yet, and reports which fields changed. Each
+/// domain can only be refreshed a few times per window, after which it
+/// answers 429; a server that cannot be reached answers 502. Refreshes of
+/// servers that are not indexed yet are also limited per client.
+#[openapi]
+#[post("/servers/<domain>/refresh")]
+pub async fn refresh_server(
+    domain: &str,
+    client: ClientIp,
+    state: &State<AppState>,
+) -> Result<Json<RefreshResponse>, RouteError<Refresh>> {
+    validate_domain(domain)?;
+    let domain = &normalize_domain(domain);
+
+    let indexed = {
+        let mut conn = db_conn(state)?;
+        get_server_by_domain(&mut conn, domain)
+            .map_err(ApiError::database("Failed to fetch server"))?
+            .is_some()
     };
+    if !indexed {
+        state.live_check_limiter.check(&client.0)?;
+    }
+    state.refresh_limiter.check(domain)?;
 
-    let _ = state.cache.set(&cache_key, &result, CACHE_TTL_SHORT).await;
+    let response = refresh::refresh_server(&state.db_pool, domain).await?;
 
-    Ok(Json(result))
+    let _ = state.cache.invalidate_pattern("servers:*").await;
+    let _ = state
+        .cache
+        .delete(&format!("server:detail:{}", domain))
+        .await;
+
+    Ok(Json(response))
 }
 
+/// Discovers and indexes a server. Answers 409 when it is already indexed
+/// and 502 when it cannot be reached.
 #[openapi]
 #[post("/servers", data = "<request>")]
 pub async fn add_server(
     request: Json<CreateServerRequest>,
     state: &State<AppState>,
-) -> Result<Json<ServerResponse>, Json<ErrorResponse>> {
-    if request.domain.is_empty() || request.domain.contains('/') || request.domain.contains(':') {
-        return Err(Json(ErrorResponse {
-            error: "invalid_domain".to_string(),
-            message: "Domain must be a valid domain name without path or port".to_string(),
-        }));
-    }
-
-    let mut conn = state.db_pool.get().map_err(|e| {
-        Json(ErrorResponse {
-            error: "pool_error".to_string(),
-            message: format!("Failed to get DB connection: {}", e),
-        })
-    })?;
-
-    if let Ok(Some(_)) = get_server_by_domain(&mut conn, &request.domain) {
-        return Err(Json(ErrorResponse {
-            error: "server_exists".to_string(),
-            message: "Server already exists in the index".to_string(),
-        }));
-    }
-
-    match MatrixService::discover_server_info(&request.domain).await {
-        Ok(discovered) => {
-            let new_server = crate::db::NewServer {
-                domain: &request.domain,
-                name: discovered.name.as_deref(),
-                description: discovered.description.as_deref(),
-                logo_url: discovered.logo_url.as_deref(),
-                theme: discovered.theme.as_deref(),
-                registration_open: discovered.registration_open,
-                public_rooms_count: discovered.public_rooms_count,
-                version: discovered.version.as_deref(),
-                federation_version: discovered.federation_version.as_deref(),
-                delegated_server: discovered.delegated_server.as_deref(),
-                room_versions: discovered.room_versions.as_deref(),
-            };
+) -> Result<Json<ServerResponse>, RouteError<AddServer>> {
+    validate_domain(&request.domain)?;
+    let domain = &normalize_domain(&request.domain);
 
-            match insert_server(&mut conn, &new_server) {
-                Ok(server) => {
-                    let _ = state.cache.invalidate_pattern("servers:*").await;
-                    let _ = state
-                        .cache
-                        .delete(&format!("server:info:{}", request.domain))
-                        .await;
-
-                    Ok(Json(ServerResponse {
-                        id: server.id,
-                        domain: server.domain,
-                        name: server.name,
-                        description: server.description,
-                        logo_url: server.logo_url,
-                        theme: server.theme,
-                        registration_open: server.registration_open,
-                        public_rooms_count: server.public_rooms_count,
-                        version: server.version,
-                        federation_version: server.federation_version,
-                        delegated_server: server.delegated_server,
-                        room_versions: server.room_versions,
-                        created_at: server.created_at,
-                        updated_at: server.updated_at,
-                    }))
-                }
-                Err(e) => Err(Json(ErrorResponse {
-                    error: "database_error".to_string(),
-                    message: format!("Failed to save server: {}", e),
-                })),
-            }
-        }
-        Err(e) => Err(Json(ErrorResponse {
-            error: "discovery_failed".to_string(),
-            message: format!("Failed to discover server information: {}", e),
-        })),
+    let mut conn = db_conn(state)?;
+
+    if let Ok(Some(_)) = get_server_by_domain(&mut conn, domain) {
+        return Err(ApiError::ServerExists.into());
+    }
+
+    let (discovered, payloads) = MatrixService::discover_server_info(domain)
+        .await
+        .map_err(|e| ApiError::DiscoveryFailed(e.to_string()))?;
+
+    let new_server = crate::db::NewServer::from_discovered(domain, &discovered);
+    let server = insert_server(&mut conn, &new_server)
+        .map_err(ApiError::database("Failed to save server"))?;
+
+    if let Err(e) = record_events(
+        &mut conn,
+        Some(server.id),
+        &server.domain,
+        &[(EventKind::ServerAdded, serde_json::json!({}))],
+    ) {
+        warn!("Failed to record events for {}: {}", server.domain, e);
+    }
+
+    if let Err(e) = insert_probe_payloads(&mut conn, server.id, &payloads) {
+        warn!(
+            "Failed to store probe payloads for {}: {}",
+            server.domain, e
+        );
+    }
+
+    if let Err(e) = record_bridges_from_payloads(&mut conn, server.id, &server.domain, &payloads) {
+        warn!("Failed to store bridges for {}: {}", server.domain, e);
     }
+
+    MatrixService::record_server_checks(&state.db_pool, &server).await;
+
+    let _ = state.cache.invalidate_pattern("servers:*").await;
+    let _ = state
+        .cache
+        .delete(&format!("server:detail:{}", domain))
+        .await;
+
+    Ok(Json(ServerResponse::from(server)))
+}
+
+/// Removes a server from the index. Its events stay in the log, ending with
+/// `server_removed`. Admin only.
+#[openapi]
+#[delete("/servers/<domain>")]
+pub async fn remove_server(
+    domain: &str,
+    _admin: Admin,
+    state: &State<AppState>,
+) -> Result<NoContent, ApiError> {
+    validate_domain(domain)?;
+    let domain = &normalize_domain(domain);
+
+    {
+        let mut conn = db_conn(state)?;
+        let server = load_server(&mut conn, domain)?;
+        let removal = NewServerEvent {
+            server_id: Some(server.id),
+            domain,
+            kind: EventKind::ServerRemoved.as_str(),
+            details: &serde_json::json!({}),
+        };
+
+        let logged = delete_server(&mut conn, server.id, &removal)
+            .map_err(ApiError::database("Failed to remove server"))?;
+        event_stream::publish(logged.into_iter().map(ServerEventResponse::from));
+    }
+
+    let _ = state.cache.invalidate_pattern("servers:*").await;
+    let _ = state
+        .cache
+        .delete(&format!("server:detail:{}", domain))
+        .await;
+
+    Ok(NoContent)
 }
 
 #[openapi]
 #[get("/servers")]
 pub async fn list_servers(
     state: &State<AppState>,
-) -> Result<Json<PaginatedServersResponse>, Json<ErrorResponse>> {
+) -> Result<Json<PaginatedServersResponse>, ApiError> {
     let cache_key = "servers:list";
 
     if let Ok(cached) = state.cache.get::<PaginatedServersResponse>(cache_key).await {
         return Ok(Json(cached));
     }
 
-    let mut conn = state.db_pool.get().map_err(|e| {
-        Json(ErrorResponse {
-            error: "pool_error".to_string(),
-            message: format!("Failed to get DB connection: {}", e),
-        })
-    })?;
+    let mut conn = db_conn(state)?;
 
     let filter = ServerFilter::default();
 
-    match get_filtered_servers(&mut conn, &filter) {
-        Ok(result) => {
-            let responses = result
-                .servers
-                .into_iter()
-                .map(|s| ServerResponse {
-                    id: s.id,
-                    domain: s.domain,
-                    name: s.name,
-                    description: s.description,
-                    logo_url: s.logo_url,
-                    theme: s.theme,
-                    registration_open: s.registration_open,
-                    public_rooms_count: s.public_rooms_count,
-                    version: s.version,
-                    federation_version: s.federation_version,
-                    delegated_server: s.delegated_server,
-                    room_versions: s.room_versions,
-                    created_at: s.created_at,
-                    updated_at: s.updated_at,
-                })
-                .collect();
+    let result = get_filtered_servers(&mut conn, &filter)
+        .map_err(ApiError::database("Failed to fetch servers"))?;
 
-            let response = PaginatedServersResponse {
-                servers: responses,
-                total: result.total,
-                limit: result.limit,
-                offset: result.offset,
-            };
+    let response = PaginatedServersResponse {
+        servers: result
+            .servers
+            .into_iter()
+            .map(ServerResponse::from)
+            .collect(),
+        total: result.total,
+        limit: result.limit,
+        offset: result.offset,
+    };
 
-            let _ = state
-                .cache
-                .set(cache_key, &response, CACHE_TTL_MEDIUM)
-                .await;
+    let _ = state
+        .cache
+        .set(cache_key, &response, CACHE_TTL_MEDIUM)
+        .await;
 
-            Ok(Json(response))
+    Ok(Json(response))
+}
+
+/// Rejects filter values that are well-formed but cannot match anything.
+fn validate_server_filter(filter: &ServerFilter) -> Result<(), ApiError> {
+    if let Some(ref mode) = filter.sliding_sync {
+        if SlidingSync::parse(&mode.to_lowercase()).is_none() {
+            return Err(ApiError::invalid_parameter(
+                "sliding_sync",
+                "expected `native` or `proxy`",
+            ));
         }
-        Err(e) => Err(Json(ErrorResponse {
-            error: "database_error".to_string(),
-            message: format!("Failed to fetch servers: {}", e),
-        })),
     }
+
+    if let (Some(min), Some(max)) = (filter.min_upload_size, filter.max_upload_size) {
+        if min > max {
+            return Err(ApiError::invalid_parameter(
+                "min_upload_size",
+                "must not exceed `max_upload_size`",
+            ));
+        }
+    }
+
+    Ok(())
 }
 
+/// Hashes the whole filter, so free-text values cannot run into each other
+/// the way joined strings would.
+fn search_cache_key(filter: &ServerFilter) -> String {
+    use sha2::{Digest, Sha256};
+
+    let serialized = serde_json::to_vec(filter).unwrap_or_default();
+    let digest: String = Sha256::digest(&serialized)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("servers:search:{}", digest)
+}
+
+/// Filters and pages the index. Invalid filter values answer 422.
 #[openapi]
 #[get(
-    "/servers/search?<search>&<registration_open>&<has_rooms>&<room_version>&<sort_by>&<sort_order>&<limit>&<offset>"
+    "/servers/search?<search>&<registration_open>&<has_rooms>&<room_version>&<well_known_problems>&<spec_version>&<feature>&<login>&<oidc>&<has_terms>&<bridge>&<sliding_sync>&<element_x>&<capability>&<min_upload_size>&<max_upload_size>&<authenticated_media>&<sort_by>&<sort_order>&<limit>&<offset>"
 )]
 #[allow(dead_code, clippy::too_many_arguments)]
 pub async fn search_servers(
@@ -250,91 +443,81 @@ pub async fn search_servers(
     registration_open: Option<bool>,
     has_rooms: Option<bool>,
     room_version: Option<String>,
+    well_known_problems: Option<bool>,
+    spec_version: Option<String>,
+    feature: Option<String>,
+    login: Option<String>,
+    oidc: Option<bool>,
+    has_terms: Option<bool>,
+    bridge: Option<String>,
+    sliding_sync: Option<String>,
+    element_x: Option<bool>,
+    capability: Option<String>,
+    min_upload_size: Option<i64>,
+    max_upload_size: Option<i64>,
+    authenticated_media: Option<bool>,
     sort_by: Option<String>,
     sort_order: Option<String>,
     limit: Option<i32>,
     offset: Option<i32>,
-) -> Result<Json<PaginatedServersResponse>, Json<ErrorResponse>> {
-    let cache_key = format!(
-        "servers:search:{}:{}:{}:{}:{}:{}:{}:{}",
-        search.as_deref().unwrap_or(""),
-        registration_open.map(|b| b.to_string()).unwrap_or_default(),
-        has_rooms.map(|b| b.to_string()).unwrap_or_default(),
-        room_version.as_deref().unwrap_or(""),
-        sort_by.as_deref().unwrap_or(""),
-        sort_order.as_deref().unwrap_or(""),
-        limit.unwrap_or(0),
-        offset.unwrap_or(0)
-    );
-
-    if let Ok(cached) = state
-        .cache
-        .get::<PaginatedServersResponse>(&cache_key)
-        .await
-    {
-        return Ok(Json(cached));
-    }
-
-    let mut conn = state.db_pool.get().map_err(|e| {
-        Json(ErrorResponse {
-            error: "pool_error".to_string(),
-            message: format!("Failed to get DB connection: {}", e),
-        })
-    })?;
-
+) -> Result<Json<PaginatedServersResponse>, RouteError<Validated>> {
     let filter = ServerFilter {
         search,
         registration_open,
         has_rooms,
         room_version,
+        well_known_problems,
+        spec_version,
+        feature,
+        login,
+        oidc,
+        has_terms,
+        bridge,
+        sliding_sync,
+        element_x,
+        capability,
+        min_upload_size,
+        max_upload_size,
+        authenticated_media,
         sort_by,
         sort_order,
         limit,
         offset,
     };
 
-    match get_filtered_servers(&mut conn, &filter) {
-        Ok(result) => {
-            let responses = result
-                .servers
-                .into_iter()
-                .map(|s| ServerResponse {
-                    id: s.id,
-                    domain: s.domain,
-                    name: s.name,
-                    description: s.description,
-                    logo_url: s.logo_url,
-                    theme: s.theme,
-                    registration_open: s.registration_open,
-                    public_rooms_count: s.public_rooms_count,
-                    version: s.version,
-                    federation_version: s.federation_version,
-                    delegated_server: s.delegated_server,
-                    room_versions: s.room_versions,
-                    created_at: s.created_at,
-                    updated_at: s.updated_at,
-                })
-                .collect();
+    validate_server_filter(&filter)?;
+    let cache_key = search_cache_key(&filter);
 
-            let response = PaginatedServersResponse {
-                servers: responses,
-                total: result.total,
-                limit: result.limit,
-                offset: result.offset,
-            };
+    if let Ok(cached) = state
+        .cache
+        .get::<PaginatedServersResponse>(&cache_key)
+        .await
+    {
+        return Ok(Json(cached));
+    }
 
-            let _ = state
-                .cache
-                .set(&cache_key, &response, CACHE_TTL_SHORT)
-                .await;
+    let mut conn = db_conn(state)?;
 
-            Ok(Json(response))
-        }
-        Err(e) => Err(Json(ErrorResponse {
-            error: "database_error".to_string(),
-            message: format!("Failed to fetch servers: {}", e),
-        })),
-    }
+    let result = get_filtered_servers(&mut conn, &filter)
+        .map_err(ApiError::database("Failed to fetch servers"))?;
+
+    let response = PaginatedServersResponse {
+        servers: result
+            .servers
+            .into_iter()
+            .map(ServerResponse::from)
+            .collect(),
+        total: result.total,
+        limit: result.limit,
+        offset: result.offset,
+    };
+
+    let _ = state
+        .cache
+        .set(&cache_key, &response, CACHE_TTL_SHORT)
+        .await;
+
+    Ok(Json(response))
 }
 
 #[cfg(test)]
@@ -391,22 +574,82 @@ mod tests {
     }
 
     #[test]
-    fn test_server_info_cache_key_format() {
+    fn test_server_detail_cache_key_format() {
         let server = "matrix.org";
-        let cache_key = format!("server:info:{}", server);
-        assert_eq!(cache_key, "server:info:matrix.org");
+        let cache_key = format!("server:detail:{}", server);
+        assert_eq!(cache_key, "server:detail:matrix.org");
     }
 
     #[test]
-    fn test_search_cache_key_format() {
-        let cache_key = format!(
-            "servers:search:{}:{}:{}:{}:{}:{}:{}:{}",
-            "matrix", "true", "false", "6", "name", "asc", 10, 0
-        );
+    fn test_search_cache_key() {
+        let key = search_cache_key(&ServerFilter {
+            search: Some("matrix".to_string()),
+            ..Default::default()
+        });
+        assert!(key.starts_with("servers:search:"));
+        assert_eq!(key.len(), "servers:search:".len() + 64);
         assert_eq!(
-            cache_key,
-            "servers:search:matrix:true:false:6:name:asc:10:0"
+            key,
+            search_cache_key(&ServerFilter {
+                search: Some("matrix".to_string()),
+                ..Default::default()
+            })
         );
+
+        let colon_in_text = search_cache_key(&ServerFilter {
+            search: Some("a:true".to_string()),
+            ..Default::default()
+        });
+        let separate_filter = search_cache_key(&ServerFilter {
+            search: Some("a".to_string()),
+            registration_open: Some(true),
+            ..Default::default()
+        });
+        assert_ne!(colon_in_text, separate_filter);
+    }
+
+    #[test]
+    fn test_openapi_lists_route_statuses() {
+        let spec = rocket_okapi::openapi_get_spec![
+            server_detail,
+            refresh_server,
+            add_server,
+            remove_server,
+            search_servers,
+            probe_server
+        ];
+        let statuses = |path: &str, method: &str| -> Vec<String> {
+            let item = &spec.paths[path];
+            let operation = match method {
+                "get" => item.get.as_ref(),
+                "post" => item.post.as_ref(),
+                _ => item.delete.as_ref(),
+            };
+            operation
+                .unwrap()
+                .responses
+                .responses
+                .keys()
+                .cloned()
+                .collect()
+        };
+
+        let detail = statuses("/servers/{domain}", "get");
+        assert!(detail.contains(&"404".to_string()));
+        assert!(!detail.contains(&"429".to_string()));
+
+        let refresh = statuses("/servers/{domain}/refresh", "post");
+        assert!(refresh.contains(&"429".to_string()));
+        assert!(refresh.contains(&"502".to_string()));
+
+        let add = statuses("/servers", "post");
+        for status in ["409", "422", "502"] {
+            assert!(add.contains(&status.to_string()), "{}", status);
+        }
+
+        assert!(statuses("/servers/{domain}", "delete").contains(&"401".to_string()));
+        assert!(statuses("/servers/search", "get").contains(&"422".to_string()));
+        assert!(statuses("/servers/{domain}/probe", "post").contains(&"429".to_string()));
     }
 
     #[test]
@@ -414,6 +657,48 @@ mod tests {
         let cache_key = "servers:list";
         assert_eq!(cache_key, "servers:list");
     }
+
+    #[test]
+    fn test_parse_event_kinds() {
+        assert!(parse_event_kinds(None).unwrap().is_empty());
+        assert_eq!(
+            parse_event_kinds(Some("server_added, server_upgraded,")).unwrap(),
+            vec!["server_added", "server_upgraded"]
+        );
+        assert!(matches!(
+            parse_event_kinds(Some("server_added,server_exploded")),
+            Err(ApiError::InvalidParameter { name: "kind", .. })
+        ));
+    }
+
+    #[test]
+    fn test_validate_webhook_request() {
+        let request = |url: &str, secret: &str, kinds: &[&str]| CreateWebhookRequest {
+            url: url.to_string(),
+            secret: secret.to_string(),
+            event_kinds: kinds.iter().map(|k| k.to_string()).collect(),
+            domain: None,
+        };
+
+        assert!(
+            validate_webhook_request(&request("https://hooks.example/mx", "s3cret", &[])).is_ok()
+        );
+        assert!(matches!(
+            validate_webhook_request(&request("ftp://hooks.example", "s3cret", &[])),
+            Err(ApiError::InvalidParameter { name: "url", .. })
+        ));
+        assert!(matches!(
+            validate_webhook_request(&request("https://hooks.example", "", &[])),
+            Err(ApiError::InvalidParameter { name: "secret", .. })
+        ));
+        assert!(matches!(
+            validate_webhook_request(&request("https://hooks.example", "s3cret", &["nope"])),
+            Err(ApiError::InvalidParameter {
+                name: "event_kinds",
+                ..
+            })
+        ));
+    }
 }
 
 #[derive(serde::Serialize, rocket_okapi::JsonSchema)]
@@ -426,17 +711,718 @@ pub struct DiscoveryResponse {
 #[post("/discover/federation")]
 pub async fn discover_federation(
     state: &rocket::State<AppState>,
-) -> Result<Json<DiscoveryResponse>, Json<ErrorResponse>> {
-    let discovery = FederationDiscovery::new(state.db_pool.clone());
-
-    match discovery.start_discovery().await {
-        Ok(count) => Ok(Json(DiscoveryResponse {
-            discovered: count,
-            message: format!("Successfully discovered {} new servers", count),
-        })),
-        Err(e) => Err(Json(ErrorResponse {
-            error: "discovery_failed".to_string(),
-            message: format!("Federation discovery failed: {}", e),
-        })),
+) -> Result<Json<DiscoveryResponse>, ApiError> {
+    let discovery = FederationDiscovery::new(state.db_pool.clone(), state.federation.clone());
+
+    let count = discovery
+        .start_discovery()
+        .await
+        .map_err(|e| ApiError::Internal(format!("Federation discovery failed: {}", e)))?;
+
+    Ok(Json(DiscoveryResponse {
+        discovered: count,
+        message: format!("Successfully discovered {} new servers", count),
+    }))
+}
+
+#[openapi]
+#[get("/_matrix/key/v2/server")]
+pub fn server_keys(state: &rocket::State<AppState>) -> Result<Json<serde_json::Value>, ApiError> {
+    let federation = state
+        .federation
+        .as_ref()
+        .ok_or(ApiError::FederationDisabled)?;
+
+    let valid_until_ts = chrono::Utc::now().timestamp_millis() + key_validity_ms();
+
+    Ok(Json(
+        federation
+            .signing_key()
+            .server_keys_response(valid_until_ts),
+    ))
+}
+
+fn load_server_keys_response(
+    state: &AppState,
+    domain: &str,
+) -> Result<ServerKeysResponse, ApiError> {
+    let mut conn = db_conn(state)?;
+    let server = load_server(&mut conn, domain)?;
+
+    let keys = get_server_keys(&mut conn, server.id)
+        .map_err(ApiError::database("Failed to fetch server keys"))?;
+    let alerts = get_key_alerts(&mut conn, server.id)
+        .map_err(ApiError::database("Failed to fetch server key alerts"))?;
+
+    Ok(ServerKeysResponse {
+        domain: server.domain,
+        keys: keys
+            .into_iter()
+            .map(|k| ServerKeyResponse {
+                key_id: k.key_id,
+                public_key: k.public_key,
+                valid_until_ts: k.valid_until_ts,
+                expired_ts: k.expired_ts,
+                is_current: k.is_current,
+                signature_valid: k.signature_valid,
+                first_seen_at: k.first_seen_at,
+                last_seen_at: k.last_seen_at,
+            })
+            .collect(),
+        alerts: alerts
+            .into_iter()
+            .map(|a| ServerKeyAlertResponse {
+                kind: a.kind,
+                key_id: a.key_id,
+                message: a.message,
+                created_at: a.created_at,
+            })
+            .collect(),
+    })
+}
+
+#[openapi]
+#[get("/servers/<domain>/keys")]
+pub async fn server_signing_keys(
+    domain: &str,
+    state: &State<AppState>,
+) -> Result<Json<ServerKeysResponse>, ApiError> {
+    validate_domain(domain)?;
+    let domain = &normalize_domain(domain);
+
+    load_server_keys_response(state, domain).map(Json)
+}
+
+/// Re-fetches a server's keys now. Shares the refresh rate limit window,
+/// counted separately from `/refresh`; 502 when the keys cannot be fetched.
+#[openapi]
+#[post("/servers/<domain>/keys/refresh")]
+pub async fn refresh_server_signing_keys(
+    domain: &str,
+    state: &State<AppState>,
+) -> Result<Json<ServerKeysResponse>, RouteError<Refresh>> {
+    validate_domain(domain)?;
+    let domain = &normalize_domain(domain);
+    state.refresh_limiter.check(&format!("keys:{}", domain))?;
+
+    let server = {
+        let mut conn = db_conn(state)?;
+        load_server(&mut conn, domain)?
+    };
+
+    refresh_server_keys(&state.db_pool, server.id, &server.domain)
+        .await
+        .map_err(|e| ApiError::KeyFetchFailed(e.to_string()))?;
+
+    Ok(Json(load_server_keys_response(state, domain)?))
+}
+
+/// Checks federation with any domain live. Cached per domain and limited
+/// per client, as each report connects to wherever the domain points.
+#[openapi]
+#[get("/servers/<domain>/federation-report")]
+pub async fn federation_report(
+    domain: &str,
+    client: ClientIp,
+    state: &State<AppState>,
+) -> Result<Json<FederationReportResponse>, RouteError<Limited>> {
+    validate_domain(domain)?;
+    let domain = &normalize_domain(domain);
+
+    let cache_key = format!("server:federation-report:{}", domain);
+
+    if let Ok(cached) = state
+        .cache
+        .get::<FederationReportResponse>(&cache_key)
+        .await
+    {
+        return Ok(Json(cached));
+    }
+
+    state.live_check_limiter.check(&client.0)?;
+    let report = build_federation_report(domain).await;
+
+    let _ = state.cache.set(&cache_key, &report, CACHE_TTL_SHORT).await;
+
+    Ok(Json(report))
+}
+
+fn load_well_known_report(
+    state: &AppState,
+    domain: &str,
+) -> Result<WellKnownReportResponse, ApiError> {
+    let mut conn = db_conn(state)?;
+    let server = load_server(&mut conn, domain)?;
+
+    let findings = get_well_known_findings(&mut conn, server.id)
+        .map_err(ApiError::database("Failed to fetch well-known findings"))?;
+
+    Ok(WellKnownReportResponse {
+        domain: server.domain,
+        checked_at: findings.iter().map(|f| f.checked_at).max(),
+        has_problems: findings.iter().any(|f| f.severity != "info"),
+        findings: findings
+            .into_iter()
+            .map(|f| WellKnownFindingResponse {
+                document: f.document,
+                severity: f.severity,
+                code: f.code,
+                message: f.message,
+            })
+            .collect(),
+    })
+}
+
+#[openapi]
+#[get("/servers/<domain>/well-known")]
+pub async fn well_known_report(
+    domain: &str,
+    state: &State<AppState>,
+) -> Result<Json<WellKnownReportResponse>, ApiError> {
+    validate_domain(domain)?;
+    let domain = &normalize_domain(domain);
+
+    load_well_known_report(state, domain).map(Json)
+}
+
+/// Lints a server's well-known documents now. Shares the refresh rate limit
+/// window, counted separately from `/refresh`.
+#[openapi]
+#[post("/servers/<domain>/well-known/lint")]
+pub async fn lint_well_known_documents(
+    domain: &str,
+    state: &State<AppState>,
+) -> Result<Json<WellKnownReportResponse>, RouteError<Limited>> {
+    validate_domain(domain)?;
+    let domain = &normalize_domain(domain);
+    state.refresh_limiter.check(&format!("lint:{}", domain))?;
+
+    let server = {
+        let mut conn = db_conn(state)?;
+        load_server(&mut conn, domain)?
+    };
+
+    lint_and_store(&state.db_pool, server.id, &server.domain)
+        .await
+        .map_err(|e| ApiError::Internal(format!("Failed to store well-known findings: {}", e)))?;
+
+    let _ = state.cache.invalidate_pattern("servers:search:*").await;
+
+    Ok(Json(load_well_known_report(state, domain)?))
+}
+
+#[openapi]
+#[get("/servers/<domain>/bridges")]
+pub async fn server_bridges(
+    domain: &str,
+    state: &State<AppState>,
+) -> Result<Json<ServerBridgesResponse>, ApiError> {
+    validate_domain(domain)?;
+    let domain = &normalize_domain(domain);
+
+    let mut conn = db_conn(state)?;
+    let server = load_server(&mut conn, domain)?;
+
+    let bridges = get_server_bridges(&mut conn, server.id)
+        .map_err(ApiError::database("Failed to fetch bridges"))?;
+
+    Ok(Json(ServerBridgesResponse {
+        domain: server.domain,
+        bridges: bridges
+            .into_iter()
+            .map(|b| ServerBridgeResponse {
+                bridge: b.bridge,
+                source: b.source,
+                evidence: b.evidence,
+                first_seen_at: b.first_seen_at,
+                last_seen_at: b.last_seen_at,
+            })
+            .collect(),
+    }))
+}
+
+/// Field changes recorded by refreshes, newest first. `field` narrows the
+/// timeline to one field, e.g. `version`.
+#[openapi]
+#[get("/servers/<domain>/history?<field>&<limit>&<offset>")]
+pub async fn server_history(
+    domain: &str,
+    field: Option<String>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+    state: &State<AppState>,
+) -> Result<Json<ServerHistoryResponse>, ApiError> {
+    validate_domain(domain)?;
+    let domain = &normalize_domain(domain);
+
+    let limit = limit.unwrap_or(50).clamp(1, 500);
+    let offset = offset.unwrap_or(0).max(0);
+
+    let mut conn = db_conn(state)?;
+    let server = load_server(&mut conn, domain)?;
+
+    let revisions = get_server_revisions(&mut conn, server.id, field.as_deref(), limit, offset)
+        .map_err(ApiError::database("Failed to fetch server history"))?;
+
+    Ok(Json(ServerHistoryResponse {
+        domain: server.domain,
+        revisions: revisions
+            .into_iter()
+            .map(|r| ServerRevisionResponse {
+                field: r.field,
+                old: r.old_value,
+                new: r.new_value,
+                observed_at: r.observed_at,
+            })
+            .collect(),
+        limit,
+        offset,
+    }))
+}
+
+/// Most events the feeds carry.
+const FEED_LENGTH: i64 = 50;
+
+/// Parses a comma-separated `kind` filter; no filter means every kind.
+fn parse_event_kinds(kind: Option<&str>) -> Result<Vec<&'static str>, ApiError> {
+    kind.unwrap_or("")
+        .split(',')
+        .map(str::trim)
+        .filter(|k| !k.is_empty())
+        .map(|k| {
+            EventKind::parse(k)
+                .map(|kind| kind.as_str())
+                .ok_or_else(|| {
+                    ApiError::invalid_parameter("kind", format!("unknown event kind `{}`", k))
+                })
+        })
+        .collect()
+}
+
+fn load_events(
+    state: &AppState,
+    kind: Option<&str>,
+    domain: Option<&str>,
+    limit: i64,
+    offset: i64,
+) -> Result<(Vec<ServerEventResponse>, i64), ApiError> {
+    let kinds = parse_event_kinds(kind)?;
+    let mut conn = db_conn(state)?;
+
+    let (events, total) = get_server_events(&mut conn, &kinds, domain, limit, offset)
+        .map_err(ApiError::database("Failed to fetch events"))?;
+
+    Ok((
+        events.into_iter().map(ServerEventResponse::from).collect(),
+        total,
+    ))
+}
+
+/// The index's event log, newest first. `kind` takes a comma-separated list
+/// of event kinds.
+#[openapi]
+#[get("/events?<kind>&<domain>&<limit>&<offset>")]
+pub async fn list_events(
+    kind: Option<String>,
+    domain: Option<String>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+    state: &State<AppState>,
+) -> Result<Json<EventsResponse>, RouteError<Validated>> {
+    let limit = limit.unwrap_or(50).clamp(1, 100);
+    let offset = offset.unwrap_or(0).max(0);
+
+    let (events, total) = load_events(state, kind.as_deref(), domain.as_deref(), limit, offset)?;
+
+    Ok(Json(EventsResponse {
+        events,
+        total,
+        limit,
+        offset,
+    }))
+}
+
+/// The latest events as an Atom feed, filtered like `/events`.
+#[openapi]
+#[get("/events/atom?<kind>&<domain>")]
+pub async fn events_atom(
+    kind: Option<String>,
+    domain: Option<String>,
+    state: &State<AppState>,
+) -> Result<(ContentType, String), RouteError<Validated>> {
+    let (events, _) = load_events(state, kind.as_deref(), domain.as_deref(), FEED_LENGTH, 0)?;
+
+    let base_url = public_base_url();
+    let mut self_url = format!("{}/events/atom", base_url);
+    let query: Vec<String> = [("kind", kind), ("domain", domain)]
+        .into_iter()
+        .filter_map(|(name, value)| value.map(|v| format!("{}={}", name, v)))
+        .collect();
+    if !query.is_empty() {
+        self_url = format!("{}?{}", self_url, query.join("&"));
     }
+
+    Ok((
+        ContentType::new("application", "atom+xml"),
+        atom_feed(&events, &base_url, &self_url),
+    ))
+}
+
+/// The latest events as an RSS feed, filtered like `/events`.
+#[openapi]
+#[get("/events/rss?<kind>&<domain>")]
+pub async fn events_rss(
+    kind: Option<String>,
+    domain: Option<String>,
+    state: &State<AppState>,
+) -> Result<(ContentType, String), RouteError<Validated>> {
+    let (events, _) = load_events(state, kind.as_deref(), domain.as_deref(), FEED_LENGTH, 0)?;
+
+    Ok((
+        ContentType::new("application", "rss+xml"),
+        rss_feed(&events, &public_base_url()),
+    ))
+}
+
+/// Live events as Server-Sent Events, filtered like `/events`. Each event's
+/// id is its id in the log; a client reconnecting with `Last-Event-ID` first
+/// receives the recent events it missed.
+#[openapi]
+#[get("/events/stream?<kind>&<domain>")]
+pub async fn stream_events(
+    kind: Option<String>,
+    domain: Option<String>,
+    last_event_id: LastEventId,
+    state: &State<AppState>,
+    mut shutdown: Shutdown,
+) -> Result<EventStream<BoxStream<'static, Event>>, RouteError<Validated>> {
+    let kinds = parse_event_kinds(kind.as_deref())?;
+
+    // Subscribe before reading the buffer so nothing falls between the two.
+    let mut live = state.event_hub.subscribe();
+    let backlog = state.event_hub.replay(&state.cache, last_event_id.0).await;
+
+    let wanted = move |event: &ServerEventResponse| {
+        (kinds.is_empty() || kinds.contains(&event.kind.as_str()))
+            && domain.as_deref().is_none_or(|d| d == event.domain)
+    };
+
+    // Replicas log events concurrently, so ids can arrive out of order; only
+    // the replayed ones are known to be duplicates.
+    let replayed: HashSet<i64> = backlog.iter().map(|e| e.id).collect();
+
+    let events = stream! {
+        for event in backlog.into_iter().filter(|e| wanted(e)) {
+            yield Event::json(&event).id(event.id.to_string()).event(event.kind.clone());
+        }
+
+        loop {
+            let event = rocket::tokio::select! {
+                received = live.recv() => match received {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Event stream client fell behind by {} events", skipped);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                _ = &mut shutdown => break,
+            };
+
+            if replayed.contains(&event.id) || !wanted(&event) {
+                continue;
+            }
+
+            yield Event::json(&event).id(event.id.to_string()).event(event.kind.clone());
+        }
+    };
+
+    Ok(EventStream::from(events.boxed()))
+}
+
+/// Rejects subscriptions that could never be delivered.
+fn validate_webhook_request(request: &CreateWebhookRequest) -> Result<(), ApiError> {
+    let scheme_ok = reqwest::Url::parse(&request.url)
+        .map(|url| matches!(url.scheme(), "http" | "https"))
+        .unwrap_or(false);
+    if !scheme_ok {
+        return Err(ApiError::invalid_parameter(
+            "url",
+            "must be an http or https URL",
+        ));
+    }
+
+    if request.secret.is_empty() {
+        return Err(ApiError::invalid_parameter("secret", "must not be empty"));
+    }
+
+    if let Some(unknown) = request
+        .event_kinds
+        .iter()
+        .find(|kind| EventKind::parse(kind).is_none())
+    {
+        return Err(ApiError::invalid_parameter(
+            "event_kinds",
+            format!("unknown event kind `{}`", unknown),
+        ));
+    }
+
+    if let Some(ref domain) = request.domain {
+        validate_domain(domain)?;
+    }
+
+    Ok(())
+}
+
+/// Whether the credentials may manage `webhook`: the admin token, or the
+/// token returned when it was created.
+fn may_manage_webhook(credentials: &Credentials, webhook: &WebhookSubscription) -> bool {
+    if credentials.admin {
+        return true;
+    }
+
+    match (&credentials.token, &webhook.token_hash) {
+        (Some(token), Some(expected)) => tokens_match(&webhooks::hash_token(token), expected),
+        _ => false,
+    }
+}
+
+/// Loads a webhook the credentials may manage. Others' webhooks answer the
+/// same as missing ones, so ids cannot be probed.
+fn load_webhook(
+    conn: &mut PgConnection,
+    id: i64,
+    credentials: &Credentials,
+) -> Result<WebhookSubscription, ApiError> {
+    get_webhook(conn, id)
+        .map_err(ApiError::database("Failed to fetch webhook"))?
+        .filter(|webhook| may_manage_webhook(credentials, webhook))
+        .ok_or(ApiError::NotFound)
+}
+
+/// Subscribes a URL to events logged from now on. Each delivery is signed
+/// with the secret, which is never returned. The response carries the token
+/// that manages the subscription; it is not shown again. An unusable URL or
+/// filter answers 422.
+#[openapi]
+#[post("/webhooks", data = "<request>")]
+pub async fn create_webhook(
+    request: Json<CreateWebhookRequest>,
+    state: &State<AppState>,
+) -> Result<Json<WebhookResponse>, RouteError<Validated>> {
+    validate_webhook_request(&request)?;
+    webhooks::resolve_target(&request.url, state.allow_private_webhooks)
+        .await
+        .map_err(|message| ApiError::invalid_parameter("url", message))?;
+
+    let token = webhooks::new_token();
+
+    let mut conn = db_conn(state)?;
+    let last_event_id =
+        get_latest_event_id(&mut conn).map_err(ApiError::database("Failed to fetch events"))?;
+
+    let webhook = insert_webhook(
+        &mut conn,
+        &NewWebhookSubscription {
+            url: &request.url,
+            secret: &request.secret,
+            event_kinds: request.event_kinds.clone(),
+            domain: request.domain.as_deref(),
+            last_event_id,
+            token_hash: &webhooks::hash_token(&token),
+        },
+    )
+    .map_err(ApiError::database("Failed to save webhook"))?;
+
+    Ok(Json(WebhookResponse {
+        token: Some(token),
+        ..WebhookResponse::from(webhook)
+    }))
+}
+
+/// Every subscription. Admin only, as it reveals subscribers' URLs.
+#[openapi]
+#[get("/webhooks")]
+pub async fn list_webhooks(
+    _admin: Admin,
+    state: &State<AppState>,
+) -> Result<Json<WebhooksResponse>, ApiError> {
+    let mut conn = db_conn(state)?;
+
+    let webhooks =
+        get_webhooks(&mut conn).map_err(ApiError::database("Failed to fetch webhooks"))?;
+
+    Ok(Json(WebhooksResponse {
+        webhooks: webhooks.into_iter().map(WebhookResponse::from).collect(),
+    }))
+}
+
+#[openapi]
+#[get("/webhooks/<id>")]
+pub async fn webhook(
+    id: i64,
+    credentials: Credentials,
+    state: &State<AppState>,
+) -> Result<Json<WebhookResponse>, ApiError> {
+    let mut conn = db_conn(state)?;
+    load_webhook(&mut conn, id, &credentials).map(|w| Json(WebhookResponse::from(w)))
+}
+
+#[openapi]
+#[delete("/webhooks/<id>")]
+pub async fn remove_webhook(
+    id: i64,
+    credentials: Credentials,
+    state: &State<AppState>,
+) -> Result<NoContent, ApiError> {
+    let mut conn = db_conn(state)?;
+    load_webhook(&mut conn, id, &credentials)?;
+
+    let deleted =
+        delete_webhook(&mut conn, id).map_err(ApiError::database("Failed to delete webhook"))?;
+
+    if deleted == 0 {
+        return Err(ApiError::NotFound);
+    }
+
+    Ok(NoContent)
+}
+
+/// Turns a webhook disabled after repeated failures back on. Events it
+/// missed meanwhile are delivered.
+#[openapi]
+#[post("/webhooks/<id>/enable")]
+pub async fn enable_webhook(
+    id: i64,
+    credentials: Credentials,
+    state: &State<AppState>,
+) -> Result<Json<WebhookResponse>, ApiError> {
+    let mut conn = db_conn(state)?;
+    load_webhook(&mut conn, id, &credentials)?;
+
+    db::enable_webhook(&mut conn, id)
+        .map_err(ApiError::database("Failed to enable webhook"))?
+        .map(|w| Json(WebhookResponse::from(w)))
+        .ok_or(ApiError::NotFound)
+}
+
+#[openapi]
+#[get("/webhooks/<id>/deliveries?<limit>&<offset>")]
+pub async fn webhook_deliveries(
+    id: i64,
+    limit: Option<i64>,
+    offset: Option<i64>,
+    credentials: Credentials,
+    state: &State<AppState>,
+) -> Result<Json<WebhookDeliveriesResponse>, ApiError> {
+    let limit = limit.unwrap_or(50).clamp(1, 100);
+    let offset = offset.unwrap_or(0).max(0);
+
+    let mut conn = db_conn(state)?;
+    let webhook = load_webhook(&mut conn, id, &credentials)?;
+
+    let deliveries = get_webhook_deliveries(&mut conn, webhook.id, limit, offset)
+        .map_err(ApiError::database("Failed to fetch webhook deliveries"))?;
+
+    Ok(Json(WebhookDeliveriesResponse {
+        webhook_id: webhook.id,
+        deliveries: deliveries
+            .into_iter()
+            .map(WebhookDeliveryResponse::from)
+            .collect(),
+        limit,
+        offset,
+    }))
+}
+
+/// Re-derives every server from its stored probe payloads. Admin only; the
+/// work runs on the blocking pool so it does not hold up a request worker.
+#[openapi]
+#[post("/servers/reprocess")]
+pub async fn reprocess_servers(
+    _admin: Admin,
+    state: &State<AppState>,
+) -> Result<Json<ReprocessResponse>, ApiError> {
+    let db_pool = state.db_pool.clone();
+
+    let result = rocket::tokio::task::spawn_blocking(move || {
+        let mut conn = db_pool.get()?;
+        MatrixService::reprocess_all_servers(&mut conn)
+            .map_err(ApiError::database("Failed to reprocess servers"))
+    })
+    .await
+    .map_err(|e| ApiError::Internal(format!("Reprocessing stopped: {}", e)))??;
+
+    let _ = state.cache.invalidate_pattern("servers:*").await;
+    let _ = state.cache.invalidate_pattern("server:detail:*").await;
+    Ok(Json(result))
+}
+
+#[openapi]
+#[get("/identity-servers")]
+pub async fn list_identity_servers(
+    state: &State<AppState>,
+) -> Result<Json<IdentityServersResponse>, ApiError> {
+    let cache_key = "identity-servers:list";
+
+    if let Ok(cached) = state.cache.get::<IdentityServersResponse>(cache_key).await {
+        return Ok(Json(cached));
+    }
+
+    let mut conn = db_conn(state)?;
+
+    let rows = get_identity_servers_with_usage(&mut conn)
+        .map_err(ApiError::database("Failed to fetch identity servers"))?;
+
+    let response = IdentityServersResponse {
+        identity_servers: rows
+            .into_iter()
+            .map(|(is, homeserver_count)| IdentityServerResponse {
+                base_url: is.base_url,
+                reachable: is.reachable,
+                terms_policies: serde_json::from_value(is.terms_policies).unwrap_or_default(),
+                homeserver_count,
+                last_checked_at: is.last_checked_at,
+                created_at: is.created_at,
+            })
+            .collect(),
+    };
+
+    let _ = state
+        .cache
+        .set(cache_key, &response, CACHE_TTL_MEDIUM)
+        .await;
+
+    Ok(Json(response))
+}
+
+#[openapi]
+#[get("/identity-servers/stats")]
+pub async fn identity_server_stats(
+    state: &State<AppState>,
+) -> Result<Json<IdentityServerStatsResponse>, ApiError> {
+    let mut conn = db_conn(state)?;
+
+    let rows = get_identity_servers_with_usage(&mut conn)
+        .map_err(ApiError::database("Failed to fetch identity server stats"))?;
+    let homeservers_with_identity_server = count_servers_with_identity_server(&mut conn)
+        .map_err(ApiError::database("Failed to fetch identity server stats"))?;
+
+    Ok(Json(IdentityServerStatsResponse {
+        identity_servers: rows.len() as i64,
+        reachable_identity_servers: rows
+            .iter()
+            .filter(|(is, _)| is.reachable == Some(true))
+            .count() as i64,
+        homeservers_with_identity_server,
+        most_used: rows
+            .into_iter()
+            .filter(|(_, count)| *count > 0)
+            .take(10)
+            .map(|(is, homeserver_count)| IdentityServerUsage {
+                base_url: is.base_url,
+                homeserver_count,
+            })
+            .collect(),
+    }))
 }
@@ -16,5 +16,189 @@ diesel::table! {
         room_versions -> Nullable<Text>,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        missing_fields -> Jsonb,
+        client_api_version -> Nullable<Text>,
+        spec_versions -> Array<Text>,
+        latest_spec_version -> Nullable<Text>,
+        unstable_features -> Jsonb,
+        login_flows -> Array<Text>,
+        sso_providers -> Jsonb,
+        oidc_issuer -> Nullable<Text>,
+        support_contacts -> Jsonb,
+        support_page -> Nullable<Text>,
+        terms_policies -> Jsonb,
+        identity_server -> Nullable<Text>,
+        identity_server_id -> Nullable<Int8>,
+        sliding_sync -> Nullable<Text>,
+        sliding_sync_proxy -> Nullable<Text>,
+        sliding_sync_proxy_reachable -> Nullable<Bool>,
+        client_capabilities -> Jsonb,
+        media_upload_size -> Nullable<Int8>,
+        authenticated_media_enforced -> Nullable<Bool>,
+        implementation -> Nullable<Text>,
+        last_refreshed_at -> Nullable<Timestamp>,
     }
 }
+
+diesel::table! {
+    identity_servers (id) {
+        id -> Int8,
+        base_url -> Text,
+        reachable -> Nullable<Bool>,
+        terms_policies -> Jsonb,
+        last_checked_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    probe_payloads (id) {
+        id -> Int8,
+        server_id -> Int8,
+        probe -> Text,
+        url -> Text,
+        status -> Nullable<Int4>,
+        headers -> Jsonb,
+        body -> Nullable<Jsonb>,
+        error -> Nullable<Text>,
+        fetched_at -> Timestamp,
+        duration_ms -> Nullable<Int4>,
+    }
+}
+
+diesel::table! {
+    server_keys (id) {
+        id -> Int8,
+        server_id -> Int8,
+        key_id -> Text,
+        public_key -> Text,
+        valid_until_ts -> Nullable<Int8>,
+        expired_ts -> Nullable<Int8>,
+        is_current -> Bool,
+        signature_valid -> Bool,
+        first_seen_at -> Timestamp,
+        last_seen_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    server_bridges (id) {
+        id -> Int8,
+        server_id -> Int8,
+        bridge -> Text,
+        source -> Text,
+        evidence -> Text,
+        first_seen_at -> Timestamp,
+        last_seen_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    server_key_alerts (id) {
+        id -> Int8,
+        server_id -> Int8,
+        key_id -> Nullable<Text>,
+        kind -> Text,
+        message -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    well_known_findings (id) {
+        id -> Int8,
+        server_id -> Int8,
+        document -> Text,
+        severity -> Text,
+        code -> Text,
+        message -> Text,
+        checked_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    server_revisions (id) {
+        id -> Int8,
+        server_id -> Int8,
+        field -> Text,
+        old_value -> Jsonb,
+        new_value -> Jsonb,
+        observed_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    server_events (id) {
+        id -> Int8,
+        server_id -> Nullable<Int8>,
+        domain -> Text,
+        kind -> Text,
+        details -> Jsonb,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    webhook_subscriptions (id) {
+        id -> Int8,
+        url -> Text,
+        secret -> Text,
+        event_kinds -> Array<Text>,
+        domain -> Nullable<Text>,
+        enabled -> Bool,
+        consecutive_failures -> Int4,
+        last_event_id -> Int8,
+        disabled_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+        claimed_until -> Nullable<Timestamp>,
+        token_hash -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    webhook_outbox (subscription_id, event_id) {
+        subscription_id -> Int8,
+        event_id -> Int8,
+    }
+}
+
+diesel::table! {
+    webhook_deliveries (id) {
+        id -> Int8,
+        subscription_id -> Int8,
+        event_id -> Int8,
+        success -> Bool,
+        attempts -> Int4,
+        status -> Nullable<Int4>,
+        error -> Nullable<Text>,
+        delivered_at -> Timestamp,
+    }
+}
+
+diesel::joinable!(servers -> identity_servers (identity_server_id));
+diesel::joinable!(probe_payloads -> servers (server_id));
+diesel::joinable!(server_bridges -> servers (server_id));
+diesel::joinable!(server_events -> servers (server_id));
+diesel::joinable!(server_key_alerts -> servers (server_id));
+diesel::joinable!(server_keys -> servers (server_id));
+diesel::joinable!(server_revisions -> servers (server_id));
+diesel::joinable!(webhook_deliveries -> server_events (event_id));
+diesel::joinable!(webhook_deliveries -> webhook_subscriptions (subscription_id));
+diesel::joinable!(webhook_outbox -> server_events (event_id));
+diesel::joinable!(webhook_outbox -> webhook_subscriptions (subscription_id));
+diesel::joinable!(well_known_findings -> servers (server_id));
+
+diesel::allow_tables_to_appear_in_same_query!(
+    identity_servers,
+    probe_payloads,
+    server_bridges,
+    server_events,
+    server_key_alerts,
+    server_keys,
+    server_revisions,
+    servers,
+    webhook_deliveries,
+    webhook_outbox,
+    webhook_subscriptions,
+    well_known_findings,
+);
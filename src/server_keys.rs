@@ -0,0 +1,378 @@
+use crate::db::{
+    get_current_server_keys, insert_key_alerts, record_server_keys, DbPool, NewServerKey,
+    NewServerKeyAlert,
+};
+use crate::federation_client::fetch_federation_json;
+use crate::signing::verify_json;
+use serde_json::Value;
+use tracing::warn;
+
+pub const SERVER_KEYS_PATH: &str = "/_matrix/key/v2/server";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObservedKey {
+    pub key_id: String,
+    pub public_key: String,
+    pub expired_ts: Option<i64>,
+    pub is_current: bool,
+}
+
+#[derive(Debug)]
+pub struct ObservedKeys {
+    pub keys: Vec<ObservedKey>,
+    pub valid_until_ts: Option<i64>,
+    pub signature_valid: bool,
+    pub signature_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyAlertKind {
+    InvalidSignature,
+    UnexpectedRotation,
+    Rotation,
+    ExpiringSoon,
+    Expired,
+}
+
+impl KeyAlertKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyAlertKind::InvalidSignature => "invalid_signature",
+            KeyAlertKind::UnexpectedRotation => "unexpected_rotation",
+            KeyAlertKind::Rotation => "rotation",
+            KeyAlertKind::ExpiringSoon => "expiring_soon",
+            KeyAlertKind::Expired => "expired",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyAlert {
+    pub kind: KeyAlertKind,
+    pub key_id: Option<String>,
+    pub message: String,
+}
+
+/// Parses a `/_matrix/key/v2/server` response and checks that it is signed
+/// by every key it lists in `verify_keys`.
+pub fn parse_server_keys(json: &Value, server_name: &str) -> Result<ObservedKeys, String> {
+    let reported_name = json["server_name"].as_str().unwrap_or_default();
+    if reported_name != server_name {
+        return Err(format!(
+            "response is for {} instead of {}",
+            reported_name, server_name
+        ));
+    }
+
+    let verify_keys = json["verify_keys"]
+        .as_object()
+        .ok_or_else(|| "response has no verify_keys".to_string())?;
+
+    let mut keys = Vec::new();
+    let mut signature_error = None;
+
+    for (key_id, key) in verify_keys {
+        let Some(public_key) = key["key"].as_str() else {
+            continue;
+        };
+
+        if let Err(e) = verify_json(json, server_name, key_id, public_key) {
+            signature_error.get_or_insert_with(|| format!("{}: {}", key_id, e));
+        }
+
+        keys.push(ObservedKey {
+            key_id: key_id.clone(),
+            public_key: public_key.to_string(),
+            expired_ts: None,
+            is_current: true,
+        });
+    }
+
+    if keys.is_empty() {
+        signature_error.get_or_insert_with(|| "no verify keys to check".to_string());
+    }
+
+    if let Some(old_keys) = json["old_verify_keys"].as_object() {
+        for (key_id, key) in old_keys {
+            if let Some(public_key) = key["key"].as_str() {
+                keys.push(ObservedKey {
+                    key_id: key_id.clone(),
+                    public_key: public_key.to_string(),
+                    expired_ts: key["expired_ts"].as_i64(),
+                    is_current: false,
+                });
+            }
+        }
+    }
+
+    Ok(ObservedKeys {
+        keys,
+        valid_until_ts: json["valid_until_ts"].as_i64(),
+        signature_valid: signature_error.is_none(),
+        signature_error,
+    })
+}
+
+/// Compares freshly observed keys with the keys that were current at the
+/// previous fetch. A current key that disappears without being listed in
+/// `old_verify_keys` is treated as an unexpected rotation.
+pub fn detect_key_alerts(
+    previous_current: &[(String, String)],
+    observed: &ObservedKeys,
+    now_ms: i64,
+    warning_window_ms: i64,
+) -> Vec<KeyAlert> {
+    let mut alerts = Vec::new();
+
+    if let Some(ref error) = observed.signature_error {
+        alerts.push(KeyAlert {
+            kind: KeyAlertKind::InvalidSignature,
+            key_id: None,
+            message: format!("Key response signature check failed: {}", error),
+        });
+    }
+
+    for (key_id, public_key) in previous_current {
+        let current = observed
+            .keys
+            .iter()
+            .find(|k| k.is_current && &k.key_id == key_id);
+
+        match current {
+            Some(key) if &key.public_key == public_key => {}
+            Some(_) => alerts.push(KeyAlert {
+                kind: KeyAlertKind::UnexpectedRotation,
+                key_id: Some(key_id.clone()),
+                message: format!("Key {} now has a different public key", key_id),
+            }),
+            None => {
+                let retired = observed
+                    .keys
+                    .iter()
+                    .any(|k| !k.is_current && &k.key_id == key_id && &k.public_key == public_key);
+
+                if retired {
+                    alerts.push(KeyAlert {
+                        kind: KeyAlertKind::Rotation,
+                        key_id: Some(key_id.clone()),
+                        message: format!("Key {} was retired to old_verify_keys", key_id),
+                    });
+                } else {
+                    alerts.push(KeyAlert {
+                        kind: KeyAlertKind::UnexpectedRotation,
+                        key_id: Some(key_id.clone()),
+                        message: format!(
+                            "Key {} disappeared without being listed in old_verify_keys",
+                            key_id
+                        ),
+                    });
+                }
+            }
+        }
+    }
+
+    if let Some(valid_until_ts) = observed.valid_until_ts {
+        if valid_until_ts <= now_ms {
+            alerts.push(KeyAlert {
+                kind: KeyAlertKind::Expired,
+                key_id: None,
+                message: format!("Keys expired at {}", valid_until_ts),
+            });
+        } else if valid_until_ts <= now_ms + warning_window_ms {
+            alerts.push(KeyAlert {
+                kind: KeyAlertKind::ExpiringSoon,
+                key_id: None,
+                message: format!("Keys expire at {}", valid_until_ts),
+            });
+        }
+    }
+
+    alerts
+}
+
+pub fn expiry_warning_window_ms() -> i64 {
+    let hours: i64 = std::env::var("SERVER_KEY_EXPIRY_WARNING_HOURS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(2);
+    hours * 60 * 60 * 1000
+}
+
+/// Fetches a server's signing keys, stores every observed key and records
+/// alerts for rotations, bad signatures and imminent expiry.
+pub async fn refresh_server_keys(
+    db_pool: &DbPool,
+    server_id: i64,
+    domain: &str,
+) -> Result<Vec<KeyAlert>, Box<dyn std::error::Error + Send + Sync>> {
+    let json = fetch_federation_json(domain, SERVER_KEYS_PATH).await?;
+    let observed = parse_server_keys(&json, domain)?;
+
+    let mut conn = db_pool.get()?;
+
+    let previous: Vec<(String, String)> = get_current_server_keys(&mut conn, server_id)?
+        .into_iter()
+        .map(|k| (k.key_id, k.public_key))
+        .collect();
+
+    let alerts = detect_key_alerts(
+        &previous,
+        &observed,
+        chrono::Utc::now().timestamp_millis(),
+        expiry_warning_window_ms(),
+    );
+
+    let new_keys: Vec<NewServerKey> = observed
+        .keys
+        .iter()
+        .map(|k| NewServerKey {
+            server_id,
+            key_id: &k.key_id,
+            public_key: &k.public_key,
+            valid_until_ts: if k.is_current {
+                observed.valid_until_ts
+            } else {
+                None
+            },
+            expired_ts: k.expired_ts,
+            is_current: k.is_current,
+            signature_valid: observed.signature_valid,
+        })
+        .collect();
+
+    record_server_keys(&mut conn, server_id, &new_keys)?;
+
+    let new_alerts: Vec<NewServerKeyAlert> = alerts
+        .iter()
+        .map(|a| NewServerKeyAlert {
+            server_id,
+            key_id: a.key_id.as_deref(),
+            kind: a.kind.as_str(),
+            message: &a.message,
+        })
+        .collect();
+
+    insert_key_alerts(&mut conn, &new_alerts)?;
+
+    for alert in &alerts {
+        if alert.kind != KeyAlertKind::Rotation {
+            warn!("Server key alert for {}: {}", domain, alert.message);
+        }
+    }
+
+    Ok(alerts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signing::ServerSigningKey;
+
+    const HOUR_MS: i64 = 60 * 60 * 1000;
+
+    fn observed(keys: Vec<ObservedKey>, valid_until_ts: Option<i64>) -> ObservedKeys {
+        ObservedKeys {
+            keys,
+            valid_until_ts,
+            signature_valid: true,
+            signature_error: None,
+        }
+    }
+
+    fn key(key_id: &str, public_key: &str, is_current: bool) -> ObservedKey {
+        ObservedKey {
+            key_id: key_id.to_string(),
+            public_key: public_key.to_string(),
+            expired_ts: None,
+            is_current,
+        }
+    }
+
+    #[test]
+    fn test_parse_server_keys_verifies_signature() {
+        let signing_key = ServerSigningKey::new("example.org", "a", [5u8; 32]);
+        let json = signing_key.server_keys_response(1_000);
+
+        let parsed = parse_server_keys(&json, "example.org").unwrap();
+        assert!(parsed.signature_valid);
+        assert_eq!(parsed.valid_until_ts, Some(1_000));
+        assert_eq!(parsed.keys.len(), 1);
+        assert_eq!(parsed.keys[0].key_id, "ed25519:a");
+    }
+
+    #[test]
+    fn test_parse_server_keys_detects_tampering() {
+        let signing_key = ServerSigningKey::new("example.org", "a", [5u8; 32]);
+        let mut json = signing_key.server_keys_response(1_000);
+        json["valid_until_ts"] = serde_json::json!(2_000);
+
+        let parsed = parse_server_keys(&json, "example.org").unwrap();
+        assert!(!parsed.signature_valid);
+        assert!(parsed.signature_error.is_some());
+    }
+
+    #[test]
+    fn test_parse_server_keys_rejects_wrong_server_name() {
+        let signing_key = ServerSigningKey::new("other.org", "a", [5u8; 32]);
+        let json = signing_key.server_keys_response(1_000);
+
+        assert!(parse_server_keys(&json, "example.org").is_err());
+    }
+
+    #[test]
+    fn test_no_alerts_for_unchanged_keys() {
+        let previous = vec![("ed25519:a".to_string(), "pk1".to_string())];
+        let observed = observed(vec![key("ed25519:a", "pk1", true)], Some(100 * HOUR_MS));
+
+        assert!(detect_key_alerts(&previous, &observed, 0, 2 * HOUR_MS).is_empty());
+    }
+
+    #[test]
+    fn test_expected_rotation() {
+        let previous = vec![("ed25519:a".to_string(), "pk1".to_string())];
+        let observed = observed(
+            vec![
+                key("ed25519:b", "pk2", true),
+                key("ed25519:a", "pk1", false),
+            ],
+            Some(100 * HOUR_MS),
+        );
+
+        let alerts = detect_key_alerts(&previous, &observed, 0, 2 * HOUR_MS);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].kind, KeyAlertKind::Rotation);
+    }
+
+    #[test]
+    fn test_unexpected_rotation() {
+        let previous = vec![("ed25519:a".to_string(), "pk1".to_string())];
+
+        let replaced = observed(vec![key("ed25519:a", "pk9", true)], Some(100 * HOUR_MS));
+        let alerts = detect_key_alerts(&previous, &replaced, 0, 2 * HOUR_MS);
+        assert_eq!(alerts[0].kind, KeyAlertKind::UnexpectedRotation);
+
+        let vanished = observed(vec![key("ed25519:b", "pk2", true)], Some(100 * HOUR_MS));
+        let alerts = detect_key_alerts(&previous, &vanished, 0, 2 * HOUR_MS);
+        assert_eq!(alerts[0].kind, KeyAlertKind::UnexpectedRotation);
+    }
+
+    #[test]
+    fn test_expiry_alerts() {
+        let soon = observed(vec![key("ed25519:a", "pk1", true)], Some(HOUR_MS));
+        let alerts = detect_key_alerts(&[], &soon, 0, 2 * HOUR_MS);
+        assert_eq!(alerts[0].kind, KeyAlertKind::ExpiringSoon);
+
+        let expired = observed(vec![key("ed25519:a", "pk1", true)], Some(HOUR_MS));
+        let alerts = detect_key_alerts(&[], &expired, 2 * HOUR_MS, 2 * HOUR_MS);
+        assert_eq!(alerts[0].kind, KeyAlertKind::Expired);
+    }
+
+    #[test]
+    fn test_alert_kind_as_str() {
+        assert_eq!(KeyAlertKind::InvalidSignature.as_str(), "invalid_signature");
+        assert_eq!(
+            KeyAlertKind::UnexpectedRotation.as_str(),
+            "unexpected_rotation"
+        );
+    }
+}
@@ -1,15 +1,225 @@
+use crate::bridges::record_bridges_from_payloads;
+use crate::db::{
+    get_all_servers, get_latest_probe_payloads, link_identity_server, update_server, DbPool,
+    NewServer, Server,
+};
 use crate::http_client::get_http_client;
-use crate::models::DiscoveredServerInfo;
+use crate::identity_servers::refresh_identity_server;
+use crate::models::{
+    ClientApiVersion, DiscoveredServerInfo, MissingField, PolicyTranslation, ProbeKind,
+    ProbePayload, ReprocessResponse, SlidingSync, SsoProvider, SupportContact, TermsPolicy,
+};
+use crate::server_keys::refresh_server_keys;
+use crate::webhooks::resolve_target;
+use crate::well_known_lint::lint_and_store;
+use diesel::PgConnection;
 use serde::Deserialize;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
 
 pub struct MatrixService;
 
+const AUTH_METADATA_PATH: &str = "/_matrix/client/v1/auth_metadata";
+const MSC2965_AUTH_METADATA_PATH: &str =
+    "/_matrix/client/unstable/org.matrix.msc2965/auth_metadata";
+const MSC2965_AUTH_ISSUER_PATH: &str = "/_matrix/client/unstable/org.matrix.msc2965/auth_issuer";
+
+const LEGACY_MEDIA_CONFIG_PATH: &str = "/_matrix/media/v3/config";
+const AUTHENTICATED_MEDIA_CONFIG_PATH: &str = "/_matrix/client/v1/media/config";
+
+const SLIDING_SYNC_PROXY_KEY: &str = "org.matrix.msc3575.proxy";
+const SLIDING_SYNC_PATH: &str = "/_matrix/client/unstable/org.matrix.msc3575/sync";
+/// `unstable_features` flags of homeservers serving sliding sync themselves.
+const NATIVE_SLIDING_SYNC_FEATURES: [&str; 2] =
+    ["org.matrix.simplified_msc3575", "org.matrix.msc3575"];
+
+/// Raw bodies that are not JSON are stored as a string, capped at this size.
+const MAX_RAW_BODY_BYTES: usize = 64 * 1024;
+
+const HEADERS_OF_INTEREST: [&str; 4] = [
+    "content-type",
+    "server",
+    "access-control-allow-origin",
+    "location",
+];
+
+/// Per-probe deadline, independent of the other probes of the same server.
+pub fn probe_timeout() -> Duration {
+    let secs: u64 = std::env::var("PROBE_TIMEOUT_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(5);
+    Duration::from_secs(secs)
+}
+
+/// Orders spec versions: every `v1.x` release is newer than the legacy
+/// `r0.x.y` ones. Unrecognised strings sort below both.
+fn spec_version_key(version: &str) -> Option<(u32, u32, u32)> {
+    let (major, rest) = if let Some(rest) = version.strip_prefix('v') {
+        (rest.split('.').next()?.parse::<u32>().ok()? + 1, rest)
+    } else if let Some(rest) = version.strip_prefix("r0.") {
+        (0, rest)
+    } else {
+        return None;
+    };
+
+    let mut parts = rest.split('.').skip(usize::from(major > 0));
+    let minor = parts.next().and_then(|p| p.parse().ok()).unwrap_or(0);
+    let patch = parts.next().and_then(|p| p.parse().ok()).unwrap_or(0);
+    Some((major, minor, patch))
+}
+
+/// The newest spec release a server advertises in `/versions`.
+pub fn latest_spec_version(versions: &[String]) -> Option<String> {
+    versions
+        .iter()
+        .filter_map(|v| spec_version_key(v).map(|key| (key, v)))
+        .max_by_key(|(key, _)| *key)
+        .map(|(_, v)| v.clone())
+}
+
+/// Flattens the `m.login.terms` policies map, where every key besides
+/// `version` is a language code with the policy's name and URL.
+pub fn terms_policies_from_params(
+    policies: serde_json::Map<String, serde_json::Value>,
+) -> Vec<TermsPolicy> {
+    policies
+        .into_iter()
+        .filter_map(|(id, policy)| {
+            let policy = policy.as_object()?;
+            let version = policy
+                .get("version")
+                .and_then(|v| v.as_str())
+                .map(String::from);
+            let translations = policy
+                .iter()
+                .filter(|(key, _)| key.as_str() != "version")
+                .filter_map(|(lang, value)| {
+                    let translation: PolicyTranslation =
+                        serde_json::from_value(value.clone()).ok()?;
+                    Some((lang.clone(), translation))
+                })
+                .collect();
+
+            Some(TermsPolicy {
+                id,
+                version,
+                translations,
+            })
+        })
+        .collect()
+}
+
+/// Keeps a base URL from the client well-known (`m.identity_server`, the
+/// sliding sync proxy) only if it is an http(s) URL, without the trailing
+/// slash so every homeserver recommending one identity server links to one row.
+fn normalize_base_url(url: &str) -> Option<String> {
+    let parsed = reqwest::Url::parse(url.trim()).ok()?;
+    if !matches!(parsed.scheme(), "http" | "https") || parsed.host_str().is_none() {
+        return None;
+    }
+    Some(parsed.as_str().trim_end_matches('/').to_string())
+}
+
+/// The `m.homeserver` base URL a client well-known document delegates to.
+fn homeserver_base_url(well_known: &serde_json::Value) -> Option<String> {
+    well_known["m.homeserver"]["base_url"]
+        .as_str()
+        .and_then(normalize_base_url)
+}
+
+/// The sliding sync proxy a client well-known document advertises.
+fn sliding_sync_proxy_url(well_known: &serde_json::Value) -> Option<String> {
+    well_known[SLIDING_SYNC_PROXY_KEY]["url"]
+        .as_str()
+        .and_then(normalize_base_url)
+}
+
+/// Client capabilities recorded per server with the value the spec assumes
+/// when a server leaves them out of `/capabilities`.
+const CLIENT_CAPABILITY_DEFAULTS: [(&str, bool); 5] = [
+    ("m.change_password", true),
+    ("m.set_displayname", true),
+    ("m.set_avatar_url", true),
+    ("m.3pid_changes", true),
+    ("m.get_login_token", false),
+];
+
+/// Whether `mxid` is a well-formed user ID on `domain`, the syntax check
+/// applied to the contacts a server publishes in its support file.
+fn is_matrix_id_on_domain(mxid: &str, domain: &str) -> bool {
+    let Some((localpart, server_name)) =
+        mxid.strip_prefix('@').and_then(|rest| rest.split_once(':'))
+    else {
+        return false;
+    };
+
+    let host = server_name.split(':').next().unwrap_or(server_name);
+
+    !localpart.is_empty()
+        && !localpart.chars().any(|c| c.is_whitespace() || c == '@')
+        && host.eq_ignore_ascii_case(domain)
+}
+
+/// Whether a server rejected the endpoint itself rather than the request.
+fn is_unrecognized(payload: &ProbePayload) -> bool {
+    let errcode = payload
+        .body
+        .as_ref()
+        .and_then(|body| body.get("errcode"))
+        .and_then(|code| code.as_str());
+
+    matches!(payload.status, Some(404) | Some(405)) || errcode == Some("M_UNRECOGNIZED")
+}
+
+/// Authenticated media is enforced when the server recognises the
+/// `/_matrix/client/v1/media` endpoints and no longer the legacy
+/// `/_matrix/media/v3` ones, and not when it does not know the new ones or
+/// still serves the legacy config without a token. A legacy endpoint that
+/// only asks for a token leaves it unknown, as do probes without an answer.
+fn authenticated_media_enforced(payloads: &[ProbePayload]) -> Option<bool> {
+    let answered = |kind: ProbeKind| {
+        payloads
+            .iter()
+            .find(|p| p.probe == kind && p.status.is_some())
+    };
+    let authenticated = answered(ProbeKind::AuthenticatedMediaConfig).map(|p| !is_unrecognized(p));
+    let legacy = answered(ProbeKind::MediaConfig);
+
+    match (authenticated, legacy) {
+        (Some(false), _) => Some(false),
+        (Some(true), Some(legacy)) if is_unrecognized(legacy) => Some(true),
+        (_, Some(legacy)) if legacy.success_body().is_some() => Some(false),
+        _ => None,
+    }
+}
+
+fn headers_of_interest(headers: &reqwest::header::HeaderMap) -> serde_json::Value {
+    let mut map = serde_json::Map::new();
+    for name in HEADERS_OF_INTEREST {
+        if let Some(value) = headers.get(name).and_then(|v| v.to_str().ok()) {
+            map.insert(
+                name.to_string(),
+                serde_json::Value::String(value.to_string()),
+            );
+        }
+    }
+    serde_json::Value::Object(map)
+}
+
 #[derive(Deserialize)]
 struct WellKnownClientInfo {
     name: Option<String>,
     description: Option<String>,
     logo_url: Option<String>,
     theme: Option<String>,
+    #[serde(rename = "m.identity_server")]
+    identity_server: Option<WellKnownIdentityServer>,
+}
+
+#[derive(Deserialize)]
+struct WellKnownIdentityServer {
+    base_url: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -20,7 +230,13 @@ struct WellKnownServerInfo {
 
 #[derive(Deserialize)]
 struct FederationVersionInfo {
-    server: Option<String>,
+    server: Option<FederationServerSoftware>,
+}
+
+#[derive(Deserialize)]
+struct FederationServerSoftware {
+    name: Option<String>,
+    version: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -32,13 +248,15 @@ struct CapabilitiesResponse {
 #[derive(Deserialize)]
 struct Capabilities {
     #[serde(rename = "m.change_password")]
-    change_password: Option<ChangePassword>,
+    change_password: Option<BooleanCapability>,
     #[serde(rename = "m.room_versions")]
     room_versions: Option<RoomVersions>,
+    #[serde(flatten)]
+    other: serde_json::Map<String, serde_json::Value>,
 }
 
 #[derive(Deserialize)]
-struct ChangePassword {
+struct BooleanCapability {
     enabled: Option<bool>,
 }
 
@@ -47,6 +265,64 @@ struct RoomVersions {
     available: Option<Vec<String>>,
 }
 
+#[derive(Deserialize)]
+struct VersionsResponse {
+    versions: Option<Vec<String>>,
+    unstable_features: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+#[derive(Deserialize)]
+struct LoginFlowsResponse {
+    flows: Option<Vec<LoginFlow>>,
+}
+
+#[derive(Deserialize)]
+struct LoginFlow {
+    #[serde(rename = "type")]
+    kind: String,
+    identity_providers: Option<Vec<SsoProvider>>,
+}
+
+#[derive(Deserialize)]
+struct WellKnownSupportInfo {
+    contacts: Option<Vec<WellKnownSupportContact>>,
+    support_page: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct WellKnownSupportContact {
+    matrix_id: Option<String>,
+    email_address: Option<String>,
+    role: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RegistrationFlowsResponse {
+    params: Option<RegistrationParams>,
+}
+
+#[derive(Deserialize)]
+struct RegistrationParams {
+    #[serde(rename = "m.login.terms")]
+    terms: Option<TermsParams>,
+}
+
+#[derive(Deserialize)]
+struct TermsParams {
+    policies: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+#[derive(Deserialize)]
+struct MediaConfigResponse {
+    #[serde(rename = "m.upload.size")]
+    upload_size: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct AuthIssuerResponse {
+    issuer: Option<String>,
+}
+
 #[derive(Deserialize)]
 struct PublicRoomsResponse {
     #[serde(rename = "total_room_count_estimate")]
@@ -54,192 +330,752 @@ struct PublicRoomsResponse {
 }
 
 impl MatrixService {
-    pub async fn check_server_status(
-        server: &str,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let server_url = format!("https://{}", server);
-        let http_client = get_http_client();
+    /// Fetches `/_matrix/client/versions` once, the check behind the live
+    /// probe action and the uptime history. It goes to the client API base
+    /// URL the server delegates to, like the discovery probes.
+    pub async fn check_server(domain: &str) -> ProbePayload {
+        let deadline = probe_timeout();
+        let well_known = Self::fetch_probe(
+            ProbeKind::WellKnownClient,
+            format!("https://{}/.well-known/matrix/client", domain),
+            deadline,
+        )
+        .await;
+        let base_url = Self::client_base_url(domain, &well_known).await;
+
+        Self::fetch_probe(
+            ProbeKind::ClientVersions,
+            format!("{}/_matrix/client/versions", base_url),
+            deadline,
+        )
+        .await
+    }
 
-        let versions_url = format!("{}/_matrix/client/versions", server_url);
-        let response = http_client.get(&versions_url).send().await?;
+    /// Probes `domain` and derives the stored server fields from the raw
+    /// responses, which are returned alongside so callers can persist them.
+    /// Probes fail independently; discovery only fails when none of them got
+    /// an HTTP response at all.
+    pub async fn discover_server_info(
+        domain: &str,
+    ) -> Result<(DiscoveredServerInfo, Vec<ProbePayload>), Box<dyn std::error::Error + Send + Sync>>
+    {
+        let payloads = Self::probe_server(domain).await;
 
-        if response.status().is_success() {
-            Ok(())
-        } else {
-            Err(format!("Server {} returned status {}", server, response.status()).into())
+        if let Some(error) = Self::unreachable_error(domain, &payloads) {
+            return Err(error.into());
         }
+
+        let info = Self::derive_server_info(domain, &payloads);
+        Ok((info, payloads))
     }
 
-    pub async fn discover_server_info(
-        domain: &str,
-    ) -> Result<DiscoveredServerInfo, Box<dyn std::error::Error + Send + Sync>> {
-        let server_url = format!("https://{}", domain);
-        let http_client = get_http_client();
-        let http_client_clone = http_client.clone();
+    /// The reason discovery fails when none of the probes got an HTTP
+    /// response at all.
+    pub fn unreachable_error(domain: &str, payloads: &[ProbePayload]) -> Option<String> {
+        if payloads.iter().any(|p| p.status.is_some()) {
+            return None;
+        }
 
-        let capabilities = Self::get_capabilities(&server_url, http_client_clone).await;
+        let reason = payloads
+            .iter()
+            .find_map(|p| p.error.as_deref())
+            .unwrap_or("no probes were run");
+        Some(format!("Server {} is unreachable: {}", domain, reason))
+    }
 
-        let registration_open = capabilities
-            .as_ref()
-            .and_then(|c| c.capabilities.as_ref())
-            .and_then(|c| c.change_password.as_ref())
-            .and_then(|c| c.enabled);
+    /// The client API base URL of `domain`: the `m.homeserver` base URL of
+    /// its client well-known document when that points at a public address,
+    /// otherwise the domain itself.
+    pub async fn client_base_url(domain: &str, well_known: &ProbePayload) -> String {
+        let fallback = format!("https://{}", domain);
 
-        let room_versions = capabilities
-            .as_ref()
-            .and_then(|c| c.capabilities.as_ref())
-            .and_then(|c| c.room_versions.as_ref())
-            .and_then(|r| r.available.as_ref())
-            .map(|v| v.join(","));
+        let Some(base_url) = well_known.success_body().and_then(homeserver_base_url) else {
+            return fallback;
+        };
 
-        let http_client_clone2 = http_client.clone();
-        let public_rooms_count = Self::get_public_rooms_count(&server_url, http_client_clone2)
-            .await
-            .ok();
+        match resolve_target(&base_url, false).await {
+            Ok(_) => base_url,
+            Err(e) => {
+                debug!("Not using base URL {} of {}: {}", base_url, domain, e);
+                fallback
+            }
+        }
+    }
 
-        let (name, description, logo_url, theme) =
-            Self::fetch_well_known_client_info(domain).await?;
+    /// Runs every discovery probe against `domain` concurrently, each under
+    /// its own deadline, keeping each raw response and how long it took.
+    /// Client API probes go to the base URL the client well-known document
+    /// delegates to, so they wait for it; versioned client endpoints also
+    /// wait for `/versions` to pick their paths.
+    pub async fn probe_server(domain: &str) -> Vec<ProbePayload> {
+        let domain_url = format!("https://{}", domain);
+        let deadline = probe_timeout();
+
+        let client_probes = async {
+            let well_known = Self::fetch_probe(
+                ProbeKind::WellKnownClient,
+                format!("{}/.well-known/matrix/client", domain_url),
+                deadline,
+            )
+            .await;
+            let base_url = Self::client_base_url(domain, &well_known).await;
+
+            let other_client_probes = futures::future::join_all(
+                [
+                    (ProbeKind::AuthIssuer, MSC2965_AUTH_ISSUER_PATH),
+                    (ProbeKind::MediaConfig, LEGACY_MEDIA_CONFIG_PATH),
+                    (
+                        ProbeKind::AuthenticatedMediaConfig,
+                        AUTHENTICATED_MEDIA_CONFIG_PATH,
+                    ),
+                ]
+                .into_iter()
+                .map(|(probe, path)| {
+                    Self::fetch_probe(probe, format!("{}{}", base_url, path), deadline)
+                }),
+            );
+
+            let (mut payloads, other, proxy, auth_metadata) = tokio::join!(
+                Self::fetch_versioned_client_probes(&base_url, deadline),
+                other_client_probes,
+                Self::fetch_sliding_sync_proxy(&well_known, deadline),
+                Self::fetch_auth_metadata(&base_url, deadline)
+            );
+            payloads.extend(other);
+            payloads.push(well_known);
+            payloads.extend(proxy);
+            payloads.push(auth_metadata);
+            payloads
+        };
 
-        let version = Self::get_server_version(domain).await.ok();
-        let federation_version = Self::get_federation_version(domain).await.ok();
-        let delegated_server = Self::fetch_well_known_server_info(domain).await?;
+        let server_probes = futures::future::join_all(
+            [
+                (
+                    ProbeKind::FederationVersion,
+                    "/_matrix/federation/v1/version",
+                ),
+                (ProbeKind::WellKnownServer, "/.well-known/matrix/server"),
+                (ProbeKind::WellKnownSupport, "/.well-known/matrix/support"),
+            ]
+            .into_iter()
+            .map(|(probe, path)| {
+                Self::fetch_probe(probe, format!("{}{}", domain_url, path), deadline)
+            }),
+        );
 
-        Ok(DiscoveredServerInfo {
-            name,
-            description,
-            logo_url,
-            theme,
-            registration_open,
-            public_rooms_count,
-            version,
-            federation_version,
-            delegated_server,
-            room_versions,
-        })
+        let (mut payloads, other) = tokio::join!(client_probes, server_probes);
+        payloads.extend(other);
+
+        debug!(
+            "Probed {} in {}",
+            domain,
+            payloads
+                .iter()
+                .map(|p| format!("{}={}ms", p.probe.as_str(), p.duration_ms.unwrap_or(0)))
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+
+        payloads
+    }
+
+    /// Fetches `/versions` and then the versioned client endpoints on the
+    /// generation it negotiates.
+    async fn fetch_versioned_client_probes(
+        base_url: &str,
+        deadline: Duration,
+    ) -> Vec<ProbePayload> {
+        let versions = Self::fetch_probe(
+            ProbeKind::ClientVersions,
+            format!("{}/_matrix/client/versions", base_url),
+            deadline,
+        )
+        .await;
+
+        let api = versions
+            .success_body()
+            .and_then(|body| serde_json::from_value::<VersionsResponse>(body.clone()).ok())
+            .and_then(|data| ClientApiVersion::negotiate(&data.versions.unwrap_or_default()));
+
+        let (capabilities, public_rooms, login, registration, protocols) = tokio::join!(
+            Self::fetch_client_probe(
+                ProbeKind::Capabilities,
+                base_url,
+                "/capabilities",
+                api,
+                deadline
+            ),
+            Self::fetch_client_probe(
+                ProbeKind::PublicRooms,
+                base_url,
+                "/publicRooms?limit=20",
+                api,
+                deadline
+            ),
+            Self::fetch_client_probe(ProbeKind::LoginFlows, base_url, "/login", api, deadline),
+            Self::fetch_client_probe(
+                ProbeKind::Registration,
+                base_url,
+                "/register",
+                api,
+                deadline
+            ),
+            Self::fetch_client_probe(
+                ProbeKind::ThirdPartyProtocols,
+                base_url,
+                "/thirdparty/protocols",
+                api,
+                deadline
+            ),
+        );
+
+        vec![
+            versions,
+            capabilities,
+            public_rooms,
+            login,
+            registration,
+            protocols,
+        ]
     }
 
-    async fn get_capabilities(
-        server_url: &str,
-        http_client: reqwest::Client,
-    ) -> Option<CapabilitiesResponse> {
-        let url = format!("{}/_matrix/client/r0/capabilities", server_url);
-        match http_client.get(&url).send().await {
-            Ok(response) if response.status().is_success() => response.json().await.ok(),
-            _ => None,
+    /// When the client well-known document advertises a sliding sync proxy,
+    /// checks the proxy answers an unauthenticated sync request. Proxies that
+    /// resolve to a non-public address are not contacted.
+    async fn fetch_sliding_sync_proxy(
+        well_known: &ProbePayload,
+        deadline: Duration,
+    ) -> Option<ProbePayload> {
+        let proxy_url = well_known.success_body().and_then(sliding_sync_proxy_url)?;
+        if let Err(e) = resolve_target(&proxy_url, false).await {
+            debug!("Not probing sliding sync proxy {}: {}", proxy_url, e);
+            return None;
         }
+
+        Some(
+            Self::fetch_probe(
+                ProbeKind::SlidingSyncProxy,
+                format!("{}{}", proxy_url, SLIDING_SYNC_PATH),
+                deadline,
+            )
+            .await,
+        )
     }
 
-    async fn get_public_rooms_count(
-        server_url: &str,
-        http_client: reqwest::Client,
-    ) -> Result<i32, Box<dyn std::error::Error + Send + Sync>> {
-        let url = format!("{}/_matrix/client/r0/publicRooms?limit=1", server_url);
+    /// Fetches the OAuth 2.0 server metadata of servers delegating auth to an
+    /// OIDC provider, preferring the stable endpoint over the MSC2965 one.
+    async fn fetch_auth_metadata(base_url: &str, deadline: Duration) -> ProbePayload {
+        let payload = Self::fetch_probe(
+            ProbeKind::AuthMetadata,
+            format!("{}{}", base_url, AUTH_METADATA_PATH),
+            deadline,
+        )
+        .await;
+
+        if is_unrecognized(&payload) {
+            return Self::fetch_probe(
+                ProbeKind::AuthMetadata,
+                format!("{}{}", base_url, MSC2965_AUTH_METADATA_PATH),
+                deadline,
+            )
+            .await;
+        }
+
+        payload
+    }
+
+    /// Fetches a versioned client endpoint on the negotiated generation. When
+    /// `/versions` gave nothing to negotiate with, `v3` is tried first and
+    /// `r0` only if the server does not recognise it.
+    async fn fetch_client_probe(
+        probe: ProbeKind,
+        base_url: &str,
+        endpoint: &str,
+        api: Option<ClientApiVersion>,
+        deadline: Duration,
+    ) -> ProbePayload {
+        let url = |version: ClientApiVersion| {
+            format!("{}{}{}", base_url, version.path_prefix(), endpoint)
+        };
 
-        let response = http_client.get(&url).send().await?;
+        let payload =
+            Self::fetch_probe(probe, url(api.unwrap_or(ClientApiVersion::V3)), deadline).await;
 
-        if !response.status().is_success() {
-            return Err("Failed to get public rooms".into());
+        if api.is_none() && is_unrecognized(&payload) {
+            return Self::fetch_probe(probe, url(ClientApiVersion::R0), deadline).await;
         }
 
-        let data: PublicRoomsResponse = response.json().await?;
+        payload
+    }
+
+    async fn fetch_probe(probe: ProbeKind, url: String, deadline: Duration) -> ProbePayload {
+        let fetched_at = chrono::Utc::now().naive_utc();
+        let started = Instant::now();
+
+        let result = tokio::time::timeout(deadline, Self::fetch_response(probe, &url)).await;
+        let duration_ms = Some(u32::try_from(started.elapsed().as_millis()).unwrap_or(u32::MAX));
+
+        let (status, headers, body, error) = match result {
+            Ok(Ok(response)) => response,
+            Ok(Err(e)) => (None, serde_json::json!({}), None, Some(e.to_string())),
+            Err(_) => (
+                None,
+                serde_json::json!({}),
+                None,
+                Some(format!("timed out after {}ms", deadline.as_millis())),
+            ),
+        };
 
-        Ok(data.total_room_count_estimate.unwrap_or(0) as i32)
+        ProbePayload {
+            probe,
+            url,
+            status,
+            headers,
+            body,
+            error,
+            fetched_at,
+            duration_ms,
+        }
     }
 
-    async fn fetch_well_known_client_info(
-        domain: &str,
+    /// Fetches `url`, returning its status, headers of interest and body, or
+    /// an error describing why reading the body failed. The registration probe
+    /// posts an empty body, which only starts user-interactive auth and gets
+    /// the flows back as a 401 without creating anything; the sliding sync
+    /// proxy probe posts one without a token and expects to be refused.
+    async fn fetch_response(
+        probe: ProbeKind,
+        url: &str,
     ) -> Result<
         (
-            Option<String>,
-            Option<String>,
-            Option<String>,
+            Option<u16>,
+            serde_json::Value,
+            Option<serde_json::Value>,
             Option<String>,
         ),
-        Box<dyn std::error::Error + Send + Sync>,
+        reqwest::Error,
     > {
-        let well_known_url = format!("https://{}/.well-known/matrix/client", domain);
+        let request = match probe {
+            ProbeKind::Registration | ProbeKind::SlidingSyncProxy => {
+                get_http_client().post(url).json(&serde_json::json!({}))
+            }
+            _ => get_http_client().get(url),
+        };
 
-        let http_client = get_http_client();
+        let response = request.send().await?;
+        let status = response.status().as_u16();
+        let headers = headers_of_interest(response.headers());
+
+        let (body, error) = match response.text().await {
+            Ok(text) => match serde_json::from_str::<serde_json::Value>(&text) {
+                Ok(json) => (Some(json), None),
+                Err(_) => {
+                    let mut text = text;
+                    if text.len() > MAX_RAW_BODY_BYTES {
+                        let mut cut = MAX_RAW_BODY_BYTES;
+                        while !text.is_char_boundary(cut) {
+                            cut -= 1;
+                        }
+                        text.truncate(cut);
+                    }
+                    (Some(serde_json::Value::String(text)), None)
+                }
+            },
+            Err(e) => (None, Some(e.to_string())),
+        };
 
-        let response = http_client.get(&well_known_url).send().await?;
+        Ok((Some(status), headers, body, error))
+    }
 
-        if !response.status().is_success() {
-            return Ok((None, None, None, None));
-        }
+    /// Derives the `servers` columns from probe payloads without touching the
+    /// network, so stored payloads can be reprocessed when extraction changes.
+    /// Fields whose probe gave nothing usable are listed in `missing_fields`.
+    pub fn derive_server_info(domain: &str, payloads: &[ProbePayload]) -> DiscoveredServerInfo {
+        let mut missing = Vec::new();
+
+        let capabilities: Option<CapabilitiesResponse> = Self::parse_probe(
+            payloads,
+            ProbeKind::Capabilities,
+            &["registration_open", "room_versions"],
+            &mut missing,
+        );
 
-        let well_known: WellKnownClientInfo = response.json().await?;
+        let registration_open = capabilities
+            .as_ref()
+            .and_then(|c| c.capabilities.as_ref())
+            .and_then(|c| c.change_password.as_ref())
+            .and_then(|c| c.enabled);
 
-        Ok((
-            well_known.name,
-            well_known.description,
-            well_known.logo_url,
-            well_known.theme,
-        ))
-    }
+        let room_versions = capabilities
+            .as_ref()
+            .and_then(|c| c.capabilities.as_ref())
+            .and_then(|c| c.room_versions.as_ref())
+            .and_then(|r| r.available.as_ref())
+            .map(|v| v.join(","));
 
-    async fn fetch_well_known_server_info(
-        domain: &str,
-    ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
-        let well_known_url = format!("https://{}/.well-known/matrix/server", domain);
+        let client_capabilities = capabilities
+            .as_ref()
+            .and_then(|c| c.capabilities.as_ref())
+            .map(|c| {
+                CLIENT_CAPABILITY_DEFAULTS
+                    .iter()
+                    .map(|&(name, default)| {
+                        let enabled = match name {
+                            "m.change_password" => {
+                                c.change_password.as_ref().and_then(|p| p.enabled)
+                            }
+                            _ => c
+                                .other
+                                .get(name)
+                                .cloned()
+                                .and_then(|v| serde_json::from_value::<BooleanCapability>(v).ok())
+                                .and_then(|v| v.enabled),
+                        };
+                        (name.to_string(), enabled.unwrap_or(default))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let public_rooms_count = Self::parse_probe::<PublicRoomsResponse>(
+            payloads,
+            ProbeKind::PublicRooms,
+            &["public_rooms_count"],
+            &mut missing,
+        )
+        .map(|data| data.total_room_count_estimate.unwrap_or(0) as i32);
+
+        let well_known_client: Option<WellKnownClientInfo> = Self::parse_probe(
+            payloads,
+            ProbeKind::WellKnownClient,
+            &["name", "description", "logo_url", "theme"],
+            &mut missing,
+        );
+
+        let versions: Option<VersionsResponse> = Self::parse_probe(
+            payloads,
+            ProbeKind::ClientVersions,
+            &[
+                "version",
+                "spec_versions",
+                "latest_spec_version",
+                "unstable_features",
+            ],
+            &mut missing,
+        );
+
+        let version = versions
+            .as_ref()
+            .map(|data| data.versions.clone().unwrap_or_default().join(", "));
+
+        let (spec_versions, unstable_features) = match versions {
+            Some(data) => (
+                data.versions.unwrap_or_default(),
+                data.unstable_features.unwrap_or_default(),
+            ),
+            None => (Vec::new(), serde_json::Map::new()),
+        };
+        let latest_spec_version = latest_spec_version(&spec_versions);
+
+        let sliding_sync_proxy = payloads
+            .iter()
+            .find(|p| p.probe == ProbeKind::WellKnownClient)
+            .and_then(|p| p.success_body())
+            .and_then(sliding_sync_proxy_url);
+
+        // Without a token the proxy refuses the sync with a 401, which shows
+        // it is up and speaking Matrix. A proxy that was not probed, being on
+        // a non-public address, is left unknown.
+        let sliding_sync_proxy_reachable = sliding_sync_proxy
+            .as_ref()
+            .and_then(|_| {
+                payloads
+                    .iter()
+                    .find(|p| p.probe == ProbeKind::SlidingSyncProxy)
+            })
+            .map(|p| {
+                p.status
+                    .is_some_and(|status| status == 401 || (200..300).contains(&status))
+            });
+
+        let sliding_sync = if NATIVE_SLIDING_SYNC_FEATURES
+            .iter()
+            .any(|feature| unstable_features.get(*feature) == Some(&serde_json::Value::Bool(true)))
+        {
+            Some(SlidingSync::Native)
+        } else if sliding_sync_proxy.is_some() {
+            Some(SlidingSync::Proxy)
+        } else {
+            None
+        };
 
-        let http_client = get_http_client();
+        let software = Self::parse_probe::<FederationVersionInfo>(
+            payloads,
+            ProbeKind::FederationVersion,
+            &["implementation", "federation_version"],
+            &mut missing,
+        )
+        .and_then(|info| info.server);
+        let (implementation, federation_version) = match software {
+            Some(software) => (software.name, software.version),
+            None => (None, None),
+        };
 
-        let response = http_client.get(&well_known_url).send().await?;
+        let delegated_server = Self::parse_probe::<WellKnownServerInfo>(
+            payloads,
+            ProbeKind::WellKnownServer,
+            &["delegated_server"],
+            &mut missing,
+        )
+        .and_then(|w| w.m_server);
+
+        let login: Option<LoginFlowsResponse> = Self::parse_probe(
+            payloads,
+            ProbeKind::LoginFlows,
+            &["login_flows", "sso_providers"],
+            &mut missing,
+        );
 
-        if !response.status().is_success() {
-            return Ok(None);
+        let mut login_flows = Vec::new();
+        let mut sso_providers = Vec::new();
+        for flow in login.and_then(|l| l.flows).unwrap_or_default() {
+            sso_providers.extend(flow.identity_providers.unwrap_or_default());
+            if !login_flows.contains(&flow.kind) {
+                login_flows.push(flow.kind);
+            }
         }
 
-        let well_known: WellKnownServerInfo = response.json().await?;
+        // Both auth endpoints answer 404 on servers without OIDC, which is a
+        // result rather than a missing field, so neither feeds `missing`.
+        let oidc_issuer = Self::parse_probe::<AuthIssuerResponse>(
+            payloads,
+            ProbeKind::AuthMetadata,
+            &[],
+            &mut missing,
+        )
+        .and_then(|m| m.issuer)
+        .or_else(|| {
+            Self::parse_probe::<AuthIssuerResponse>(
+                payloads,
+                ProbeKind::AuthIssuer,
+                &[],
+                &mut missing,
+            )
+            .and_then(|m| m.issuer)
+        });
+
+        // Both media config endpoints normally want an access token, so a 401
+        // is the expected answer and does not make the upload size missing.
+        let media_upload_size = [ProbeKind::AuthenticatedMediaConfig, ProbeKind::MediaConfig]
+            .into_iter()
+            .find_map(|kind| {
+                Self::parse_probe::<MediaConfigResponse>(payloads, kind, &[], &mut missing)
+                    .and_then(|config| config.upload_size)
+            });
+
+        let authenticated_media_enforced = authenticated_media_enforced(payloads);
+
+        let support: Option<WellKnownSupportInfo> = Self::parse_probe(
+            payloads,
+            ProbeKind::WellKnownSupport,
+            &["support_contacts", "support_page"],
+            &mut missing,
+        );
 
-        Ok(well_known.m_server)
-    }
+        let (support_contacts, support_page) = match support {
+            Some(s) => (
+                s.contacts
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|c| SupportContact {
+                        matrix_id_on_domain: c
+                            .matrix_id
+                            .as_deref()
+                            .map(|mxid| is_matrix_id_on_domain(mxid, domain)),
+                        matrix_id: c.matrix_id,
+                        email_address: c.email_address,
+                        role: c.role,
+                    })
+                    .collect(),
+                s.support_page,
+            ),
+            None => (Vec::new(), None),
+        };
 
-    pub async fn get_server_version(
-        server: &str,
-    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
-        let server_url = format!("https://{}/_matrix/client/versions", server);
+        let terms_policies = payloads
+            .iter()
+            .find(|p| p.probe == ProbeKind::Registration)
+            .and_then(|p| p.body.clone())
+            .and_then(|body| serde_json::from_value::<RegistrationFlowsResponse>(body).ok())
+            .and_then(|r| r.params)
+            .and_then(|p| p.terms)
+            .and_then(|t| t.policies)
+            .map(terms_policies_from_params)
+            .unwrap_or_default();
+
+        let client_api_version = [ProbeKind::Capabilities, ProbeKind::PublicRooms]
+            .into_iter()
+            .filter_map(|kind| payloads.iter().find(|p| p.probe == kind))
+            .filter(|p| p.success_body().is_some())
+            .find_map(|p| ClientApiVersion::from_url(&p.url));
+
+        let (name, description, logo_url, theme, identity_server) = match well_known_client {
+            Some(w) => (
+                w.name,
+                w.description,
+                w.logo_url,
+                w.theme,
+                w.identity_server
+                    .and_then(|is| is.base_url)
+                    .and_then(|url| normalize_base_url(&url)),
+            ),
+            None => (None, None, None, None, None),
+        };
 
-        let http_client = get_http_client();
+        DiscoveredServerInfo {
+            name,
+            description,
+            logo_url,
+            theme,
+            registration_open,
+            public_rooms_count,
+            version,
+            federation_version,
+            delegated_server,
+            room_versions,
+            missing_fields: missing,
+            client_api_version,
+            spec_versions,
+            latest_spec_version,
+            unstable_features: serde_json::Value::Object(unstable_features),
+            login_flows,
+            sso_providers,
+            oidc_issuer,
+            support_contacts,
+            support_page,
+            terms_policies,
+            identity_server,
+            sliding_sync,
+            sliding_sync_proxy,
+            sliding_sync_proxy_reachable,
+            client_capabilities,
+            media_upload_size,
+            authenticated_media_enforced,
+            implementation,
+        }
+    }
 
-        let response = http_client.get(&server_url).send().await?;
+    /// Parses the payload of one probe. When it failed, returned a non-success
+    /// status or a malformed document, every field it feeds is recorded in
+    /// `missing` with the reason.
+    fn parse_probe<T: serde::de::DeserializeOwned>(
+        payloads: &[ProbePayload],
+        kind: ProbeKind,
+        fields: &[&str],
+        missing: &mut Vec<MissingField>,
+    ) -> Option<T> {
+        let parsed = match payloads.iter().find(|p| p.probe == kind) {
+            None => Err("not probed".to_string()),
+            Some(payload) => match (&payload.error, payload.status, payload.success_body()) {
+                (Some(error), _, _) => Err(format!("request failed: {}", error)),
+                (None, _, Some(body)) => serde_json::from_value(body.clone())
+                    .map_err(|e| format!("invalid response: {}", e)),
+                (None, Some(status), None) => Err(format!("HTTP {}", status)),
+                (None, None, None) => Err("no response".to_string()),
+            },
+        };
 
-        if !response.status().is_success() {
-            return Err("Failed to get server version".into());
+        match parsed {
+            Ok(value) => Some(value),
+            Err(reason) => {
+                missing.extend(fields.iter().map(|field| MissingField {
+                    field: field.to_string(),
+                    probe: kind.as_str().to_string(),
+                    reason: reason.clone(),
+                }));
+                None
+            }
         }
+    }
 
-        #[derive(Deserialize)]
-        struct VersionsResponse {
-            versions: Option<Vec<String>>,
+    /// Re-derives a server's columns from its latest stored probe payloads.
+    /// Returns `Ok(false)` when nothing has been stored for the server yet.
+    pub fn reprocess_server(
+        conn: &mut PgConnection,
+        server: &Server,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let payloads: Vec<ProbePayload> = get_latest_probe_payloads(conn, server.id)?
+            .into_iter()
+            .filter_map(|row| row.into_payload())
+            .collect();
+
+        if payloads.is_empty() {
+            return Ok(false);
         }
 
-        let data: VersionsResponse = response.json().await?;
+        let info = Self::derive_server_info(&server.domain, &payloads);
+        let changes = NewServer::from_discovered(&server.domain, &info);
 
-        Ok(data.versions.unwrap_or_default().join(", "))
+        update_server(conn, server.id, &changes)?;
+        link_identity_server(conn, server.id, info.identity_server.as_deref())?;
+        record_bridges_from_payloads(conn, server.id, &server.domain, &payloads)?;
+        Ok(true)
     }
 
-    pub async fn get_federation_version(
-        server: &str,
-    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
-        let federation_url = format!("https://{}/_matrix/federation/v1/version", server);
+    /// Reprocesses every indexed server from stored payloads. Servers without
+    /// payloads are skipped; derivation or database failures are counted.
+    pub fn reprocess_all_servers(
+        conn: &mut PgConnection,
+    ) -> Result<ReprocessResponse, diesel::result::Error> {
+        let mut result = ReprocessResponse {
+            reprocessed: 0,
+            skipped: 0,
+            failed: 0,
+        };
+
+        for server in get_all_servers(conn)? {
+            match Self::reprocess_server(conn, &server) {
+                Ok(true) => result.reprocessed += 1,
+                Ok(false) => result.skipped += 1,
+                Err(e) => {
+                    warn!("Failed to reprocess {}: {}", server.domain, e);
+                    result.failed += 1;
+                }
+            }
+        }
 
-        let http_client = get_http_client();
+        Ok(result)
+    }
 
-        let response = http_client.get(&federation_url).send().await?;
+    /// Runs the follow-up checks stored alongside a server row: signing key
+    /// tracking, the well-known linter and the recommended identity server.
+    /// Failures are logged, not returned.
+    pub async fn record_server_checks(db_pool: &DbPool, server: &Server) {
+        let (server_id, domain) = (server.id, server.domain.as_str());
 
-        if !response.status().is_success() {
-            return Err("Failed to get federation version".into());
+        if let Err(e) = refresh_server_keys(db_pool, server_id, domain).await {
+            warn!("Failed to fetch signing keys for {}: {}", domain, e);
         }
 
-        let info: FederationVersionInfo = response.json().await?;
+        if let Err(e) = lint_and_store(db_pool, server_id, domain).await {
+            warn!("Failed to lint well-known documents for {}: {}", domain, e);
+        }
 
-        Ok(info.server.unwrap_or_default())
+        if let Err(e) =
+            refresh_identity_server(db_pool, server_id, server.identity_server.as_deref()).await
+        {
+            warn!("Failed to check identity server for {}: {}", domain, e);
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use httptest::matchers::request;
+    use httptest::responders::{delay_and_then, json_encoded, status_code};
+    use httptest::{Expectation, Server};
+    use std::collections::BTreeMap;
 
     #[test]
     fn test_server_url_format() {
@@ -291,6 +1127,25 @@ mod tests {
             federation_version: None,
             delegated_server: None,
             room_versions: None,
+            missing_fields: vec![],
+            client_api_version: None,
+            spec_versions: vec![],
+            latest_spec_version: None,
+            unstable_features: serde_json::json!({}),
+            login_flows: vec![],
+            sso_providers: vec![],
+            oidc_issuer: None,
+            support_contacts: vec![],
+            support_page: None,
+            terms_policies: vec![],
+            identity_server: None,
+            sliding_sync: None,
+            sliding_sync_proxy: None,
+            sliding_sync_proxy_reachable: None,
+            client_capabilities: BTreeMap::new(),
+            media_upload_size: None,
+            authenticated_media_enforced: None,
+            implementation: None,
         };
 
         assert!(info.name.is_none());
@@ -311,9 +1166,28 @@ mod tests {
             registration_open: Some(true),
             public_rooms_count: Some(100),
             version: Some("v1.11".to_string()),
-            federation_version: Some("Synapse/1.99".to_string()),
+            federation_version: Some("1.99.0".to_string()),
             delegated_server: Some("test.org:8448".to_string()),
             room_versions: Some("1,2,6".to_string()),
+            missing_fields: vec![],
+            client_api_version: None,
+            spec_versions: vec![],
+            latest_spec_version: None,
+            unstable_features: serde_json::json!({}),
+            login_flows: vec![],
+            sso_providers: vec![],
+            oidc_issuer: None,
+            support_contacts: vec![],
+            support_page: None,
+            terms_policies: vec![],
+            identity_server: None,
+            sliding_sync: None,
+            sliding_sync_proxy: None,
+            sliding_sync_proxy_reachable: None,
+            client_capabilities: BTreeMap::new(),
+            media_upload_size: None,
+            authenticated_media_enforced: None,
+            implementation: None,
         };
 
         assert_eq!(info.name, Some("Test Server".to_string()));
@@ -345,9 +1219,534 @@ mod tests {
 
     #[test]
     fn test_federation_version_info_deserialization() {
-        let json = r#"{"server": "Synapse/1.99.0"}"#;
+        let json = r#"{"server": {"name": "Synapse", "version": "1.110.0"}}"#;
         let info: FederationVersionInfo = serde_json::from_str(json).unwrap();
+        let server = info.server.unwrap();
+
+        assert_eq!(server.name, Some("Synapse".to_string()));
+        assert_eq!(server.version, Some("1.110.0".to_string()));
+    }
+
+    fn payload(probe: ProbeKind, status: Option<u16>, body: serde_json::Value) -> ProbePayload {
+        ProbePayload {
+            probe,
+            url: String::new(),
+            status,
+            headers: serde_json::json!({}),
+            body: Some(body),
+            error: None,
+            fetched_at: chrono::Utc::now().naive_utc(),
+            duration_ms: Some(12),
+        }
+    }
+
+    #[test]
+    fn test_derive_server_info_from_payloads() {
+        let payloads = vec![
+            payload(
+                ProbeKind::Capabilities,
+                Some(200),
+                serde_json::json!({"capabilities": {
+                    "m.change_password": {"enabled": true},
+                    "m.room_versions": {"available": ["9", "10"]}
+                }}),
+            ),
+            payload(
+                ProbeKind::ClientVersions,
+                Some(200),
+                serde_json::json!({
+                    "versions": ["r0.6.1", "v1.11", "v1.2"],
+                    "unstable_features": {"org.matrix.msc3575": true}
+                }),
+            ),
+            payload(
+                ProbeKind::WellKnownClient,
+                Some(200),
+                serde_json::json!({
+                    "name": "Test Server",
+                    "m.identity_server": {"base_url": "https://vector.im/"}
+                }),
+            ),
+            payload(
+                ProbeKind::WellKnownServer,
+                Some(404),
+                serde_json::json!({"errcode": "M_NOT_FOUND"}),
+            ),
+        ];
+
+        let info = MatrixService::derive_server_info("test.org", &payloads);
+
+        assert_eq!(info.registration_open, Some(true));
+        assert_eq!(info.room_versions, Some("9,10".to_string()));
+        assert_eq!(info.version, Some("r0.6.1, v1.11, v1.2".to_string()));
+        assert_eq!(info.spec_versions, vec!["r0.6.1", "v1.11", "v1.2"]);
+        assert_eq!(info.latest_spec_version, Some("v1.11".to_string()));
+        assert_eq!(
+            info.unstable_features,
+            serde_json::json!({"org.matrix.msc3575": true})
+        );
+        assert_eq!(info.name, Some("Test Server".to_string()));
+        assert_eq!(info.identity_server, Some("https://vector.im".to_string()));
+        assert!(info.delegated_server.is_none());
+        assert!(info.public_rooms_count.is_none());
+
+        let delegated = info
+            .missing_fields
+            .iter()
+            .find(|m| m.field == "delegated_server")
+            .unwrap();
+        assert_eq!(delegated.probe, "well_known_server");
+        assert_eq!(delegated.reason, "HTTP 404");
+
+        let public_rooms = info
+            .missing_fields
+            .iter()
+            .find(|m| m.field == "public_rooms_count")
+            .unwrap();
+        assert_eq!(public_rooms.reason, "not probed");
+    }
+
+    #[test]
+    fn test_derive_server_info_keeps_fields_of_working_probes() {
+        let mut unreachable = payload(ProbeKind::WellKnownClient, None, serde_json::Value::Null);
+        unreachable.body = None;
+        unreachable.error = Some("connection refused".to_string());
+
+        let payloads = vec![
+            unreachable,
+            payload(
+                ProbeKind::WellKnownServer,
+                Some(200),
+                serde_json::Value::String("<html></html>".to_string()),
+            ),
+        ];
+
+        let info = MatrixService::derive_server_info("test.org", &payloads);
+
+        let reason = |field: &str| {
+            info.missing_fields
+                .iter()
+                .find(|m| m.field == field)
+                .map(|m| m.reason.clone())
+                .unwrap()
+        };
+        assert_eq!(reason("name"), "request failed: connection refused");
+        assert_eq!(reason("theme"), "request failed: connection refused");
+        assert!(reason("delegated_server").starts_with("invalid response"));
+    }
+
+    #[test]
+    fn test_derive_auth_methods() {
+        let payloads = vec![
+            payload(
+                ProbeKind::LoginFlows,
+                Some(200),
+                serde_json::json!({"flows": [
+                    {"type": "m.login.password"},
+                    {"type": "m.login.sso", "identity_providers": [
+                        {"id": "oidc-github", "name": "GitHub", "brand": "github"}
+                    ]},
+                    {"type": "m.login.token"}
+                ]}),
+            ),
+            payload(
+                ProbeKind::AuthMetadata,
+                Some(404),
+                serde_json::json!({"errcode": "M_UNRECOGNIZED"}),
+            ),
+            payload(
+                ProbeKind::AuthIssuer,
+                Some(200),
+                serde_json::json!({"issuer": "https://account.example.org/"}),
+            ),
+        ];
+
+        let info = MatrixService::derive_server_info("test.org", &payloads);
+
+        assert_eq!(
+            info.login_flows,
+            vec!["m.login.password", "m.login.sso", "m.login.token"]
+        );
+        assert_eq!(info.sso_providers.len(), 1);
+        assert_eq!(info.sso_providers[0].brand, Some("github".to_string()));
+        assert_eq!(
+            info.oidc_issuer,
+            Some("https://account.example.org/".to_string())
+        );
+        assert!(!info
+            .missing_fields
+            .iter()
+            .any(|m| m.probe == "auth_metadata"));
+    }
+
+    #[test]
+    fn test_derive_sliding_sync_proxy() {
+        let payloads = vec![
+            payload(
+                ProbeKind::WellKnownClient,
+                Some(200),
+                serde_json::json!({
+                    "m.homeserver": {"base_url": "https://matrix.test.org"},
+                    "org.matrix.msc3575.proxy": {"url": "https://slidingsync.test.org/"}
+                }),
+            ),
+            payload(
+                ProbeKind::SlidingSyncProxy,
+                Some(401),
+                serde_json::json!({"errcode": "M_MISSING_TOKEN"}),
+            ),
+            payload(
+                ProbeKind::ClientVersions,
+                Some(200),
+                serde_json::json!({"versions": ["v1.11"], "unstable_features": {}}),
+            ),
+        ];
+
+        let info = MatrixService::derive_server_info("test.org", &payloads);
+
+        assert_eq!(info.sliding_sync, Some(SlidingSync::Proxy));
+        assert_eq!(
+            info.sliding_sync_proxy,
+            Some("https://slidingsync.test.org".to_string())
+        );
+        assert_eq!(info.sliding_sync_proxy_reachable, Some(true));
+
+        let info = MatrixService::derive_server_info("test.org", &payloads[..1]);
+        assert_eq!(info.sliding_sync_proxy_reachable, None);
+    }
 
-        assert_eq!(info.server, Some("Synapse/1.99.0".to_string()));
+    #[tokio::test]
+    async fn test_sliding_sync_proxy_on_private_address_is_not_probed() {
+        let well_known = payload(
+            ProbeKind::WellKnownClient,
+            Some(200),
+            serde_json::json!({"org.matrix.msc3575.proxy": {"url": "http://127.0.0.1:8009"}}),
+        );
+
+        let proxy =
+            MatrixService::fetch_sliding_sync_proxy(&well_known, Duration::from_secs(1)).await;
+        assert!(proxy.is_none());
+    }
+
+    #[test]
+    fn test_derive_native_sliding_sync_and_capabilities() {
+        let payloads = vec![
+            payload(
+                ProbeKind::ClientVersions,
+                Some(200),
+                serde_json::json!({
+                    "versions": ["v1.11"],
+                    "unstable_features": {"org.matrix.simplified_msc3575": true}
+                }),
+            ),
+            payload(
+                ProbeKind::Capabilities,
+                Some(200),
+                serde_json::json!({"capabilities": {
+                    "m.set_displayname": {"enabled": false},
+                    "m.3pid_changes": {"enabled": false}
+                }}),
+            ),
+        ];
+
+        let info = MatrixService::derive_server_info("test.org", &payloads);
+
+        assert_eq!(info.sliding_sync, Some(SlidingSync::Native));
+        assert_eq!(info.sliding_sync_proxy_reachable, None);
+        assert_eq!(
+            info.client_capabilities,
+            BTreeMap::from([
+                ("m.3pid_changes".to_string(), false),
+                ("m.change_password".to_string(), true),
+                ("m.get_login_token".to_string(), false),
+                ("m.set_avatar_url".to_string(), true),
+                ("m.set_displayname".to_string(), false),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_derive_media_config() {
+        let payloads = vec![
+            payload(
+                ProbeKind::AuthenticatedMediaConfig,
+                Some(200),
+                serde_json::json!({"m.upload.size": 104857600}),
+            ),
+            payload(
+                ProbeKind::MediaConfig,
+                Some(404),
+                serde_json::json!({"errcode": "M_UNRECOGNIZED"}),
+            ),
+        ];
+
+        let info = MatrixService::derive_server_info("test.org", &payloads);
+
+        assert_eq!(info.media_upload_size, Some(104857600));
+        assert_eq!(info.authenticated_media_enforced, Some(true));
+        assert!(!info
+            .missing_fields
+            .iter()
+            .any(|f| f.field == "media_upload_size"));
+    }
+
+    #[test]
+    fn test_authenticated_media_enforced() {
+        let missing_token = serde_json::json!({"errcode": "M_MISSING_TOKEN"});
+        let unrecognized = serde_json::json!({"errcode": "M_UNRECOGNIZED"});
+
+        let both_need_token = vec![
+            payload(
+                ProbeKind::AuthenticatedMediaConfig,
+                Some(401),
+                missing_token.clone(),
+            ),
+            payload(ProbeKind::MediaConfig, Some(401), missing_token.clone()),
+        ];
+        assert_eq!(authenticated_media_enforced(&both_need_token), None);
+
+        let enforced = vec![
+            payload(
+                ProbeKind::AuthenticatedMediaConfig,
+                Some(401),
+                missing_token.clone(),
+            ),
+            payload(ProbeKind::MediaConfig, Some(404), unrecognized.clone()),
+        ];
+        assert_eq!(authenticated_media_enforced(&enforced), Some(true));
+
+        let legacy_open = vec![
+            payload(
+                ProbeKind::AuthenticatedMediaConfig,
+                Some(401),
+                missing_token.clone(),
+            ),
+            payload(
+                ProbeKind::MediaConfig,
+                Some(200),
+                serde_json::json!({"m.upload.size": 1024}),
+            ),
+        ];
+        assert_eq!(authenticated_media_enforced(&legacy_open), Some(false));
+
+        let legacy_only = vec![
+            payload(ProbeKind::AuthenticatedMediaConfig, Some(404), unrecognized),
+            payload(ProbeKind::MediaConfig, Some(401), missing_token),
+        ];
+        assert_eq!(authenticated_media_enforced(&legacy_only), Some(false));
+
+        assert_eq!(authenticated_media_enforced(&[]), None);
+    }
+
+    #[test]
+    fn test_derive_support_contacts() {
+        let payloads = vec![payload(
+            ProbeKind::WellKnownSupport,
+            Some(200),
+            serde_json::json!({
+                "contacts": [
+                    {"matrix_id": "@admin:test.org", "role": "m.role.admin"},
+                    {"matrix_id": "@security:elsewhere.org", "email_address": "security@test.org", "role": "m.role.security"},
+                    {"email_address": "abuse@test.org", "role": "m.role.admin"}
+                ],
+                "support_page": "https://test.org/support"
+            }),
+        )];
+
+        let info = MatrixService::derive_server_info("test.org", &payloads);
+
+        assert_eq!(
+            info.support_page,
+            Some("https://test.org/support".to_string())
+        );
+        assert_eq!(info.support_contacts.len(), 3);
+        assert_eq!(info.support_contacts[0].matrix_id_on_domain, Some(true));
+        assert_eq!(info.support_contacts[1].matrix_id_on_domain, Some(false));
+        assert_eq!(info.support_contacts[2].matrix_id_on_domain, None);
+    }
+
+    #[test]
+    fn test_normalize_base_url() {
+        assert_eq!(
+            normalize_base_url("https://vector.im/"),
+            Some("https://vector.im".to_string())
+        );
+        assert_eq!(
+            normalize_base_url(" https://id.test.org:8090/ "),
+            Some("https://id.test.org:8090".to_string())
+        );
+        assert_eq!(normalize_base_url("vector.im"), None);
+        assert_eq!(normalize_base_url("ftp://vector.im"), None);
+    }
+
+    #[tokio::test]
+    async fn test_client_base_url_follows_public_delegation() {
+        let well_known = |base_url: &str| {
+            payload(
+                ProbeKind::WellKnownClient,
+                Some(200),
+                serde_json::json!({"m.homeserver": {"base_url": base_url}}),
+            )
+        };
+
+        assert_eq!(
+            MatrixService::client_base_url("example.org", &well_known("https://93.184.216.34/"))
+                .await,
+            "https://93.184.216.34"
+        );
+        assert_eq!(
+            MatrixService::client_base_url("example.org", &well_known("http://127.0.0.1:8008"))
+                .await,
+            "https://example.org"
+        );
+        assert_eq!(
+            MatrixService::client_base_url(
+                "example.org",
+                &payload(ProbeKind::WellKnownClient, Some(404), serde_json::json!({}))
+            )
+            .await,
+            "https://example.org"
+        );
+    }
+
+    #[test]
+    fn test_is_matrix_id_on_domain() {
+        assert!(is_matrix_id_on_domain("@admin:test.org", "test.org"));
+        assert!(is_matrix_id_on_domain("@admin:test.org:8448", "test.org"));
+        assert!(!is_matrix_id_on_domain("@admin:other.org", "test.org"));
+        assert!(!is_matrix_id_on_domain("admin:test.org", "test.org"));
+        assert!(!is_matrix_id_on_domain("@:test.org", "test.org"));
+    }
+
+    #[test]
+    fn test_derive_terms_policies() {
+        let payloads = vec![payload(
+            ProbeKind::Registration,
+            Some(401),
+            serde_json::json!({
+                "flows": [{"stages": ["m.login.terms", "m.login.dummy"]}],
+                "params": {"m.login.terms": {"policies": {
+                    "privacy_policy": {
+                        "version": "1.2",
+                        "en": {"name": "Privacy Policy", "url": "https://test.org/privacy-en"},
+                        "fr": {"name": "Politique de confidentialité", "url": "https://test.org/privacy-fr"}
+                    }
+                }}},
+                "session": "abc"
+            }),
+        )];
+
+        let info = MatrixService::derive_server_info("test.org", &payloads);
+
+        assert_eq!(info.terms_policies.len(), 1);
+        let policy = &info.terms_policies[0];
+        assert_eq!(policy.id, "privacy_policy");
+        assert_eq!(policy.version, Some("1.2".to_string()));
+        assert_eq!(policy.translations.len(), 2);
+        assert_eq!(
+            policy.translations["fr"].url,
+            "https://test.org/privacy-fr".to_string()
+        );
+    }
+
+    #[test]
+    fn test_latest_spec_version() {
+        let versions = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(
+            latest_spec_version(&versions(&["r0.5.0", "r0.6.1", "v1.1", "v1.9", "v1.10"])),
+            Some("v1.10".to_string())
+        );
+        assert_eq!(
+            latest_spec_version(&versions(&["r0.4.0", "r0.6.1", "r0.5.0"])),
+            Some("r0.6.1".to_string())
+        );
+        assert_eq!(latest_spec_version(&versions(&["unknown"])), None);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_probe_enforces_its_own_deadline() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/slow")).respond_with(
+                delay_and_then(Duration::from_secs(2), json_encoded(serde_json::json!({}))),
+            ),
+        );
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/fast"))
+                .respond_with(json_encoded(serde_json::json!({"versions": ["v1.11"]}))),
+        );
+
+        let (slow, fast) = tokio::join!(
+            MatrixService::fetch_probe(
+                ProbeKind::Capabilities,
+                server.url_str("/slow"),
+                Duration::from_millis(100),
+            ),
+            MatrixService::fetch_probe(
+                ProbeKind::ClientVersions,
+                server.url_str("/fast"),
+                Duration::from_secs(5),
+            ),
+        );
+
+        assert!(slow.status.is_none());
+        assert_eq!(slow.error.as_deref(), Some("timed out after 100ms"));
+        assert!(slow.duration_ms.unwrap() < 2000);
+
+        assert_eq!(fast.status, Some(200));
+        assert!(fast.success_body().is_some());
+        assert!(fast.duration_ms.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_client_probe_falls_back_to_r0() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(request::method_path(
+                "GET",
+                "/_matrix/client/v3/capabilities",
+            ))
+            .times(2)
+            .respond_with(status_code(404).body(r#"{"errcode":"M_UNRECOGNIZED"}"#)),
+        );
+        server.expect(
+            Expectation::matching(request::method_path(
+                "GET",
+                "/_matrix/client/r0/capabilities",
+            ))
+            .respond_with(json_encoded(serde_json::json!({"capabilities": {}}))),
+        );
+
+        let base_url = server.url_str("");
+        let base_url = base_url.trim_end_matches('/');
+
+        let unknown = MatrixService::fetch_client_probe(
+            ProbeKind::Capabilities,
+            base_url,
+            "/capabilities",
+            None,
+            Duration::from_secs(5),
+        )
+        .await;
+        assert_eq!(unknown.status, Some(200));
+        assert_eq!(
+            ClientApiVersion::from_url(&unknown.url),
+            Some(ClientApiVersion::R0)
+        );
+
+        let negotiated = MatrixService::fetch_client_probe(
+            ProbeKind::Capabilities,
+            base_url,
+            "/capabilities",
+            Some(ClientApiVersion::V3),
+            Duration::from_secs(5),
+        )
+        .await;
+        assert_eq!(negotiated.status, Some(404));
+        assert_eq!(
+            ClientApiVersion::from_url(&negotiated.url),
+            Some(ClientApiVersion::V3)
+        );
     }
 }
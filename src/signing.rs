@@ -0,0 +1,451 @@
+use base64::engine::general_purpose::STANDARD_NO_PAD;
+use base64::Engine;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde_json::{json, Map, Value};
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum SigningError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid signing key file: {0}")]
+    InvalidKeyFile(String),
+    #[error("Invalid base64: {0}")]
+    Base64(#[from] base64::DecodeError),
+    #[error("Invalid key: {0}")]
+    InvalidKey(String),
+    #[error("JSON value is not an object")]
+    NotAnObject,
+    #[error("Missing signature from {0}")]
+    MissingSignature(String),
+    #[error("Signature verification failed")]
+    BadSignature,
+}
+
+/// The ed25519 key mxindex uses to sign federation requests and its own
+/// `/_matrix/key/v2/server` response.
+pub struct ServerSigningKey {
+    pub server_name: String,
+    pub key_id: String,
+    signing_key: SigningKey,
+}
+
+impl ServerSigningKey {
+    pub fn new(server_name: &str, key_version: &str, seed: [u8; 32]) -> Self {
+        Self {
+            server_name: server_name.to_string(),
+            key_id: format!("ed25519:{}", key_version),
+            signing_key: SigningKey::from_bytes(&seed),
+        }
+    }
+
+    pub fn generate(server_name: &str, key_version: &str) -> Self {
+        let mut seed = [0u8; 32];
+        OsRng.fill_bytes(&mut seed);
+        Self::new(server_name, key_version, seed)
+    }
+
+    /// Parses a Synapse-style key file line: `ed25519 <version> <base64 seed>`.
+    pub fn from_key_file_contents(server_name: &str, contents: &str) -> Result<Self, SigningError> {
+        let line = contents
+            .lines()
+            .find(|l| !l.trim().is_empty())
+            .ok_or_else(|| SigningError::InvalidKeyFile("file is empty".to_string()))?;
+
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.len() != 3 || parts[0] != "ed25519" {
+            return Err(SigningError::InvalidKeyFile(
+                "expected `ed25519 <version> <seed>`".to_string(),
+            ));
+        }
+
+        let seed: [u8; 32] = decode_base64(parts[2])?
+            .try_into()
+            .map_err(|_| SigningError::InvalidKey("seed must be 32 bytes".to_string()))?;
+
+        Ok(Self::new(server_name, parts[1], seed))
+    }
+
+    pub fn to_key_file_contents(&self) -> String {
+        format!(
+            "ed25519 {} {}\n",
+            self.key_version(),
+            encode_base64(self.signing_key.as_bytes())
+        )
+    }
+
+    /// Loads the key at `path`, or generates one there readable only by
+    /// its owner. A key other users can read is refused.
+    pub fn load_or_generate(server_name: &str, path: &Path) -> Result<Self, SigningError> {
+        if path.exists() {
+            check_private(path)?;
+            let contents = std::fs::read_to_string(path)?;
+            return Self::from_key_file_contents(server_name, &contents);
+        }
+
+        let key = Self::generate(server_name, "mxindex1");
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).ok();
+        }
+        write_private_file(path, key.to_key_file_contents().as_bytes())?;
+        Ok(key)
+    }
+
+    pub fn key_version(&self) -> &str {
+        self.key_id.trim_start_matches("ed25519:")
+    }
+
+    pub fn public_key_base64(&self) -> String {
+        encode_base64(self.signing_key.verifying_key().as_bytes())
+    }
+
+    pub fn sign(&self, message: &[u8]) -> String {
+        encode_base64(&self.signing_key.sign(message).to_bytes())
+    }
+
+    /// Adds this server's signature to `value` following the Matrix signing
+    /// JSON algorithm: `signatures` and `unsigned` are excluded from the
+    /// signed bytes and existing signatures are preserved.
+    pub fn sign_json(&self, value: &mut Value) -> Result<(), SigningError> {
+        let object = value.as_object_mut().ok_or(SigningError::NotAnObject)?;
+
+        let signatures = object.remove("signatures");
+        let unsigned = object.remove("unsigned");
+
+        let signature = self.sign(canonical_json(&Value::Object(object.clone())).as_bytes());
+
+        let mut signatures = match signatures {
+            Some(Value::Object(map)) => map,
+            _ => Map::new(),
+        };
+        let entry = signatures
+            .entry(self.server_name.clone())
+            .or_insert_with(|| Value::Object(Map::new()));
+        if let Some(map) = entry.as_object_mut() {
+            map.insert(self.key_id.clone(), Value::String(signature));
+        }
+
+        object.insert("signatures".to_string(), Value::Object(signatures));
+        if let Some(unsigned) = unsigned {
+            object.insert("unsigned".to_string(), unsigned);
+        }
+
+        Ok(())
+    }
+
+    /// Builds the `Authorization: X-Matrix ...` header value for a request
+    /// from this server to `destination`.
+    pub fn x_matrix_authorization(
+        &self,
+        method: &str,
+        uri: &str,
+        destination: &str,
+        content: Option<&Value>,
+    ) -> String {
+        let mut request = json!({
+            "method": method,
+            "uri": uri,
+            "origin": self.server_name,
+            "destination": destination,
+        });
+        if let Some(content) = content {
+            request["content"] = content.clone();
+        }
+
+        let signature = self.sign(canonical_json(&request).as_bytes());
+
+        format!(
+            "X-Matrix origin=\"{}\",destination=\"{}\",key=\"{}\",sig=\"{}\"",
+            self.server_name, destination, self.key_id, signature
+        )
+    }
+
+    /// The signed body served at `/_matrix/key/v2/server`.
+    pub fn server_keys_response(&self, valid_until_ts: i64) -> Value {
+        let mut verify_keys = Map::new();
+        verify_keys.insert(
+            self.key_id.clone(),
+            json!({ "key": self.public_key_base64() }),
+        );
+
+        let mut response = json!({
+            "server_name": self.server_name,
+            "valid_until_ts": valid_until_ts,
+            "verify_keys": verify_keys,
+            "old_verify_keys": {},
+        });
+
+        self.sign_json(&mut response)
+            .expect("server keys response is always an object");
+        response
+    }
+}
+
+pub fn encode_base64(bytes: &[u8]) -> String {
+    STANDARD_NO_PAD.encode(bytes)
+}
+
+/// Decodes unpadded base64, tolerating padding some implementations still emit.
+pub fn decode_base64(input: &str) -> Result<Vec<u8>, SigningError> {
+    Ok(STANDARD_NO_PAD.decode(input.trim_end_matches('='))?)
+}
+
+/// Serializes `value` as Matrix canonical JSON: object keys sorted
+/// lexicographically and no insignificant whitespace.
+pub fn canonical_json(value: &Value) -> String {
+    let mut output = String::new();
+    write_canonical(value, &mut output);
+    output
+}
+
+fn write_canonical(value: &Value, output: &mut String) {
+    match value {
+        Value::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+
+            output.push('{');
+            for (i, key) in keys.into_iter().enumerate() {
+                if i > 0 {
+                    output.push(',');
+                }
+                output.push_str(&Value::String(key.clone()).to_string());
+                output.push(':');
+                write_canonical(&map[key], output);
+            }
+            output.push('}');
+        }
+        Value::Array(items) => {
+            output.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    output.push(',');
+                }
+                write_canonical(item, output);
+            }
+            output.push(']');
+        }
+        other => output.push_str(&other.to_string()),
+    }
+}
+
+/// Verifies the signature `server_name` made over `value` with `key_id`.
+pub fn verify_json(
+    value: &Value,
+    server_name: &str,
+    key_id: &str,
+    public_key_base64: &str,
+) -> Result<(), SigningError> {
+    let object = value.as_object().ok_or(SigningError::NotAnObject)?;
+
+    let signature = object
+        .get("signatures")
+        .and_then(|s| s.get(server_name))
+        .and_then(|s| s.get(key_id))
+        .and_then(|s| s.as_str())
+        .ok_or_else(|| SigningError::MissingSignature(format!("{} {}", server_name, key_id)))?;
+
+    let mut unsigned = object.clone();
+    unsigned.remove("signatures");
+    unsigned.remove("unsigned");
+
+    let public_key: [u8; 32] = decode_base64(public_key_base64)?
+        .try_into()
+        .map_err(|_| SigningError::InvalidKey("public key must be 32 bytes".to_string()))?;
+    let verifying_key = VerifyingKey::from_bytes(&public_key)
+        .map_err(|e| SigningError::InvalidKey(e.to_string()))?;
+
+    let signature: [u8; 64] = decode_base64(signature)?
+        .try_into()
+        .map_err(|_| SigningError::InvalidKey("signature must be 64 bytes".to_string()))?;
+
+    verifying_key
+        .verify(
+            canonical_json(&Value::Object(unsigned)).as_bytes(),
+            &Signature::from_bytes(&signature),
+        )
+        .map_err(|_| SigningError::BadSignature)
+}
+
+/// Loads the signing key from `MXINDEX_SIGNING_KEY_PATH` for the server name in
+/// `MXINDEX_SERVER_NAME`, generating one on first start. Federation signing is
+/// disabled when no server name is configured.
+pub fn signing_key_from_config() -> Option<ServerSigningKey> {
+    let server_name = std::env::var("MXINDEX_SERVER_NAME").ok()?;
+    let path = std::env::var("MXINDEX_SIGNING_KEY_PATH")
+        .unwrap_or_else(|_| "mxindex.signing.key".to_string());
+
+    match ServerSigningKey::load_or_generate(&server_name, Path::new(&path)) {
+        Ok(key) => Some(key),
+        Err(e) => {
+            tracing::error!("Failed to load signing key from {}: {}", path, e);
+            None
+        }
+    }
+}
+
+pub fn key_validity_ms() -> i64 {
+    let hours: i64 = std::env::var("MXINDEX_KEY_VALIDITY_HOURS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(24);
+    hours * 60 * 60 * 1000
+}
+
+/// Writes `contents` to a new file only its owner can read or write.
+/// Fails if `path` already exists.
+pub fn write_private_file(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    options.open(path)?.write_all(contents)
+}
+
+#[cfg(unix)]
+fn check_private(path: &Path) -> Result<(), SigningError> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = std::fs::metadata(path)?.permissions().mode();
+    if mode & 0o077 != 0 {
+        return Err(SigningError::InvalidKeyFile(format!(
+            "{} is readable by other users (mode {:o}); run chmod 600 on it",
+            path.display(),
+            mode & 0o777
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_private(_path: &Path) -> Result<(), SigningError> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_key() -> ServerSigningKey {
+        ServerSigningKey::new("mxindex.test", "a_test", [7u8; 32])
+    }
+
+    #[test]
+    fn test_canonical_json_sorts_keys() {
+        let value = json!({"b": 1, "a": {"d": [1, 2], "c": "x"}});
+        assert_eq!(canonical_json(&value), r#"{"a":{"c":"x","d":[1,2]},"b":1}"#);
+    }
+
+    #[test]
+    fn test_canonical_json_escapes_strings() {
+        let value = json!({"k": "line\nbreak \"quoted\""});
+        assert_eq!(canonical_json(&value), r#"{"k":"line\nbreak \"quoted\""}"#);
+    }
+
+    #[test]
+    fn test_key_file_round_trip() {
+        let key = test_key();
+        let contents = key.to_key_file_contents();
+        assert!(contents.starts_with("ed25519 a_test "));
+
+        let loaded = ServerSigningKey::from_key_file_contents("mxindex.test", &contents).unwrap();
+        assert_eq!(loaded.key_id, "ed25519:a_test");
+        assert_eq!(loaded.public_key_base64(), key.public_key_base64());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_load_or_generate_keeps_key_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("signing.key");
+
+        let key = ServerSigningKey::load_or_generate("mxindex.test", &path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let loaded = ServerSigningKey::load_or_generate("mxindex.test", &path).unwrap();
+        assert_eq!(loaded.public_key_base64(), key.public_key_base64());
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let result = ServerSigningKey::load_or_generate("mxindex.test", &path);
+        assert!(matches!(result, Err(SigningError::InvalidKeyFile(_))));
+    }
+
+    #[test]
+    fn test_invalid_key_file() {
+        let result = ServerSigningKey::from_key_file_contents("mxindex.test", "rsa 1 abc");
+        assert!(matches!(result, Err(SigningError::InvalidKeyFile(_))));
+    }
+
+    #[test]
+    fn test_sign_and_verify_json() {
+        let key = test_key();
+        let mut value = json!({"hello": "world", "unsigned": {"age": 1}});
+        key.sign_json(&mut value).unwrap();
+
+        assert!(value["unsigned"].is_object());
+        verify_json(
+            &value,
+            "mxindex.test",
+            "ed25519:a_test",
+            &key.public_key_base64(),
+        )
+        .unwrap();
+
+        value["hello"] = json!("tampered");
+        let result = verify_json(
+            &value,
+            "mxindex.test",
+            "ed25519:a_test",
+            &key.public_key_base64(),
+        );
+        assert!(matches!(result, Err(SigningError::BadSignature)));
+    }
+
+    #[test]
+    fn test_server_keys_response_is_self_signed() {
+        let key = test_key();
+        let response = key.server_keys_response(1_700_000_000_000);
+
+        assert_eq!(response["server_name"], "mxindex.test");
+        assert_eq!(
+            response["verify_keys"]["ed25519:a_test"]["key"],
+            key.public_key_base64()
+        );
+        verify_json(
+            &response,
+            "mxindex.test",
+            "ed25519:a_test",
+            &key.public_key_base64(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_x_matrix_authorization_format() {
+        let key = test_key();
+        let header =
+            key.x_matrix_authorization("GET", "/_matrix/federation/v1/version", "matrix.org", None);
+
+        assert!(header.starts_with("X-Matrix origin=\"mxindex.test\",destination=\"matrix.org\""));
+        assert!(header.contains("key=\"ed25519:a_test\""));
+        assert!(header.contains("sig=\""));
+    }
+
+    #[test]
+    fn test_decode_base64_accepts_padding() {
+        assert_eq!(decode_base64("aGk=").unwrap(), b"hi");
+        assert_eq!(decode_base64("aGk").unwrap(), b"hi");
+    }
+}
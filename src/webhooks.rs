@@ -0,0 +1,618 @@
+use crate::db::{
+    claim_webhooks, get_queued_webhook_events, record_webhook_delivery, release_webhook, DbPool,
+    NewWebhookDelivery, WebhookDelivery, WebhookSubscription,
+};
+use crate::models::{
+    ServerEventResponse, WebhookDeliveryResponse, WebhookPayload, WebhookResponse,
+};
+use backoff::backoff::Backoff;
+use backoff::{ExponentialBackoff, ExponentialBackoffBuilder};
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tracing::warn;
+
+/// `sha256=` followed by the hex HMAC-SHA256 of the body, keyed with the
+/// subscription's secret.
+pub const SIGNATURE_HEADER: &str = "X-Mxindex-Signature";
+pub const EVENT_HEADER: &str = "X-Mxindex-Event";
+/// The event id, stable across retries so receivers can deduplicate.
+pub const DELIVERY_HEADER: &str = "X-Mxindex-Delivery";
+
+/// Events sent to one subscription per pass of the worker.
+const BATCH_SIZE: i64 = 100;
+
+pub struct WebhookConfig {
+    pub poll_interval: Duration,
+    /// Failed deliveries in a row after which a subscription is disabled.
+    pub max_failures: i32,
+    /// How long one delivery keeps retrying before it counts as failed.
+    pub max_retry_elapsed: Duration,
+    /// Lets webhooks target loopback and private networks, for local setups.
+    pub allow_private_targets: bool,
+}
+
+impl WebhookConfig {
+    pub fn from_env() -> Self {
+        let poll_secs = std::env::var("WEBHOOK_POLL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(10);
+        let max_failures = std::env::var("WEBHOOK_MAX_FAILURES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(5);
+        let retry_secs = std::env::var("WEBHOOK_RETRY_MAX_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(60);
+
+        Self {
+            poll_interval: Duration::from_secs(poll_secs),
+            max_failures,
+            max_retry_elapsed: Duration::from_secs(retry_secs),
+            allow_private_targets: allow_private_targets_from_config(),
+        }
+    }
+
+    fn retry_schedule(&self) -> ExponentialBackoff {
+        ExponentialBackoffBuilder::new()
+            .with_initial_interval(Duration::from_millis(500))
+            .with_max_interval(Duration::from_secs(30))
+            .with_max_elapsed_time(Some(self.max_retry_elapsed))
+            .build()
+    }
+
+    /// How long a claim on a subscription lasts: one delivery with all its
+    /// retries, plus the timeout of the last attempt.
+    fn lease_secs(&self) -> i32 {
+        let lease = self.max_retry_elapsed + Duration::from_secs(60);
+        i32::try_from(lease.as_secs()).unwrap_or(i32::MAX)
+    }
+}
+
+/// `WEBHOOK_ALLOW_PRIVATE_TARGETS`, checked both when a subscription is made
+/// and before each delivery.
+pub fn allow_private_targets_from_config() -> bool {
+    std::env::var("WEBHOOK_ALLOW_PRIVATE_TARGETS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(false)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+
+    format!("sha256={}", hex(&mac.finalize().into_bytes()))
+}
+
+/// A random token that manages one subscription. Only its hash is stored.
+pub fn new_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    hex(&bytes)
+}
+
+pub fn hash_token(token: &str) -> String {
+    hex(&Sha256::digest(token.as_bytes()))
+}
+
+/// Whether `ip` is reachable on the public internet. Loopback, private,
+/// shared, link-local, unique-local, documentation and other reserved
+/// ranges are not.
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || a == 0
+                || a >= 240
+                || (a == 100 && (64..128).contains(&b))
+                || (a == 198 && (18..20).contains(&b)))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(mapped) = ip.to_ipv4_mapped() {
+                return is_public_ip(IpAddr::V4(mapped));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || first & 0xfe00 == 0xfc00
+                || first & 0xffc0 == 0xfe80
+                || (first == 0x2001 && ip.segments()[1] == 0x0db8))
+        }
+    }
+}
+
+/// Resolves the host of a webhook URL, refusing hosts with any address
+/// that is not public unless `allow_private` is set.
+pub async fn resolve_target(url: &str, allow_private: bool) -> Result<SocketAddr, String> {
+    let url = reqwest::Url::parse(url).map_err(|e| e.to_string())?;
+    let host = url.host_str().ok_or("URL has no host")?;
+    let port = url
+        .port_or_known_default()
+        .ok_or("URL has no port or known scheme")?;
+
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.trim_matches(['[', ']']), port))
+        .await
+        .map_err(|e| format!("cannot resolve {}: {}", host, e))?
+        .collect();
+
+    if !allow_private && addrs.iter().any(|addr| !is_public_ip(addr.ip())) {
+        return Err(format!("{} resolves to a non-public address", host));
+    }
+
+    addrs
+        .into_iter()
+        .next()
+        .ok_or_else(|| format!("{} has no addresses", host))
+}
+
+/// A client that connects to `target` whatever DNS says by the time of the
+/// request, and does not follow redirects, so a delivery cannot be steered
+/// to an address `resolve_target` refused.
+pub fn pinned_client(url: &str, target: SocketAddr) -> Result<reqwest::Client, String> {
+    let mut builder = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .redirect(reqwest::redirect::Policy::none());
+
+    if let Some(host) = reqwest::Url::parse(url)
+        .ok()
+        .and_then(|url| url.domain().map(str::to_string))
+    {
+        builder = builder.resolve(&host, target);
+    }
+
+    builder.build().map_err(|e| e.to_string())
+}
+
+/// A pinned client for a URL taken from a document a server publishes.
+/// Non-public targets are always refused.
+pub async fn public_client(url: &str) -> Result<reqwest::Client, String> {
+    let target = resolve_target(url, false).await?;
+    pinned_client(url, target)
+}
+
+#[derive(Debug, PartialEq)]
+pub struct DeliveryOutcome {
+    pub success: bool,
+    pub attempts: i32,
+    /// HTTP status of the last attempt.
+    pub status: Option<u16>,
+    pub error: Option<String>,
+}
+
+/// Network failures, timeouts, rate limiting and server errors may pass;
+/// any other rejection will not.
+fn is_retryable(status: Option<u16>) -> bool {
+    match status {
+        None | Some(408) | Some(429) => true,
+        Some(status) => status >= 500,
+    }
+}
+
+/// POSTs a signed event body, retrying transient failures until `schedule`
+/// runs out.
+pub async fn deliver(
+    client: &reqwest::Client,
+    url: &str,
+    secret: &str,
+    event: &ServerEventResponse,
+    body: &[u8],
+    mut schedule: impl Backoff,
+) -> DeliveryOutcome {
+    let signature = sign(secret, body);
+    let mut attempts = 0;
+
+    loop {
+        attempts += 1;
+
+        let response = client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(SIGNATURE_HEADER, &signature)
+            .header(EVENT_HEADER, &event.kind)
+            .header(DELIVERY_HEADER, event.id.to_string())
+            .body(body.to_vec())
+            .send()
+            .await;
+
+        let (status, error) = match response {
+            Ok(response) if response.status().is_success() => {
+                return DeliveryOutcome {
+                    success: true,
+                    attempts,
+                    status: Some(response.status().as_u16()),
+                    error: None,
+                };
+            }
+            Ok(response) => {
+                let status = response.status().as_u16();
+                (Some(status), format!("HTTP {}", status))
+            }
+            Err(e) => (None, e.to_string()),
+        };
+
+        match schedule.next_backoff() {
+            Some(delay) if is_retryable(status) => tokio::time::sleep(delay).await,
+            _ => {
+                return DeliveryOutcome {
+                    success: false,
+                    attempts,
+                    status,
+                    error: Some(error),
+                }
+            }
+        }
+    }
+}
+
+/// Sends a claimed subscription the events queued for it, oldest first.
+/// Stops at the first failed delivery so the event is retried on the next
+/// pass and events keep their order.
+async fn deliver_pending(
+    db_pool: &DbPool,
+    subscription: &WebhookSubscription,
+    config: &WebhookConfig,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let events = {
+        let mut conn = db_pool.get()?;
+        get_queued_webhook_events(&mut conn, subscription.id, BATCH_SIZE)?
+    };
+
+    if events.is_empty() {
+        return Ok(());
+    }
+
+    let target = resolve_target(&subscription.url, config.allow_private_targets).await;
+    let client = target.and_then(|target| pinned_client(&subscription.url, target));
+
+    for event in events {
+        let payload = WebhookPayload {
+            webhook_id: subscription.id,
+            event: ServerEventResponse::from(event),
+        };
+        let body = serde_json::to_vec(&payload)?;
+
+        let outcome = match &client {
+            Ok(client) => {
+                deliver(
+                    client,
+                    &subscription.url,
+                    &subscription.secret,
+                    &payload.event,
+                    &body,
+                    config.retry_schedule(),
+                )
+                .await
+            }
+            Err(error) => DeliveryOutcome {
+                success: false,
+                attempts: 0,
+                status: None,
+                error: Some(error.clone()),
+            },
+        };
+
+        let mut conn = db_pool.get()?;
+        let disabled = record_webhook_delivery(
+            &mut conn,
+            &NewWebhookDelivery {
+                subscription_id: subscription.id,
+                event_id: payload.event.id,
+                success: outcome.success,
+                attempts: outcome.attempts,
+                status: outcome.status.map(i32::from),
+                error: outcome.error.as_deref(),
+            },
+            config.max_failures,
+            config.lease_secs(),
+        )?;
+
+        if disabled {
+            warn!(
+                "Disabled webhook {} after {} failed deliveries",
+                subscription.id, config.max_failures
+            );
+        }
+
+        if !outcome.success {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// One pass over the subscriptions with queued events that this worker
+/// could claim. A slow receiver only holds up its own subscription.
+pub async fn deliver_all(db_pool: &DbPool, config: &WebhookConfig) {
+    let subscriptions = match db_pool
+        .get()
+        .map(|mut conn| claim_webhooks(&mut conn, config.lease_secs()))
+    {
+        Ok(Ok(subscriptions)) => subscriptions,
+        Ok(Err(e)) => {
+            warn!("Failed to load webhooks: {}", e);
+            return;
+        }
+        Err(e) => {
+            warn!("Failed to get DB connection for webhooks: {}", e);
+            return;
+        }
+    };
+
+    futures::future::join_all(subscriptions.iter().map(|subscription| async move {
+        if let Err(e) = deliver_pending(db_pool, subscription, config).await {
+            warn!("Failed to deliver webhook {}: {}", subscription.id, e);
+        }
+
+        let released = db_pool
+            .get()
+            .map(|mut conn| release_webhook(&mut conn, subscription.id));
+        if !matches!(released, Ok(Ok(_))) {
+            warn!(
+                "Failed to release webhook {}; it is claimed until its lease runs out",
+                subscription.id
+            );
+        }
+    }))
+    .await;
+}
+
+/// Delivers new events to subscribers every `poll_interval`, for the life of
+/// the process.
+pub async fn run_webhook_worker(db_pool: DbPool, config: WebhookConfig) {
+    let mut interval = tokio::time::interval(config.poll_interval);
+
+    loop {
+        interval.tick().await;
+        deliver_all(&db_pool, &config).await;
+    }
+}
+
+impl From<WebhookSubscription> for WebhookResponse {
+    fn from(subscription: WebhookSubscription) -> Self {
+        WebhookResponse {
+            id: subscription.id,
+            url: subscription.url,
+            event_kinds: subscription.event_kinds,
+            domain: subscription.domain,
+            enabled: subscription.enabled,
+            consecutive_failures: subscription.consecutive_failures,
+            last_event_id: subscription.last_event_id,
+            disabled_at: subscription.disabled_at,
+            created_at: subscription.created_at,
+            token: None,
+        }
+    }
+}
+
+impl From<WebhookDelivery> for WebhookDeliveryResponse {
+    fn from(delivery: WebhookDelivery) -> Self {
+        WebhookDeliveryResponse {
+            event_id: delivery.event_id,
+            success: delivery.success,
+            attempts: delivery.attempts,
+            status: delivery.status,
+            error: delivery.error,
+            delivered_at: delivery.delivered_at,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDateTime;
+    use httptest::matchers::{all_of, contains, request};
+    use httptest::responders::status_code;
+    use httptest::{cycle, Expectation, Server};
+
+    fn event() -> ServerEventResponse {
+        ServerEventResponse {
+            id: 42,
+            kind: "server_added".to_string(),
+            domain: "example.org".to_string(),
+            title: "example.org was added to the index".to_string(),
+            details: serde_json::json!({}),
+            created_at: NaiveDateTime::parse_from_str("2024-01-01 00:00:00", "%Y-%m-%d %H:%M:%S")
+                .unwrap(),
+        }
+    }
+
+    fn fast_schedule() -> ExponentialBackoff {
+        ExponentialBackoffBuilder::new()
+            .with_initial_interval(Duration::from_millis(1))
+            .with_max_interval(Duration::from_millis(1))
+            .with_randomization_factor(0.0)
+            .with_max_elapsed_time(Some(Duration::from_millis(200)))
+            .build()
+    }
+
+    #[test]
+    fn test_sign() {
+        assert_eq!(
+            sign("key", b"The quick brown fox jumps over the lazy dog"),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[test]
+    fn test_tokens() {
+        let token = new_token();
+        assert_eq!(token.len(), 64);
+        assert_ne!(token, new_token());
+        assert_eq!(
+            hash_token("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_is_public_ip() {
+        let public = |ip: &str| is_public_ip(ip.parse().unwrap());
+
+        assert!(public("93.184.216.34"));
+        assert!(public("2606:2800:220:1::"));
+        assert!(!public("127.0.0.1"));
+        assert!(!public("10.1.2.3"));
+        assert!(!public("172.16.0.1"));
+        assert!(!public("192.168.1.1"));
+        assert!(!public("169.254.169.254"));
+        assert!(!public("100.64.0.1"));
+        assert!(!public("0.0.0.0"));
+        assert!(!public("::1"));
+        assert!(!public("fd00::1"));
+        assert!(!public("fe80::1"));
+        assert!(!public("::ffff:127.0.0.1"));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_target_refuses_private_hosts() {
+        assert!(resolve_target("http://127.0.0.1:8080/hook", false)
+            .await
+            .is_err());
+        assert!(resolve_target("http://[::1]/hook", false).await.is_err());
+
+        let target = resolve_target("http://127.0.0.1:8080/hook", true).await;
+        assert_eq!(target, Ok("127.0.0.1:8080".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        assert!(is_retryable(None));
+        assert!(is_retryable(Some(503)));
+        assert!(is_retryable(Some(429)));
+        assert!(!is_retryable(Some(404)));
+    }
+
+    #[tokio::test]
+    async fn test_deliver_signs_body() {
+        let body = br#"{"webhook_id":1}"#;
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("POST", "/hook"),
+                request::headers(contains(("x-mxindex-signature", sign("secret", body)))),
+                request::headers(contains(("x-mxindex-event", "server_added"))),
+                request::headers(contains(("x-mxindex-delivery", "42"))),
+                request::body(&body[..]),
+            ])
+            .respond_with(status_code(204)),
+        );
+
+        let outcome = deliver(
+            &reqwest::Client::new(),
+            &server.url_str("/hook"),
+            "secret",
+            &event(),
+            body,
+            fast_schedule(),
+        )
+        .await;
+
+        assert_eq!(
+            outcome,
+            DeliveryOutcome {
+                success: true,
+                attempts: 1,
+                status: Some(204),
+                error: None,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deliver_retries_server_errors() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("POST", "/hook"))
+                .times(3)
+                .respond_with(cycle![status_code(500), status_code(503), status_code(200),]),
+        );
+
+        let outcome = deliver(
+            &reqwest::Client::new(),
+            &server.url_str("/hook"),
+            "secret",
+            &event(),
+            b"{}",
+            fast_schedule(),
+        )
+        .await;
+
+        assert!(outcome.success);
+        assert_eq!(outcome.attempts, 3);
+    }
+
+    #[tokio::test]
+    async fn test_deliver_does_not_retry_rejections() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("POST", "/hook"))
+                .times(1)
+                .respond_with(status_code(410)),
+        );
+
+        let outcome = deliver(
+            &reqwest::Client::new(),
+            &server.url_str("/hook"),
+            "secret",
+            &event(),
+            b"{}",
+            fast_schedule(),
+        )
+        .await;
+
+        assert_eq!(
+            outcome,
+            DeliveryOutcome {
+                success: false,
+                attempts: 1,
+                status: Some(410),
+                error: Some("HTTP 410".to_string()),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deliver_gives_up_when_schedule_runs_out() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("POST", "/hook"))
+                .times(1..)
+                .respond_with(status_code(500)),
+        );
+
+        let outcome = deliver(
+            &reqwest::Client::new(),
+            &server.url_str("/hook"),
+            "secret",
+            &event(),
+            b"{}",
+            fast_schedule(),
+        )
+        .await;
+
+        assert!(!outcome.success);
+        assert!(outcome.attempts > 1);
+        assert_eq!(outcome.status, Some(500));
+    }
+}
@@ -0,0 +1,481 @@
+use crate::db::{replace_well_known_findings, DbPool, NewWellKnownFinding};
+use crate::federation_client::split_host_port;
+use crate::webhooks::public_client;
+use serde_json::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub document: &'static str,
+    pub severity: Severity,
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// The parts of a well-known HTTP response the linter looks at.
+#[derive(Debug, Default)]
+pub struct FetchedDocument {
+    pub status: u16,
+    pub content_type: Option<String>,
+    pub access_control_allow_origin: Option<String>,
+    pub body: String,
+}
+
+fn finding(
+    document: &'static str,
+    severity: Severity,
+    code: &'static str,
+    message: impl Into<String>,
+) -> Finding {
+    Finding {
+        document,
+        severity,
+        code,
+        message: message.into(),
+    }
+}
+
+fn lint_common(document: &'static str, fetched: &FetchedDocument) -> (Vec<Finding>, Option<Value>) {
+    let mut findings = Vec::new();
+
+    let is_json_type = fetched
+        .content_type
+        .as_deref()
+        .map(|ct| ct.split(';').next().unwrap_or("").trim() == "application/json")
+        .unwrap_or(false);
+
+    if !is_json_type {
+        findings.push(finding(
+            document,
+            Severity::Warning,
+            "wrong_content_type",
+            format!(
+                "Content-Type is {}, expected application/json",
+                fetched.content_type.as_deref().unwrap_or("missing")
+            ),
+        ));
+    }
+
+    match serde_json::from_str::<Value>(&fetched.body) {
+        Ok(json) if json.is_object() => (findings, Some(json)),
+        Ok(_) => {
+            findings.push(finding(
+                document,
+                Severity::Error,
+                "not_an_object",
+                "Document is valid JSON but not an object",
+            ));
+            (findings, None)
+        }
+        Err(e) => {
+            findings.push(finding(
+                document,
+                Severity::Error,
+                "invalid_json",
+                format!("Document is not valid JSON: {}", e),
+            ));
+            (findings, None)
+        }
+    }
+}
+
+/// Lints a `/.well-known/matrix/client` response without touching the network.
+/// Returns the findings and the advertised homeserver base URL, if any.
+pub fn lint_client_document(fetched: Option<&FetchedDocument>) -> (Vec<Finding>, Option<String>) {
+    let document = "client";
+
+    let Some(fetched) = fetched else {
+        return (
+            vec![finding(
+                document,
+                Severity::Error,
+                "unreachable",
+                "Document could not be fetched",
+            )],
+            None,
+        );
+    };
+
+    if fetched.status == 404 {
+        return (
+            vec![finding(
+                document,
+                Severity::Warning,
+                "not_found",
+                "No client well-known, clients cannot auto-discover the homeserver",
+            )],
+            None,
+        );
+    }
+
+    if !(200..300).contains(&fetched.status) {
+        return (
+            vec![finding(
+                document,
+                Severity::Error,
+                "http_error",
+                format!("Returned HTTP {}", fetched.status),
+            )],
+            None,
+        );
+    }
+
+    let (mut findings, json) = lint_common(document, fetched);
+
+    if fetched.access_control_allow_origin.as_deref() != Some("*") {
+        findings.push(finding(
+            document,
+            Severity::Warning,
+            "missing_cors",
+            "Access-Control-Allow-Origin: * is missing, web clients cannot read the document",
+        ));
+    }
+
+    let Some(json) = json else {
+        return (findings, None);
+    };
+
+    let base_url = match json["m.homeserver"]["base_url"].as_str() {
+        Some(base_url) => base_url.to_string(),
+        None => {
+            findings.push(finding(
+                document,
+                Severity::Error,
+                "missing_base_url",
+                "m.homeserver.base_url is missing",
+            ));
+            return (findings, None);
+        }
+    };
+
+    match reqwest::Url::parse(&base_url) {
+        Ok(url) if url.scheme() == "https" => {}
+        Ok(url) if url.scheme() == "http" => findings.push(finding(
+            document,
+            Severity::Warning,
+            "insecure_base_url",
+            format!("base_url {} does not use https", base_url),
+        )),
+        _ => {
+            findings.push(finding(
+                document,
+                Severity::Error,
+                "invalid_base_url",
+                format!("base_url {:?} is not a valid http(s) URL", base_url),
+            ));
+            return (findings, None);
+        }
+    }
+
+    if base_url.ends_with('/') {
+        findings.push(finding(
+            document,
+            Severity::Warning,
+            "trailing_slash",
+            "base_url has a trailing slash, some clients build URLs with a double slash",
+        ));
+    }
+
+    if let Some(identity) = json.get("m.identity_server") {
+        let valid = identity["base_url"]
+            .as_str()
+            .and_then(|u| reqwest::Url::parse(u).ok())
+            .is_some();
+        if !valid {
+            findings.push(finding(
+                document,
+                Severity::Warning,
+                "invalid_identity_server",
+                "m.identity_server.base_url is missing or not a valid URL",
+            ));
+        }
+    }
+
+    (findings, Some(base_url))
+}
+
+/// Lints a `/.well-known/matrix/server` response. A missing document is
+/// allowed since federation falls back to SRV and port 8448.
+pub fn lint_server_document(fetched: Option<&FetchedDocument>) -> Vec<Finding> {
+    let document = "server";
+
+    let Some(fetched) = fetched else {
+        return vec![finding(
+            document,
+            Severity::Info,
+            "unreachable",
+            "Document could not be fetched, federation falls back to SRV and port 8448",
+        )];
+    };
+
+    if fetched.status == 404 {
+        return vec![finding(
+            document,
+            Severity::Info,
+            "not_found",
+            "No server well-known, federation falls back to SRV and port 8448",
+        )];
+    }
+
+    if !(200..300).contains(&fetched.status) {
+        return vec![finding(
+            document,
+            Severity::Error,
+            "http_error",
+            format!("Returned HTTP {}", fetched.status),
+        )];
+    }
+
+    let (mut findings, json) = lint_common(document, fetched);
+
+    let Some(json) = json else {
+        return findings;
+    };
+
+    match json["m.server"].as_str() {
+        None => findings.push(finding(
+            document,
+            Severity::Error,
+            "missing_m_server",
+            "m.server is missing",
+        )),
+        Some(server) if server.contains("://") || server.contains('/') => findings.push(finding(
+            document,
+            Severity::Error,
+            "invalid_m_server",
+            format!(
+                "m.server {:?} must be host[:port] without scheme or path",
+                server
+            ),
+        )),
+        Some(server) => {
+            let (host, port) = split_host_port(server);
+            let has_port =
+                server.contains("]:") || (!server.starts_with('[') && server.contains(':'));
+            let bad_port = has_port && port.is_none();
+            if host.is_empty() || bad_port {
+                findings.push(finding(
+                    document,
+                    Severity::Error,
+                    "invalid_m_server",
+                    format!("m.server {:?} is not a valid host[:port]", server),
+                ));
+            }
+        }
+    }
+
+    findings
+}
+
+async fn fetch_document(url: &str) -> Option<FetchedDocument> {
+    let response = public_client(url).await.ok()?.get(url).send().await.ok()?;
+
+    let header = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from)
+    };
+
+    let status = response.status().as_u16();
+    let content_type = header("content-type");
+    let access_control_allow_origin = header("access-control-allow-origin");
+
+    Some(FetchedDocument {
+        status,
+        content_type,
+        access_control_allow_origin,
+        body: response.text().await.unwrap_or_default(),
+    })
+}
+
+/// Checks that `base_url` answers `/_matrix/client/versions`. Non-public
+/// targets are not contacted, and every failure reads the same, so the
+/// finding says nothing about what sits behind the URL.
+async fn check_base_url_reachable(base_url: &str) -> Option<Finding> {
+    let url = format!("{}/_matrix/client/versions", base_url.trim_end_matches('/'));
+
+    let reachable = match public_client(&url).await {
+        Ok(client) => client
+            .get(&url)
+            .send()
+            .await
+            .is_ok_and(|response| response.status().is_success()),
+        Err(_) => false,
+    };
+
+    (!reachable).then(|| {
+        finding(
+            "client",
+            Severity::Error,
+            "dead_base_url",
+            format!("{} is unreachable", url),
+        )
+    })
+}
+
+/// Fetches and lints both well-known documents for `domain`.
+pub async fn lint_well_known(domain: &str) -> Vec<Finding> {
+    let client = fetch_document(&format!("https://{}/.well-known/matrix/client", domain)).await;
+    let server = fetch_document(&format!("https://{}/.well-known/matrix/server", domain)).await;
+
+    let (mut findings, base_url) = lint_client_document(client.as_ref());
+
+    if let Some(base_url) = base_url {
+        findings.extend(check_base_url_reachable(&base_url).await);
+    }
+
+    findings.extend(lint_server_document(server.as_ref()));
+    findings
+}
+
+/// Lints `domain` and replaces the findings stored for `server_id`.
+pub async fn lint_and_store(
+    db_pool: &DbPool,
+    server_id: i64,
+    domain: &str,
+) -> Result<Vec<Finding>, Box<dyn std::error::Error + Send + Sync>> {
+    let findings = lint_well_known(domain).await;
+
+    let new_findings: Vec<NewWellKnownFinding> = findings
+        .iter()
+        .map(|f| NewWellKnownFinding {
+            server_id,
+            document: f.document,
+            severity: f.severity.as_str(),
+            code: f.code,
+            message: &f.message,
+        })
+        .collect();
+
+    let mut conn = db_pool.get()?;
+    replace_well_known_findings(&mut conn, server_id, &new_findings)?;
+
+    Ok(findings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn doc(body: &str) -> FetchedDocument {
+        FetchedDocument {
+            status: 200,
+            content_type: Some("application/json".to_string()),
+            access_control_allow_origin: Some("*".to_string()),
+            body: body.to_string(),
+        }
+    }
+
+    fn codes(findings: &[Finding]) -> Vec<&'static str> {
+        findings.iter().map(|f| f.code).collect()
+    }
+
+    #[test]
+    fn test_clean_client_document() {
+        let fetched = doc(r#"{"m.homeserver": {"base_url": "https://matrix.example.org"}}"#);
+        let (findings, base_url) = lint_client_document(Some(&fetched));
+
+        assert!(findings.is_empty());
+        assert_eq!(base_url.as_deref(), Some("https://matrix.example.org"));
+    }
+
+    #[test]
+    fn test_client_document_header_problems() {
+        let mut fetched = doc(r#"{"m.homeserver": {"base_url": "https://matrix.example.org"}}"#);
+        fetched.content_type = Some("text/plain".to_string());
+        fetched.access_control_allow_origin = None;
+
+        let (findings, _) = lint_client_document(Some(&fetched));
+        assert_eq!(codes(&findings), vec!["wrong_content_type", "missing_cors"]);
+    }
+
+    #[test]
+    fn test_client_document_content_type_with_charset() {
+        let mut fetched = doc(r#"{"m.homeserver": {"base_url": "https://matrix.example.org"}}"#);
+        fetched.content_type = Some("application/json; charset=utf-8".to_string());
+
+        let (findings, _) = lint_client_document(Some(&fetched));
+        assert!(findings.is_empty());
+    }
+
+    #[test]
+    fn test_client_document_invalid_json() {
+        let (findings, base_url) = lint_client_document(Some(&doc("{not json")));
+        assert_eq!(codes(&findings), vec!["invalid_json"]);
+        assert!(base_url.is_none());
+    }
+
+    #[test]
+    fn test_client_document_base_url_problems() {
+        let (findings, _) = lint_client_document(Some(&doc(
+            r#"{"m.homeserver": {"base_url": "http://x.org/"}}"#,
+        )));
+        assert_eq!(
+            codes(&findings),
+            vec!["insecure_base_url", "trailing_slash"]
+        );
+
+        let (findings, _) =
+            lint_client_document(Some(&doc(r#"{"m.homeserver": {"base_url": "matrix"}}"#)));
+        assert_eq!(codes(&findings), vec!["invalid_base_url"]);
+
+        let (findings, _) = lint_client_document(Some(&doc(r#"{"m.homeserver": {}}"#)));
+        assert_eq!(codes(&findings), vec!["missing_base_url"]);
+    }
+
+    #[test]
+    fn test_client_document_missing() {
+        let fetched = FetchedDocument {
+            status: 404,
+            ..Default::default()
+        };
+        let (findings, _) = lint_client_document(Some(&fetched));
+        assert_eq!(findings[0].severity, Severity::Warning);
+        assert_eq!(findings[0].code, "not_found");
+    }
+
+    #[test]
+    fn test_server_document() {
+        assert!(
+            lint_server_document(Some(&doc(r#"{"m.server": "fed.example.org:443"}"#))).is_empty()
+        );
+
+        let findings =
+            lint_server_document(Some(&doc(r#"{"m.server": "https://fed.example.org"}"#)));
+        assert_eq!(codes(&findings), vec!["invalid_m_server"]);
+
+        let findings = lint_server_document(Some(&doc(r#"{"m.server": "fed.example.org:abc"}"#)));
+        assert_eq!(codes(&findings), vec!["invalid_m_server"]);
+
+        let findings = lint_server_document(Some(&doc("{}")));
+        assert_eq!(codes(&findings), vec!["missing_m_server"]);
+    }
+
+    #[test]
+    fn test_missing_server_document_is_info() {
+        let fetched = FetchedDocument {
+            status: 404,
+            ..Default::default()
+        };
+        let findings = lint_server_document(Some(&fetched));
+        assert_eq!(findings[0].severity, Severity::Info);
+    }
+}